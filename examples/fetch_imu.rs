@@ -18,7 +18,7 @@ fn main() -> Result<()> {
     let port_name = "/dev/cu.usbmodem3754346A31331";
     let baud_rate = 1_000_000;
 
-    let mut port = wait_for_port(port_name, baud_rate, 200);
+    let mut port = wait_for_port(port_name, baud_rate, 200)?;
 
     let mut raw_cr = MspRc::new();
 
@@ -59,7 +59,7 @@ fn main() -> Result<()> {
                     },
                     Ok(MspCommandCode::MSP_BATTERY_STATE) => {
                         let byte = pkt.decode_as::<MspBatteryState>()?;
-                        println!("Cell V: {}", byte.cell_voltage());
+                        println!("Cell V: {:?}", byte.cell_voltage());
                     },
                     Ok(MspCommandCode::MSP_RC) => {
                         let byte = pkt.decode_as::<MspRc>()?;
@@ -0,0 +1,343 @@
+//! Voltage and current meters with their calibration, and battery state with capacity and
+//! flight time estimates
+
+use std::time::Duration;
+use anyhow::{Error, Result};
+use packed_struct::{PackedStructInfo, PackedStructSlice};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{
+        MspAmperageMeterConfig, MspBatteryState, MspCurrentMeter, MspSetAmperageMeterConfig,
+        MspSetVoltageMeterConfig, MspVoltageMeter, MspVoltageMeterConfig,
+    },
+};
+use crate::units::{AmpHours, Amperes, Volts};
+
+/// Meter id of the main battery on Betaflight, for both voltage and current
+pub const METER_ID_BATTERY: u8 = 10;
+
+/// The battery state byte of `MSP_BATTERY_STATE`
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum BatteryStatus {
+    Ok,
+    /// Below the warning cell voltage or the capacity warning
+    Warning,
+    /// Below the minimum cell voltage
+    Critical,
+    NotPresent,
+    /// Still detecting the cell count
+    Init,
+    Unknown(u8),
+}
+
+impl BatteryStatus {
+    pub fn from_u8(value: u8) -> BatteryStatus {
+        match value {
+            0 => BatteryStatus::Ok,
+            1 => BatteryStatus::Warning,
+            2 => BatteryStatus::Critical,
+            3 => BatteryStatus::NotPresent,
+            4 => BatteryStatus::Init,
+            v => BatteryStatus::Unknown(v),
+        }
+    }
+}
+
+fn decode_list<T: PackedStructSlice + PackedStructInfo>(data: &[u8]) -> Result<Vec<T>> {
+    let size = T::packed_bits() / 8;
+    if !data.len().is_multiple_of(size) {
+        return Err(Error::msg(format!("Meter list of {} bytes is not made of {} byte entries", data.len(), size)));
+    }
+    Ok(data.chunks_exact(size).map(T::unpack_from_slice).collect::<Result<_, _>>()?)
+}
+
+/// Entries of a meter config reply: a count, then each entry behind its length so newer
+/// firmware can append fields
+fn decode_configs<T: PackedStructSlice + PackedStructInfo>(data: &[u8]) -> Result<Vec<T>> {
+    let size = T::packed_bits() / 8;
+    let (&count, mut rest) = data.split_first().ok_or_else(|| Error::msg("Empty meter config"))?;
+    let mut configs = Vec::with_capacity(count as usize);
+    for i in 0..count {
+        let entry = rest
+            .split_first()
+            .and_then(|(&len, tail)| Some((tail.get(..len as usize)?, &tail[len as usize..])))
+            .filter(|(entry, _)| entry.len() >= size)
+            .ok_or_else(|| Error::msg(format!("Meter config {} of {} is truncated", i + 1, count)))?;
+        configs.push(T::unpack_from_slice(&entry.0[..size])?);
+        rest = entry.1;
+    }
+    Ok(configs)
+}
+
+/// Split an `MSP_VOLTAGE_METERS` reply into its meters
+pub fn decode_voltage_meters(data: &[u8]) -> Result<Vec<MspVoltageMeter>> {
+    decode_list(data)
+}
+
+/// Split an `MSP_AMPERAGE_METERS` reply into its meters
+pub fn decode_amperage_meters(data: &[u8]) -> Result<Vec<MspCurrentMeter>> {
+    decode_list(data)
+}
+
+pub fn decode_voltage_meter_configs(data: &[u8]) -> Result<Vec<MspVoltageMeterConfig>> {
+    decode_configs(data)
+}
+
+pub fn decode_amperage_meter_configs(data: &[u8]) -> Result<Vec<MspAmperageMeterConfig>> {
+    decode_configs(data)
+}
+
+pub fn read_voltage_meters(port: &mut dyn SerialPort) -> Result<Vec<MspVoltageMeter>> {
+    decode_voltage_meters(request(port, MspCommandCode::MSP_VOLTAGE_METERS as u16, &[])?.data.as_slice())
+}
+
+pub fn read_amperage_meters(port: &mut dyn SerialPort) -> Result<Vec<MspCurrentMeter>> {
+    decode_amperage_meters(request(port, MspCommandCode::MSP_AMPERAGE_METERS as u16, &[])?.data.as_slice())
+}
+
+/// Configs of the ADC voltage meters, the others have nothing to calibrate
+pub fn read_voltage_meter_configs(port: &mut dyn SerialPort) -> Result<Vec<MspVoltageMeterConfig>> {
+    decode_voltage_meter_configs(request(port, MspCommandCode::MSP_VOLTAGE_METER_CONFIG as u16, &[])?.data.as_slice())
+}
+
+pub fn read_amperage_meter_configs(port: &mut dyn SerialPort) -> Result<Vec<MspAmperageMeterConfig>> {
+    decode_amperage_meter_configs(
+        request(port, MspCommandCode::MSP_AMPERAGE_METER_CONFIG as u16, &[])?.data.as_slice(),
+    )
+}
+
+/// Voltage scale that makes a meter reading `measured` with `scale` read `reference`. The
+/// firmware voltage is proportional to the scale.
+pub fn voltage_scale(scale: u8, measured: Volts, reference: Volts) -> Result<u8> {
+    if measured.0 <= 0.0 || reference.0 <= 0.0 {
+        return Err(Error::msg(format!("Cannot calibrate from {} against {}", measured, reference)));
+    }
+    let fitted = (scale as f32 * reference.0 / measured.0).round();
+    if !(1.0..=255.0).contains(&fitted) {
+        return Err(Error::msg(format!("Voltage scale {} out of range", fitted)));
+    }
+    Ok(fitted as u8)
+}
+
+/// Current scale and offset fitted to `(measured, reference)` pairs taken with `scale` and
+/// `offset` in use. The firmware computes mA as `mV * 10000 / scale + offset`, so one pair
+/// only fixes the scale and at least two spread out pairs also fit the offset.
+pub fn amperage_calibration(scale: i16, offset: i16, readings: &[(Amperes, Amperes)]) -> Result<(i16, i16)> {
+    if scale == 0 || readings.is_empty() {
+        return Err(Error::msg("Current calibration needs a non-zero scale and a reading"));
+    }
+    // sensor output in the firmware's units, before scale and offset are applied
+    let points: Vec<(f64, f64)> = readings
+        .iter()
+        .map(|(m, r)| ((m.0 as f64 * 1000.0 - offset as f64) * scale as f64, r.0 as f64 * 1000.0))
+        .collect();
+
+    let (gain, fitted_offset) = match points.len() {
+        1 => ((points[0].1 - offset as f64) / points[0].0, offset as f64),
+        n => {
+            let n = n as f64;
+            let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+            let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+            let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+            let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+            if sxx == 0.0 {
+                return Err(Error::msg("Current readings all measure the same"));
+            }
+            (sxy / sxx, mean_y - sxy / sxx * mean_x)
+        }
+    };
+
+    let fitted_scale = (1.0 / gain).round();
+    if !gain.is_finite() || gain == 0.0 || fitted_scale.abs() > i16::MAX as f64 || fitted_scale == 0.0 {
+        return Err(Error::msg("Current readings do not fit a scale"));
+    }
+    if fitted_offset.abs() > i16::MAX as f64 {
+        return Err(Error::msg(format!("Current offset {} mA out of range", fitted_offset)));
+    }
+    Ok((fitted_scale as i16, fitted_offset.round() as i16))
+}
+
+fn config_of<T: Copy>(configs: &[T], id: u8, id_of: impl Fn(&T) -> u8) -> Result<T> {
+    configs
+        .iter()
+        .find(|c| id_of(c) == id)
+        .copied()
+        .ok_or_else(|| Error::msg(format!("No configurable meter {}", id)))
+}
+
+/// Calibrate voltage meter `id` so it reads `reference` now, measured with a multimeter on the
+/// battery leads. Returns the new config once it reads back.
+pub fn calibrate_voltage_meter(port: &mut dyn SerialPort, id: u8, reference: Volts) -> Result<MspVoltageMeterConfig> {
+    let config = config_of(&read_voltage_meter_configs(port)?, id, |c| c.id)?;
+    let meter = read_voltage_meters(port)?
+        .into_iter()
+        .find(|m| m.id == id)
+        .ok_or_else(|| Error::msg(format!("Voltage meter {} is not reporting", id)))?;
+
+    let vbat_scale = voltage_scale(config.vbat_scale, Volts(meter.value as f32 / 10.0), reference)?;
+    let set = MspSetVoltageMeterConfig {
+        id,
+        vbat_scale,
+        vbat_res_div_val: config.vbat_res_div_val,
+        vbat_res_div_multiplier: config.vbat_res_div_multiplier,
+    };
+    request(port, MspCommandCode::MSP_SET_VOLTAGE_METER_CONFIG as u16, &set.pack_to_vec()?)?;
+
+    let stored = config_of(&read_voltage_meter_configs(port)?, id, |c| c.id)?;
+    if stored.vbat_scale != vbat_scale {
+        return Err(Error::msg(format!("Voltage meter {} read back as {:?}", id, stored)));
+    }
+    Ok(stored)
+}
+
+/// Calibrate current meter `id` from `(measured, reference)` pairs, see
+/// [`amperage_calibration`]. Returns the new config once it reads back.
+pub fn calibrate_amperage_meter(
+    port: &mut dyn SerialPort,
+    id: u8,
+    readings: &[(Amperes, Amperes)],
+) -> Result<MspAmperageMeterConfig> {
+    let config = config_of(&read_amperage_meter_configs(port)?, id, |c| c.id)?;
+    let (scale, offset) = amperage_calibration(config.scale, config.offset, readings)?;
+    let set = MspSetAmperageMeterConfig { id, scale, offset };
+    request(port, MspCommandCode::MSP_SET_AMPERAGE_METER_CONFIG as u16, &set.pack_to_vec()?)?;
+
+    let stored = config_of(&read_amperage_meter_configs(port)?, id, |c| c.id)?;
+    if (stored.scale, stored.offset) != (scale, offset) {
+        return Err(Error::msg(format!("Current meter {} read back as {:?}", id, stored)));
+    }
+    Ok(stored)
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct BatteryEstimate {
+    pub status: BatteryStatus,
+    /// Charge left before the reserve, `None` without a configured capacity
+    pub remaining: Option<AmpHours>,
+    /// Of the usable capacity, 0 to 1
+    pub remaining_fraction: Option<f32>,
+    /// Average draw so far, or the current draw when there is no flight time yet
+    pub average_current: Amperes,
+    /// Until the reserve at `average_current`
+    pub flight_time: Option<Duration>,
+}
+
+impl MspBatteryState {
+    pub fn status(&self) -> BatteryStatus {
+        BatteryStatus::from_u8(self.alerts)
+    }
+
+    /// Estimate what is left of the battery after `flown` on it, keeping `reserve` (0 to 1) of
+    /// the capacity for landing
+    pub fn estimate(&self, flown: Duration, reserve: f32) -> BatteryEstimate {
+        let usable = self.battery_capacity as f32 * (1.0 - reserve.clamp(0.0, 1.0));
+        let remaining_mah = (self.battery_capacity > 0).then(|| (usable - self.mah_drawn as f32).max(0.0));
+
+        let hours = flown.as_secs_f32() / 3600.0;
+        let average_current = if hours > 0.0 && self.mah_drawn > 0 {
+            Amperes(self.mah_drawn as f32 / 1000.0 / hours)
+        } else {
+            Amperes(self.amperage.max(0) as f32 / 100.0)
+        };
+        let flight_time = remaining_mah
+            .filter(|_| average_current.0 > 0.0)
+            .map(|mah| Duration::from_secs_f32(mah / 1000.0 / average_current.0 * 3600.0));
+
+        BatteryEstimate {
+            status: self.status(),
+            remaining: remaining_mah.map(|mah| AmpHours(mah / 1000.0)),
+            remaining_fraction: remaining_mah.filter(|_| usable > 0.0).map(|mah| mah / usable),
+            average_current,
+            flight_time,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+
+    #[test]
+    fn meter_lists_and_voltage_calibration() {
+        let amperage = decode_amperage_meters(&[10, 0xF4, 0x01, 0x88, 0x13, 80, 0, 0, 0, 0]).unwrap();
+        assert_eq!((2, 500, 5000), (amperage.len(), amperage[0].mah_drawn, amperage[0].amperage));
+        assert!(decode_amperage_meters(&[10, 0, 0]).is_err());
+        // a newer entry with an extra byte
+        let configs = decode_amperage_meter_configs(&[1, 7, 10, 1, 0x90, 0x01, 0xF6, 0xFF, 9]).unwrap();
+        assert_eq!(MspAmperageMeterConfig { id: 10, sensor_type: 1, scale: 400, offset: -10 }, configs[0]);
+        assert!(decode_voltage_meter_configs(&[2, 5, 10, 0, 110, 10, 1]).is_err());
+
+        let mut voltage_config = vec![1, 5, 10, 0, 110, 10, 1];
+        let mut fc = MockFc::new()
+            .reply(MSP_VOLTAGE_METERS as u16, &[10, 162, 20, 0])
+            .handler(move |p| match p.cmd {
+                c if c == MSP_VOLTAGE_METER_CONFIG as u16 => Some(voltage_config.clone()),
+                c if c == MSP_SET_VOLTAGE_METER_CONFIG as u16 => {
+                    voltage_config[4] = p.data.as_slice()[1];
+                    Some(Vec::new())
+                }
+                _ => None,
+            });
+
+        // reads 16.2 V against 16.8 V on a multimeter
+        let calibrated = calibrate_voltage_meter(&mut fc, METER_ID_BATTERY, Volts(16.8)).unwrap();
+        assert_eq!(114, calibrated.vbat_scale);
+        assert_eq!((10, 1), (calibrated.vbat_res_div_val, calibrated.vbat_res_div_multiplier));
+        assert!(calibrate_voltage_meter(&mut fc, 20, Volts(16.8)).is_err());
+    }
+
+    #[test]
+    fn current_calibration_and_estimates() {
+        // reads 10% high at 10 A with no offset
+        assert_eq!((440, 0), amperage_calibration(400, 0, &[(Amperes(11.0), Amperes(10.0))]).unwrap());
+        // 0.5 A offset and 20% high
+        let readings = [(Amperes(1.8), Amperes(2.0)), (Amperes(11.4), Amperes(10.0))];
+        assert_eq!((480, 500), amperage_calibration(400, 0, &readings).unwrap());
+        assert!(amperage_calibration(400, 0, &[(Amperes(5.0), Amperes(4.0)); 2]).is_err());
+
+        let battery = MspBatteryState {
+            battery_cell_count: 4,
+            battery_capacity: 1500,
+            battery_voltage: 152,
+            mah_drawn: 600,
+            amperage: 2500,
+            alerts: 1,
+        };
+        let estimate = battery.estimate(Duration::from_secs(120), 0.2);
+        assert_eq!(BatteryStatus::Warning, estimate.status);
+        assert_eq!(Some(AmpHours(0.6)), estimate.remaining);
+        assert_eq!(Some(0.5), estimate.remaining_fraction);
+        assert!((estimate.average_current.0 - 18.0).abs() < 1e-3);
+        assert_eq!(120, estimate.flight_time.unwrap().as_secs_f32().round() as u64);
+
+        let idle = MspBatteryState { battery_capacity: 0, mah_drawn: 0, ..battery }.estimate(Duration::ZERO, 0.2);
+        assert_eq!((None, Amperes(25.0), None), (idle.remaining, idle.average_current, idle.flight_time));
+    }
+
+    #[test]
+    fn calibration_errors_send_nothing_or_fail_read_back() {
+        // a firmware that acknowledges the new current scale without storing it
+        let mut fc = MockFc::new()
+            .reply(MSP_AMPERAGE_METER_CONFIG as u16, &[1, 6, 10, 1, 0x90, 0x01, 0, 0])
+            .reply(MSP_SET_AMPERAGE_METER_CONFIG as u16, &[])
+            .reply(MSP_VOLTAGE_METER_CONFIG as u16, &[1, 5, 10, 0, 110, 10, 1])
+            .reply(MSP_VOLTAGE_METERS as u16, &[10, 162]);
+
+        let error = calibrate_amperage_meter(&mut fc, 10, &[(Amperes(11.0), Amperes(10.0))]).unwrap_err();
+        assert!(error.to_string().starts_with("Current meter 10 read back as"));
+        let set = fc.requests.iter().find(|p| p.cmd == MSP_SET_AMPERAGE_METER_CONFIG as u16).unwrap();
+        assert_eq!([10, 0xB8, 0x01, 0, 0], set.data.as_slice());
+        assert!(calibrate_amperage_meter(&mut fc, 11, &[(Amperes(11.0), Amperes(10.0))]).is_err());
+
+        // 16.2 V against 40 V needs a scale above 255, which is refused before sending
+        let error = calibrate_voltage_meter(&mut fc, METER_ID_BATTERY, Volts(40.0)).unwrap_err();
+        assert_eq!("Voltage scale 272 out of range", error.to_string());
+        assert!(!fc.commands().contains(&(MSP_SET_VOLTAGE_METER_CONFIG as u16)));
+    }
+}
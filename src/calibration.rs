@@ -0,0 +1,355 @@
+//! Accelerometer and magnetometer calibration, board alignment and accelerometer trim
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspAccTrim, MspAttitude, MspBoardAlignment, MspRawImu},
+};
+use crate::status::{ArmingDisabled, FcStatus};
+use crate::units::{Imu, ImuScale};
+
+/// Largest angle trim the firmware takes [deg]
+pub const MAX_ACC_TRIM: f32 = 30.0;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum CalibrationKind {
+    Accelerometer,
+    Magnetometer,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct CalibrationReport {
+    pub kind: CalibrationKind,
+    pub elapsed: Duration,
+    /// Sensor readings right after calibrating
+    pub imu: Imu,
+    /// Angle between the measured gravity vector and straight down [deg], accelerometer only.
+    /// Should be close to zero as the board has to be level while calibrating.
+    pub tilt: Option<f32>,
+}
+
+/// Board orientation relative to the frame [deg]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct BoardAlignment {
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+/// Calibration workflows for a disarmed flight controller. Completion is taken from the
+/// `CALIB` arming-disable flag of `MSP_STATUS_EX`.
+pub struct Calibration<'a> {
+    port: &'a mut dyn SerialPort,
+    firmware: FirmwareInfo,
+    scale: ImuScale,
+    poll: Duration,
+    timeout: Duration,
+}
+
+impl<'a> Calibration<'a> {
+    pub fn new(port: &'a mut dyn SerialPort, firmware: &FirmwareInfo) -> Calibration<'a> {
+        Calibration {
+            port,
+            firmware: firmware.clone(),
+            scale: ImuScale::for_firmware(firmware),
+            poll: Duration::from_millis(100),
+            timeout: Duration::from_secs(10),
+        }
+    }
+
+    /// Scale of the raw IMU readings in reports, [`ImuScale::for_firmware`] by default
+    pub fn with_scale(mut self, scale: ImuScale) -> Self {
+        self.scale = scale;
+        self
+    }
+
+    /// How often calibration status is polled, 100 ms by default
+    pub fn with_poll_interval(mut self, poll: Duration) -> Self {
+        self.poll = poll;
+        self
+    }
+
+    /// Longest an accelerometer calibration may take, 10 s by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Calibrate the accelerometer. The board has to sit level and still until this returns.
+    pub fn accelerometer(&mut self) -> Result<CalibrationReport> {
+        self.check_disarmed()?;
+        let started = Instant::now();
+        request(self.port, MspCommandCode::MSP_ACC_CALIBRATION as u16, &[])?;
+
+        loop {
+            sleep(self.poll);
+            if !FcStatus::read(self.port, &self.firmware)?.arming_disabled.contains(ArmingDisabled::CALIBRATING) {
+                break;
+            }
+            if started.elapsed() > self.timeout {
+                return Err(Error::msg("Accelerometer calibration did not finish"));
+            }
+        }
+
+        let imu = self.read_imu()?;
+        let [x, y, z] = imu.acc.map(|a| a.0);
+        let tilt = (x.hypot(y)).atan2(z).to_degrees();
+        Ok(CalibrationReport {
+            kind: CalibrationKind::Accelerometer,
+            elapsed: started.elapsed(),
+            imu,
+            tilt: Some(tilt),
+        })
+    }
+
+    /// Calibrate the magnetometer. The craft has to be turned through every orientation for
+    /// `duration`, 30 s on Betaflight and iNav.
+    pub fn magnetometer(&mut self, duration: Duration) -> Result<CalibrationReport> {
+        self.check_disarmed()?;
+        let started = Instant::now();
+        request(self.port, MspCommandCode::MSP_MAG_CALIBRATION as u16, &[])?;
+
+        while started.elapsed() < duration {
+            sleep(self.poll.min(duration.saturating_sub(started.elapsed())));
+            // keeps the link alive and notices a disconnect early
+            FcStatus::read(self.port, &self.firmware)?;
+        }
+
+        let imu = self.read_imu()?;
+        if imu.mag == [0.0; 3] {
+            return Err(Error::msg("Magnetometer reads zero after calibration"));
+        }
+        Ok(CalibrationReport { kind: CalibrationKind::Magnetometer, elapsed: started.elapsed(), imu, tilt: None })
+    }
+
+    /// Board alignment units per degree, iNav sends decidegrees
+    fn alignment_scale(&self) -> f32 {
+        match self.firmware.variant {
+            FirmwareVariant::Inav => 10.0,
+            _ => 1.0,
+        }
+    }
+
+    pub fn board_alignment(&mut self) -> Result<BoardAlignment> {
+        let raw =
+            request(self.port, MspCommandCode::MSP_BOARD_ALIGNMENT as u16, &[])?.decode_as::<MspBoardAlignment>()?;
+        let scale = self.alignment_scale();
+        Ok(BoardAlignment {
+            roll: raw.roll as f32 / scale,
+            pitch: raw.pitch as f32 / scale,
+            yaw: raw.yaw as f32 / scale,
+        })
+    }
+
+    /// Set how the board is mounted in the frame from measured angles [deg], rounded to whole
+    /// degrees on Betaflight and tenths on iNav, with yaw wrapped to ±180
+    pub fn set_board_alignment(&mut self, roll: f32, pitch: f32, yaw: f32) -> Result<BoardAlignment> {
+        if roll.abs() > 180.0 || pitch.abs() > 90.0 {
+            return Err(Error::msg(format!("Board alignment roll {} pitch {} out of range", roll, pitch)));
+        }
+        let scale = self.alignment_scale();
+        let raw = |deg: f32| (((deg + 180.0).rem_euclid(360.0) - 180.0) * scale).round() as i16;
+        let alignment = MspBoardAlignment { roll: raw(roll), pitch: raw(pitch), yaw: raw(yaw) };
+        request(self.port, MspCommandCode::MSP_SET_BOARD_ALIGNMENT as u16, &alignment.pack_to_vec()?)?;
+
+        let stored =
+            request(self.port, MspCommandCode::MSP_BOARD_ALIGNMENT as u16, &[])?.decode_as::<MspBoardAlignment>()?;
+        if stored != alignment {
+            return Err(Error::msg(format!("Board alignment read back as {:?}", stored)));
+        }
+        self.board_alignment()
+    }
+
+    /// With the frame sitting level, fold the attitude the flight controller reports into the
+    /// board alignment so it reads level too
+    pub fn level_board_alignment(&mut self) -> Result<BoardAlignment> {
+        let current = self.board_alignment()?;
+        let attitude = request(self.port, MspCommandCode::MSP_ATTITUDE as u16, &[])?.decode_as::<MspAttitude>()?;
+        self.set_board_alignment(
+            current.roll + attitude.roll as f32 / 10.0,
+            current.pitch + attitude.pitch as f32 / 10.0,
+            current.yaw,
+        )
+    }
+
+    /// Accelerometer trim as (pitch, roll) [deg]. Not on iNav, which levels by board alignment.
+    pub fn acc_trim(&mut self) -> Result<(f32, f32)> {
+        self.check_acc_trim()?;
+        let trim = request(self.port, MspCommandCode::MSP_ACC_TRIM as u16, &[])?.decode_as::<MspAccTrim>()?;
+        Ok((trim.pitch as f32 / 10.0, trim.roll as f32 / 10.0))
+    }
+
+    /// Trim the level the self-levelling modes hold [deg], within ±[`MAX_ACC_TRIM`]
+    pub fn set_acc_trim(&mut self, pitch: f32, roll: f32) -> Result<()> {
+        self.check_acc_trim()?;
+        if pitch.abs() > MAX_ACC_TRIM || roll.abs() > MAX_ACC_TRIM {
+            return Err(Error::msg(format!(
+                "Accelerometer trim pitch {} roll {} beyond ±{}°",
+                pitch, roll, MAX_ACC_TRIM
+            )));
+        }
+        let trim = MspAccTrim { pitch: (pitch * 10.0).round() as i16, roll: (roll * 10.0).round() as i16 };
+        request(self.port, MspCommandCode::MSP_SET_ACC_TRIM as u16, &trim.pack_to_vec()?)?;
+
+        let stored = request(self.port, MspCommandCode::MSP_ACC_TRIM as u16, &[])?.decode_as::<MspAccTrim>()?;
+        if stored != trim {
+            return Err(Error::msg(format!("Accelerometer trim read back as {:?}", stored)));
+        }
+        Ok(())
+    }
+
+    /// iNav answers `MSP_ACC_TRIM` with zeros and ignores `MSP_SET_ACC_TRIM`
+    fn check_acc_trim(&self) -> Result<()> {
+        if self.firmware.variant == FirmwareVariant::Inav {
+            return Err(Error::msg("iNav has no accelerometer trim, level with the board alignment instead"));
+        }
+        Ok(())
+    }
+
+    /// Store alignment and trim in EEPROM, calibrations are saved by the firmware itself
+    pub fn save(&mut self) -> Result<()> {
+        request(self.port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        Ok(())
+    }
+
+    fn check_disarmed(&mut self) -> Result<()> {
+        if FcStatus::read(self.port, &self.firmware)?.armed {
+            return Err(Error::msg("Cannot calibrate while armed"));
+        }
+        Ok(())
+    }
+
+    fn read_imu(&mut self) -> Result<Imu> {
+        let raw = request(self.port, MspCommandCode::MSP_RAW_IMU as u16, &[])?.decode_as::<MspRawImu>()?;
+        Ok(raw.to_si(&self.scale))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::firmware::{FirmwareVariant, test_firmware};
+    use crate::status::{inav_status_payload, status_ex_payload};
+    use packed_struct::PackedStruct;
+
+    #[test]
+    fn accelerometer_calibration_waits_for_flag() {
+        let imu = MspRawImu {
+            acc_x: 9,
+            acc_y: 0,
+            acc_z: 512,
+            gyro_x: 0,
+            gyro_y: 0,
+            gyro_z: 0,
+            mag_x: 0,
+            mag_y: 0,
+            mag_z: 0,
+        };
+        let mut polls = 0;
+        let mut fc = MockFc::new()
+            .reply(MSP_ACC_CALIBRATION as u16, &[])
+            .reply(MSP_RAW_IMU as u16, &imu.pack().unwrap())
+            .handler(move |p| {
+                (p.cmd == MSP_STATUS_EX as u16).then(|| {
+                    polls += 1;
+                    // the first poll is the armed check
+                    status_ex_payload(0, if (2..4).contains(&polls) { ArmingDisabled::CALIBRATING } else { 0 })
+                })
+            });
+
+        let bf = test_firmware(FirmwareVariant::Betaflight, 46);
+        let mut calibration = Calibration::new(&mut fc, &bf).with_poll_interval(Duration::from_millis(1));
+        let report = calibration.accelerometer().unwrap();
+        assert!((report.tilt.unwrap() - 1.0).abs() < 0.01);
+        assert!(calibration.magnetometer(Duration::from_millis(5)).is_err());
+
+        let status_polls = fc.commands().iter().filter(|c| **c == MSP_STATUS_EX as u16).count();
+        assert!(status_polls >= 4);
+
+        let mut fc = MockFc::new().reply(MSP_STATUS_EX as u16, &status_ex_payload(1, 0));
+        assert!(Calibration::new(&mut fc, &bf).accelerometer().is_err());
+        assert!(!fc.commands().contains(&(MSP_ACC_CALIBRATION as u16)));
+
+        // iNav reports calibration through its own arming flags, here calibrating on every poll
+        let inav = test_firmware(FirmwareVariant::Inav, 5);
+        let mut fc = MockFc::new()
+            .reply(MSP_ACC_CALIBRATION as u16, &[])
+            .reply(MSP2_INAV_STATUS as u16, &inav_status_payload(0, 1 << 9, 0));
+        let err = Calibration::new(&mut fc, &inav)
+            .with_poll_interval(Duration::from_millis(1))
+            .with_timeout(Duration::from_millis(5))
+            .accelerometer()
+            .unwrap_err();
+        assert_eq!("Accelerometer calibration did not finish", err.to_string());
+        assert!(!fc.commands().contains(&(MSP_STATUS_EX as u16)));
+    }
+
+    #[test]
+    fn alignment_and_trim_in_degrees() {
+        let mut fc = MockFc::new()
+            .reply(MSP_BOARD_ALIGNMENT as u16, &[0, 0, 0, 0, 0, 0])
+            .setter(MSP_SET_BOARD_ALIGNMENT as u16, MSP_BOARD_ALIGNMENT as u16)
+            .reply(MSP_ACC_TRIM as u16, &[0, 0, 0, 0])
+            .setter(MSP_SET_ACC_TRIM as u16, MSP_ACC_TRIM as u16)
+            .reply(MSP_ATTITUDE as u16, &MspAttitude { roll: -32, pitch: 181, yaw: 90 }.pack().unwrap());
+        let bf = test_firmware(FirmwareVariant::Betaflight, 46);
+        let mut calibration = Calibration::new(&mut fc, &bf);
+
+        let set = calibration.set_board_alignment(0.4, -1.6, 270.0).unwrap();
+        assert_eq!(BoardAlignment { roll: 0.0, pitch: -2.0, yaw: -90.0 }, set);
+        assert!(calibration.set_board_alignment(0.0, 95.0, 0.0).is_err());
+
+        // level frame reads 3.2° left and 18.1° nose up
+        let level = calibration.level_board_alignment().unwrap();
+        assert_eq!(BoardAlignment { roll: -3.0, pitch: 16.0, yaw: -90.0 }, level);
+
+        calibration.set_acc_trim(-1.5, 0.3).unwrap();
+        assert_eq!((-1.5, 0.3), calibration.acc_trim().unwrap());
+        assert!(calibration.set_acc_trim(31.0, 0.0).is_err());
+        assert_eq!(Some(&[0xF1, 0xFF, 3, 0][..]), fc.value(MSP_ACC_TRIM as u16));
+    }
+
+    #[test]
+    fn inav_alignment_in_decidegrees() {
+        let mut fc = MockFc::new()
+            .reply(MSP_BOARD_ALIGNMENT as u16, &[0, 0, 0, 0, 0, 0])
+            .setter(MSP_SET_BOARD_ALIGNMENT as u16, MSP_BOARD_ALIGNMENT as u16)
+            .reply(MSP_ACC_TRIM as u16, &[0, 0, 0, 0])
+            .reply(MSP_SET_ACC_TRIM as u16, &[])
+            .reply(MSP_ATTITUDE as u16, &MspAttitude { roll: -32, pitch: 181, yaw: 90 }.pack().unwrap());
+        let inav = test_firmware(FirmwareVariant::Inav, 5);
+        let mut calibration = Calibration::new(&mut fc, &inav);
+
+        let set = calibration.set_board_alignment(0.44, -1.6, 270.0).unwrap();
+        assert_eq!(BoardAlignment { roll: 0.4, pitch: -1.6, yaw: -90.0 }, set);
+        let level = calibration.level_board_alignment().unwrap();
+        assert_eq!(BoardAlignment { roll: -2.8, pitch: 16.5, yaw: -90.0 }, level);
+
+        // trim is refused before anything is sent
+        assert!(calibration.set_acc_trim(1.0, 0.0).is_err());
+        assert!(calibration.acc_trim().is_err());
+        drop(calibration);
+        assert_eq!(
+            MspBoardAlignment { roll: -28, pitch: 165, yaw: -900 }.pack().unwrap(),
+            fc.value(MSP_BOARD_ALIGNMENT as u16).unwrap()
+        );
+        assert!(!fc.commands().iter().any(|c| *c == MSP_SET_ACC_TRIM as u16 || *c == MSP_ACC_TRIM as u16));
+
+        // an alignment the firmware does not keep is reported
+        let mut fc = MockFc::new()
+            .reply(MSP_BOARD_ALIGNMENT as u16, &[0, 0, 0, 0, 0, 0])
+            .reply(MSP_SET_BOARD_ALIGNMENT as u16, &[]);
+        let err = Calibration::new(&mut fc, &inav).set_board_alignment(10.0, 0.0, 0.0).unwrap_err();
+        assert!(err.to_string().starts_with("Board alignment read back as"));
+    }
+}
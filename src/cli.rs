@@ -0,0 +1,367 @@
+//! Betaflight CLI passthrough on the MSP serial port
+
+use std::io;
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Error, Result};
+use serialport::SerialPort;
+
+use crate::helpers::wait_for_port_timeout;
+
+const PROMPT: &str = "\r\n# ";
+/// How long the port has to stay silent after a prompt before the output counts as complete
+const PROMPT_QUIET: Duration = Duration::from_millis(100);
+
+/// Which profile a `set` line of `diff all` applies to
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DiffScope {
+    Master,
+    Profile(u8),
+    RateProfile(u8),
+}
+
+/// A line of `diff all` / `dump` output
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiffLine {
+    /// `set name = value`
+    Set { scope: DiffScope, name: String, value: String },
+    /// `feature GPS` or `feature -AIRMODE`
+    Feature { name: String, enabled: bool },
+    /// `aux index mode channel start end logic linked`
+    Aux {
+        index: u8,
+        mode_id: u8,
+        channel: u8,
+        range_start: u16,
+        range_end: u16,
+        logic: u8,
+        linked_to: u8,
+    },
+    /// `resource MOTOR 1 B00`, `pin` is `NONE` for freed resources
+    Resource { function: String, index: u8, pin: String },
+    /// Any other command, such as `serial`, `board_name` or `profile`
+    Other(String),
+}
+
+/// Parsed output of `diff all`
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct CliDiff {
+    /// Firmware banner from the `# version` section
+    pub version: Option<String>,
+    pub lines: Vec<DiffLine>,
+}
+
+impl CliDiff {
+    pub fn parse(output: &str) -> CliDiff {
+        let mut diff = CliDiff::default();
+        let mut scope = DiffScope::Master;
+        let mut after_version = false;
+
+        for line in output.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            if let Some(comment) = line.strip_prefix('#') {
+                let comment = comment.trim();
+                if after_version && diff.version.is_none() {
+                    diff.version = Some(comment.to_owned());
+                }
+                after_version = comment == "version";
+                continue;
+            }
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let parsed = match words.as_slice() {
+                ["set", name, "=", value @ ..] => Some(DiffLine::Set {
+                    scope,
+                    name: (*name).to_owned(),
+                    value: value.join(" "),
+                }),
+                ["feature", name] => Some(match name.strip_prefix('-') {
+                    Some(name) => DiffLine::Feature { name: name.to_owned(), enabled: false },
+                    None => DiffLine::Feature { name: (*name).to_owned(), enabled: true },
+                }),
+                ["aux", fields @ ..] if fields.len() >= 7 => Self::parse_aux(fields),
+                ["resource", function, index, pin] => index.parse().ok().map(|index| DiffLine::Resource {
+                    function: (*function).to_owned(),
+                    index,
+                    pin: (*pin).to_owned(),
+                }),
+                ["profile", index] => {
+                    scope = index.parse().map(DiffScope::Profile).unwrap_or(scope);
+                    None
+                }
+                ["rateprofile", index] => {
+                    scope = index.parse().map(DiffScope::RateProfile).unwrap_or(scope);
+                    None
+                }
+                _ => None,
+            };
+            diff.lines.push(parsed.unwrap_or_else(|| DiffLine::Other(line.to_owned())));
+        }
+
+        diff
+    }
+
+    fn parse_aux(fields: &[&str]) -> Option<DiffLine> {
+        let n = |i: usize| fields[i].parse::<u16>().ok();
+        Some(DiffLine::Aux {
+            index: n(0)? as u8,
+            mode_id: n(1)? as u8,
+            channel: n(2)? as u8,
+            range_start: n(3)?,
+            range_end: n(4)?,
+            logic: n(5)? as u8,
+            linked_to: n(6)? as u8,
+        })
+    }
+
+    pub fn sets(&self) -> impl Iterator<Item = (DiffScope, &str, &str)> {
+        self.lines.iter().filter_map(|l| match l {
+            DiffLine::Set { scope, name, value } => Some((*scope, name.as_str(), value.as_str())),
+            _ => None,
+        })
+    }
+
+    pub fn features(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.lines.iter().filter_map(|l| match l {
+            DiffLine::Feature { name, enabled } => Some((name.as_str(), *enabled)),
+            _ => None,
+        })
+    }
+}
+
+/// The CLI of a flight controller, entered by sending `#` on the MSP port. MSP requests are
+/// not answered while the session is open.
+pub struct CliSession {
+    port: Box<dyn SerialPort>,
+    timeout: Duration,
+}
+
+impl CliSession {
+    /// Switch the port into CLI mode and wait for the first prompt
+    pub fn enter(port: Box<dyn SerialPort>) -> Result<CliSession> {
+        let mut session = CliSession { port, timeout: Duration::from_secs(2) };
+        session.port.write_all(b"#")?;
+        session.read_until(|out| out.ends_with(PROMPT))?;
+        Ok(session)
+    }
+
+    /// How long to wait for a command to finish, default 2 seconds
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Read until `done` holds and nothing more has arrived for [`PROMPT_QUIET`]. Betaflight
+    /// sends long output in blocks, and the `# ` comment lines of `diff all` look just like the
+    /// prompt when a block ends after one of them.
+    fn read_until(&mut self, done: impl Fn(&str) -> bool) -> Result<String> {
+        let deadline = Instant::now() + self.timeout;
+        let mut output = Vec::new();
+        let mut buf = [0u8; 256];
+        let mut last_data = Instant::now();
+
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(n) => {
+                    output.extend_from_slice(&buf[..n]);
+                    last_data = Instant::now();
+                }
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => sleep(Duration::from_millis(1)),
+                Err(e) => return Err(e.into()),
+            }
+
+            let text = String::from_utf8_lossy(&output);
+            if last_data.elapsed() >= PROMPT_QUIET && done(&text) {
+                return Ok(text.into_owned());
+            }
+            if Instant::now() > deadline {
+                return Err(Error::msg(format!("Timed out waiting for CLI, got {:?}", text)));
+            }
+        }
+    }
+
+    /// Run a command and return its output, without the echoed command and the prompt
+    pub fn run(&mut self, command: &str) -> Result<String> {
+        self.port.write_all(format!("{}\n", command).as_bytes())?;
+        let output = self.read_until(|out| out.ends_with(PROMPT))?;
+
+        let output = output.strip_suffix(PROMPT).unwrap_or(&output);
+        let output = match output.split_once("\r\n") {
+            Some((echo, rest)) if echo.trim() == command => rest,
+            _ => output,
+        };
+        Ok(output.to_owned())
+    }
+
+    /// Run `diff all` and parse its output
+    pub fn diff_all(&mut self) -> Result<CliDiff> {
+        Ok(CliDiff::parse(&self.run("diff all")?))
+    }
+
+    /// Save and leave the CLI, the flight controller reboots
+    pub fn save(self) -> Result<Box<dyn SerialPort>> {
+        self.leave("save")
+    }
+
+    /// Leave the CLI without saving. Betaflight reboots on exit, in which case the port is
+    /// reopened once the flight controller is back.
+    pub fn exit(self) -> Result<Box<dyn SerialPort>> {
+        self.leave("exit")
+    }
+
+    fn leave(mut self, command: &str) -> Result<Box<dyn SerialPort>> {
+        if !self.send_leave(command)? {
+            return Ok(self.port);
+        }
+
+        let name = self
+            .port
+            .name()
+            .ok_or_else(|| Error::msg("Cannot reconnect to a port without a name"))?;
+        let baud_rate = self.port.baud_rate()?;
+        drop(self.port);
+
+        // give the USB device time to go away before polling for it
+        sleep(Duration::from_secs(1));
+        wait_for_port_timeout(&name, baud_rate, Duration::from_millis(200), Duration::from_secs(10))
+    }
+
+    /// Send `exit` or `save`, true if the flight controller reboots. Firmware versions differ in
+    /// the case of these messages.
+    fn send_leave(&mut self, command: &str) -> Result<bool> {
+        self.port.write_all(format!("{}\n", command).as_bytes())?;
+
+        let leaving = |out: &str| {
+            let out = out.to_lowercase();
+            out.contains("rebooting") || out.contains("leaving cli mode")
+        };
+        match self.read_until(leaving) {
+            Ok(output) => {
+                let output = output.to_lowercase();
+                Ok(output.contains("rebooting") || output.contains("unsaved changes lost"))
+            }
+            // the port disappearing mid read is a reboot too
+            Err(e) if e.downcast_ref::<io::Error>().is_some() => Ok(true),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+
+    const DIFF_ALL: &str = "\
+# version
+# Betaflight / STM32F7X2 (S7X2) 4.3.1 Jul 13 2022 / 03:32:52 (8d4f005) MSP API: 1.44
+
+# start the command batch
+batch start
+
+board_name MATEKF722
+
+# resources
+resource MOTOR 1 B00
+resource LED_STRIP 1 NONE
+
+# feature
+feature -AIRMODE
+feature GPS
+
+# aux
+aux 0 0 0 1700 2100 0 0
+
+# master
+set gyro_lpf1_static_hz = 0
+set acc_calibration = 1,2,3,1
+
+profile 1
+
+# profile 1
+set p_pitch = 50
+
+rateprofile 2
+
+# rateprofile 2
+set roll_rc_rate = 100
+";
+
+    #[test]
+    fn parse_diff_all() {
+        let diff = CliDiff::parse(DIFF_ALL);
+
+        assert_eq!(
+            Some("Betaflight / STM32F7X2 (S7X2) 4.3.1 Jul 13 2022 / 03:32:52 (8d4f005) MSP API: 1.44"),
+            diff.version.as_deref()
+        );
+        assert_eq!(vec![("AIRMODE", false), ("GPS", true)], diff.features().collect::<Vec<_>>());
+        assert!(diff.lines.contains(&DiffLine::Resource { function: "MOTOR".into(), index: 1, pin: "B00".into() }));
+        assert!(diff.lines.contains(&DiffLine::Aux {
+            index: 0,
+            mode_id: 0,
+            channel: 0,
+            range_start: 1700,
+            range_end: 2100,
+            logic: 0,
+            linked_to: 0
+        }));
+        assert!(diff.lines.contains(&DiffLine::Other("board_name MATEKF722".into())));
+
+        let sets: Vec<_> = diff.sets().collect();
+        assert_eq!(
+            vec![
+                (DiffScope::Master, "gyro_lpf1_static_hz", "0"),
+                (DiffScope::Master, "acc_calibration", "1,2,3,1"),
+                (DiffScope::Profile(1), "p_pitch", "50"),
+                (DiffScope::RateProfile(2), "roll_rc_rate", "100"),
+            ],
+            sets
+        );
+    }
+
+    #[test]
+    fn run_command_and_leave() {
+        let fc = MockFc::new().cli(|line| match line {
+            "get p_pitch" => "p_pitch = 50".to_owned(),
+            "diff all" => DIFF_ALL.replace('\n', "\r\n"),
+            "exit" => "\r\nLeaving CLI mode\r\n".to_owned(),
+            _ => String::new(),
+        });
+
+        let mut cli = CliSession::enter(Box::new(fc)).unwrap();
+        assert_eq!("p_pitch = 50", cli.run("get p_pitch").unwrap());
+        assert_eq!(4, cli.diff_all().unwrap().sets().count());
+        let port = cli.exit().unwrap();
+        assert_eq!(Some("mock".to_owned()), port.name());
+    }
+
+    #[test]
+    fn leave_detects_reboot() {
+        let fc = MockFc::new().cli(|line| match line {
+            "save" => "Saving\r\nRebooting".to_owned(),
+            "exit" => "\r\nleaving CLI mode, unsaved changes lost\r\n".to_owned(),
+            _ => String::new(),
+        });
+        let mut cli = CliSession::enter(Box::new(fc)).unwrap();
+        assert!(cli.send_leave("save").unwrap());
+
+        let mut cli = CliSession::enter(cli.port).unwrap();
+        assert!(cli.send_leave("exit").unwrap());
+    }
+
+    #[test]
+    fn prompt_lookalike_at_block_end() {
+        // every block ends right after a "\r\n# " of the diff comments
+        let fc = MockFc::new()
+            .cli(|line| match line {
+                "diff all" => DIFF_ALL.replace('\n', "\r\n"),
+                _ => String::new(),
+            })
+            .pause_after(PROMPT, Duration::from_millis(20));
+
+        let mut cli = CliSession::enter(Box::new(fc)).unwrap();
+        let output = cli.run("diff all").unwrap();
+        assert!(output.ends_with("set roll_rc_rate = 100\r\n"), "{:?}", output);
+        assert_eq!(4, CliDiff::parse(&output).sets().count());
+    }
+}
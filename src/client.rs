@@ -0,0 +1,220 @@
+//! Flight controller connection that survives reboots
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Error, Result};
+use serialport::SerialPort;
+
+use crate::firmware::FirmwareInfo;
+use crate::helpers::{request, wait_for_port_gone, wait_for_port_timeout};
+use crate::msp::commands::MspCommandCode;
+
+const POLL: Duration = Duration::from_millis(200);
+const READ_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Reboot mode sent with `MSP_SET_REBOOT`
+#[derive(Debug, Copy, Clone, PartialEq)]
+#[repr(u8)]
+pub enum RebootKind {
+    Firmware = 0,
+    /// DFU bootloader, for flashing
+    Bootloader = 1,
+    /// USB mass storage, to read the onboard flash or SD card
+    Msc = 2,
+    /// Mass storage with file times in UTC rather than local time
+    MscUtc = 3,
+}
+
+/// Where the flight controller ended up after [`MspClient::reboot`]
+pub enum RebootOutcome {
+    /// The firmware came back on the same port and answered `MSP_API_VERSION` again
+    Reconnected(MspClient),
+    /// The serial device went away for good, the flight controller now enumerates as a DFU or
+    /// mass storage device
+    Detached(RebootKind),
+}
+
+/// An open MSP port together with what it is connected to
+pub struct MspClient {
+    port: Box<dyn SerialPort>,
+    name: String,
+    baud_rate: u32,
+    firmware: FirmwareInfo,
+    reconnect_timeout: Duration,
+}
+
+impl MspClient {
+    pub fn open(name: &str, baud_rate: u32) -> Result<MspClient> {
+        let port = serialport::new(name, baud_rate).timeout(READ_TIMEOUT).open()?;
+        Self::from_port(port)
+    }
+
+    /// Wrap an already open port, probing the firmware on the other end
+    pub fn from_port(mut port: Box<dyn SerialPort>) -> Result<MspClient> {
+        let name = port
+            .name()
+            .ok_or_else(|| Error::msg("Cannot reconnect to a port without a name"))?;
+        let baud_rate = port.baud_rate()?;
+        let firmware = FirmwareInfo::probe(&mut *port)?;
+
+        Ok(MspClient {
+            port,
+            name,
+            baud_rate,
+            firmware,
+            reconnect_timeout: Duration::from_secs(10),
+        })
+    }
+
+    /// How long a reboot may take to drop and bring back the port, default 10 seconds
+    pub fn with_reconnect_timeout(mut self, timeout: Duration) -> Self {
+        self.reconnect_timeout = timeout;
+        self
+    }
+
+    pub fn port(&mut self) -> &mut dyn SerialPort {
+        &mut *self.port
+    }
+
+    pub fn into_port(self) -> Box<dyn SerialPort> {
+        self.port
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn firmware(&self) -> &FirmwareInfo {
+        &self.firmware
+    }
+
+    /// Reboot the flight controller. For [`RebootKind::Firmware`] this waits for the USB serial
+    /// device to go away and come back, then re-probes the firmware. The other kinds end once
+    /// the serial device is gone.
+    pub fn reboot(self, kind: RebootKind) -> Result<RebootOutcome> {
+        let MspClient { mut port, name, baud_rate, reconnect_timeout, .. } = self;
+
+        let reply = request(&mut *port, MspCommandCode::MSP_SET_REBOOT as u16, &[kind as u8])?;
+        // Betaflight answers a mass storage request with [mode, ready] and stays up if not ready
+        if matches!(kind, RebootKind::Msc | RebootKind::MscUtc) && reply.data.as_slice().get(1) == Some(&0) {
+            return Err(Error::msg("Flight controller has no mass storage device ready"));
+        }
+        drop(port);
+
+        let gone = wait_for_port_gone(&name, POLL, reconnect_timeout);
+        if kind != RebootKind::Firmware {
+            if !gone {
+                return Err(Error::msg(format!("{} is still present after rebooting to {:?}", name, kind)));
+            }
+            return Ok(RebootOutcome::Detached(kind));
+        }
+
+        // a fast reboot can come and go between two polls, so carry on even if it was never seen gone
+        Self::reconnect(name, baud_rate, reconnect_timeout, |name, remaining| {
+            wait_for_port_timeout(name, baud_rate, POLL, remaining)
+        })
+        .map(RebootOutcome::Reconnected)
+    }
+
+    /// Reopen the port with `open` until the firmware answers `MSP_API_VERSION` again
+    fn reconnect(
+        name: String,
+        baud_rate: u32,
+        reconnect_timeout: Duration,
+        mut open: impl FnMut(&str, Duration) -> Result<Box<dyn SerialPort>>,
+    ) -> Result<MspClient> {
+        let deadline = Instant::now() + reconnect_timeout;
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let mut port = open(&name, remaining)?;
+            port.set_timeout(READ_TIMEOUT)?;
+
+            match FirmwareInfo::probe(&mut *port) {
+                Ok(firmware) => {
+                    return Ok(MspClient {
+                        port,
+                        name,
+                        baud_rate,
+                        firmware,
+                        reconnect_timeout,
+                    })
+                }
+                Err(e) if Instant::now() > deadline => {
+                    return Err(Error::msg(format!("{} reappeared but does not answer MSP: {}", name, e)))
+                }
+                Err(_) => sleep(POLL),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firmware::FirmwareVariant;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+
+    fn betaflight() -> MockFc {
+        MockFc::new()
+            .reply(MSP_API_VERSION as u16, &[0, 1, 46])
+            .reply(MSP_FC_VARIANT as u16, b"BTFL")
+            .reply(MSP_FC_VERSION as u16, &[4, 5, 1])
+    }
+
+    #[test]
+    fn reboot_to_bootloader_detaches() {
+        let fc = betaflight().handler(|p| match p.cmd {
+            c if c == MSP_SET_REBOOT as u16 => Some(p.data.as_slice().to_vec()),
+            _ => None,
+        });
+        let client = MspClient::from_port(Box::new(fc)).unwrap();
+        assert_eq!(FirmwareVariant::Betaflight, client.firmware().variant);
+
+        // the mock port is never enumerated, so it counts as gone straight away
+        match client.reboot(RebootKind::Bootloader).unwrap() {
+            RebootOutcome::Detached(kind) => assert_eq!(RebootKind::Bootloader, kind),
+            RebootOutcome::Reconnected(_) => panic!("bootloader should not reconnect"),
+        }
+    }
+
+    #[test]
+    fn msc_not_ready_is_an_error() {
+        let fc = betaflight().reply(MSP_SET_REBOOT as u16, &[2, 0]);
+        let client = MspClient::from_port(Box::new(fc)).unwrap();
+        assert!(client.reboot(RebootKind::Msc).is_err());
+    }
+
+    #[test]
+    fn reconnects_once_firmware_answers() {
+        // enumerated again but still booting, then answering
+        let mut attempts = 0;
+        let client = MspClient::reconnect("mock".to_owned(), 115_200, Duration::from_secs(5), |_, _| {
+            attempts += 1;
+            match attempts {
+                1 => Ok(Box::new(MockFc::new())),
+                _ => Ok(Box::new(betaflight())),
+            }
+        })
+        .unwrap();
+        assert_eq!(2, attempts);
+        assert_eq!(FirmwareVariant::Betaflight, client.firmware().variant);
+        assert_eq!(Duration::from_secs(5), client.reconnect_timeout);
+
+        // a port that comes back but never answers gives up at the deadline
+        let silent =
+            MspClient::reconnect("mock".to_owned(), 115_200, Duration::ZERO, |_, _| Ok(Box::new(MockFc::new())));
+        match silent {
+            Err(e) => assert!(e.to_string().contains("does not answer MSP")),
+            Ok(_) => panic!("a silent port should not reconnect"),
+        }
+    }
+
+    #[test]
+    fn firmware_reboot_times_out_without_port() {
+        let fc = betaflight().reply(MSP_SET_REBOOT as u16, &[0]);
+        let client = MspClient::from_port(Box::new(fc)).unwrap().with_reconnect_timeout(Duration::ZERO);
+        // the mock's name is no real device, so it never reappears
+        assert!(client.reboot(RebootKind::Firmware).is_err());
+    }
+}
@@ -0,0 +1,331 @@
+//! Field level comparison of configuration snapshots
+
+use std::borrow::Cow;
+use std::fmt;
+use anyhow::Result;
+use serde_json::Value;
+use serialport::SerialPort;
+
+use crate::snapshot::{ConfigSnapshot, ConfigWrite};
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ChangeKind {
+    Changed,
+    /// Only present in the newer configuration
+    Added,
+    /// Only present in the older configuration
+    Removed,
+}
+
+/// Display name, unit and scale of a raw configuration field
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldInfo {
+    pub name: Cow<'static, str>,
+    pub unit: &'static str,
+    /// Multiply the raw value by this to get `unit`
+    pub scale: f64,
+}
+
+const fn field(name: &'static str, unit: &'static str, scale: f64) -> FieldInfo {
+    FieldInfo { name: Cow::Borrowed(name), unit, scale }
+}
+
+// (path, info) for the fields we know how to present
+const FIELDS: &[(&str, FieldInfo)] = &[
+    ("bf_config.features", field("Enabled features", "", 1.0)),
+    ("bf_config.board_align_roll", field("Board alignment roll", "deg", 1.0)),
+    ("bf_config.board_align_pitch", field("Board alignment pitch", "deg", 1.0)),
+    ("bf_config.board_align_yaw", field("Board alignment yaw", "deg", 1.0)),
+    ("advanced_config.gyro_sync_denom", field("Gyro sync denominator", "", 1.0)),
+    ("advanced_config.pid_process_denom", field("PID loop denominator", "", 1.0)),
+    ("advanced_config.use_unsynced_pwm", field("Unsynced PWM", "", 1.0)),
+    ("advanced_config.motor_pwm_protocol", field("Motor protocol", "", 1.0)),
+    ("advanced_config.motor_pwm_rate", field("Motor PWM frequency", "Hz", 1.0)),
+    ("advanced_config.digital_idle_offset_percent", field("Digital idle", "%", 0.01)),
+    ("advanced_config.motor_pwm_inversion", field("Motor PWM inversion", "", 1.0)),
+    ("motor_config.min_throttle", field("Minimum throttle", "us", 1.0)),
+    ("motor_config.max_throttle", field("Maximum throttle", "us", 1.0)),
+    ("motor_config.min_command", field("Minimum command", "us", 1.0)),
+    ("motor_3d_config.deadband_3d_low", field("3D deadband low", "us", 1.0)),
+    ("motor_3d_config.deadband_3d_high", field("3D deadband high", "us", 1.0)),
+    ("motor_3d_config.neutral_3d", field("3D neutral", "us", 1.0)),
+    ("battery_config.vbat_min_cell_voltage", field("Minimum cell voltage", "V", 0.1)),
+    ("battery_config.vbat_max_cell_voltage", field("Maximum cell voltage", "V", 0.1)),
+    ("battery_config.vbat_warning_cell_voltage", field("Warning cell voltage", "V", 0.1)),
+    ("battery_config.battery_capacity", field("Battery capacity", "mAh", 1.0)),
+    ("rx_config.maxcheck", field("Stick high threshold", "us", 1.0)),
+    ("rx_config.midrc", field("Stick center", "us", 1.0)),
+    ("rx_config.mincheck", field("Stick low threshold", "us", 1.0)),
+    ("rx_config.rx_min_usec", field("RX minimum pulse", "us", 1.0)),
+    ("rx_config.rx_max_usec", field("RX maximum pulse", "us", 1.0)),
+    ("rx_config.air_mode_activate_threshold", field("Air mode activation throttle", "us", 1.0)),
+    ("rx_config.fpv_cam_angle_degrees", field("FPV camera angle", "deg", 1.0)),
+    ("rc_deadband.deadband", field("Roll/pitch deadband", "us", 1.0)),
+    ("rc_deadband.yaw_deadband", field("Yaw deadband", "us", 1.0)),
+    ("rc_deadband.alt_hold_deadband", field("Altitude hold deadband", "us", 1.0)),
+    ("rc_deadband.deadband_3d_throttle", field("3D throttle deadband", "us", 1.0)),
+    ("rc_tuning.rc_rate8", field("Roll/pitch RC rate", "", 0.01)),
+    ("rc_tuning.rc_expo8", field("Roll/pitch RC expo", "", 0.01)),
+    ("rc_tuning.rate_roll", field("Roll super rate", "", 0.01)),
+    ("rc_tuning.rate_pitch", field("Pitch super rate", "", 0.01)),
+    ("rc_tuning.rate_yaw", field("Yaw super rate", "", 0.01)),
+    ("rc_tuning.dyn_thr_pid", field("TPA", "%", 1.0)),
+    ("rc_tuning.thr_mid8", field("Throttle mid", "", 0.01)),
+    ("rc_tuning.thr_expo8", field("Throttle expo", "", 0.01)),
+    ("rc_tuning.tpa_breakpoint", field("TPA breakpoint", "us", 1.0)),
+    ("rc_tuning.rc_yaw_expo8", field("Yaw RC expo", "", 0.01)),
+    ("rc_tuning.rc_yaw_rate8", field("Yaw RC rate", "", 0.01)),
+    ("pid_advanced.vbat_pid_compensation", field("Voltage PID compensation", "", 1.0)),
+    ("pid_advanced.setpoint_relax_ratio", field("Setpoint relax ratio", "", 0.01)),
+    ("pid_advanced.dterm_setpoint_weight", field("D-term setpoint weight", "", 0.01)),
+    ("pid_advanced.rate_accel_limit", field("Rate acceleration limit", "deg/s/ms", 1.0)),
+    ("pid_advanced.yaw_rate_accel_limit", field("Yaw rate acceleration limit", "deg/s/ms", 1.0)),
+    ("pid_advanced.level_angle_limit", field("Angle mode limit", "deg", 1.0)),
+    ("pid_advanced.level_sensitivity", field("Horizon sensitivity", "", 1.0)),
+    ("filter_config.gyro_soft_lpf_hz", field("Gyro lowpass", "Hz", 1.0)),
+    ("filter_config.dterm_lpf_hz", field("D-term lowpass", "Hz", 1.0)),
+    ("filter_config.yaw_lpf_hz", field("Yaw lowpass", "Hz", 1.0)),
+    ("filter_config.gyro_soft_notch_hz_1", field("Gyro notch 1 center", "Hz", 1.0)),
+    ("filter_config.gyro_soft_notch_cutoff_1", field("Gyro notch 1 cutoff", "Hz", 1.0)),
+    ("filter_config.dterm_notch_hz", field("D-term notch center", "Hz", 1.0)),
+    ("filter_config.dterm_notch_cutoff", field("D-term notch cutoff", "Hz", 1.0)),
+    ("filter_config.gyro_soft_notch_hz_2", field("Gyro notch 2 center", "Hz", 1.0)),
+    ("filter_config.gyro_soft_notch_cutoff_2", field("Gyro notch 2 cutoff", "Hz", 1.0)),
+];
+
+/// Presentation of a field path, e.g. `rc_tuning.rc_rate8`. Unknown fields are named
+/// after their last path segment.
+pub fn field_info(path: &str) -> FieldInfo {
+    FIELDS
+        .iter()
+        .find(|(p, _)| *p == path)
+        .map(|(_, info)| info.clone())
+        .unwrap_or_else(|| {
+            let last = path.rsplit('.').next().unwrap_or(path);
+            FieldInfo { name: Cow::Owned(last.replace('_', " ")), unit: "", scale: 1.0 }
+        })
+}
+
+/// A single differing field
+#[derive(Debug, Clone, PartialEq)]
+pub struct FieldChange {
+    pub path: String,
+    pub info: FieldInfo,
+    pub kind: ChangeKind,
+    pub old: Option<Value>,
+    pub new: Option<Value>,
+}
+
+impl FieldChange {
+    /// Top level section, e.g. `rc_tuning`
+    pub fn section(&self) -> &str {
+        self.path.split(['.', '[']).next().unwrap_or(&self.path)
+    }
+
+    fn format_value(&self, value: &Option<Value>) -> String {
+        match value {
+            None => "-".to_owned(),
+            Some(Value::Number(n)) if self.info.scale != 1.0 => {
+                let scaled = n.as_f64().unwrap_or_default() * self.info.scale;
+                format!("{}{}", (scaled * 1000.0).round() / 1000.0, self.unit_suffix())
+            }
+            Some(Value::Number(n)) => format!("{}{}", n, self.unit_suffix()),
+            Some(Value::String(s)) => s.clone(),
+            Some(v) => v.to_string(),
+        }
+    }
+
+    fn unit_suffix(&self) -> String {
+        match self.info.unit {
+            "" => String::new(),
+            unit => format!(" {}", unit),
+        }
+    }
+}
+
+impl fmt::Display for FieldChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let marker = match self.kind {
+            ChangeKind::Changed => '~',
+            ChangeKind::Added => '+',
+            ChangeKind::Removed => '-',
+        };
+        write!(
+            f,
+            "{} {} ({}): {} -> {}",
+            marker,
+            self.info.name,
+            self.path,
+            self.format_value(&self.old),
+            self.format_value(&self.new)
+        )
+    }
+}
+
+/// Every field that differs between two configurations
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct ConfigDiff {
+    pub changes: Vec<FieldChange>,
+}
+
+impl ConfigDiff {
+    /// Compare `old` to `new`
+    pub fn between(old: &ConfigSnapshot, new: &ConfigSnapshot) -> Result<ConfigDiff> {
+        let mut diff = ConfigDiff::default();
+        diff.walk(
+            String::new(),
+            Some(&serde_json::to_value(old)?),
+            Some(&serde_json::to_value(new)?),
+        );
+        Ok(diff)
+    }
+
+    /// Compare a saved configuration against what is currently on the flight controller
+    pub fn against_fc(port: &mut dyn SerialPort, saved: &ConfigSnapshot) -> Result<ConfigDiff> {
+        let live = ConfigSnapshot::capture(port)?;
+        Self::between(saved, &live)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    fn walk(&mut self, path: String, old: Option<&Value>, new: Option<&Value>) {
+        match (old, new) {
+            (Some(Value::Object(a)), Some(Value::Object(b))) => {
+                let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+                keys.sort();
+                keys.dedup();
+                for key in keys {
+                    self.walk(join(&path, key), a.get(key), b.get(key));
+                }
+            }
+            (Some(Value::Array(a)), Some(Value::Array(b))) => {
+                for i in 0..a.len().max(b.len()) {
+                    self.walk(format!("{}[{}]", path, i), a.get(i), b.get(i));
+                }
+            }
+            (Some(Value::Object(a)), None) => {
+                for (key, value) in a {
+                    self.walk(join(&path, key), Some(value), None);
+                }
+            }
+            (None, Some(Value::Object(b))) => {
+                for (key, value) in b {
+                    self.walk(join(&path, key), None, Some(value));
+                }
+            }
+            (Some(a), Some(b)) if a == b => {}
+            (None, None) => {}
+            (old, new) => {
+                let kind = match (old, new) {
+                    (None, _) => ChangeKind::Added,
+                    (_, None) => ChangeKind::Removed,
+                    _ => ChangeKind::Changed,
+                };
+                self.changes.push(FieldChange {
+                    info: field_info(&path),
+                    path,
+                    kind,
+                    old: old.cloned(),
+                    new: new.cloned(),
+                });
+            }
+        }
+    }
+}
+
+impl fmt::Display for ConfigDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(f, "{}", change)?;
+        }
+        Ok(())
+    }
+}
+
+fn join(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_owned()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+/// The minimal `MSP_SET_*` writes that turn configuration `from` into `to`, in restore order.
+/// Apply them with [`crate::snapshot::apply_writes`].
+pub fn patch(from: &ConfigSnapshot, to: &ConfigSnapshot) -> Result<Vec<ConfigWrite>> {
+    let current = from.writes()?;
+    Ok(to
+        .writes()?
+        .into_iter()
+        .filter(|w| !current.iter().any(|c| c.set == w.set && c.payload == w.payload))
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firmware::{test_firmware, FirmwareVariant};
+    use crate::msp::commands::MspCommandCode;
+    use crate::msp::structs::{MspFilterConfig, MspMotorConfig, MspRcTuning};
+    use crate::snapshot::SNAPSHOT_FORMAT_VERSION;
+
+    fn snapshot() -> ConfigSnapshot {
+        ConfigSnapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            firmware: test_firmware(FirmwareVariant::Betaflight, 46),
+            mixer: None,
+            bf_config: None,
+            sensor_config: None,
+            sensor_alignment: None,
+            advanced_config: None,
+            motor_config: Some(MspMotorConfig { min_throttle: 1070, max_throttle: 2000, min_command: 1000 }),
+            motor_3d_config: None,
+            battery_config: None,
+            rx_config: None,
+            rc_deadband: None,
+            rc_tuning: Some(MspRcTuning { rc_rate8: 100, ..Default::default() }),
+            pid_advanced: None,
+            filter_config: Some(MspFilterConfig::default()),
+        }
+    }
+
+    #[test]
+    fn reports_changed_added_removed() {
+        let old = snapshot();
+        let mut new = snapshot();
+        new.rc_tuning.as_mut().unwrap().rc_rate8 = 120;
+        new.filter_config = None;
+
+        let diff = ConfigDiff::between(&old, &new).unwrap();
+
+        let rate = diff.changes.iter().find(|c| c.path == "rc_tuning.rc_rate8").unwrap();
+        assert_eq!(ChangeKind::Changed, rate.kind);
+        assert_eq!("~ Roll/pitch RC rate (rc_tuning.rc_rate8): 1 -> 1.2", rate.to_string());
+
+        let removed: Vec<_> = diff.changes.iter().filter(|c| c.kind == ChangeKind::Removed).collect();
+        assert_eq!(9, removed.len());
+        assert!(removed.iter().all(|c| c.section() == "filter_config"));
+
+        let added = ConfigDiff::between(&new, &old).unwrap();
+        assert!(added.changes.iter().any(|c| c.kind == ChangeKind::Added && c.path == "filter_config.dterm_lpf_hz"));
+    }
+
+    #[test]
+    fn identical_snapshots_have_no_diff() {
+        assert!(ConfigDiff::between(&snapshot(), &snapshot()).unwrap().is_empty());
+    }
+
+    #[test]
+    fn patch_only_writes_changed_sections() {
+        let old = snapshot();
+        let mut new = snapshot();
+        new.rc_tuning.as_mut().unwrap().rc_rate8 = 120;
+
+        let writes = patch(&old, &new).unwrap();
+        assert_eq!(1, writes.len());
+        assert_eq!(MspCommandCode::MSP_SET_RC_TUNING, writes[0].set);
+        assert_eq!(120, writes[0].payload[0]);
+    }
+}
@@ -0,0 +1,407 @@
+//! MSP DisplayPort, the canvas OSD protocol spoken between flight controllers and digital FPV
+//! systems (DJI, HDZero, Walksnail)
+
+use anyhow::{Error, Result};
+use serialport::SerialPort;
+
+use crate::msp::{
+    commands::MspCommandCode,
+    packet::{MspPacket, MspPacketDirection},
+};
+
+/// Attribute byte of a DisplayPort string
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Attribute(pub u8);
+
+impl Attribute {
+    const FONT_PAGE_MASK: u8 = 0x03;
+    const BLINK: u8 = 0x40;
+
+    pub fn new(font_page: u8, blink: bool) -> Attribute {
+        let blink = if blink { Self::BLINK } else { 0 };
+        Attribute((font_page & Self::FONT_PAGE_MASK) | blink)
+    }
+
+    /// Which 256 character page of the font the string is drawn from
+    pub fn font_page(self) -> u8 {
+        self.0 & Self::FONT_PAGE_MASK
+    }
+
+    pub fn blink(self) -> bool {
+        self.0 & Self::BLINK != 0
+    }
+}
+
+/// Canvas size requested with the options subcommand
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum CanvasResolution {
+    Sd30x16 = 0,
+    Hd50x18 = 1,
+    Hd30x16 = 2,
+    Hd60x22 = 3,
+}
+
+impl CanvasResolution {
+    pub fn from_u8(value: u8) -> Option<CanvasResolution> {
+        match value {
+            0 => Some(CanvasResolution::Sd30x16),
+            1 => Some(CanvasResolution::Hd50x18),
+            2 => Some(CanvasResolution::Hd30x16),
+            3 => Some(CanvasResolution::Hd60x22),
+            _ => None,
+        }
+    }
+
+    /// (columns, rows)
+    pub fn size(self) -> (u8, u8) {
+        match self {
+            CanvasResolution::Sd30x16 | CanvasResolution::Hd30x16 => (30, 16),
+            CanvasResolution::Hd50x18 => (50, 18),
+            CanvasResolution::Hd60x22 => (60, 22),
+        }
+    }
+}
+
+/// One `MSP_DISPLAYPORT` message, the first payload byte selects the subcommand
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisplayPortCommand {
+    /// Keeps the canvas owned by the flight controller
+    Heartbeat,
+    /// Hand the screen back to the video system
+    Release,
+    Clear,
+    /// Characters at `row`, `col`, font indices rather than text
+    Write { row: u8, col: u8, attr: Attribute, text: Vec<u8> },
+    /// Show everything written since the last draw
+    Draw,
+    Options { font: u8, resolution: CanvasResolution },
+}
+
+impl DisplayPortCommand {
+    pub fn write(row: u8, col: u8, attr: Attribute, text: &str) -> DisplayPortCommand {
+        DisplayPortCommand::Write { row, col, attr, text: text.as_bytes().to_vec() }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            DisplayPortCommand::Heartbeat => vec![0],
+            DisplayPortCommand::Release => vec![1],
+            DisplayPortCommand::Clear => vec![2],
+            DisplayPortCommand::Write { row, col, attr, text } => {
+                let mut payload = vec![3, *row, *col, attr.0];
+                payload.extend_from_slice(text);
+                payload
+            }
+            DisplayPortCommand::Draw => vec![4],
+            DisplayPortCommand::Options { font, resolution } => vec![5, *font, *resolution as u8],
+        }
+    }
+
+    pub fn decode(payload: &[u8]) -> Result<DisplayPortCommand> {
+        let short = || Error::msg(format!("DisplayPort payload too short: {:?}", payload));
+        let (&sub, args) = payload.split_first().ok_or_else(short)?;
+
+        Ok(match sub {
+            0 => DisplayPortCommand::Heartbeat,
+            1 => DisplayPortCommand::Release,
+            2 => DisplayPortCommand::Clear,
+            3 => match args {
+                [row, col, attr, text @ ..] => DisplayPortCommand::Write {
+                    row: *row,
+                    col: *col,
+                    attr: Attribute(*attr),
+                    // some firmwares null-terminate the string
+                    text: text.iter().copied().take_while(|&b| b != 0).collect(),
+                },
+                _ => return Err(short()),
+            },
+            4 => DisplayPortCommand::Draw,
+            5 => match args {
+                [font, resolution, ..] => DisplayPortCommand::Options {
+                    font: *font,
+                    resolution: CanvasResolution::from_u8(*resolution)
+                        .ok_or_else(|| Error::msg(format!("Unknown canvas resolution {}", resolution)))?,
+                },
+                _ => return Err(short()),
+            },
+            _ => return Err(Error::msg(format!("Unknown DisplayPort subcommand {}", sub))),
+        })
+    }
+
+    /// The command as sent by a flight controller
+    pub fn to_packet(&self) -> MspPacket {
+        MspPacket {
+            cmd: MspCommandCode::MSP_DISPLAYPORT as u16,
+            direction: MspPacketDirection::FromFlightController,
+            data: self.encode().as_slice().into(),
+        }
+    }
+
+    /// Serialized frame, v2 framing when the payload does not fit v1
+    pub fn to_frame(&self) -> Result<Vec<u8>> {
+        let packet = self.to_packet();
+        let mut frame;
+        let res = if packet.data.as_slice().len() > u8::MAX as usize {
+            frame = vec![0u8; packet.packet_size_bytes_v2()];
+            packet.serialize_v2(&mut frame)
+        } else {
+            frame = vec![0u8; packet.packet_size_bytes()];
+            packet.serialize(&mut frame)
+        };
+        res.map_err(|e| Error::msg(format!("Serialization Error: {:?}", e)))?;
+        Ok(frame)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct Cell {
+    /// Index into the font page, 0 and space are blank
+    pub ch: u8,
+    pub attr: Attribute,
+}
+
+impl Cell {
+    pub fn is_blank(&self) -> bool {
+        self.ch == 0 || self.ch == b' '
+    }
+
+    /// Glyph index across all font pages
+    pub fn glyph(&self) -> u16 {
+        (self.attr.font_page() as u16) << 8 | self.ch as u16
+    }
+}
+
+/// Character grid driven by DisplayPort commands. Writes go to a back buffer which becomes
+/// visible on [`DisplayPortCommand::Draw`], as on the goggles.
+#[derive(Debug, Clone)]
+pub struct VirtualOsdScreen {
+    cols: u8,
+    rows: u8,
+    back: Vec<Cell>,
+    front: Vec<Cell>,
+    /// The flight controller released the screen
+    pub released: bool,
+    pub font: u8,
+}
+
+impl VirtualOsdScreen {
+    pub fn new(cols: u8, rows: u8) -> VirtualOsdScreen {
+        let size = cols as usize * rows as usize;
+        VirtualOsdScreen {
+            cols,
+            rows,
+            back: vec![Cell::default(); size],
+            front: vec![Cell::default(); size],
+            released: false,
+            font: 0,
+        }
+    }
+
+    pub fn with_resolution(resolution: CanvasResolution) -> VirtualOsdScreen {
+        let (cols, rows) = resolution.size();
+        Self::new(cols, rows)
+    }
+
+    pub fn cols(&self) -> u8 {
+        self.cols
+    }
+
+    pub fn rows(&self) -> u8 {
+        self.rows
+    }
+
+    /// Visible cell, `None` outside the grid
+    pub fn cell(&self, row: u8, col: u8) -> Option<Cell> {
+        (row < self.rows && col < self.cols).then(|| self.front[self.index(row, col)])
+    }
+
+    fn index(&self, row: u8, col: u8) -> usize {
+        row as usize * self.cols as usize + col as usize
+    }
+
+    /// Put characters into the back buffer, clipped at the right edge
+    pub fn put(&mut self, row: u8, col: u8, attr: Attribute, text: &[u8]) {
+        if row >= self.rows {
+            return;
+        }
+        for (i, &ch) in text.iter().enumerate() {
+            let col = col as usize + i;
+            if col >= self.cols as usize {
+                break;
+            }
+            let index = self.index(row, col as u8);
+            self.back[index] = Cell { ch, attr };
+        }
+    }
+
+    pub fn apply(&mut self, command: &DisplayPortCommand) {
+        match command {
+            DisplayPortCommand::Heartbeat => self.released = false,
+            DisplayPortCommand::Release => self.released = true,
+            DisplayPortCommand::Clear => self.back.fill(Cell::default()),
+            DisplayPortCommand::Write { row, col, attr, text } => self.put(*row, *col, *attr, text),
+            DisplayPortCommand::Draw => self.front.copy_from_slice(&self.back),
+            DisplayPortCommand::Options { font, resolution } => {
+                *self = VirtualOsdScreen { font: *font, ..Self::with_resolution(*resolution) };
+            }
+        }
+    }
+
+    /// Apply an `MSP_DISPLAYPORT` packet, other packets are ignored
+    pub fn apply_packet(&mut self, packet: &MspPacket) -> Result<()> {
+        if packet.cmd == MspCommandCode::MSP_DISPLAYPORT as u16 {
+            self.apply(&DisplayPortCommand::decode(packet.data.as_slice())?);
+        }
+        Ok(())
+    }
+
+    /// Visible screen as text, one line per row. Printable ASCII on font page 0 is shown as is,
+    /// blanks as spaces and everything else (symbols, other pages) as `·`.
+    pub fn text(&self) -> String {
+        let mut out = String::with_capacity(self.front.len() + self.rows as usize);
+        for row in self.front.chunks(self.cols as usize) {
+            for cell in row {
+                out.push(match cell {
+                    c if c.is_blank() => ' ',
+                    c if c.attr.font_page() == 0 && c.ch.is_ascii_graphic() => c.ch as char,
+                    _ => '·',
+                });
+            }
+            out.push('\n');
+        }
+        out
+    }
+
+    /// Render the visible screen as an RGBA image of `cols * glyph_width` by
+    /// `rows * glyph_height` pixels. `pixel` returns the colour of a glyph pixel, or `None` for
+    /// transparent. Blinking cells are left out when `blink_on` is false.
+    pub fn render(
+        &self,
+        glyph_width: usize,
+        glyph_height: usize,
+        blink_on: bool,
+        mut pixel: impl FnMut(u16, usize, usize) -> Option<[u8; 4]>,
+    ) -> Vec<u8> {
+        let stride = self.cols as usize * glyph_width;
+        let mut image = vec![0u8; stride * self.rows as usize * glyph_height * 4];
+
+        for (i, cell) in self.front.iter().enumerate() {
+            if cell.is_blank() || (cell.attr.blink() && !blink_on) {
+                continue;
+            }
+            let (row, col) = (i / self.cols as usize, i % self.cols as usize);
+            for y in 0..glyph_height {
+                for x in 0..glyph_width {
+                    if let Some(rgba) = pixel(cell.glyph(), x, y) {
+                        let offset = ((row * glyph_height + y) * stride + col * glyph_width + x) * 4;
+                        image[offset..offset + 4].copy_from_slice(&rgba);
+                    }
+                }
+            }
+        }
+        image
+    }
+}
+
+/// Drives a canvas OSD device, taking the place of the flight controller
+pub struct CanvasWriter<'a> {
+    port: &'a mut dyn SerialPort,
+}
+
+impl<'a> CanvasWriter<'a> {
+    pub fn new(port: &'a mut dyn SerialPort) -> CanvasWriter<'a> {
+        CanvasWriter { port }
+    }
+
+    pub fn send(&mut self, command: &DisplayPortCommand) -> Result<()> {
+        self.port.write_all(&command.to_frame()?)?;
+        Ok(())
+    }
+
+    /// Send the back buffer of `screen` as a full frame: clear, one write per run of
+    /// characters sharing an attribute, then draw
+    pub fn show(&mut self, screen: &VirtualOsdScreen) -> Result<()> {
+        self.send(&DisplayPortCommand::Heartbeat)?;
+        self.send(&DisplayPortCommand::Clear)?;
+
+        for (row, cells) in screen.back.chunks(screen.cols as usize).enumerate() {
+            let mut col = 0;
+            while col < cells.len() {
+                if cells[col].is_blank() {
+                    col += 1;
+                    continue;
+                }
+                let attr = cells[col].attr;
+                let run: Vec<u8> = cells[col..]
+                    .iter()
+                    .take_while(|c| !c.is_blank() && c.attr == attr)
+                    .map(|c| c.ch)
+                    .collect();
+                self.send(&DisplayPortCommand::Write { row: row as u8, col: col as u8, attr, text: run.clone() })?;
+                col += run.len();
+            }
+        }
+
+        self.send(&DisplayPortCommand::Draw)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::parser::MspParser;
+
+    #[test]
+    fn encode_decode() {
+        let commands = [
+            DisplayPortCommand::Heartbeat,
+            DisplayPortCommand::Clear,
+            DisplayPortCommand::write(2, 3, Attribute::new(1, true), "ALT"),
+            DisplayPortCommand::Draw,
+            DisplayPortCommand::Options { font: 0, resolution: CanvasResolution::Hd50x18 },
+        ];
+        for c in &commands {
+            assert_eq!(*c, DisplayPortCommand::decode(&c.encode()).unwrap());
+        }
+        assert_eq!(vec![3, 2, 3, 0x41, b'A', b'L', b'T'], commands[2].encode());
+        assert!(DisplayPortCommand::decode(&[3, 1]).is_err());
+    }
+
+    #[test]
+    fn screen_round_trip() {
+        let mut screen = VirtualOsdScreen::new(30, 16);
+        screen.put(0, 25, Attribute::default(), b"12.6V");
+        screen.put(15, 0, Attribute::new(0, true), b"LOW BAT");
+        screen.put(15, 7, Attribute::new(1, false), &[0x10]);
+
+        let mut port = MockFc::new();
+        CanvasWriter::new(&mut port).show(&screen).unwrap();
+
+        // what the goggles would see, decoded back into a second screen
+        let mut goggles = VirtualOsdScreen::new(30, 16);
+        let mut parser = MspParser::from_fc();
+        let mut writes = 0;
+        for &b in &port.written {
+            if let Ok(Some(p)) = parser.parse(b) {
+                writes += (p.data.as_slice()[0] == 3) as usize;
+                goggles.apply_packet(&p).unwrap();
+            }
+        }
+        // "LOW BAT" is split at the space
+        assert_eq!(4, writes);
+
+        screen.apply(&DisplayPortCommand::Draw);
+        assert_eq!(screen.text(), goggles.text());
+        assert!(goggles.text().starts_with("                         12.6V\n"));
+        assert!(goggles.text().lines().nth(15).unwrap().starts_with("LOW BAT·"));
+        assert_eq!(0x110, goggles.cell(15, 7).unwrap().glyph());
+
+        let image = goggles.render(2, 2, false, |_, _, _| Some([255; 4]));
+        assert_eq!(60 * 32 * 4, image.len());
+        // the blinking cell at row 15, col 0 is hidden, row 0 col 29 is drawn
+        let at = |x: usize, y: usize| &image[(y * 60 + x) * 4..(y * 60 + x) * 4 + 4];
+        assert_eq!(&[0; 4], at(0, 30));
+        assert_eq!(&[255; 4], at(58, 0));
+    }
+}
@@ -0,0 +1,330 @@
+//! Failsafe and per-channel RX-fail configuration, and timestamped failsafe events
+
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime};
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::FirmwareInfo;
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspFailsafeConfig, MspRxFailConfig, MspSetRxFailConfig, RxFailMode},
+};
+use crate::rx::decode_rc;
+use crate::status::{ArmingDisabled, FcStatus};
+
+/// Channel values a `Set` RX-fail mode takes [us]
+pub const RXFAIL_RANGE: std::ops::RangeInclusive<u16> = 750..=2250;
+
+impl MspFailsafeConfig {
+    pub fn delay(&self) -> Duration {
+        Duration::from_millis(self.failsafe_delay as u64 * 100)
+    }
+
+    pub fn off_delay(&self) -> Duration {
+        Duration::from_millis(self.failsafe_off_delay as u64 * 100)
+    }
+
+    pub fn throttle_low_delay(&self) -> Duration {
+        Duration::from_millis(self.failsafe_throttle_low_delay as u64 * 100)
+    }
+}
+
+/// `MSP_FAILSAFE_CONFIG` as read from the flight controller. iNav only takes its whole 20 byte
+/// payload, so the fields it appends to the shared ones are kept and sent back unchanged.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FailsafeConfig {
+    pub config: MspFailsafeConfig,
+    tail: Vec<u8>,
+}
+
+impl FailsafeConfig {
+    /// The iNav fields after the shared ones, empty on Betaflight
+    pub fn tail(&self) -> &[u8] {
+        &self.tail
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = self.config.pack_to_vec()?;
+        data.extend(&self.tail);
+        Ok(data)
+    }
+}
+
+pub fn read_failsafe_config(port: &mut dyn SerialPort) -> Result<FailsafeConfig> {
+    let reply = request(port, MspCommandCode::MSP_FAILSAFE_CONFIG as u16, &[])?;
+    let data = reply.data.as_slice();
+    let head = data.get(..MspFailsafeConfig::packed_bytes_size(None)?).ok_or_else(|| {
+        Error::msg(format!("MSP_FAILSAFE_CONFIG payload too short, {} bytes", data.len()))
+    })?;
+    Ok(FailsafeConfig { config: MspFailsafeConfig::unpack_from_slice(head)?, tail: data[head.len()..].to_vec() })
+}
+
+/// Send the failsafe config and check it reads back the same
+pub fn write_failsafe_config(port: &mut dyn SerialPort, config: &FailsafeConfig) -> Result<()> {
+    request(port, MspCommandCode::MSP_SET_FAILSAFE_CONFIG as u16, &config.encode()?)?;
+    let stored = read_failsafe_config(port)?;
+    if stored != *config {
+        return Err(Error::msg(format!("Failsafe config read back as {:?}", stored)));
+    }
+    Ok(())
+}
+
+/// RX-fail mode and value of every channel, roll, pitch, yaw and throttle first
+pub fn read_rxfail_config(port: &mut dyn SerialPort) -> Result<Vec<MspRxFailConfig>> {
+    let reply = request(port, MspCommandCode::MSP_RXFAIL_CONFIG as u16, &[])?;
+    Ok(reply
+        .data
+        .as_slice()
+        .chunks_exact(3)
+        .map(MspRxFailConfig::unpack_from_slice)
+        .collect::<Result<_, _>>()?)
+}
+
+/// Set the RX-fail mode of one channel. The sticks only take `Auto` or `Hold`, aux channels
+/// only `Hold` or `Set`.
+pub fn set_rxfail_config(port: &mut dyn SerialPort, index: u8, config: MspRxFailConfig) -> Result<()> {
+    let allowed = match index {
+        0..=3 => matches!(config.mode, RxFailMode::Auto | RxFailMode::Hold),
+        _ => matches!(config.mode, RxFailMode::Hold | RxFailMode::Set),
+    };
+    if !allowed {
+        return Err(Error::msg(format!("Channel {} cannot use RX-fail mode {:?}", index, config.mode)));
+    }
+    if config.mode == RxFailMode::Set && !RXFAIL_RANGE.contains(&config.value) {
+        return Err(Error::msg(format!("RX-fail value {} outside {:?}", config.value, RXFAIL_RANGE)));
+    }
+
+    let set = MspSetRxFailConfig { index, config };
+    request(port, MspCommandCode::MSP_SET_RXFAIL_CONFIG as u16, &set.pack_to_vec()?)?;
+    let stored = read_rxfail_config(port)?;
+    match stored.get(index as usize) {
+        // the value only matters, and is only kept, in Set mode
+        Some(s) if s.mode == config.mode && (s.mode != RxFailMode::Set || s.value == config.value) => Ok(()),
+        s => Err(Error::msg(format!("RX-fail config of channel {} read back as {:?}", index, s))),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum FailsafeStage {
+    /// The receiver has no valid signal
+    RxLoss,
+    /// The failsafe procedure is running
+    Failsafe,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FailsafeEvent {
+    pub stage: FailsafeStage,
+    /// Entered, or exited when false
+    pub entered: bool,
+    pub at: SystemTime,
+    /// Since the monitor started
+    pub elapsed: Duration,
+    /// RC channels when the change was seen
+    pub rc: Vec<u16>,
+}
+
+/// Watches the failsafe and RX loss flags of [`FcStatus`] and records every change with
+/// the RC values at the time
+pub struct FailsafeMonitor<'a> {
+    port: &'a mut dyn SerialPort,
+    firmware: FirmwareInfo,
+    started: Instant,
+    rx_loss: bool,
+    failsafe: bool,
+    events: Vec<FailsafeEvent>,
+}
+
+impl<'a> FailsafeMonitor<'a> {
+    pub fn new(port: &'a mut dyn SerialPort, firmware: &FirmwareInfo) -> FailsafeMonitor<'a> {
+        FailsafeMonitor {
+            port,
+            firmware: firmware.clone(),
+            started: Instant::now(), rx_loss: false, failsafe: false, events: Vec::new() }
+    }
+
+    /// Check the flags once, returning the events it caused
+    pub fn poll(&mut self) -> Result<&[FailsafeEvent]> {
+        let flags = FcStatus::read(self.port, &self.firmware)?.arming_disabled;
+        let rx_loss = flags.contains(ArmingDisabled::RX_LOSS);
+        let failsafe = flags.contains(ArmingDisabled::FAILSAFE);
+
+        let first = self.events.len();
+        if rx_loss != self.rx_loss || failsafe != self.failsafe {
+            let rc = decode_rc(request(self.port, MspCommandCode::MSP_RC as u16, &[])?.data.as_slice());
+            let (at, elapsed) = (SystemTime::now(), self.started.elapsed());
+            // entering RX loss comes before failsafe, leaving it after
+            let mut changes = vec![];
+            if rx_loss && !self.rx_loss {
+                changes.push((FailsafeStage::RxLoss, true));
+            }
+            if failsafe != self.failsafe {
+                changes.push((FailsafeStage::Failsafe, failsafe));
+            }
+            if !rx_loss && self.rx_loss {
+                changes.push((FailsafeStage::RxLoss, false));
+            }
+            for (stage, entered) in changes {
+                self.events.push(FailsafeEvent { stage, entered, at, elapsed, rc: rc.clone() });
+            }
+            (self.rx_loss, self.failsafe) = (rx_loss, failsafe);
+        }
+        Ok(&self.events[first..])
+    }
+
+    /// Poll every `interval` for `duration`
+    pub fn run_for(&mut self, interval: Duration, duration: Duration) -> Result<()> {
+        let end = Instant::now() + duration;
+        while Instant::now() < end {
+            let next = Instant::now() + interval;
+            self.poll()?;
+            sleep(next.min(end).saturating_duration_since(Instant::now()));
+        }
+        Ok(())
+    }
+
+    pub fn events(&self) -> &[FailsafeEvent] {
+        &self.events
+    }
+
+    /// Whether failsafe is active as of the last poll
+    pub fn in_failsafe(&self) -> bool {
+        self.failsafe
+    }
+
+    /// Each stage as (stage, entered after, lasted), a stage still active lasting until now
+    pub fn episodes(&self) -> Vec<(FailsafeStage, Duration, Duration)> {
+        let mut episodes = Vec::new();
+        for (i, entry) in self.events.iter().enumerate().filter(|(_, e)| e.entered) {
+            let exit = self.events[i + 1..].iter().find(|e| e.stage == entry.stage && !e.entered);
+            let end = exit.map_or(self.started.elapsed(), |e| e.elapsed);
+            episodes.push((entry.stage, entry.elapsed, end.saturating_sub(entry.elapsed)));
+        }
+        episodes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::msp::structs::{FailsafeProcedure, FailsafeSwitchMode};
+    use crate::firmware::{FirmwareVariant, test_firmware};
+    use crate::status::{inav_status_payload, status_ex_payload};
+    use packed_struct::PackedStruct;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn failsafe_and_rxfail_config() {
+        let config = MspFailsafeConfig {
+            failsafe_delay: 15,
+            failsafe_off_delay: 10,
+            failsafe_throttle: 1000,
+            failsafe_switch_mode: FailsafeSwitchMode::Stage1,
+            failsafe_throttle_low_delay: 100,
+            failsafe_procedure: FailsafeProcedure::Drop,
+        };
+        assert_eq!([15, 10, 0xE8, 0x03, 0, 100, 0, 1], config.pack().unwrap());
+
+        let mut rxfail = Vec::new();
+        for i in 0..8 {
+            let mode = if i < 4 { RxFailMode::Auto } else { RxFailMode::Hold };
+            rxfail.extend(MspRxFailConfig { mode, value: 1500 }.pack().unwrap());
+        }
+        let mut fc = MockFc::new()
+            .reply(MSP_FAILSAFE_CONFIG as u16, &[5, 10, 0xE8, 0x03, 0, 100, 0, 0])
+            .setter(MSP_SET_FAILSAFE_CONFIG as u16, MSP_FAILSAFE_CONFIG as u16)
+            .handler(move |p| match p.cmd {
+                c if c == MSP_RXFAIL_CONFIG as u16 => Some(rxfail.clone()),
+                c if c == MSP_SET_RXFAIL_CONFIG as u16 => {
+                    let at = p.data.as_slice()[0] as usize * 3;
+                    rxfail[at..at + 3].copy_from_slice(&p.data.as_slice()[1..]);
+                    Some(Vec::new())
+                }
+                _ => None,
+            });
+
+        let mut current = read_failsafe_config(&mut fc).unwrap();
+        assert_eq!(Duration::from_millis(500), current.config.delay());
+        assert_eq!(FailsafeProcedure::Land, current.config.failsafe_procedure);
+        current.config = config;
+        write_failsafe_config(&mut fc, &current).unwrap();
+        assert_eq!(Duration::from_secs(10), read_failsafe_config(&mut fc).unwrap().config.throttle_low_delay());
+
+        assert_eq!(8, read_rxfail_config(&mut fc).unwrap().len());
+        set_rxfail_config(&mut fc, 5, MspRxFailConfig { mode: RxFailMode::Set, value: 2000 }).unwrap();
+        assert_eq!(2000, read_rxfail_config(&mut fc).unwrap()[5].value);
+        assert!(set_rxfail_config(&mut fc, 3, MspRxFailConfig { mode: RxFailMode::Set, value: 1000 }).is_err());
+        assert!(set_rxfail_config(&mut fc, 6, MspRxFailConfig { mode: RxFailMode::Set, value: 2500 }).is_err());
+    }
+
+    #[test]
+    fn inav_failsafe_config_keeps_its_fields() {
+        // shared fields with RTH, then recovery delay, plane angles and rates, stick threshold and
+        // minimum distance with its procedure
+        let mut inav = vec![5, 200, 0xE8, 0x03, 0, 100, 0, 2];
+        inav.extend([5, 0x64, 0, 0xC8, 0, 0x2D, 0, 0x32, 0, 0, 0, 1]);
+        let mut fc = MockFc::new()
+            .reply(MSP_FAILSAFE_CONFIG as u16, &inav)
+            .handler(|p| (p.cmd == MSP_SET_FAILSAFE_CONFIG as u16 && p.data.as_slice().len() != 20).then(Vec::new))
+            .setter(MSP_SET_FAILSAFE_CONFIG as u16, MSP_FAILSAFE_CONFIG as u16);
+
+        let mut config = read_failsafe_config(&mut fc).unwrap();
+        assert_eq!((FailsafeProcedure::Rescue, 12), (config.config.failsafe_procedure, config.tail().len()));
+        config.config.failsafe_procedure = FailsafeProcedure::Land;
+        write_failsafe_config(&mut fc, &config).unwrap();
+        let stored = fc.value(MSP_FAILSAFE_CONFIG as u16).unwrap();
+        assert_eq!((20, 0), (stored.len(), stored[7]));
+        assert_eq!(inav[8..], stored[8..]);
+
+        // a short write leaves the old config in place, which the read back catches
+        let short = FailsafeConfig { tail: Vec::new(), ..config.clone() };
+        assert!(write_failsafe_config(&mut fc, &short).unwrap_err().to_string().contains("read back as"));
+        assert!(read_failsafe_config(&mut MockFc::new().reply(MSP_FAILSAFE_CONFIG as u16, &[5, 200])).is_err());
+    }
+
+    #[test]
+    fn failsafe_events() {
+        let rx_loss = ArmingDisabled::RX_LOSS;
+        let both = ArmingDisabled::RX_LOSS | ArmingDisabled::FAILSAFE;
+        let mut flags: VecDeque<u32> = vec![0, rx_loss, both, both, ArmingDisabled::FAILSAFE, 0].into();
+        let mut fc = MockFc::new().reply(MSP_RC as u16, &[0xDC, 0x05, 0xE8, 0x03]).handler(move |p| {
+            (p.cmd == MSP_STATUS_EX as u16).then(|| status_ex_payload(0, flags.pop_front().unwrap_or(0)))
+        });
+
+        let mut monitor = FailsafeMonitor::new(&mut fc, &test_firmware(FirmwareVariant::Betaflight, 46));
+        assert!(monitor.poll().unwrap().is_empty());
+        assert_eq!(FailsafeStage::RxLoss, monitor.poll().unwrap()[0].stage);
+        let entered = monitor.poll().unwrap();
+        assert_eq!((FailsafeStage::Failsafe, true), (entered[0].stage, entered[0].entered));
+        assert_eq!(vec![1500, 1000], entered[0].rc);
+        assert!(monitor.poll().unwrap().is_empty());
+        assert!(monitor.in_failsafe());
+        monitor.run_for(Duration::from_millis(1), Duration::from_millis(5)).unwrap();
+        assert!(!monitor.in_failsafe());
+
+        let kinds: Vec<_> = monitor.events().iter().map(|e| (e.stage, e.entered)).collect();
+        use FailsafeStage::*;
+        assert_eq!(vec![(RxLoss, true), (Failsafe, true), (RxLoss, false), (Failsafe, false)], kinds);
+        let episodes = monitor.episodes();
+        assert_eq!(2, episodes.len());
+        assert!(episodes.iter().all(|(_, _, d)| *d < Duration::from_secs(1)));
+        drop(monitor);
+        assert_eq!(4, fc.commands().iter().filter(|c| **c == MSP_RC as u16).count());
+
+        // iNav RC link loss and failsafe system flags
+        let mut flags: VecDeque<u32> = vec![1 << 18, (1 << 18) | (1 << 7)].into();
+        let mut fc = MockFc::new().reply(MSP_RC as u16, &[0xDC, 0x05]).handler(move |p| {
+            (p.cmd == MSP2_INAV_STATUS as u16).then(|| inav_status_payload(0, flags.pop_front().unwrap_or(0), 0))
+        });
+        let mut monitor = FailsafeMonitor::new(&mut fc, &test_firmware(FirmwareVariant::Inav, 5));
+        assert_eq!(FailsafeStage::RxLoss, monitor.poll().unwrap()[0].stage);
+        assert_eq!(FailsafeStage::Failsafe, monitor.poll().unwrap()[0].stage);
+        assert!(monitor.in_failsafe());
+    }
+}
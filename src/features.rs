@@ -0,0 +1,282 @@
+//! Feature flags of `MSP_FEATURE`, named from the table of the probed firmware
+
+use std::fmt;
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::de::{DeserializeSeed, Deserializer};
+use serde::ser::{SerializeSeq, Serializer};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspBfConfig, MspFeatures},
+};
+
+/// Betaflight `features_e` names, by bit. Empty bits are unused.
+pub const BETAFLIGHT_FEATURES: [&str; 30] = [
+    "RX_PPM", "", "INFLIGHT_ACC_CAL", "RX_SERIAL", "MOTOR_STOP", "SERVO_TILT", "SOFTSERIAL", "GPS", "",
+    "RANGEFINDER", "TELEMETRY", "", "3D", "RX_PARALLEL_PWM", "RX_MSP", "RSSI_ADC", "LED_STRIP", "DISPLAY",
+    "OSD", "", "CHANNEL_FORWARDING", "TRANSPONDER", "AIRMODE", "", "", "RX_SPI", "", "ESC_SENSOR",
+    "ANTI_GRAVITY", "DYNAMIC_FILTER",
+];
+
+/// iNav `features_e` names, by bit. Empty bits are unused.
+pub const INAV_FEATURES: [&str; 32] = [
+    "THR_VBAT_COMP", "VBAT", "TX_PROF_SEL", "BAT_PROF_AUTOSWITCH", "MOTOR_STOP", "", "SOFTSERIAL", "GPS", "",
+    "", "TELEMETRY", "CURRENT_METER", "REVERSIBLE_MOTORS", "", "", "RSSI_ADC", "LED_STRIP", "DASHBOARD", "",
+    "BLACKBOX", "", "TRANSPONDER", "AIRMODE", "SUPEREXPO", "VTX", "", "", "", "PWM_OUTPUT_ENABLE", "OSD",
+    "FW_LAUNCH", "FW_AUTOTRIM",
+];
+
+fn feature_names(variant: &FirmwareVariant) -> &'static [&'static str] {
+    match variant {
+        FirmwareVariant::Inav => &INAV_FEATURES,
+        _ => &BETAFLIGHT_FEATURES,
+    }
+}
+
+/// Enabled features of one firmware. Serializes as the list of enabled names; deserialize
+/// through [`FeaturesSeed`] as the names only mean something for a given firmware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Features {
+    pub variant: FirmwareVariant,
+    pub bits: u32,
+}
+
+impl Features {
+    // same bit on Betaflight and iNav
+    pub const MOTOR_STOP: u32 = 1 << 4;
+    pub const SOFTSERIAL: u32 = 1 << 6;
+    pub const GPS: u32 = 1 << 7;
+    pub const TELEMETRY: u32 = 1 << 10;
+    pub const RSSI_ADC: u32 = 1 << 15;
+    pub const LED_STRIP: u32 = 1 << 16;
+    pub const TRANSPONDER: u32 = 1 << 21;
+    pub const AIRMODE: u32 = 1 << 22;
+
+    pub const BF_RX_PPM: u32 = 1 << 0;
+    pub const BF_RX_SERIAL: u32 = 1 << 3;
+    pub const BF_3D: u32 = 1 << 12;
+    pub const BF_RX_MSP: u32 = 1 << 14;
+    pub const BF_OSD: u32 = 1 << 18;
+    pub const BF_RX_SPI: u32 = 1 << 25;
+    pub const BF_ESC_SENSOR: u32 = 1 << 27;
+
+    pub const INAV_VBAT: u32 = 1 << 1;
+    pub const INAV_CURRENT_METER: u32 = 1 << 11;
+    pub const INAV_PWM_OUTPUT_ENABLE: u32 = 1 << 28;
+    pub const INAV_OSD: u32 = 1 << 29;
+
+    pub fn new(variant: FirmwareVariant, bits: u32) -> Features {
+        Features { variant, bits }
+    }
+
+    /// Bit of feature `name` on `variant`, case insensitive
+    pub fn bit(variant: &FirmwareVariant, name: &str) -> Option<u32> {
+        feature_names(variant)
+            .iter()
+            .position(|n| !n.is_empty() && n.eq_ignore_ascii_case(name))
+            .map(|bit| 1 << bit)
+    }
+
+    /// Features by name, failing on names `variant` does not have
+    pub fn from_names<S: AsRef<str>>(variant: FirmwareVariant, names: &[S]) -> Result<Features> {
+        let mut features = Features::new(variant, 0);
+        for name in names {
+            features.enable(name.as_ref())?;
+        }
+        Ok(features)
+    }
+
+    pub fn contains(&self, bits: u32) -> bool {
+        self.bits & bits == bits
+    }
+
+    pub fn is_enabled(&self, name: &str) -> bool {
+        Self::bit(&self.variant, name).is_some_and(|bit| self.contains(bit))
+    }
+
+    pub fn enable(&mut self, name: &str) -> Result<()> {
+        self.bits |= self.lookup(name)?;
+        Ok(())
+    }
+
+    pub fn disable(&mut self, name: &str) -> Result<()> {
+        self.bits &= !self.lookup(name)?;
+        Ok(())
+    }
+
+    /// Names of the enabled features, `BIT<n>` for ones newer than the table
+    pub fn names(&self) -> Vec<String> {
+        let table = feature_names(&self.variant);
+        (0..32)
+            .filter(|bit| self.bits & (1 << bit) != 0)
+            .map(|bit| match table.get(bit) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => format!("BIT{}", bit),
+            })
+            .collect()
+    }
+
+    fn lookup(&self, name: &str) -> Result<u32> {
+        if let Some(bit) = name.strip_prefix("BIT").and_then(|n| n.parse::<u32>().ok()).filter(|n| *n < 32) {
+            return Ok(1 << bit);
+        }
+        Self::bit(&self.variant, name).ok_or_else(|| Error::msg(format!("{} has no feature {}", self.variant, name)))
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.bits {
+            0 => f.write_str("none"),
+            _ => f.write_str(&self.names().join(" ")),
+        }
+    }
+}
+
+impl Serialize for Features {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let names = self.names();
+        let mut seq = serializer.serialize_seq(Some(names.len()))?;
+        for name in &names {
+            seq.serialize_element(name)?;
+        }
+        seq.end()
+    }
+}
+
+/// Deserializes a list of feature names for one firmware
+pub struct FeaturesSeed(pub FirmwareVariant);
+
+impl<'de> DeserializeSeed<'de> for FeaturesSeed {
+    type Value = Features;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Features, D::Error> {
+        let names = Vec::<String>::deserialize(deserializer)?;
+        Features::from_names(self.0, &names).map_err(serde::de::Error::custom)
+    }
+}
+
+impl MspBfConfig {
+    pub fn feature_set(&self, variant: FirmwareVariant) -> Features {
+        Features::new(variant, self.features)
+    }
+}
+
+pub fn read_features(port: &mut dyn SerialPort, firmware: &FirmwareInfo) -> Result<Features> {
+    let raw = request(port, MspCommandCode::MSP_FEATURE as u16, &[])?.decode_as::<MspFeatures>()?;
+    Ok(Features::new(firmware.variant.clone(), raw.features))
+}
+
+/// Send the features and check they read back the same. They take effect after saving and
+/// rebooting.
+pub fn write_features(port: &mut dyn SerialPort, features: &Features) -> Result<()> {
+    let raw = MspFeatures { features: features.bits };
+    request(port, MspCommandCode::MSP_SET_FEATURE as u16, &raw.pack_to_vec()?)?;
+    let stored = request(port, MspCommandCode::MSP_FEATURE as u16, &[])?.decode_as::<MspFeatures>()?;
+    if stored.features != features.bits {
+        let stored = Features::new(features.variant.clone(), stored.features);
+        return Err(Error::msg(format!("Features read back as {}", stored)));
+    }
+    Ok(())
+}
+
+/// Read the features, enable and disable the named ones, write them back and save to EEPROM.
+/// Returns the features as written.
+pub fn update_features(
+    port: &mut dyn SerialPort,
+    firmware: &FirmwareInfo,
+    enable: &[&str],
+    disable: &[&str],
+) -> Result<Features> {
+    let mut features = read_features(port, firmware)?;
+    let before = features.bits;
+    for name in enable {
+        features.enable(name)?;
+    }
+    for name in disable {
+        features.disable(name)?;
+    }
+    if features.bits != before {
+        write_features(port, &features)?;
+        request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+    }
+    Ok(features)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::firmware::test_firmware;
+
+    #[test]
+    fn names_per_firmware_and_serde() {
+        let bf = Features::new(FirmwareVariant::Betaflight, Features::GPS | Features::BF_OSD | Features::BF_RX_PPM);
+        assert_eq!(vec!["RX_PPM", "GPS", "OSD"], bf.names());
+        assert!(bf.is_enabled("osd") && !bf.is_enabled("LED_STRIP"));
+        let inav = Features::new(FirmwareVariant::Inav, bf.bits);
+        assert_eq!("THR_VBAT_COMP GPS BIT18", inav.to_string());
+        assert_eq!(Some(Features::INAV_OSD), Features::bit(&FirmwareVariant::Inav, "OSD"));
+        assert!(Features::from_names(FirmwareVariant::Betaflight, &["FW_LAUNCH"]).is_err());
+
+        let json = serde_json::to_string(&bf).unwrap();
+        assert_eq!(r#"["RX_PPM","GPS","OSD"]"#, json);
+        let mut de = serde_json::Deserializer::from_str(&json);
+        assert_eq!(bf, FeaturesSeed(FirmwareVariant::Betaflight).deserialize(&mut de).unwrap());
+        let mut de = serde_json::Deserializer::from_str(r#"["GPS","NOPE"]"#);
+        assert!(FeaturesSeed(FirmwareVariant::Inav).deserialize(&mut de).is_err());
+    }
+
+    #[test]
+    fn read_modify_write() {
+        let firmware = test_firmware(FirmwareVariant::Betaflight, 46);
+        let initial = (Features::GPS | Features::BF_RX_SERIAL).to_le_bytes();
+        let mut fc = MockFc::new()
+            .reply(MSP_FEATURE as u16, &initial)
+            .setter(MSP_SET_FEATURE as u16, MSP_FEATURE as u16)
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+
+        let features = update_features(&mut fc, &firmware, &["LED_STRIP", "osd"], &["GPS"]).unwrap();
+        assert_eq!(vec!["RX_SERIAL", "LED_STRIP", "OSD"], features.names());
+        assert_eq!(Some(MSP_EEPROM_WRITE as u16), fc.commands().last().copied());
+        assert_eq!(features, read_features(&mut fc, &firmware).unwrap());
+
+        let sent = fc.commands().len();
+        update_features(&mut fc, &firmware, &["LED_STRIP"], &[]).unwrap();
+        assert_eq!(sent + 1, fc.commands().len());
+        assert!(update_features(&mut fc, &firmware, &["DASHBOARD"], &[]).is_err());
+    }
+
+    #[test]
+    fn inav_names_and_dropped_feature() {
+        let firmware = test_firmware(FirmwareVariant::Inav, 5);
+        // an iNav build without OSD support silently drops the bit
+        let mut stored = Features::INAV_VBAT;
+        let mut fc = MockFc::new().reply(MSP_EEPROM_WRITE as u16, &[]).handler(move |p| match p.cmd {
+            c if c == MSP_SET_FEATURE as u16 => {
+                let data = p.data.as_slice();
+                stored = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) & !Features::INAV_OSD;
+                Some(Vec::new())
+            }
+            c if c == MSP_FEATURE as u16 => Some(stored.to_le_bytes().to_vec()),
+            _ => None,
+        });
+
+        assert!(update_features(&mut fc, &firmware, &["ESC_SENSOR"], &[]).is_err());
+        assert_eq!(vec![MSP_FEATURE as u16], fc.commands());
+
+        let error = update_features(&mut fc, &firmware, &["osd", "FW_LAUNCH"], &["VBAT"]).unwrap_err();
+        assert_eq!("Features read back as FW_LAUNCH", error.to_string());
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+
+        update_features(&mut fc, &firmware, &["VBAT"], &[]).unwrap();
+        assert_eq!(vec!["VBAT", "FW_LAUNCH"], read_features(&mut fc, &firmware).unwrap().names());
+        assert_eq!(Some(MSP_EEPROM_WRITE as u16), fc.commands().iter().rev().nth(1).copied());
+    }
+}
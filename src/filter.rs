@@ -0,0 +1,502 @@
+//! Gyro and D-term filter config in the `MSP_FILTER_CONFIG` layout of the firmware's API
+//! version, and the frequency response of the filter chain it describes
+
+use std::f32::consts::PI;
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::commands::MspCommandCode;
+
+/// Payload sizes of `MSP_FILTER_CONFIG` by the Betaflight MSP API version that extended it
+const LAYOUTS: [((u8, u8), usize); 6] =
+    [((1, 44), 49), ((1, 43), 47), ((1, 42), 45), ((1, 41), 37), ((1, 39), 28), ((1, 36), 18)];
+/// Size of the head every version sends, `MspFilterConfig`
+const LEGACY_SIZE: usize = 17;
+const FULL_SIZE: usize = 49;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+pub enum LowpassType {
+    #[default]
+    Pt1,
+    Biquad,
+    Pt2,
+    Pt3,
+}
+
+impl LowpassType {
+    pub fn from_u8(value: u8) -> Option<LowpassType> {
+        match value {
+            0 => Some(LowpassType::Pt1),
+            1 => Some(LowpassType::Biquad),
+            2 => Some(LowpassType::Pt2),
+            3 => Some(LowpassType::Pt3),
+            _ => None,
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            LowpassType::Pt1 => 0,
+            LowpassType::Biquad => 1,
+            LowpassType::Pt2 => 2,
+            LowpassType::Pt3 => 3,
+        }
+    }
+}
+
+/// Every field of the newest Betaflight `MSP_FILTER_CONFIG` [Hz unless noted]. Fields past
+/// the layout of the firmware it was read from stay zero and are not sent back.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct FilterConfig {
+    pub gyro_lpf1_hz: u16,
+    pub dterm_lpf1_hz: u16,
+    pub yaw_lpf_hz: u16,
+    pub gyro_notch1_hz: u16,
+    pub gyro_notch1_cutoff: u16,
+    pub dterm_notch_hz: u16,
+    pub dterm_notch_cutoff: u16,
+    pub gyro_notch2_hz: u16,
+    pub gyro_notch2_cutoff: u16,
+    // API 1.36
+    pub dterm_lpf1_type: LowpassType,
+    // API 1.39
+    pub gyro_hardware_lpf: u8,
+    pub gyro_lpf2_hz: u16,
+    pub gyro_lpf1_type: LowpassType,
+    pub gyro_lpf2_type: LowpassType,
+    pub dterm_lpf2_hz: u16,
+    // API 1.41
+    pub dterm_lpf2_type: LowpassType,
+    /// Dynamic lowpass replaces the static one when the minimum is set
+    pub gyro_lpf1_dyn_min_hz: u16,
+    pub gyro_lpf1_dyn_max_hz: u16,
+    pub dterm_lpf1_dyn_min_hz: u16,
+    pub dterm_lpf1_dyn_max_hz: u16,
+    // API 1.42
+    pub dyn_notch_range: u8,
+    pub dyn_notch_width_percent: u8,
+    pub dyn_notch_q: u16,
+    pub dyn_notch_min_hz: u16,
+    pub rpm_filter_harmonics: u8,
+    pub rpm_filter_min_hz: u8,
+    // API 1.43
+    pub dyn_notch_max_hz: u16,
+    // API 1.44
+    pub dterm_lpf1_dyn_expo: u8,
+    pub dyn_notch_count: u8,
+    /// Bytes of the layout this was read with, zero when not read from a flight controller
+    size: usize,
+    /// Fields of firmware newer than this decoder, sent back unchanged
+    tail: Vec<u8>,
+}
+
+impl FilterConfig {
+    /// Payload size of `firmware`. iNav appends its own fields to the shared head, which are
+    /// kept in [`FilterConfig::tail`].
+    pub fn layout_size(firmware: &FirmwareInfo) -> usize {
+        match firmware.variant {
+            FirmwareVariant::Betaflight => LAYOUTS
+                .iter()
+                .find(|((major, minor), _)| firmware.api_at_least(*major, *minor))
+                .map_or(LEGACY_SIZE, |(_, size)| *size),
+            _ => LEGACY_SIZE,
+        }
+    }
+
+    pub fn decode(data: &[u8], firmware: &FirmwareInfo) -> Result<FilterConfig> {
+        let size = Self::layout_size(firmware);
+        if data.len() < size {
+            return Err(Error::msg(format!("MSP_FILTER_CONFIG payload of {} bytes, expected {}", data.len(), size)));
+        }
+        let mut full = data[..size].to_vec();
+        full.resize(FULL_SIZE, 0);
+        let u8_at = |at: usize| full[at];
+        let u16_at = |at: usize| u16::from_le_bytes([full[at], full[at + 1]]);
+        let lowpass = |at: usize| {
+            LowpassType::from_u8(full[at]).ok_or_else(|| Error::msg(format!("Unknown lowpass type {}", full[at])))
+        };
+
+        Ok(FilterConfig {
+            // only the low byte up front, the whole value follows from API 1.39
+            gyro_lpf1_hz: if size >= 22 { u16_at(20) } else { u8_at(0) as u16 },
+            dterm_lpf1_hz: u16_at(1),
+            yaw_lpf_hz: u16_at(3),
+            gyro_notch1_hz: u16_at(5),
+            gyro_notch1_cutoff: u16_at(7),
+            dterm_notch_hz: u16_at(9),
+            dterm_notch_cutoff: u16_at(11),
+            gyro_notch2_hz: u16_at(13),
+            gyro_notch2_cutoff: u16_at(15),
+            dterm_lpf1_type: lowpass(17)?,
+            gyro_hardware_lpf: u8_at(18),
+            gyro_lpf2_hz: u16_at(22),
+            gyro_lpf1_type: lowpass(24)?,
+            gyro_lpf2_type: lowpass(25)?,
+            dterm_lpf2_hz: u16_at(26),
+            dterm_lpf2_type: lowpass(28)?,
+            gyro_lpf1_dyn_min_hz: u16_at(29),
+            gyro_lpf1_dyn_max_hz: u16_at(31),
+            dterm_lpf1_dyn_min_hz: u16_at(33),
+            dterm_lpf1_dyn_max_hz: u16_at(35),
+            dyn_notch_range: u8_at(37),
+            dyn_notch_width_percent: u8_at(38),
+            dyn_notch_q: u16_at(39),
+            dyn_notch_min_hz: u16_at(41),
+            rpm_filter_harmonics: u8_at(43),
+            rpm_filter_min_hz: u8_at(44),
+            dyn_notch_max_hz: u16_at(45),
+            dterm_lpf1_dyn_expo: u8_at(47),
+            dyn_notch_count: u8_at(48),
+            size,
+            tail: data[size..].to_vec(),
+        })
+    }
+
+    /// Fields after the known layout, from iNav or firmware newer than this decoder
+    pub fn tail(&self) -> &[u8] {
+        &self.tail
+    }
+
+    /// Payload for `MSP_SET_FILTER_CONFIG` in the layout this was read with, which has to be the
+    /// one of `firmware`
+    pub fn encode(&self, firmware: &FirmwareInfo) -> Result<Vec<u8>> {
+        let expected = Self::layout_size(firmware);
+        if self.size != expected {
+            return Err(Error::msg(format!(
+                "Filter config has a {} byte layout, {} takes {}; read it from the flight controller first",
+                self.size, firmware, expected
+            )));
+        }
+        let mut data = vec![self.gyro_lpf1_hz.min(255) as u8];
+        let u16s = |data: &mut Vec<u8>, values: &[u16]| values.iter().for_each(|v| data.extend(v.to_le_bytes()));
+        u16s(&mut data, &[
+            self.dterm_lpf1_hz,
+            self.yaw_lpf_hz,
+            self.gyro_notch1_hz,
+            self.gyro_notch1_cutoff,
+            self.dterm_notch_hz,
+            self.dterm_notch_cutoff,
+            self.gyro_notch2_hz,
+            self.gyro_notch2_cutoff,
+        ]);
+        data.extend([self.dterm_lpf1_type.to_u8(), self.gyro_hardware_lpf, 0]);
+        u16s(&mut data, &[self.gyro_lpf1_hz, self.gyro_lpf2_hz]);
+        data.extend([self.gyro_lpf1_type.to_u8(), self.gyro_lpf2_type.to_u8()]);
+        u16s(&mut data, &[self.dterm_lpf2_hz]);
+        data.push(self.dterm_lpf2_type.to_u8());
+        u16s(&mut data, &[
+            self.gyro_lpf1_dyn_min_hz,
+            self.gyro_lpf1_dyn_max_hz,
+            self.dterm_lpf1_dyn_min_hz,
+            self.dterm_lpf1_dyn_max_hz,
+        ]);
+        data.extend([self.dyn_notch_range, self.dyn_notch_width_percent]);
+        u16s(&mut data, &[self.dyn_notch_q, self.dyn_notch_min_hz]);
+        data.extend([self.rpm_filter_harmonics, self.rpm_filter_min_hz]);
+        u16s(&mut data, &[self.dyn_notch_max_hz]);
+        data.extend([self.dterm_lpf1_dyn_expo, self.dyn_notch_count]);
+
+        data.truncate(self.size);
+        data.extend(&self.tail);
+        Ok(data)
+    }
+
+    pub fn read(port: &mut dyn SerialPort, firmware: &FirmwareInfo) -> Result<FilterConfig> {
+        let reply = request(port, MspCommandCode::MSP_FILTER_CONFIG as u16, &[])?;
+        Self::decode(reply.data.as_slice(), firmware)
+    }
+
+    /// Notch cutoffs below their centers and dynamic lowpass minimums at or below their
+    /// maximums, otherwise the firmware silently disables or clamps them
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        for (name, hz, cutoff) in [
+            ("gyro notch 1", self.gyro_notch1_hz, self.gyro_notch1_cutoff),
+            ("gyro notch 2", self.gyro_notch2_hz, self.gyro_notch2_cutoff),
+            ("D-term notch", self.dterm_notch_hz, self.dterm_notch_cutoff),
+        ] {
+            if hz > 0 && cutoff >= hz {
+                problems.push(format!("{} cutoff {} not below center {}", name, cutoff, hz));
+            }
+        }
+        for (name, min, max) in [
+            ("gyro", self.gyro_lpf1_dyn_min_hz, self.gyro_lpf1_dyn_max_hz),
+            ("D-term", self.dterm_lpf1_dyn_min_hz, self.dterm_lpf1_dyn_max_hz),
+        ] {
+            if min > max {
+                problems.push(format!("{} dynamic lowpass min {} above max {}", name, min, max));
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(Error::msg(format!("Invalid filter config: {}", problems.join(", "))));
+        }
+        Ok(())
+    }
+
+    /// Validate, send, check it reads back the same and save to EEPROM
+    pub fn write(&self, port: &mut dyn SerialPort, firmware: &FirmwareInfo) -> Result<()> {
+        self.validate()?;
+        let data = self.encode(firmware)?;
+        request(port, MspCommandCode::MSP_SET_FILTER_CONFIG as u16, &data)?;
+        let stored = Self::read(port, firmware)?;
+        if stored != *self {
+            return Err(Error::msg(format!("Filter config read back as {:?}", stored)));
+        }
+        request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        Ok(())
+    }
+
+    /// Static gyro filters. A dynamic lowpass is taken at its minimum cutoff, where it delays
+    /// the most. The dynamic notches and RPM filter follow the motors and are left out.
+    pub fn gyro_chain(&self, sample_hz: f32) -> FilterChain {
+        let mut stages = Vec::new();
+        let lpf1 = match self.gyro_lpf1_dyn_min_hz {
+            0 => self.gyro_lpf1_hz,
+            min => min,
+        };
+        push_lowpass(&mut stages, self.gyro_lpf1_type, lpf1);
+        push_lowpass(&mut stages, self.gyro_lpf2_type, self.gyro_lpf2_hz);
+        push_notch(&mut stages, self.gyro_notch1_hz, self.gyro_notch1_cutoff);
+        push_notch(&mut stages, self.gyro_notch2_hz, self.gyro_notch2_cutoff);
+        FilterChain { sample_hz, stages }
+    }
+
+    /// The gyro chain followed by the D-term filters, as D is computed from filtered gyro
+    pub fn dterm_chain(&self, sample_hz: f32) -> FilterChain {
+        let mut chain = self.gyro_chain(sample_hz);
+        let lpf1 = match self.dterm_lpf1_dyn_min_hz {
+            0 => self.dterm_lpf1_hz,
+            min => min,
+        };
+        push_lowpass(&mut chain.stages, self.dterm_lpf1_type, lpf1);
+        push_lowpass(&mut chain.stages, self.dterm_lpf2_type, self.dterm_lpf2_hz);
+        push_notch(&mut chain.stages, self.dterm_notch_hz, self.dterm_notch_cutoff);
+        chain
+    }
+}
+
+fn push_lowpass(stages: &mut Vec<FilterStage>, kind: LowpassType, hz: u16) {
+    if hz > 0 {
+        stages.push(FilterStage::Lowpass { kind, hz: hz as f32 });
+    }
+}
+
+fn push_notch(stages: &mut Vec<FilterStage>, center: u16, cutoff: u16) {
+    if center > 0 && cutoff > 0 && cutoff < center {
+        stages.push(FilterStage::Notch { center: center as f32, cutoff: cutoff as f32 });
+    }
+}
+
+/// One filter of a chain, discretised the way Betaflight does it
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum FilterStage {
+    Lowpass { kind: LowpassType, hz: f32 },
+    /// Cutoff is the lower frequency where the notch is 3 dB down
+    Notch { center: f32, cutoff: f32 },
+}
+
+/// Normalised biquad coefficients `[b0, b1, b2, a1, a2]`
+type Biquad = [f32; 5];
+
+fn pt_gain(hz: f32, order: i32, dt: f32) -> f32 {
+    // cutoff correction so cascaded stages are 3 dB down at `hz` together
+    let correction = 1.0 / (2f32.powf(1.0 / order as f32) - 1.0).sqrt();
+    let rc = 1.0 / (2.0 * PI * hz * correction);
+    dt / (rc + dt)
+}
+
+fn biquad(hz: f32, q: f32, dt: f32, notch: bool) -> Biquad {
+    let omega = 2.0 * PI * hz * dt;
+    let (sn, cs) = omega.sin_cos();
+    let alpha = sn / (2.0 * q);
+    let a0 = 1.0 + alpha;
+    let b = if notch {
+        [1.0, -2.0 * cs, 1.0]
+    } else {
+        [(1.0 - cs) / 2.0, 1.0 - cs, (1.0 - cs) / 2.0]
+    };
+    [b[0] / a0, b[1] / a0, b[2] / a0, -2.0 * cs / a0, (1.0 - alpha) / a0]
+}
+
+impl FilterStage {
+    fn sections(&self, sample_hz: f32) -> Vec<Biquad> {
+        let dt = 1.0 / sample_hz;
+        let pt = |order: i32, hz: f32| {
+            let k = pt_gain(hz, order, dt);
+            vec![[k, 0.0, 0.0, k - 1.0, 0.0]; order as usize]
+        };
+        match *self {
+            FilterStage::Lowpass { kind: LowpassType::Pt1, hz } => pt(1, hz),
+            FilterStage::Lowpass { kind: LowpassType::Pt2, hz } => pt(2, hz),
+            FilterStage::Lowpass { kind: LowpassType::Pt3, hz } => pt(3, hz),
+            FilterStage::Lowpass { kind: LowpassType::Biquad, hz } => vec![biquad(hz, 1.0 / 2f32.sqrt(), dt, false)],
+            FilterStage::Notch { center, cutoff } => {
+                let q = center * cutoff / (center * center - cutoff * cutoff);
+                vec![biquad(center, q, dt, true)]
+            }
+        }
+    }
+}
+
+/// Response of a filter at one frequency
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Response {
+    pub frequency: f32,
+    /// Output over input amplitude
+    pub gain: f32,
+    /// [deg], negative is lagging
+    pub phase: f32,
+    /// Phase lag as time [ms]
+    pub delay_ms: f32,
+}
+
+impl Response {
+    pub fn gain_db(&self) -> f32 {
+        20.0 * self.gain.log10()
+    }
+}
+
+/// Filters applied one after another at `sample_hz`, the PID loop rate
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FilterChain {
+    pub sample_hz: f32,
+    pub stages: Vec<FilterStage>,
+}
+
+impl FilterChain {
+    pub fn response(&self, frequency: f32) -> Response {
+        let w = 2.0 * PI * frequency / self.sample_hz;
+        let (mut gain, mut phase) = (1.0f32, 0.0f32);
+        for [b0, b1, b2, a1, a2] in self.stages.iter().flat_map(|s| s.sections(self.sample_hz)) {
+            // H(e^jw) with z^-1 = cos w - j sin w
+            let eval = |c0: f32, c1: f32, c2: f32| {
+                (c0 + c1 * w.cos() + c2 * (2.0 * w).cos(), -c1 * w.sin() - c2 * (2.0 * w).sin())
+            };
+            let (nr, ni) = eval(b0, b1, b2);
+            let (dr, di) = eval(1.0, a1, a2);
+            gain *= nr.hypot(ni) / dr.hypot(di);
+            phase += ni.atan2(nr) - di.atan2(dr);
+        }
+        let phase = phase.to_degrees();
+        let delay_ms = if frequency > 0.0 {
+            -phase / 360.0 / frequency * 1000.0
+        } else {
+            0.0
+        };
+        Response { frequency, gain, phase, delay_ms }
+    }
+
+    pub fn responses(&self, frequencies: &[f32]) -> Vec<Response> {
+        frequencies.iter().map(|f| self.response(*f)).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::firmware::test_firmware;
+    use crate::msp::structs::MspFilterConfig;
+    use packed_struct::PackedStruct;
+
+    #[test]
+    fn layouts_by_api_version() {
+        let [api31, api41, api46, api47] =
+            [31, 41, 46, 47].map(|minor| test_firmware(FirmwareVariant::Betaflight, minor));
+        let legacy = MspFilterConfig {
+            gyro_soft_lpf_hz: 90,
+            dterm_lpf_hz: 100,
+            gyro_soft_notch_hz_1: 200,
+            ..Default::default()
+        };
+        let data = legacy.pack().unwrap();
+        let config = FilterConfig::decode(&data, &api31).unwrap();
+        assert_eq!((17, 90, 100, 200), (config.size, config.gyro_lpf1_hz, config.dterm_lpf1_hz, config.gyro_notch1_hz));
+        assert_eq!(data.to_vec(), config.encode(&api31).unwrap());
+        assert!(FilterConfig::decode(&data, &api46).is_err());
+
+        let mut config = FilterConfig { size: 49, ..config };
+        config.gyro_lpf1_hz = 500;
+        config.gyro_lpf2_type = LowpassType::Pt2;
+        config.dyn_notch_max_hz = 600;
+        config.dyn_notch_count = 3;
+        let data = config.encode(&api46).unwrap();
+        assert_eq!(49, data.len());
+        assert_eq!(255, data[0]);
+        let decoded = FilterConfig::decode(&data, &api46).unwrap();
+        assert_eq!(config, decoded);
+
+        // bytes past the layout land in the tail and are sent back as they came
+        let mut newer = data.clone();
+        newer.push(7);
+        assert_eq!([7], FilterConfig::decode(&newer, &api47).unwrap().tail());
+        let older = FilterConfig::decode(&data, &api41).unwrap();
+        assert_eq!((37, 0, 0), (older.size, older.dyn_notch_max_hz, older.dyn_notch_count));
+        assert_eq!((12, data.clone()), (older.tail().len(), older.encode(&api41).unwrap()));
+
+        let mut fc = MockFc::new()
+            .reply(MSP_FILTER_CONFIG as u16, &data)
+            .setter(MSP_SET_FILTER_CONFIG as u16, MSP_FILTER_CONFIG as u16)
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+        let mut config = FilterConfig::read(&mut fc, &api46).unwrap();
+        config.dterm_notch_hz = 150;
+        config.dterm_notch_cutoff = 160;
+        assert!(config.write(&mut fc, &api46).unwrap_err().to_string().contains("D-term notch"));
+        config.dterm_notch_cutoff = 100;
+        config.write(&mut fc, &api46).unwrap();
+        assert_eq!(150, FilterConfig::read(&mut fc, &api46).unwrap().dterm_notch_hz);
+
+        // a config not read from this firmware is never sent
+        let sent = fc.commands().len();
+        assert!(FilterConfig::default().write(&mut fc, &api46).unwrap_err().to_string().contains("0 byte"));
+        assert!(older.write(&mut fc, &api46).is_err());
+        assert_eq!(sent, fc.commands().len());
+
+        // the firmware clamping a value fails the read back and skips the save
+        let mut fc = MockFc::new()
+            .reply(MSP_FILTER_CONFIG as u16, &data)
+            .reply(MSP_SET_FILTER_CONFIG as u16, &[])
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+        config.gyro_lpf2_hz = 1000;
+        assert!(config.write(&mut fc, &api46).unwrap_err().to_string().starts_with("Filter config read back"));
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+    }
+
+    #[test]
+    fn frequency_response() {
+        let lowpass = |kind| FilterChain { sample_hz: 8000.0, stages: vec![FilterStage::Lowpass { kind, hz: 100.0 }] };
+        for kind in [LowpassType::Pt1, LowpassType::Pt2, LowpassType::Pt3, LowpassType::Biquad] {
+            let at_cutoff = lowpass(kind).response(100.0);
+            // the cutoff correction is for continuous time, discretising adds a little
+            assert!((at_cutoff.gain_db() + 3.0).abs() < 0.5, "{:?} {}", kind, at_cutoff.gain_db());
+            assert!(lowpass(kind).response(1000.0).gain < 0.2);
+        }
+        // a PT1 delays low frequencies by its time constant
+        let pt1 = lowpass(LowpassType::Pt1).response(5.0);
+        assert!((pt1.delay_ms - 1000.0 / (2.0 * PI * 100.0)).abs() < 0.05);
+        assert!(lowpass(LowpassType::Pt3).response(50.0).delay_ms > pt1.delay_ms);
+
+        let config = FilterConfig {
+            gyro_lpf1_hz: 250,
+            gyro_lpf1_dyn_min_hz: 200,
+            gyro_lpf2_hz: 500,
+            gyro_notch1_hz: 300,
+            gyro_notch1_cutoff: 200,
+            dterm_lpf1_hz: 100,
+            dterm_lpf1_type: LowpassType::Pt2,
+            ..Default::default()
+        };
+        let gyro = config.gyro_chain(8000.0);
+        assert_eq!(3, gyro.stages.len());
+        assert!(gyro.response(300.0).gain < 0.01);
+        let dterm = config.dterm_chain(8000.0);
+        let (g, d) = (gyro.response(50.0), dterm.response(50.0));
+        assert!(d.gain < g.gain && d.delay_ms > g.delay_ms);
+        assert_eq!(4, dterm.responses(&[10.0, 50.0, 100.0, 200.0]).len());
+    }
+}
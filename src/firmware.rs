@@ -0,0 +1,112 @@
+//! Firmware identification, as reported by `MSP_FC_VARIANT`, `MSP_FC_VERSION` and `MSP_API_VERSION`
+
+use std::fmt;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspApiVersion, MspFlightControllerVariant, MspFlightControllerVersion},
+};
+
+/// Flight controller firmware family
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum FirmwareVariant {
+    Betaflight,
+    Inav,
+    Cleanflight,
+    Baseflight,
+    Other(String),
+}
+
+impl FirmwareVariant {
+    /// From the 4 character identifier of `MSP_FC_VARIANT`
+    pub fn from_identifier(identifier: &[u8; 4]) -> Self {
+        match identifier {
+            b"BTFL" => FirmwareVariant::Betaflight,
+            b"INAV" => FirmwareVariant::Inav,
+            b"CLFL" => FirmwareVariant::Cleanflight,
+            b"BAFL" => FirmwareVariant::Baseflight,
+            _ => FirmwareVariant::Other(String::from_utf8_lossy(identifier).into_owned()),
+        }
+    }
+}
+
+impl fmt::Display for FirmwareVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FirmwareVariant::Betaflight => write!(f, "BTFL"),
+            FirmwareVariant::Inav => write!(f, "INAV"),
+            FirmwareVariant::Cleanflight => write!(f, "CLFL"),
+            FirmwareVariant::Baseflight => write!(f, "BAFL"),
+            FirmwareVariant::Other(id) => write!(f, "{}", id),
+        }
+    }
+}
+
+/// Firmware variant together with its release and MSP API versions
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FirmwareInfo {
+    pub variant: FirmwareVariant,
+    pub version: MspFlightControllerVersion,
+    pub api: MspApiVersion,
+}
+
+impl FirmwareInfo {
+    /// Query the flight controller for its firmware variant and versions
+    pub fn probe(port: &mut dyn SerialPort) -> Result<FirmwareInfo> {
+        let api = request(port, MspCommandCode::MSP_API_VERSION as u16, &[])?
+            .decode_as::<MspApiVersion>()?;
+        let variant = request(port, MspCommandCode::MSP_FC_VARIANT as u16, &[])?
+            .decode_as::<MspFlightControllerVariant>()?;
+        let version = request(port, MspCommandCode::MSP_FC_VERSION as u16, &[])?
+            .decode_as::<MspFlightControllerVersion>()?;
+
+        Ok(FirmwareInfo {
+            variant: FirmwareVariant::from_identifier(&variant.identifier),
+            version,
+            api,
+        })
+    }
+
+    /// Is the MSP API version at least `major.minor`?
+    pub fn api_at_least(&self, major: u8, minor: u8) -> bool {
+        let api = &self.api;
+        (api.api_version_major, api.api_version_minor) >= (major, minor)
+    }
+
+    /// Same firmware family and MSP API `major.minor`, so payload layouts match
+    pub fn is_compatible_with(&self, other: &FirmwareInfo) -> bool {
+        let (a, b) = (&self.api, &other.api);
+        self.variant == other.variant
+            && a.api_version_major == b.api_version_major
+            && a.api_version_minor == b.api_version_minor
+    }
+}
+
+impl fmt::Display for FirmwareInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {}.{}.{} (MSP API {}.{})",
+            self.variant,
+            self.version.major,
+            self.version.minor,
+            self.version.patch,
+            self.api.api_version_major,
+            self.api.api_version_minor
+        )
+    }
+}
+
+/// Release 4.5.0 of `variant` speaking MSP API 1.`api_minor`
+#[cfg(test)]
+pub(crate) fn test_firmware(variant: FirmwareVariant, api_minor: u8) -> FirmwareInfo {
+    FirmwareInfo {
+        variant,
+        version: MspFlightControllerVersion { major: 4, minor: 5, patch: 0 },
+        api: MspApiVersion { protocol_version: 0, api_version_major: 1, api_version_minor: api_minor },
+    }
+}
@@ -0,0 +1,306 @@
+//! OSD fonts: MAX7456 `.mcm` files, HD PNG tile sheets and upload over `MSP_OSD_CHAR_WRITE`
+
+use anyhow::{Error, Result};
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::commands::MspCommandCode;
+
+/// Glyph size of the MAX7456 analog OSD chip
+pub const MAX7456_WIDTH: usize = 12;
+pub const MAX7456_HEIGHT: usize = 18;
+/// Bytes of one character in an `.mcm` file and in `MSP_OSD_CHAR_WRITE`, pixels then metadata
+pub const CHAR_BYTES: usize = 64;
+/// Bytes holding pixels, 4 pixels of 2 bits per byte
+pub const CHAR_PIXEL_BYTES: usize = 54;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Pixel {
+    Black,
+    White,
+    Transparent,
+}
+
+impl Pixel {
+    /// MAX7456 pixel bits: `00` black, `10` white, `01` and `11` transparent
+    fn from_bits(bits: u8) -> Pixel {
+        match bits {
+            0b00 => Pixel::Black,
+            0b10 => Pixel::White,
+            _ => Pixel::Transparent,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Pixel::Black => 0b00,
+            Pixel::White => 0b10,
+            Pixel::Transparent => 0b01,
+        }
+    }
+
+    pub fn rgba(self) -> Option<[u8; 4]> {
+        match self {
+            Pixel::Black => Some([0, 0, 0, 255]),
+            Pixel::White => Some([255, 255, 255, 255]),
+            Pixel::Transparent => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Glyph {
+    /// Row major
+    pub pixels: Vec<Pixel>,
+    /// The 10 bytes after the pixels in an `.mcm` character, zero for PNG fonts
+    pub metadata: [u8; CHAR_BYTES - CHAR_PIXEL_BYTES],
+}
+
+/// A set of equally sized character bitmaps
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsdFont {
+    pub width: usize,
+    pub height: usize,
+    pub glyphs: Vec<Glyph>,
+}
+
+impl OsdFont {
+    /// Parse a MAX7456 `.mcm` file: a `MAX7456` header line, then 64 lines of 8 binary digits per
+    /// character
+    pub fn from_mcm(text: &str) -> Result<OsdFont> {
+        let mut lines = text.lines().map(str::trim).filter(|l| !l.is_empty());
+        if lines.next() != Some("MAX7456") {
+            return Err(Error::msg("Not an MCM font, missing MAX7456 header"));
+        }
+
+        let bytes = lines
+            .enumerate()
+            .map(|(i, line)| {
+                u8::from_str_radix(line, 2)
+                    .ok()
+                    .filter(|_| line.len() == 8)
+                    .ok_or_else(|| Error::msg(format!("Bad MCM line {}: {:?}", i + 2, line)))
+            })
+            .collect::<Result<Vec<u8>>>()?;
+        if bytes.is_empty() || bytes.len() % CHAR_BYTES != 0 {
+            return Err(Error::msg(format!("MCM data of {} bytes is not whole characters", bytes.len())));
+        }
+
+        let glyphs = bytes.chunks(CHAR_BYTES).map(Self::decode_char).collect();
+        Ok(OsdFont { width: MAX7456_WIDTH, height: MAX7456_HEIGHT, glyphs })
+    }
+
+    fn decode_char(data: &[u8]) -> Glyph {
+        let pixels = data[..CHAR_PIXEL_BYTES]
+            .iter()
+            .flat_map(|b| [6, 4, 2, 0].map(|shift| Pixel::from_bits((b >> shift) & 0b11)))
+            .collect();
+        let mut metadata = [0; CHAR_BYTES - CHAR_PIXEL_BYTES];
+        metadata.copy_from_slice(&data[CHAR_PIXEL_BYTES..]);
+        Glyph { pixels, metadata }
+    }
+
+    /// Cut a PNG tile sheet into glyphs of `width` by `height`, left to right then top to
+    /// bottom. Pixels with alpha below 128 are transparent, the rest black or white by
+    /// brightness.
+    pub fn from_png(data: &[u8], width: usize, height: usize) -> Result<OsdFont> {
+        let mut decoder = png::Decoder::new(data);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info()?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf)?;
+
+        let channels = info.color_type.samples();
+        let (sheet_w, sheet_h) = (info.width as usize, info.height as usize);
+        if width == 0 || height == 0 || sheet_w % width != 0 || sheet_h % height != 0 {
+            return Err(Error::msg(format!(
+                "{}x{} sheet is not made of {}x{} glyphs",
+                sheet_w, sheet_h, width, height
+            )));
+        }
+
+        let pixel = |x: usize, y: usize| {
+            let p = &buf[y * info.line_size + x * channels..][..channels];
+            let (luma, alpha) = match p {
+                [l] => (*l as u32, 255),
+                [l, a] => (*l as u32, *a),
+                [r, g, b] => ((*r as u32 + *g as u32 + *b as u32) / 3, 255),
+                [r, g, b, a, ..] => ((*r as u32 + *g as u32 + *b as u32) / 3, *a),
+                [] => (0, 0),
+            };
+            match (alpha, luma) {
+                (a, _) if a < 128 => Pixel::Transparent,
+                (_, l) if l >= 128 => Pixel::White,
+                _ => Pixel::Black,
+            }
+        };
+
+        let mut glyphs = Vec::new();
+        for tile_y in (0..sheet_h).step_by(height) {
+            for tile_x in (0..sheet_w).step_by(width) {
+                let pixels = (0..height)
+                    .flat_map(|y| (0..width).map(move |x| (tile_x + x, tile_y + y)))
+                    .map(|(x, y)| pixel(x, y))
+                    .collect();
+                glyphs.push(Glyph { pixels, metadata: [0; CHAR_BYTES - CHAR_PIXEL_BYTES] });
+            }
+        }
+        Ok(OsdFont { width, height, glyphs })
+    }
+
+    /// Colour of a glyph pixel, `None` for transparent or out of range. Fits
+    /// [`crate::displayport::VirtualOsdScreen::render`].
+    pub fn pixel(&self, glyph: u16, x: usize, y: usize) -> Option<[u8; 4]> {
+        if x >= self.width || y >= self.height {
+            return None;
+        }
+        self.glyphs.get(glyph as usize)?.pixels[y * self.width + x].rgba()
+    }
+
+    /// The 64 byte MAX7456 encoding of character `index`, only for 12x18 fonts
+    pub fn char_bytes(&self, index: usize) -> Result<[u8; CHAR_BYTES]> {
+        if (self.width, self.height) != (MAX7456_WIDTH, MAX7456_HEIGHT) {
+            return Err(Error::msg(format!("{}x{} glyphs cannot be sent to a MAX7456", self.width, self.height)));
+        }
+        let glyph = self
+            .glyphs
+            .get(index)
+            .ok_or_else(|| Error::msg(format!("Font has no character {}", index)))?;
+
+        let mut data = [0u8; CHAR_BYTES];
+        for (byte, pixels) in data.iter_mut().zip(glyph.pixels.chunks(4)) {
+            *byte = pixels.iter().fold(0, |acc, p| acc << 2 | p.bits());
+        }
+        data[CHAR_PIXEL_BYTES..].copy_from_slice(&glyph.metadata);
+        Ok(data)
+    }
+
+    /// Write every character with `MSP_OSD_CHAR_WRITE` as `[address u16, 64 bytes]`. With
+    /// `verify` each character is read back with `MSP_OSD_CHAR_READ` and its pixels compared.
+    /// `progress` is called with (characters done, total) after each one.
+    pub fn upload(
+        &self,
+        port: &mut dyn SerialPort,
+        verify: bool,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<()> {
+        let total = self.glyphs.len();
+        for index in 0..total {
+            let address = (index as u16).to_le_bytes();
+            let data = self.char_bytes(index)?;
+
+            let mut payload = address.to_vec();
+            payload.extend_from_slice(&data);
+            request(port, MspCommandCode::MSP_OSD_CHAR_WRITE as u16, &payload)?;
+
+            if verify {
+                let reply = request(port, MspCommandCode::MSP_OSD_CHAR_READ as u16, &address)?;
+                // reply echoes the address, then the character
+                let read = reply.data.as_slice().get(2..2 + CHAR_PIXEL_BYTES);
+                if read != Some(&data[..CHAR_PIXEL_BYTES]) {
+                    return Err(Error::msg(format!("Character {} read back differently", index)));
+                }
+            }
+            progress(index + 1, total);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Two characters: all transparent, and a white top row over black
+    fn mcm() -> String {
+        let mut text = String::from("MAX7456\r\n");
+        for _ in 0..CHAR_BYTES {
+            text.push_str("01010101\r\n");
+        }
+        for i in 0..CHAR_BYTES {
+            let line = match i {
+                0..=2 => "10101010",
+                3..=53 => "00000000",
+                _ => "01010101",
+            };
+            text.push_str(line);
+            text.push_str("\r\n");
+        }
+        text
+    }
+
+    #[test]
+    fn parse_mcm() {
+        let font = OsdFont::from_mcm(&mcm()).unwrap();
+        assert_eq!(2, font.glyphs.len());
+        assert_eq!(None, font.pixel(0, 5, 5));
+        assert_eq!(Some([255, 255, 255, 255]), font.pixel(1, 11, 0));
+        assert_eq!(Some([0, 0, 0, 255]), font.pixel(1, 0, 1));
+        assert_eq!([0x55; 10], font.glyphs[1].metadata);
+
+        let bytes = font.char_bytes(1).unwrap();
+        assert_eq!([0xAA, 0xAA, 0xAA, 0x00], bytes[..4]);
+        assert!(OsdFont::from_mcm("MAX7456\n0101").is_err());
+    }
+
+    #[test]
+    fn parse_png_sheet() {
+        // 2x1 sheet of 2x2 glyphs: white/black/transparent pixels
+        let rgba: [u8; 16 * 2] = [
+            255, 255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0,
+            0, 0, 0, 255, 255, 255, 255, 255, 0, 0, 0, 0, 255, 255, 255, 255,
+        ];
+        let mut png_data = Vec::new();
+        {
+            let mut encoder = png::Encoder::new(&mut png_data, 4, 2);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.write_header().unwrap().write_image_data(&rgba).unwrap();
+        }
+
+        let font = OsdFont::from_png(&png_data, 2, 2).unwrap();
+        assert_eq!(2, font.glyphs.len());
+        assert_eq!(vec![Pixel::White, Pixel::Black, Pixel::Black, Pixel::White], font.glyphs[0].pixels);
+        assert_eq!(vec![Pixel::Transparent, Pixel::Transparent, Pixel::Transparent, Pixel::White], font.glyphs[1].pixels);
+        assert!(font.char_bytes(0).is_err());
+        assert!(OsdFont::from_png(&png_data, 3, 2).is_err());
+    }
+
+    #[test]
+    fn upload_byte_layout() {
+        let font = OsdFont::from_mcm(&mcm()).unwrap();
+
+        // remembers written characters by address and serves them back
+        let chars = Arc::new(Mutex::new(std::collections::HashMap::new()));
+        let store = chars.clone();
+        let mut fc = MockFc::new().handler(move |p| {
+            let data = p.data.as_slice();
+            match p.cmd {
+                c if c == MSP_OSD_CHAR_WRITE as u16 => {
+                    store.lock().unwrap().insert(data[..2].to_vec(), data[2..].to_vec());
+                    Some(Vec::new())
+                }
+                c if c == MSP_OSD_CHAR_READ as u16 => {
+                    let mut reply = data.to_vec();
+                    reply.extend(store.lock().unwrap().get(data)?);
+                    Some(reply)
+                }
+                _ => None,
+            }
+        });
+
+        let mut calls = Vec::new();
+        font.upload(&mut fc, true, |done, total| calls.push((done, total))).unwrap();
+        assert_eq!(vec![(1, 2), (2, 2)], calls);
+
+        let writes: Vec<_> = fc.requests.iter().filter(|p| p.cmd == MSP_OSD_CHAR_WRITE as u16).collect();
+        assert_eq!(2, writes.len());
+        let second = writes[1].data.as_slice();
+        assert_eq!(2 + CHAR_BYTES, second.len());
+        assert_eq!([1, 0, 0xAA, 0xAA, 0xAA, 0x00], second[..6]);
+        assert_eq!([0x55; 10], second[2 + CHAR_PIXEL_BYTES..]);
+        assert_eq!(2, chars.lock().unwrap().len());
+    }
+}
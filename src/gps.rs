@@ -0,0 +1,214 @@
+//! GPS telemetry from `MSP_RAW_GPS`, `MSP_COMP_GPS` and `MSP_GPS_SV_INFO` in SI units
+
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspCompGps, MspGpsSvInfo, MspRawGps},
+};
+
+/// Mean earth radius [m]
+pub const EARTH_RADIUS: f64 = 6_371_000.0;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum GpsFix {
+    None,
+    Fix2D,
+    Fix3D,
+}
+
+impl GpsFix {
+    pub fn from_u8(value: u8) -> GpsFix {
+        match value {
+            0 => GpsFix::None,
+            1 => GpsFix::Fix2D,
+            _ => GpsFix::Fix3D,
+        }
+    }
+}
+
+/// A point on the earth in degrees
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct GeoPosition {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl GeoPosition {
+    pub fn new(lat: f64, lon: f64) -> GeoPosition {
+        GeoPosition { lat, lon }
+    }
+
+    /// From the 1e-7 degree integers used on the wire
+    pub fn from_e7(lat: i32, lon: i32) -> GeoPosition {
+        GeoPosition { lat: lat as f64 / 1e7, lon: lon as f64 / 1e7 }
+    }
+
+    pub fn to_e7(self) -> (i32, i32) {
+        ((self.lat * 1e7).round() as i32, (self.lon * 1e7).round() as i32)
+    }
+
+    /// Great circle distance [m]
+    pub fn distance_to(&self, other: &GeoPosition) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.lon - self.lon).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS * a.sqrt().asin()
+    }
+
+    /// Initial bearing towards `other` [deg], 0 north, clockwise
+    pub fn bearing_to(&self, other: &GeoPosition) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lon = (other.lon - self.lon).to_radians();
+
+        let y = d_lon.sin() * lat2.cos();
+        let x = lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_lon.cos();
+        y.atan2(x).to_degrees().rem_euclid(360.0)
+    }
+
+    /// Point `distance` meters away along `bearing` degrees
+    pub fn offset(&self, bearing: f64, distance: f64) -> GeoPosition {
+        let (lat1, lon1) = (self.lat.to_radians(), self.lon.to_radians());
+        let (theta, delta) = (bearing.to_radians(), distance / EARTH_RADIUS);
+
+        let lat2 = (lat1.sin() * delta.cos() + lat1.cos() * delta.sin() * theta.cos()).asin();
+        let lon2 = lon1 + (theta.sin() * delta.sin() * lat1.cos()).atan2(delta.cos() - lat1.sin() * lat2.sin());
+        GeoPosition { lat: lat2.to_degrees(), lon: (lon2.to_degrees() + 540.0) % 360.0 - 180.0 }
+    }
+}
+
+/// `MSP_RAW_GPS` in SI units
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct GpsState {
+    pub fix: GpsFix,
+    pub satellites: u8,
+    pub position: GeoPosition,
+    /// Above mean sea level [m]
+    pub altitude: f32,
+    /// [m/s]
+    pub ground_speed: f32,
+    /// [deg]
+    pub course: f32,
+    /// `None` when the firmware does not report it
+    pub hdop: Option<f32>,
+}
+
+impl From<&MspRawGps> for GpsState {
+    fn from(raw: &MspRawGps) -> GpsState {
+        GpsState {
+            fix: GpsFix::from_u8(raw.fix_type),
+            satellites: raw.num_sat,
+            position: GeoPosition::from_e7(raw.lat, raw.lon),
+            altitude: raw.alt as f32,
+            ground_speed: raw.ground_speed as f32 / 100.0,
+            course: raw.ground_course as f32 / 10.0,
+            hdop: (raw.hdop != 0).then(|| raw.hdop as f32 / 100.0),
+        }
+    }
+}
+
+/// `MSP_COMP_GPS`, where home is from here
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct HomeVector {
+    /// [m]
+    pub distance: f32,
+    /// [deg]
+    pub direction: f32,
+}
+
+impl From<&MspCompGps> for HomeVector {
+    fn from(comp: &MspCompGps) -> HomeVector {
+        HomeVector { distance: comp.distance_to_home as f32, direction: comp.direction_to_home as f32 }
+    }
+}
+
+pub fn read_gps(port: &mut dyn SerialPort) -> Result<GpsState> {
+    let raw = request(port, MspCommandCode::MSP_RAW_GPS as u16, &[])?.decode_as_padded::<MspRawGps>()?;
+    Ok(GpsState::from(&raw))
+}
+
+pub fn read_home(port: &mut dyn SerialPort) -> Result<HomeVector> {
+    let comp = request(port, MspCommandCode::MSP_COMP_GPS as u16, &[])?.decode_as::<MspCompGps>()?;
+    Ok(HomeVector::from(&comp))
+}
+
+/// Split an `MSP_GPS_SV_INFO` payload into its channels
+pub fn decode_satellites(data: &[u8]) -> Result<Vec<MspGpsSvInfo>> {
+    let (&count, rest) = data.split_first().ok_or_else(|| Error::msg("Empty GPS SV info"))?;
+    if rest.len() < count as usize * 4 {
+        return Err(Error::msg(format!("GPS SV info has {} channels but {} bytes", count, rest.len())));
+    }
+    Ok(rest
+        .chunks_exact(4)
+        .take(count as usize)
+        .map(MspGpsSvInfo::unpack_from_slice)
+        .collect::<Result<_, _>>()?)
+}
+
+pub fn read_satellites(port: &mut dyn SerialPort) -> Result<Vec<MspGpsSvInfo>> {
+    decode_satellites(request(port, MspCommandCode::MSP_GPS_SV_INFO as u16, &[])?.data.as_slice())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use packed_struct::PackedStruct;
+
+    #[test]
+    fn haversine_and_bearing() {
+        let london = GeoPosition::new(51.5007, -0.1246);
+        let paris = GeoPosition::new(48.8584, 2.2945);
+        assert!((london.distance_to(&paris) - 340_600.0).abs() < 1_000.0);
+
+        let origin = GeoPosition::new(0.0, 0.0);
+        assert!((origin.bearing_to(&GeoPosition::new(1.0, 0.0)) - 0.0).abs() < 1e-9);
+        assert!((origin.bearing_to(&GeoPosition::new(0.0, 1.0)) - 90.0).abs() < 1e-9);
+        assert!((origin.bearing_to(&GeoPosition::new(0.0, -1.0)) - 270.0).abs() < 1e-9);
+
+        let moved = london.offset(45.0, 1_000.0);
+        assert!((london.distance_to(&moved) - 1_000.0).abs() < 0.01);
+        assert!((london.bearing_to(&moved) - 45.0).abs() < 0.01);
+        assert_eq!((515_007_000, -1_246_000), london.to_e7());
+    }
+
+    #[test]
+    fn decode_telemetry() {
+        let raw = MspRawGps {
+            fix_type: 2,
+            num_sat: 14,
+            lat: 515_007_000,
+            lon: -1_246_000,
+            alt: 35,
+            ground_speed: 1250,
+            ground_course: 2705,
+            hdop: 0,
+        };
+        // strip hdop as older Betaflight does
+        let packed = raw.pack().unwrap();
+        let mut fc = MockFc::new()
+            .reply(MSP_RAW_GPS as u16, &packed[..16])
+            .reply(MSP_COMP_GPS as u16, &[0xE8, 0x03, 90, 0, 1])
+            .reply(MSP_GPS_SV_INFO as u16, &[2, 0, 5, 7, 41, 1, 12, 7, 38]);
+
+        let gps = read_gps(&mut fc).unwrap();
+        assert_eq!(GpsFix::Fix3D, gps.fix);
+        assert_eq!(12.5, gps.ground_speed);
+        assert_eq!(270.5, gps.course);
+        assert_eq!(None, gps.hdop);
+        assert!((gps.position.lat - 51.5007).abs() < 1e-9);
+
+        assert_eq!(HomeVector { distance: 1000.0, direction: 90.0 }, read_home(&mut fc).unwrap());
+
+        let sats = read_satellites(&mut fc).unwrap();
+        assert_eq!(vec![41, 38], sats.iter().map(|s| s.cno).collect::<Vec<_>>());
+        assert!(decode_satellites(&[3, 0, 5, 7, 41]).is_err());
+    }
+}
@@ -1,23 +1,31 @@
 use std::thread::sleep;
-use std::time::{Duration};
+use std::time::{Duration, Instant};
 use anyhow::{Error, Result};
 use serialport::SerialPort;
 
 use crate::msp::{
-    packet::{MspPacket, MspPacketDirection::ToFlightController},
+    packet::{MspPacket, MspPacketDirection, MspPacketDirection::ToFlightController},
     parser::MspParser,
 };
 
 
+/// Send a request to the flight controller. MSP v2 framing is used when the command code or
+/// payload does not fit into a v1 frame.
 pub fn send_request(port: &mut dyn SerialPort, cmd: u16, payload:&[u8]) -> Result<()> {
-    let motor_req = MspPacket {
+    let req = MspPacket {
         direction: ToFlightController,
         cmd,
         data: payload.into(),
     };
-    let mut packet_data = vec![0u8; payload.len() + 6];
-    motor_req.serialize(&mut packet_data)
-        .map_err(|e| Error::msg(format!("Serialization Error: {:?}", e)))?;
+    let mut packet_data;
+    let res = if cmd > u8::MAX as u16 || payload.len() > u8::MAX as usize {
+        packet_data = vec![0u8; req.packet_size_bytes_v2()];
+        req.serialize_v2(&mut packet_data)
+    } else {
+        packet_data = vec![0u8; req.packet_size_bytes()];
+        req.serialize(&mut packet_data)
+    };
+    res.map_err(|e| Error::msg(format!("Serialization Error: {:?}", e)))?;
     port.write_all(&packet_data)?;
     Ok(())
 }
@@ -26,8 +34,8 @@ pub fn read_until_response(port: &mut dyn SerialPort, cmd: u16) -> Result<MspPac
     let mut parser = MspParser::from_fc();
     let mut response: Vec<u8> = vec![0; 64];
     loop {
-        port.read(&mut response.as_mut_slice())?;
-        for b in &response {
+        let n = port.read(response.as_mut_slice())?;
+        for b in &response[..n] {
             let s = parser.parse(*b);
             if let Ok(Some(p)) = s {
                 if cmd == p.cmd {
@@ -38,16 +46,61 @@ pub fn read_until_response(port: &mut dyn SerialPort, cmd: u16) -> Result<MspPac
     }
 }
 
-pub fn wait_for_port(port_name: &str, baud_rate: u32, timeout_ms: u64) -> Box<dyn SerialPort> {
+/// Send a request and wait for the flight controller's reply to it. Replies flagged with the
+/// `!` direction (command not supported by the firmware) are returned as errors.
+pub fn request(port: &mut dyn SerialPort, cmd: u16, payload: &[u8]) -> Result<MspPacket> {
+    send_request(port, cmd, payload)?;
+    let reply = read_until_response(port, cmd)?;
+    if reply.direction == MspPacketDirection::Unsupported {
+        return Err(Error::msg(format!("Command {} not supported by flight controller", cmd)));
+    }
+    Ok(reply)
+}
+
+/// How long [`wait_for_port`] waits for the port to appear
+const WAIT_FOR_PORT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Open `port_name`, retrying every `poll_ms` until it appears. Gives up after 30 seconds, use
+/// [`wait_for_port_timeout`] to choose another limit.
+pub fn wait_for_port(port_name: &str, baud_rate: u32, poll_ms: u64) -> Result<Box<dyn SerialPort>> {
+    wait_for_port_timeout(port_name, baud_rate, Duration::from_millis(poll_ms), WAIT_FOR_PORT_TIMEOUT)
+}
+
+/// Is a serial port called `port_name` currently enumerated?
+pub fn port_present(port_name: &str) -> bool {
+    serialport::available_ports()
+        .map(|ports| ports.iter().any(|p| p.port_name == port_name))
+        .unwrap_or(false)
+}
+
+/// Poll until `port_name` disappears from the system, `false` if it is still there after
+/// `timeout`
+pub fn wait_for_port_gone(port_name: &str, poll: Duration, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    while port_present(port_name) {
+        if Instant::now() > deadline {
+            return false;
+        }
+        sleep(poll);
+    }
+    true
+}
+
+/// Open `port_name`, retrying every `poll` until it appears or `timeout` has passed
+pub fn wait_for_port_timeout(
+    port_name: &str,
+    baud_rate: u32,
+    poll: Duration,
+    timeout: Duration,
+) -> Result<Box<dyn SerialPort>> {
+    let deadline = Instant::now() + timeout;
     loop {
-        match serialport::new(port_name, baud_rate)
-            .open()
-        {
-            Ok(port) => return port, // Port opened successfully
-            Err(_) => {
-                println!("Waiting for {} to reappear...", port_name);
-                sleep(Duration::from_millis(timeout_ms));
+        match serialport::new(port_name, baud_rate).open() {
+            Ok(port) => return Ok(port),
+            Err(e) if Instant::now() > deadline => {
+                return Err(Error::msg(format!("{} did not reappear within {:?}: {}", port_name, timeout, e)))
             }
+            Err(_) => sleep(poll),
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,467 @@
+//! LED strip layout, colors and mode colors in the Betaflight 32 bit LED config format
+
+use std::fmt;
+use std::str::FromStr;
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspLedColor, MspLedConfig, MspLedModeColor, MspSetLedStripConfig},
+};
+
+/// Size of the LED grid in each direction
+pub const GRID_SIZE: u8 = 16;
+/// Entries of the color palette
+pub const COLOR_COUNT: usize = 16;
+/// `MspLedModeColor::mode` of the special colors, e.g. disarmed and armed
+pub const MODE_SPECIAL: u8 = 6;
+/// `MspLedModeColor::mode` of the entry that holds the aux channel for the color function
+pub const MODE_AUX_CHANNEL: u8 = 7;
+
+/// Direction codes, by bit
+const DIRECTION_CODES: [char; 6] = ['N', 'E', 'S', 'W', 'U', 'D'];
+/// Overlay codes, by bit: throttle, rainbow, larson scanner, blink, VTX, indicator, warning
+const OVERLAY_CODES: [char; 7] = ['T', 'Y', 'O', 'B', 'V', 'I', 'W'];
+
+const FUNCTION_OFFSET: u32 = 8;
+const OVERLAY_OFFSET: u32 = 12;
+const COLOR_OFFSET: u32 = 22;
+const DIRECTION_OFFSET: u32 = 26;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum LedFunction {
+    Color,
+    FlightMode,
+    ArmState,
+    Battery,
+    Rssi,
+    Gps,
+    ThrustRing,
+    GpsBar,
+    BatteryBar,
+    Unknown(u8),
+}
+
+impl LedFunction {
+    const CODES: [char; 9] = ['C', 'F', 'A', 'L', 'S', 'G', 'R', 'P', 'E'];
+
+    pub fn from_u8(value: u8) -> LedFunction {
+        match value {
+            0 => LedFunction::Color,
+            1 => LedFunction::FlightMode,
+            2 => LedFunction::ArmState,
+            3 => LedFunction::Battery,
+            4 => LedFunction::Rssi,
+            5 => LedFunction::Gps,
+            6 => LedFunction::ThrustRing,
+            7 => LedFunction::GpsBar,
+            8 => LedFunction::BatteryBar,
+            v => LedFunction::Unknown(v),
+        }
+    }
+
+    pub fn to_u8(self) -> u8 {
+        match self {
+            LedFunction::Color => 0,
+            LedFunction::FlightMode => 1,
+            LedFunction::ArmState => 2,
+            LedFunction::Battery => 3,
+            LedFunction::Rssi => 4,
+            LedFunction::Gps => 5,
+            LedFunction::ThrustRing => 6,
+            LedFunction::GpsBar => 7,
+            LedFunction::BatteryBar => 8,
+            LedFunction::Unknown(v) => v,
+        }
+    }
+
+    /// Letter the CLI uses, `?` for unknown functions
+    pub fn code(self) -> char {
+        Self::CODES.get(self.to_u8() as usize).copied().unwrap_or('?')
+    }
+
+    pub fn from_code(code: char) -> Option<LedFunction> {
+        Self::CODES.iter().position(|c| *c == code.to_ascii_uppercase()).map(|i| Self::from_u8(i as u8))
+    }
+}
+
+/// One LED of the strip. Displays and parses in the CLI `led` format, e.g. `2,1:NE:FT:3`.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct LedConfig {
+    /// Column on the grid, 0 left
+    pub x: u8,
+    /// Row on the grid, 0 top
+    pub y: u8,
+    pub function: LedFunction,
+    /// Overlay bits, see the `T`, `Y`, `O`, `B`, `V`, `I` and `W` codes
+    pub overlays: u16,
+    /// Index into the color palette
+    pub color: u8,
+    /// Direction bits, see the `N`, `E`, `S`, `W`, `U` and `D` codes
+    pub directions: u8,
+}
+
+impl LedConfig {
+    pub fn decode(raw: u32) -> LedConfig {
+        LedConfig {
+            x: (raw >> 4 & 0xF) as u8,
+            y: (raw & 0xF) as u8,
+            function: LedFunction::from_u8((raw >> FUNCTION_OFFSET & 0xF) as u8),
+            overlays: (raw >> OVERLAY_OFFSET & 0x3FF) as u16,
+            color: (raw >> COLOR_OFFSET & 0xF) as u8,
+            directions: (raw >> DIRECTION_OFFSET & 0x3F) as u8,
+        }
+    }
+
+    pub fn encode(&self) -> u32 {
+        (self.x as u32 & 0xF) << 4
+            | self.y as u32 & 0xF
+            | (self.function.to_u8() as u32 & 0xF) << FUNCTION_OFFSET
+            | (self.overlays as u32 & 0x3FF) << OVERLAY_OFFSET
+            | (self.color as u32 & 0xF) << COLOR_OFFSET
+            | (self.directions as u32 & 0x3F) << DIRECTION_OFFSET
+    }
+
+    /// Unset LEDs are all zero
+    pub fn is_used(&self) -> bool {
+        self.encode() != 0
+    }
+}
+
+fn codes(bits: u32, table: &[char]) -> String {
+    table.iter().enumerate().filter(|(bit, _)| bits & (1 << bit) != 0).map(|(_, c)| *c).collect()
+}
+
+fn bits_of(text: &str, table: &[char]) -> Result<u32> {
+    text.chars().try_fold(0, |bits, c| {
+        let bit = table
+            .iter()
+            .position(|t| *t == c.to_ascii_uppercase())
+            .ok_or_else(|| Error::msg(format!("Unknown LED code {}", c)))?;
+        Ok(bits | 1 << bit)
+    })
+}
+
+impl fmt::Display for LedConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{},{}:{}:{}{}:{}",
+            self.x,
+            self.y,
+            codes(self.directions as u32, &DIRECTION_CODES),
+            self.function.code(),
+            codes(self.overlays as u32, &OVERLAY_CODES),
+            self.color
+        )
+    }
+}
+
+impl FromStr for LedConfig {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<LedConfig> {
+        let invalid = || Error::msg(format!("Invalid LED config {}", s));
+        let parts: Vec<&str> = s.trim().split(':').collect();
+        let [position, directions, functions, color] = parts[..] else {
+            return Err(invalid());
+        };
+        let (x, y) = position.split_once(',').ok_or_else(invalid)?;
+        let coordinate = |v: &str| v.trim().parse::<u8>().ok().filter(|v| *v < GRID_SIZE).ok_or_else(invalid);
+        let mut functions = functions.chars();
+        let function = functions.next().and_then(LedFunction::from_code).ok_or_else(invalid)?;
+
+        Ok(LedConfig {
+            x: coordinate(x)?,
+            y: coordinate(y)?,
+            function,
+            overlays: bits_of(functions.as_str(), &OVERLAY_CODES)? as u16,
+            color: color.trim().parse::<u8>().ok().filter(|c| (*c as usize) < COLOR_COUNT).ok_or_else(invalid)?,
+            directions: bits_of(directions, &DIRECTION_CODES)? as u8,
+        })
+    }
+}
+
+/// The whole LED strip: palette, per-LED layout and mode colors. Edits are tracked so
+/// [`LedStrip::write`] only sends what changed.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LedStrip {
+    pub colors: Vec<MspLedColor>,
+    /// Every slot of the strip, unused ones all zero
+    pub leds: Vec<LedConfig>,
+    pub mode_colors: Vec<MspLedModeColor>,
+    /// Selected LED profile, sent by Betaflight 4.1 and later
+    pub profile: Option<u8>,
+    loaded_colors: Vec<MspLedColor>,
+    loaded_leds: Vec<LedConfig>,
+    loaded_mode_colors: Vec<MspLedModeColor>,
+}
+
+fn read_colors(port: &mut dyn SerialPort) -> Result<Vec<MspLedColor>> {
+    let reply = request(port, MspCommandCode::MSP_LED_COLORS as u16, &[])?;
+    Ok(reply.data.as_slice().chunks_exact(4).map(MspLedColor::unpack_from_slice).collect::<Result<_, _>>()?)
+}
+
+/// LEDs and the selected profile
+fn read_leds(port: &mut dyn SerialPort) -> Result<(Vec<LedConfig>, Option<u8>)> {
+    let reply = request(port, MspCommandCode::MSP_LED_STRIP_CONFIG as u16, &[])?;
+    let data = reply.data.as_slice();
+    let leds = data
+        .chunks_exact(4)
+        .map(|b| LedConfig::decode(u32::from_le_bytes([b[0], b[1], b[2], b[3]])))
+        .collect();
+    // profile support flag and selected profile after the LEDs
+    let profile = match data.len() % 4 {
+        2 => Some(data[data.len() - 1]),
+        _ => None,
+    };
+    Ok((leds, profile))
+}
+
+fn read_mode_colors(port: &mut dyn SerialPort) -> Result<Vec<MspLedModeColor>> {
+    let reply = request(port, MspCommandCode::MSP_LED_STRIP_MODECOLOR as u16, &[])?;
+    Ok(reply.data.as_slice().chunks_exact(3).map(MspLedModeColor::unpack_from_slice).collect::<Result<_, _>>()?)
+}
+
+impl LedStrip {
+    pub fn load(port: &mut dyn SerialPort) -> Result<LedStrip> {
+        let colors = read_colors(port)?;
+        let (leds, profile) = read_leds(port)?;
+        let mode_colors = read_mode_colors(port)?;
+        Ok(LedStrip {
+            loaded_colors: colors.clone(),
+            loaded_leds: leds.clone(),
+            loaded_mode_colors: mode_colors.clone(),
+            colors,
+            leds,
+            mode_colors,
+            profile,
+        })
+    }
+
+    /// Used LEDs with their index, in wiring order
+    pub fn used(&self) -> impl Iterator<Item = (usize, &LedConfig)> {
+        self.leds.iter().enumerate().filter(|(_, l)| l.is_used())
+    }
+
+    /// Palette index of `mode` and `function`, e.g. [`MODE_SPECIAL`] and a special color
+    pub fn mode_color(&self, mode: u8, function: u8) -> Option<u8> {
+        self.mode_colors.iter().find(|m| m.mode == mode && m.function == function).map(|m| m.color)
+    }
+
+    pub fn set_mode_color(&mut self, mode: u8, function: u8, color: u8) -> Result<()> {
+        let entry = self
+            .mode_colors
+            .iter_mut()
+            .find(|m| m.mode == mode && m.function == function)
+            .ok_or_else(|| Error::msg(format!("No mode color {} {}", mode, function)))?;
+        entry.color = color;
+        Ok(())
+    }
+
+    /// Every LED and mode color must point into the palette, which has [`COLOR_COUNT`] entries
+    /// with hues below 360
+    pub fn validate(&self) -> Result<()> {
+        let mut problems: Vec<String> = Vec::new();
+        if self.colors.len() != COLOR_COUNT {
+            problems.push(format!("{} colors", self.colors.len()));
+        }
+        problems.extend(
+            self.colors.iter().enumerate().filter(|(_, c)| c.h >= 360).map(|(i, c)| format!("color {} hue {}", i, c.h)),
+        );
+        problems.extend(
+            self.used()
+                .filter(|(_, l)| l.color as usize >= COLOR_COUNT || matches!(l.function, LedFunction::Unknown(_)))
+                .map(|(i, l)| format!("LED {} {}", i, l)),
+        );
+        problems.extend(
+            self.mode_colors
+                .iter()
+                .filter(|m| m.mode != MODE_AUX_CHANNEL && m.color as usize >= COLOR_COUNT)
+                .map(|m| format!("mode {} function {} color {}", m.mode, m.function, m.color)),
+        );
+
+        if !problems.is_empty() {
+            return Err(Error::msg(format!("Invalid LED strip: {}", problems.join(", "))));
+        }
+        Ok(())
+    }
+
+    /// Validate, send the palette, LEDs and mode colors that changed, read everything back and
+    /// save to EEPROM. Returns the number of set commands sent.
+    pub fn write(&mut self, port: &mut dyn SerialPort) -> Result<usize> {
+        self.validate()?;
+        let mut sent = 0;
+        if self.colors != self.loaded_colors {
+            let mut data = Vec::new();
+            for color in &self.colors {
+                data.extend(color.pack_to_vec()?);
+            }
+            request(port, MspCommandCode::MSP_SET_LED_COLORS as u16, &data)?;
+            sent += 1;
+        }
+        for (index, led) in self.leds.iter().enumerate() {
+            if self.loaded_leds.get(index) != Some(led) {
+                let set = MspSetLedStripConfig { index: index as u8, led: MspLedConfig { config: led.encode() } };
+                request(port, MspCommandCode::MSP_SET_LED_STRIP_CONFIG as u16, &set.pack_to_vec()?)?;
+                sent += 1;
+            }
+        }
+        for mode_color in &self.mode_colors {
+            if !self.loaded_mode_colors.contains(mode_color) {
+                request(port, MspCommandCode::MSP_SET_LED_STRIP_MODECOLOR as u16, &mode_color.pack_to_vec()?)?;
+                sent += 1;
+            }
+        }
+        if sent == 0 {
+            return Ok(0);
+        }
+
+        let stored = LedStrip::load(port)?;
+        if (&stored.colors, &stored.leds, &stored.mode_colors) != (&self.colors, &self.leds, &self.mode_colors) {
+            return Err(Error::msg("LED strip did not read back as written"));
+        }
+        request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        (self.loaded_colors, self.loaded_leds, self.loaded_mode_colors) =
+            (stored.colors, stored.leds, stored.mode_colors);
+        Ok(sent)
+    }
+
+    /// Text drawing of the layout, cropped to the used part of the grid. Each LED shows its
+    /// index and function code, `*` instead of the code where LEDs share a position.
+    pub fn grid(&self) -> String {
+        let used: Vec<_> = self.used().collect();
+        if used.is_empty() {
+            return "no LEDs configured\n".to_owned();
+        }
+        let (x0, x1) = (used.iter().map(|(_, l)| l.x).min().unwrap(), used.iter().map(|(_, l)| l.x).max().unwrap());
+        let (y0, y1) = (used.iter().map(|(_, l)| l.y).min().unwrap(), used.iter().map(|(_, l)| l.y).max().unwrap());
+
+        let mut out = String::from("  ");
+        for x in x0..=x1 {
+            out += &format!("{:>4}", x);
+        }
+        out.push('\n');
+        for y in y0..=y1 {
+            out += &format!("{:>2}", y);
+            for x in x0..=x1 {
+                let here: Vec<_> = used.iter().filter(|(_, l)| l.x == x && l.y == y).collect();
+                match here.as_slice() {
+                    [] => out += "   .",
+                    [(i, l)] => out += &format!("{:>3}{}", i, l.function.code()),
+                    [(i, _), ..] => out += &format!("{:>3}*", i),
+                }
+            }
+            out.push('\n');
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+
+    #[test]
+    fn led_config_codes() {
+        let led: LedConfig = "0,15:NE:FT:1".parse().unwrap();
+        assert_eq!(0x0C40_110F, led.encode());
+        assert_eq!(led, LedConfig::decode(0x0C40_110F));
+        assert_eq!((LedFunction::FlightMode, 0b11), (led.function, led.directions));
+        assert_eq!("0,15:NE:FT:1", led.to_string());
+
+        let led: LedConfig = "12,3::cwi:15".parse().unwrap();
+        assert_eq!("12,3::CIW:15", led.to_string());
+        assert_eq!(led, LedConfig::decode(led.encode()));
+        for invalid in ["16,0:N:C:0", "1,1:Q:C:0", "1,1:N:Z:0", "1,1:N:C:16", "1,1:N:C"] {
+            assert!(invalid.parse::<LedConfig>().is_err(), "{}", invalid);
+        }
+        assert!(!LedConfig::decode(0).is_used());
+    }
+
+    #[test]
+    fn edit_write_and_draw_strip() {
+        let mut leds = vec![0u8; 8 * 4];
+        leds.extend([1, 0]);
+        let mut mode_colors = vec![0, 0, 1, 6, 0, 2, 6, 1, 3, 7, 0, 2];
+        let mut fc = MockFc::new()
+            .reply(MSP_LED_COLORS as u16, &[0; COLOR_COUNT * 4])
+            .setter(MSP_SET_LED_COLORS as u16, MSP_LED_COLORS as u16)
+            .reply(MSP_EEPROM_WRITE as u16, &[])
+            .handler(move |p| {
+                let data = p.data.as_slice();
+                match p.cmd {
+                    c if c == MSP_LED_STRIP_CONFIG as u16 => Some(leds.clone()),
+                    c if c == MSP_LED_STRIP_MODECOLOR as u16 => Some(mode_colors.clone()),
+                    c if c == MSP_SET_LED_STRIP_CONFIG as u16 => {
+                        let at = data[0] as usize * 4;
+                        leds[at..at + 4].copy_from_slice(&data[1..5]);
+                        Some(Vec::new())
+                    }
+                    c if c == MSP_SET_LED_STRIP_MODECOLOR as u16 => {
+                        let entry = mode_colors.chunks_exact_mut(3).find(|m| m[..2] == data[..2]).unwrap();
+                        entry[2] = data[2];
+                        Some(Vec::new())
+                    }
+                    _ => None,
+                }
+            });
+
+        let mut strip = LedStrip::load(&mut fc).unwrap();
+        assert_eq!((16, 8, 4, Some(0)), (strip.colors.len(), strip.leds.len(), strip.mode_colors.len(), strip.profile));
+        assert_eq!("no LEDs configured\n", strip.grid());
+        assert_eq!(0, strip.write(&mut fc).unwrap());
+
+        strip.colors[2] = MspLedColor { h: 120, s: 0, v: 255 };
+        for (i, config) in ["1,2:N:F:2", "2,2:N:F:2", "3,3:S:AW:2", "3,3:S:L:2"].iter().enumerate() {
+            strip.leds[i] = config.parse().unwrap();
+        }
+        strip.set_mode_color(MODE_SPECIAL, 1, 4).unwrap();
+        assert_eq!(Some(4), strip.mode_color(MODE_SPECIAL, 1));
+        assert!(strip.set_mode_color(5, 5, 1).is_err());
+
+        strip.leds[4] = "0,0:N:C:3".parse().unwrap();
+        strip.leds[4].color = 17;
+        assert!(strip.validate().unwrap_err().to_string().contains("LED 4"));
+        strip.leds[4] = LedConfig::decode(0);
+
+        // the palette, four LEDs and one mode color
+        assert_eq!(6, strip.write(&mut fc).unwrap());
+        assert_eq!(Some(MSP_EEPROM_WRITE as u16), fc.commands().last().copied());
+        assert_eq!(strip.leds, LedStrip::load(&mut fc).unwrap().leds);
+        assert_eq!("     1   2   3\n 2  0F  1F   .\n 3   .   .  2*\n", strip.grid());
+    }
+
+    #[test]
+    fn write_fails_when_not_stored() {
+        let mut leds = vec![0u8; 8 * 4];
+        leds.extend([1, 0]);
+        // a firmware that acknowledges LED changes without storing them
+        let mut fc = MockFc::new()
+            .reply(MSP_LED_COLORS as u16, &[0; COLOR_COUNT * 4])
+            .reply(MSP_LED_STRIP_CONFIG as u16, &leds)
+            .reply(MSP_LED_STRIP_MODECOLOR as u16, &[0, 0, 1])
+            .reply(MSP_SET_LED_STRIP_CONFIG as u16, &[])
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+        let mut strip = LedStrip::load(&mut fc).unwrap();
+
+        strip.colors[0].h = 360;
+        assert!(strip.write(&mut fc).unwrap_err().to_string().contains("color 0 hue 360"));
+        assert_eq!(3, fc.commands().len());
+        strip.colors[0].h = 0;
+
+        strip.leds[0] = "1,1:N:F:2".parse().unwrap();
+        assert_eq!("LED strip did not read back as written", strip.write(&mut fc).unwrap_err().to_string());
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+        // nothing counts as written, so the next attempt sends the LED again
+        assert!(strip.write(&mut fc).is_err());
+        let sets = fc.commands().iter().filter(|c| **c == MSP_SET_LED_STRIP_CONFIG as u16).count();
+        assert_eq!(2, sets);
+    }
+}
@@ -1,2 +1,32 @@
 pub mod msp;          
-pub mod helpers;
\ No newline at end of file
+pub mod helpers;
+pub mod firmware;
+pub mod snapshot;
+pub mod diff;
+pub mod settings;
+pub mod cli;
+pub mod client;
+pub mod displayport;
+pub mod osd;
+pub mod font;
+pub mod vtx;
+pub mod gps;
+pub mod sensors;
+pub mod mission;
+pub mod status;
+pub mod motor;
+pub mod mixer;
+pub mod servo;
+pub mod units;
+pub mod calibration;
+pub mod rx;
+pub mod failsafe;
+pub mod battery;
+pub mod serial;
+pub mod features;
+pub mod led;
+pub mod tuning;
+pub mod filter;
+
+#[cfg(test)]
+mod mock;
@@ -0,0 +1,408 @@
+//! iNav waypoint missions over `MSP_WP` / `MSP_SET_WP` and the Configurator `.mission` format
+
+use std::fmt;
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use quick_xml::events::{BytesDecl, BytesEnd, BytesStart, Event};
+use quick_xml::{Reader, Writer};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::gps::GeoPosition;
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspWaypoint, MspWaypointInfo},
+};
+
+/// Flag of the last mission waypoint
+pub const FLAG_LAST: u8 = 0xA5;
+/// Flag of the home waypoint, number 0
+pub const FLAG_HOME: u8 = 0x48;
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum WaypointAction {
+    Waypoint = 1,
+    /// Hold position forever
+    PosholdUnlim = 2,
+    /// Hold position for p1 seconds
+    PosholdTime = 3,
+    Rth = 4,
+    /// Point the nose at this location for the following waypoints
+    SetPoi = 5,
+    /// Jump to waypoint p1, p2 times (-1 forever)
+    Jump = 6,
+    /// Fly the following waypoints with heading p1 (-1 to reset)
+    SetHead = 7,
+    Land = 8,
+}
+
+impl WaypointAction {
+    const ALL: [WaypointAction; 8] = [
+        WaypointAction::Waypoint,
+        WaypointAction::PosholdUnlim,
+        WaypointAction::PosholdTime,
+        WaypointAction::Rth,
+        WaypointAction::SetPoi,
+        WaypointAction::Jump,
+        WaypointAction::SetHead,
+        WaypointAction::Land,
+    ];
+
+    pub fn from_u8(value: u8) -> Option<WaypointAction> {
+        Self::ALL.into_iter().find(|a| *a as u8 == value)
+    }
+
+    /// Name used in `.mission` files
+    pub fn name(self) -> &'static str {
+        match self {
+            WaypointAction::Waypoint => "WAYPOINT",
+            WaypointAction::PosholdUnlim => "POSHOLD_UNLIM",
+            WaypointAction::PosholdTime => "POSHOLD_TIME",
+            WaypointAction::Rth => "RTH",
+            WaypointAction::SetPoi => "SET_POI",
+            WaypointAction::Jump => "JUMP",
+            WaypointAction::SetHead => "SET_HEAD",
+            WaypointAction::Land => "LAND",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<WaypointAction> {
+        Self::ALL.into_iter().find(|a| a.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Actions without a location of their own
+    pub fn has_position(self) -> bool {
+        !matches!(self, WaypointAction::Rth | WaypointAction::Jump | WaypointAction::SetHead)
+    }
+}
+
+impl fmt::Display for WaypointAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct MissionItem {
+    pub action: WaypointAction,
+    pub position: GeoPosition,
+    /// Relative to home unless p3 says otherwise [m]
+    pub altitude: f64,
+    pub p1: i16,
+    pub p2: i16,
+    pub p3: u16,
+    /// Passed through, except [`FLAG_LAST`] which is set on upload
+    pub flag: u8,
+}
+
+impl MissionItem {
+    pub fn waypoint(position: GeoPosition, altitude: f64) -> MissionItem {
+        MissionItem { action: WaypointAction::Waypoint, position, altitude, p1: 0, p2: 0, p3: 0, flag: 0 }
+    }
+
+    fn from_msp(wp: &MspWaypoint) -> Result<MissionItem> {
+        let action = WaypointAction::from_u8(wp.action)
+            .ok_or_else(|| Error::msg(format!("Waypoint {} has unknown action {}", wp.wp_no, wp.action)))?;
+        Ok(MissionItem {
+            action,
+            position: GeoPosition::from_e7(wp.lat, wp.lon),
+            altitude: wp.alt as f64 / 100.0,
+            p1: wp.p1,
+            p2: wp.p2,
+            p3: wp.p3,
+            flag: wp.flag,
+        })
+    }
+
+    fn to_msp(self, wp_no: u8, last: bool) -> MspWaypoint {
+        let (lat, lon) = self.position.to_e7();
+        MspWaypoint {
+            wp_no,
+            action: self.action as u8,
+            lat,
+            lon,
+            alt: (self.altitude * 100.0).round() as i32,
+            p1: self.p1,
+            p2: self.p2,
+            p3: self.p3,
+            flag: if last { FLAG_LAST } else if self.flag == FLAG_LAST { 0 } else { self.flag },
+        }
+    }
+}
+
+/// An ordered list of mission items, waypoint 1 first. Home is not part of the mission.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Mission {
+    pub items: Vec<MissionItem>,
+}
+
+fn waypoint_info(port: &mut dyn SerialPort) -> Result<MspWaypointInfo> {
+    Ok(request(port, MspCommandCode::MSP_WP_GETINFO as u16, &[])?.decode_as::<MspWaypointInfo>()?)
+}
+
+fn read_waypoint(port: &mut dyn SerialPort, wp_no: u8) -> Result<MspWaypoint> {
+    Ok(request(port, MspCommandCode::MSP_WP as u16, &[wp_no])?.decode_as::<MspWaypoint>()?)
+}
+
+impl Mission {
+    /// Check the mission makes sense before it is sent: jumps must target an earlier
+    /// waypoint that is not itself a jump, and the mission must not start with a jump
+    pub fn validate(&self) -> Result<()> {
+        if self.items.len() > u8::MAX as usize {
+            return Err(Error::msg(format!("{} waypoints do not fit a mission", self.items.len())));
+        }
+        for (i, item) in self.items.iter().enumerate() {
+            let no = i + 1;
+            if item.action != WaypointAction::Jump {
+                continue;
+            }
+            let target = item.p1 as usize;
+            if no == 1 {
+                return Err(Error::msg("A mission cannot start with a jump"));
+            }
+            match self.items.get(target.wrapping_sub(1)) {
+                Some(t) if target < no && t.action != WaypointAction::Jump => {}
+                _ => return Err(Error::msg(format!("Waypoint {} jumps to invalid waypoint {}", no, target))),
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the mission currently held by the flight controller
+    pub fn download(port: &mut dyn SerialPort) -> Result<Mission> {
+        let info = waypoint_info(port)?;
+        let mut items = Vec::with_capacity(info.waypoint_count as usize);
+        for wp_no in 1..=info.waypoint_count {
+            let wp = read_waypoint(port, wp_no)?;
+            items.push(MissionItem::from_msp(&wp)?);
+            if wp.flag == FLAG_LAST {
+                break;
+            }
+        }
+        Ok(Mission { items })
+    }
+
+    /// Send every waypoint, reading each one back to check it was stored. The flight
+    /// controller only accepts a mission while disarmed.
+    pub fn upload(&self, port: &mut dyn SerialPort) -> Result<()> {
+        self.validate()?;
+        let info = waypoint_info(port)?;
+        if self.items.len() > info.max_waypoints as usize {
+            return Err(Error::msg(format!(
+                "Mission has {} waypoints, flight controller takes {}",
+                self.items.len(),
+                info.max_waypoints
+            )));
+        }
+
+        for (i, item) in self.items.iter().enumerate() {
+            let wp = item.to_msp(i as u8 + 1, i + 1 == self.items.len());
+            request(port, MspCommandCode::MSP_SET_WP as u16, &wp.pack_to_vec()?)?;
+            let stored = read_waypoint(port, wp.wp_no)?;
+            if stored != wp {
+                return Err(Error::msg(format!("Waypoint {} read back as {:?}", wp.wp_no, stored)));
+            }
+        }
+
+        let info = waypoint_info(port)?;
+        if info.mission_valid == 0 || info.waypoint_count as usize != self.items.len() {
+            return Err(Error::msg("Flight controller did not accept the mission"));
+        }
+        Ok(())
+    }
+
+    /// Store the uploaded mission in EEPROM
+    pub fn save(port: &mut dyn SerialPort) -> Result<()> {
+        request(port, MspCommandCode::MSP_WP_MISSION_SAVE as u16, &[0])?;
+        Ok(())
+    }
+
+    /// Parse an iNav Configurator `.mission` file. Positions are in degrees and altitudes in
+    /// meters, p1 to p3 are passed through as is.
+    pub fn from_xml(xml: &str) -> Result<Mission> {
+        let mut reader = Reader::from_str(xml);
+        let mut items: Vec<(u32, MissionItem)> = Vec::new();
+
+        loop {
+            match reader.read_event()? {
+                Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"missionitem" => {
+                    let mut attr = std::collections::HashMap::new();
+                    for a in e.attributes() {
+                        let a = a?;
+                        let key = String::from_utf8_lossy(a.key.as_ref()).to_ascii_lowercase();
+                        attr.insert(key, a.unescape_value()?.into_owned());
+                    }
+                    let get = |key: &str| {
+                        attr.get(key)
+                            .map(String::as_str)
+                            .ok_or_else(|| Error::msg(format!("missionitem without {}", key)))
+                    };
+                    let num = |key: &str| -> Result<f64> {
+                        attr.get(key).map_or(Ok(0.0), |v| {
+                            v.trim().parse().map_err(|_| Error::msg(format!("Bad {} {:?}", key, v)))
+                        })
+                    };
+
+                    let action = WaypointAction::from_name(get("action")?)
+                        .ok_or_else(|| Error::msg(format!("Unknown action {:?}", attr["action"])))?;
+                    items.push((
+                        num("no")? as u32,
+                        MissionItem {
+                            action,
+                            position: GeoPosition::new(num("lat")?, num("lon")?),
+                            altitude: num("alt")?,
+                            p1: num("parameter1")? as i16,
+                            p2: num("parameter2")? as i16,
+                            p3: num("parameter3")? as u16,
+                            flag: num("flag")? as u8,
+                        },
+                    ));
+                }
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        items.sort_by_key(|(no, _)| *no);
+        Ok(Mission { items: items.into_iter().map(|(_, item)| item).collect() })
+    }
+
+    /// Write the mission in the iNav Configurator `.mission` format
+    pub fn to_xml(&self) -> Result<String> {
+        let mut writer = Writer::new_with_indent(Vec::new(), b' ', 2);
+        writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("utf-8"), None)))?;
+        writer.write_event(Event::Start(BytesStart::new("mission")))?;
+        writer.write_event(Event::Empty(BytesStart::new("version").with_attributes([("value", "2.3-pre8")])))?;
+
+        for (i, item) in self.items.iter().enumerate() {
+            let last = i + 1 == self.items.len();
+            let fields = [
+                ("no", (i + 1).to_string()),
+                ("action", item.action.name().to_owned()),
+                ("lat", format!("{:.7}", item.position.lat)),
+                ("lon", format!("{:.7}", item.position.lon)),
+                ("alt", format!("{}", item.altitude)),
+                ("parameter1", item.p1.to_string()),
+                ("parameter2", item.p2.to_string()),
+                ("parameter3", item.p3.to_string()),
+                ("flag", item.to_msp(0, last).flag.to_string()),
+            ];
+            let element = BytesStart::new("missionitem").with_attributes(fields.iter().map(|(k, v)| (*k, v.as_str())));
+            writer.write_event(Event::Empty(element))?;
+        }
+
+        writer.write_event(Event::End(BytesEnd::new("mission")))?;
+        Ok(String::from_utf8(writer.into_inner())?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    const MISSION: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<mission>
+  <version value="2.3-pre8"></version>
+  <mwp cx="8.5455938" cy="47.3977419" home-x="0" home-y="0" zoom="18"></mwp>
+  <missionitem no="2" action="POSHOLD_TIME" lat="47.3980000" lon="8.5460000" alt="30" parameter1="10" parameter2="0" parameter3="0" flag="0"></missionitem>
+  <missionitem no="1" action="WAYPOINT" lat="47.3977419" lon="8.5455938" alt="25.5" parameter1="0" parameter2="0" parameter3="0" flag="0"></missionitem>
+  <missionitem no="3" action="JUMP" lat="0" lon="0" alt="0" parameter1="1" parameter2="2" parameter3="0" flag="0"></missionitem>
+  <missionitem no="4" action="RTH" lat="0" lon="0" alt="0" parameter1="1" parameter2="0" parameter3="0" flag="165"></missionitem>
+</mission>
+"#;
+
+    #[test]
+    fn mission_xml_round_trip() {
+        let mission = Mission::from_xml(MISSION).unwrap();
+        let actions: Vec<_> = mission.items.iter().map(|i| i.action).collect();
+        use WaypointAction::*;
+        assert_eq!(vec![Waypoint, PosholdTime, Jump, Rth], actions);
+        assert_eq!(25.5, mission.items[0].altitude);
+        assert_eq!((1, 2), (mission.items[2].p1, mission.items[2].p2));
+        mission.validate().unwrap();
+
+        let xml = mission.to_xml().unwrap();
+        assert!(xml.contains(r#"<missionitem no="4" action="RTH""#));
+        assert_eq!(mission, Mission::from_xml(&xml).unwrap());
+
+        let mut bad = mission.clone();
+        bad.items[2].p1 = 3;
+        assert!(bad.validate().is_err());
+    }
+
+    #[test]
+    fn upload_and_download() {
+        let mission = Mission::from_xml(MISSION).unwrap();
+
+        let store: Arc<Mutex<HashMap<u8, Vec<u8>>>> = Arc::default();
+        let fc_store = store.clone();
+        let mut fc = MockFc::new().handler(move |p| {
+            let mut wps = fc_store.lock().unwrap();
+            let data = p.data.as_slice();
+            match p.cmd {
+                c if c == MSP_SET_WP as u16 => {
+                    wps.insert(data[0], data.to_vec());
+                    Some(Vec::new())
+                }
+                c if c == MSP_WP as u16 => wps.get(&data[0]).cloned(),
+                c if c == MSP_WP_GETINFO as u16 => {
+                    let last = wps.values().find(|w| w[20] == FLAG_LAST).map(|w| w[0]);
+                    Some(vec![0, 60, last.is_some() as u8, last.unwrap_or(wps.len() as u8)])
+                }
+                _ => None,
+            }
+        });
+
+        mission.upload(&mut fc).unwrap();
+        let stored = store.lock().unwrap();
+        assert_eq!(4, stored.len());
+        // wp_no, action, lat i32 ... flag, with the last one flagged
+        let first = &stored[&1];
+        assert_eq!(21, first.len());
+        assert_eq!([1, 1], first[..2]);
+        assert_eq!(473_977_419i32.to_le_bytes(), first[2..6]);
+        assert_eq!(2550i32.to_le_bytes(), first[10..14]);
+        assert_eq!(0, first[20]);
+        assert_eq!(FLAG_LAST, stored[&4][20]);
+        drop(stored);
+
+        assert_eq!(mission, Mission::download(&mut fc).unwrap());
+    }
+
+    #[test]
+    fn upload_refused_or_not_stored() {
+        let mission = Mission::from_xml(MISSION).unwrap();
+
+        let mut fc = MockFc::new().reply(MSP_WP_GETINFO as u16, &[0, 2, 0, 0]);
+        let error = mission.upload(&mut fc).unwrap_err();
+        assert_eq!("Mission has 4 waypoints, flight controller takes 2", error.to_string());
+        assert!(!fc.commands().contains(&(MSP_SET_WP as u16)));
+
+        // the first waypoint comes back empty
+        let mut fc = MockFc::new()
+            .reply(MSP_WP_GETINFO as u16, &[0, 60, 0, 0])
+            .reply(MSP_SET_WP as u16, &[])
+            .reply(MSP_WP as u16, &[1; 21]);
+        assert!(mission.upload(&mut fc).unwrap_err().to_string().starts_with("Waypoint 1 read back as"));
+        assert_eq!(1, fc.commands().iter().filter(|c| **c == MSP_SET_WP as u16).count());
+
+        // every waypoint is stored, but an armed flight controller keeps the mission invalid
+        let mut stored: HashMap<u8, Vec<u8>> = HashMap::new();
+        for (i, item) in mission.items.iter().enumerate() {
+            let wp = item.to_msp(i as u8 + 1, i + 1 == mission.items.len());
+            stored.insert(wp.wp_no, wp.pack_to_vec().unwrap());
+        }
+        let mut fc = MockFc::new().handler(move |p| match p.cmd {
+            c if c == MSP_SET_WP as u16 => Some(Vec::new()),
+            c if c == MSP_WP as u16 => stored.get(&p.data.as_slice()[0]).cloned(),
+            c if c == MSP_WP_GETINFO as u16 => Some(vec![0, 60, 0, 4]),
+            _ => None,
+        });
+        assert_eq!("Flight controller did not accept the mission", mission.upload(&mut fc).unwrap_err().to_string());
+    }
+}
@@ -0,0 +1,318 @@
+//! Custom motor and servo mixes of iNav over `MSP2_MOTOR_MIXER` and `MSP2_INAV_SERVO_MIXER`
+
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspMotorMixer, MspServoMixer, MspSetMotorMixer, MspSetServoMixer},
+};
+
+/// iNav `MAX_SUPPORTED_MOTORS`
+pub const MAX_MOTORS: usize = 12;
+/// iNav `MAX_SERVO_RULES`
+pub const MAX_SERVO_RULES: usize = 32;
+/// Largest net roll, pitch or yaw weight a multirotor mix may leave over
+const TORQUE_TOLERANCE: f32 = 0.1;
+
+fn weight_from_raw(raw: u16) -> f32 {
+    raw as f32 / 1000.0 - 2.0
+}
+
+fn weight_to_raw(weight: f32) -> u16 {
+    ((weight.clamp(-2.0, 2.0) + 2.0) * 1000.0).round() as u16
+}
+
+/// One motor of the mix, weights in [-2, 2]
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct MotorRule {
+    pub throttle: f32,
+    pub roll: f32,
+    pub pitch: f32,
+    pub yaw: f32,
+}
+
+impl MotorRule {
+    pub fn new(throttle: f32, roll: f32, pitch: f32, yaw: f32) -> MotorRule {
+        MotorRule { throttle, roll, pitch, yaw }
+    }
+
+    pub fn from_msp(mixer: &MspMotorMixer) -> MotorRule {
+        MotorRule {
+            throttle: weight_from_raw(mixer.throttle),
+            roll: weight_from_raw(mixer.roll),
+            pitch: weight_from_raw(mixer.pitch),
+            yaw: weight_from_raw(mixer.yaw),
+        }
+    }
+
+    pub fn to_msp(self) -> MspMotorMixer {
+        MspMotorMixer {
+            throttle: weight_to_raw(self.throttle),
+            roll: weight_to_raw(self.roll),
+            pitch: weight_to_raw(self.pitch),
+            yaw: weight_to_raw(self.yaw),
+        }
+    }
+
+    fn weights(&self) -> [f32; 4] {
+        [self.throttle, self.roll, self.pitch, self.yaw]
+    }
+}
+
+/// The motor and servo rules of the active mixer profile. Both lists end at the first unused
+/// rule on the flight controller: a motor without throttle or a servo rule with zero rate.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct Mixer {
+    pub motors: Vec<MotorRule>,
+    pub servos: Vec<MspServoMixer>,
+}
+
+fn read_motors(port: &mut dyn SerialPort) -> Result<Vec<MotorRule>> {
+    let reply = request(port, MspCommandCode::MSP2_MOTOR_MIXER as u16, &[])?;
+    Ok(reply
+        .data
+        .as_slice()
+        .chunks_exact(8)
+        .take(MAX_MOTORS)
+        .map(MspMotorMixer::unpack_from_slice)
+        .collect::<Result<Vec<_>, _>>()?
+        .iter()
+        .map(MotorRule::from_msp)
+        .take_while(|m| m.throttle != 0.0)
+        .collect())
+}
+
+fn read_servos(port: &mut dyn SerialPort) -> Result<Vec<MspServoMixer>> {
+    let reply = request(port, MspCommandCode::MSP2_INAV_SERVO_MIXER as u16, &[])?;
+    Ok(reply
+        .data
+        .as_slice()
+        .chunks_exact(6)
+        .take(MAX_SERVO_RULES)
+        .map(MspServoMixer::unpack_from_slice)
+        .collect::<Result<Vec<_>, _>>()?
+        .into_iter()
+        .take_while(|s| s.rate != 0)
+        .collect())
+}
+
+impl Mixer {
+    pub fn load(port: &mut dyn SerialPort) -> Result<Mixer> {
+        Ok(Mixer { motors: read_motors(port)?, servos: read_servos(port)? })
+    }
+
+    /// Check the rules fit the firmware and, with three or more motors that steer, that roll,
+    /// pitch and yaw torques cancel out and some motors spin each way
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.motors.len() > MAX_MOTORS {
+            problems.push(format!("{} motors, at most {}", self.motors.len(), MAX_MOTORS));
+        }
+        if self.servos.len() > MAX_SERVO_RULES {
+            problems.push(format!("{} servo rules, at most {}", self.servos.len(), MAX_SERVO_RULES));
+        }
+
+        for (i, m) in self.motors.iter().enumerate() {
+            if m.weights().iter().any(|w| !(-2.0..=2.0).contains(w)) {
+                problems.push(format!("motor {} has a weight outside [-2, 2]", i + 1));
+            }
+            if m.throttle <= 0.0 {
+                problems.push(format!("motor {} has no throttle", i + 1));
+            }
+        }
+        for (i, s) in self.servos.iter().enumerate() {
+            if s.rate == 0 || !(-1000..=1000).contains(&s.rate) {
+                problems.push(format!("servo rule {} has rate {}", i + 1, s.rate));
+            }
+        }
+
+        let steering = self.motors.iter().filter(|m| m.roll != 0.0 || m.pitch != 0.0 || m.yaw != 0.0).count();
+        if steering >= 3 {
+            for (axis, pick) in [("roll", 1), ("pitch", 2), ("yaw", 3)] {
+                let net: f32 = self.motors.iter().map(|m| m.weights()[pick]).sum();
+                if net.abs() > TORQUE_TOLERANCE {
+                    problems.push(format!("{} weights do not cancel out, net {:.3}", axis, net));
+                }
+            }
+            // a tricopter yaws with a servo and leaves every motor's yaw at zero
+            let cw = self.motors.iter().filter(|m| m.yaw > 0.0).count();
+            let ccw = self.motors.iter().filter(|m| m.yaw < 0.0).count();
+            if cw + ccw > 0 && (cw == 0 || ccw == 0) {
+                problems.push("all motors spin the same way, yaw has no authority".to_owned());
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(Error::msg(format!("Invalid mixer: {}", problems.join(", "))));
+        }
+        Ok(())
+    }
+
+    /// Validate and replace the whole mix, then save to EEPROM. Every rule is read back; if
+    /// anything did not stick the previous mix is written again before returning the error.
+    pub fn write(&self, port: &mut dyn SerialPort) -> Result<()> {
+        self.validate()?;
+        let previous = Mixer::load(port)?;
+
+        if let Err(e) = self.send(port, &previous).and_then(|_| self.verify(port)) {
+            return match previous.send(port, self).and_then(|_| previous.verify(port)) {
+                Ok(()) => Err(e.context("Mixer write failed, previous mix restored")),
+                Err(rollback) => Err(e.context(format!(
+                    "Mixer write failed and restoring the previous mix failed too: {}",
+                    rollback
+                ))),
+            };
+        }
+
+        request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        Ok(())
+    }
+
+    /// Send every rule, clearing the ones `old` used beyond the end of this mix
+    fn send(&self, port: &mut dyn SerialPort, old: &Mixer) -> Result<()> {
+        let unused_motor = MotorRule::new(0.0, 0.0, 0.0, 0.0).to_msp();
+        for index in 0..self.motors.len().max(old.motors.len()) {
+            let motor_mixer = self.motors.get(index).map_or(unused_motor, |m| m.to_msp());
+            let set = MspSetMotorMixer { index: index as u8, motor_mixer };
+            request(port, MspCommandCode::MSP2_SET_MOTOR_MIXER as u16, &set.pack_to_vec()?)?;
+        }
+
+        let unused_servo = MspServoMixer { target_channel: 0, input_source: 0, rate: 0, speed: 0, condition_id: -1 };
+        for index in 0..self.servos.len().max(old.servos.len()) {
+            let servo_rule = self.servos.get(index).copied().unwrap_or(unused_servo);
+            let set = MspSetServoMixer { index: index as u8, servo_rule };
+            request(port, MspCommandCode::MSP2_INAV_SET_SERVO_MIXER as u16, &set.pack_to_vec()?)?;
+        }
+        Ok(())
+    }
+
+    fn verify(&self, port: &mut dyn SerialPort) -> Result<()> {
+        let stored = Mixer::load(port)?;
+        let same_motors = stored.motors.len() == self.motors.len()
+            && stored.motors.iter().zip(&self.motors).all(|(s, m)| s.to_msp() == m.to_msp());
+        if !same_motors || stored.servos != self.servos {
+            return Err(Error::msg(format!("Mixer read back as {:?}", stored)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use packed_struct::PackedStruct;
+    use std::sync::{Arc, Mutex};
+
+    fn hex_x() -> Vec<MotorRule> {
+        vec![
+            MotorRule::new(1.0, -0.5, 0.866, 1.0),
+            MotorRule::new(1.0, -1.0, 0.0, -1.0),
+            MotorRule::new(1.0, -0.5, -0.866, 1.0),
+            MotorRule::new(1.0, 0.5, -0.866, -1.0),
+            MotorRule::new(1.0, 1.0, 0.0, 1.0),
+            MotorRule::new(1.0, 0.5, 0.866, -1.0),
+        ]
+    }
+
+    /// iNav with room for every rule, `broken` motor indexes ignore writes
+    fn inav(motors: &[MotorRule], broken: Vec<u8>) -> MockFc {
+        let mut table = Vec::new();
+        for i in 0..MAX_MOTORS {
+            let m = motors.get(i).copied().unwrap_or(MotorRule::new(0.0, 0.0, 0.0, 0.0));
+            table.extend(m.to_msp().pack().unwrap());
+        }
+        let m = Arc::new(Mutex::new(table));
+        let s = Arc::new(Mutex::new(vec![0u8; MAX_SERVO_RULES * 6]));
+        MockFc::new().reply(MSP_EEPROM_WRITE as u16, &[]).handler(move |p| {
+            let data = p.data.as_slice();
+            let (mut m, mut s) = (m.lock().unwrap(), s.lock().unwrap());
+            match p.cmd {
+                c if c == MSP2_MOTOR_MIXER as u16 => Some(m.clone()),
+                c if c == MSP2_INAV_SERVO_MIXER as u16 => Some(s.clone()),
+                c if c == MSP2_SET_MOTOR_MIXER as u16 => {
+                    if !broken.contains(&data[0]) {
+                        let at = data[0] as usize * 8;
+                        m[at..at + 8].copy_from_slice(&data[1..]);
+                    }
+                    Some(Vec::new())
+                }
+                c if c == MSP2_INAV_SET_SERVO_MIXER as u16 => {
+                    let at = data[0] as usize * 6;
+                    s[at..at + 6].copy_from_slice(&data[1..]);
+                    Some(Vec::new())
+                }
+                _ => None,
+            }
+        })
+    }
+
+    #[test]
+    fn weights_and_geometry() {
+        let quad_corner = MspMotorMixer { throttle: 3000, roll: 1000, pitch: 3000, yaw: 1000 };
+        assert_eq!(MotorRule::new(1.0, -1.0, 1.0, -1.0), MotorRule::from_msp(&quad_corner));
+        assert_eq!(quad_corner, MotorRule::new(1.0, -1.0, 1.0, -1.0).to_msp());
+        assert_eq!(0, MotorRule::new(0.0, -2.5, 0.0, 0.0).to_msp().roll);
+
+        let mut mixer = Mixer { motors: hex_x(), servos: Vec::new() };
+        mixer.validate().unwrap();
+
+        // a motor turning the wrong way leaves yaw torque behind
+        mixer.motors[1].yaw = 1.0;
+        let e = mixer.validate().unwrap_err().to_string();
+        assert!(e.contains("yaw weights do not cancel out"), "{}", e);
+
+        // every motor yawing the same way
+        let mut same_way = Mixer { motors: hex_x(), servos: Vec::new() };
+        same_way.motors.iter_mut().for_each(|m| m.yaw = 0.5);
+        let e = same_way.validate().unwrap_err().to_string();
+        assert!(e.contains("all motors spin the same way"), "{}", e);
+
+        // a tricopter yaws with its tail servo, no motor carries yaw
+        let tri = vec![
+            MotorRule::new(1.0, 0.0, 4.0 / 3.0, 0.0),
+            MotorRule::new(1.0, -1.0, -2.0 / 3.0, 0.0),
+            MotorRule::new(1.0, 1.0, -2.0 / 3.0, 0.0),
+        ];
+        let tail = MspServoMixer { target_channel: 5, input_source: 2, rate: 100, speed: 0, condition_id: -1 };
+        Mixer { motors: tri, servos: vec![tail] }.validate().unwrap();
+
+        // a twin-motor plane only uses throttle
+        Mixer { motors: vec![MotorRule::new(1.0, 0.0, 0.0, 0.0); 2], servos: Vec::new() }.validate().unwrap();
+        assert!(Mixer { motors: vec![MotorRule::new(1.0, 0.0, 0.0, 2.5)], servos: Vec::new() }.validate().is_err());
+    }
+
+    #[test]
+    fn write_is_verified_and_rolled_back() {
+        let quad = vec![
+            MotorRule::new(1.0, -1.0, 1.0, -1.0),
+            MotorRule::new(1.0, -1.0, -1.0, 1.0),
+            MotorRule::new(1.0, 1.0, 1.0, 1.0),
+            MotorRule::new(1.0, 1.0, -1.0, -1.0),
+        ];
+        let servo = MspServoMixer { target_channel: 3, input_source: 2, rate: -100, speed: 0, condition_id: -1 };
+
+        let mut fc = inav(&quad, Vec::new());
+        let loaded = Mixer::load(&mut fc).unwrap();
+        assert_eq!(quad, loaded.motors);
+        assert!(loaded.servos.is_empty());
+
+        let hex = Mixer { motors: hex_x(), servos: vec![servo] };
+        hex.write(&mut fc).unwrap();
+        assert_eq!(Some(MSP_EEPROM_WRITE as u16), fc.commands().last().copied());
+        let stored = Mixer::load(&mut fc).unwrap();
+        assert_eq!(hex.servos, stored.servos);
+        assert_eq!(6, stored.motors.len());
+
+        // motor 5 does not take the write, so the quad comes back
+        let mut fc = inav(&quad, vec![4]);
+        assert!(hex.write(&mut fc).unwrap_err().to_string().contains("previous mix restored"));
+        assert_eq!(quad, Mixer::load(&mut fc).unwrap().motors);
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+    }
+}
@@ -0,0 +1,288 @@
+//! In-memory flight controller used by the unit tests
+#![allow(dead_code)]
+
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::time::{Duration, Instant};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+use crate::msp::{
+    data::MspPacketData,
+    packet::{MspPacket, MspPacketDirection},
+    parser::MspParser,
+};
+
+type Handler = Box<dyn FnMut(&MspPacket) -> Option<Vec<u8>> + Send>;
+type CliHandler = Box<dyn FnMut(&str) -> String + Send>;
+
+/// Answers MSP requests from a table of canned replies. SET commands registered with
+/// [`MockFc::setter`] overwrite the reply of their GET counterpart, so written values can be
+/// read back. Anything unknown is answered with the `!` (unsupported) direction.
+///
+/// With a CLI handler set, a lone `#` switches to a Betaflight style CLI that echoes each line,
+/// prints the handler's output and a `# ` prompt. [`MockFc::pause_after`] splits the output
+/// into blocks with silence in between, like a firmware flushing its output buffer.
+pub(crate) struct MockFc {
+    parser: MspParser,
+    replies: HashMap<u16, Vec<u8>>,
+    setters: HashMap<u16, u16>,
+    handler: Option<Handler>,
+    cli: Option<CliHandler>,
+    in_cli: bool,
+    line: Vec<u8>,
+    outgoing: VecDeque<u8>,
+    /// Blocks of output end right after this, followed by a pause
+    pause: Option<(Vec<u8>, Duration)>,
+    quiet_until: Option<Instant>,
+    /// Every packet received, in order
+    pub requests: Vec<MspPacket>,
+    /// Every byte written to the port, in order
+    pub written: Vec<u8>,
+}
+
+impl MockFc {
+    pub fn new() -> Self {
+        Self {
+            parser: MspParser::to_fc(),
+            replies: HashMap::new(),
+            setters: HashMap::new(),
+            handler: None,
+            cli: None,
+            in_cli: false,
+            line: Vec::new(),
+            outgoing: VecDeque::new(),
+            pause: None,
+            quiet_until: None,
+            requests: Vec::new(),
+            written: Vec::new(),
+        }
+    }
+
+    /// Canned reply payload for `cmd`
+    pub fn reply(mut self, cmd: u16, payload: &[u8]) -> Self {
+        self.replies.insert(cmd, payload.to_vec());
+        self
+    }
+
+    /// Store the payload of `set` as the reply of `get`
+    pub fn setter(mut self, set: u16, get: u16) -> Self {
+        self.setters.insert(set, get);
+        self
+    }
+
+    /// Custom handler, consulted before the canned replies
+    pub fn handler(mut self, f: impl FnMut(&MspPacket) -> Option<Vec<u8>> + Send + 'static) -> Self {
+        self.handler = Some(Box::new(f));
+        self
+    }
+
+    /// CLI handler, returns the output of each command line
+    pub fn cli(mut self, f: impl FnMut(&str) -> String + Send + 'static) -> Self {
+        self.cli = Some(Box::new(f));
+        self
+    }
+
+    /// End each read at the next `marker` that has more output behind it, then answer
+    /// nothing for `pause`
+    pub fn pause_after(mut self, marker: &str, pause: Duration) -> Self {
+        self.pause = Some((marker.as_bytes().to_vec(), pause));
+        self
+    }
+
+    pub fn in_cli(&self) -> bool {
+        self.in_cli
+    }
+
+    pub fn value(&self, cmd: u16) -> Option<&[u8]> {
+        self.replies.get(&cmd).map(|v| v.as_slice())
+    }
+
+    /// Commands received, in order
+    pub fn commands(&self) -> Vec<u16> {
+        self.requests.iter().map(|p| p.cmd).collect()
+    }
+
+    fn cli_byte(&mut self, b: u8) {
+        if b != b'\r' && b != b'\n' {
+            self.line.push(b);
+            return;
+        }
+        if self.line.is_empty() {
+            return;
+        }
+
+        let line = String::from_utf8_lossy(&self.line).into_owned();
+        self.line.clear();
+        let output = self.cli.as_mut().map(|f| f(&line)).unwrap_or_default();
+        self.outgoing.extend(format!("{}\r\n{}", line, output).bytes());
+        if line == "exit" || line == "save" {
+            self.in_cli = false;
+        } else {
+            self.outgoing.extend(b"\r\n# ");
+        }
+    }
+
+    fn respond(&mut self, packet: MspPacket) {
+        let reply = match self.handler.as_mut().and_then(|h| h(&packet)) {
+            Some(payload) => Some(payload),
+            None => match self.setters.get(&packet.cmd) {
+                Some(get) => {
+                    self.replies.insert(*get, packet.data.as_slice().to_vec());
+                    Some(Vec::new())
+                }
+                None => self.replies.get(&packet.cmd).cloned(),
+            },
+        };
+
+        let direction = match reply {
+            Some(_) => MspPacketDirection::FromFlightController,
+            None => MspPacketDirection::Unsupported,
+        };
+        let out = MspPacket {
+            cmd: packet.cmd,
+            direction,
+            data: MspPacketData::from(reply.unwrap_or_default().as_slice()),
+        };
+
+        let mut buf;
+        if out.cmd > 0xFF {
+            buf = vec![0u8; out.packet_size_bytes_v2()];
+            out.serialize_v2(&mut buf).unwrap();
+        } else {
+            buf = vec![0u8; out.packet_size_bytes()];
+            out.serialize(&mut buf).unwrap();
+        }
+        self.outgoing.extend(buf);
+        self.requests.push(packet);
+    }
+}
+
+impl io::Read for MockFc {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let quiet = self.quiet_until.is_some_and(|until| Instant::now() < until);
+        if self.outgoing.is_empty() || quiet {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "no data"));
+        }
+        let mut n = buf.len().min(self.outgoing.len());
+        if let Some((marker, pause)) = &self.pause {
+            let pending = self.outgoing.make_contiguous();
+            let block_end = pending
+                .windows(marker.len())
+                .position(|w| w == marker.as_slice())
+                .map(|at| at + marker.len())
+                .filter(|end| *end < pending.len() && *end <= n);
+            if let Some(end) = block_end {
+                n = end;
+                self.quiet_until = Some(Instant::now() + *pause);
+            }
+        }
+        for (dst, src) in buf.iter_mut().zip(self.outgoing.drain(..n)) {
+            *dst = src;
+        }
+        Ok(n)
+    }
+}
+
+impl io::Write for MockFc {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.written.extend_from_slice(buf);
+        for &b in buf {
+            if self.in_cli {
+                self.cli_byte(b);
+                continue;
+            }
+            if self.cli.is_some() && b == b'#' && self.parser.state_is_between_packets() {
+                self.in_cli = true;
+                self.outgoing.extend(b"\r\nEntering CLI Mode, type 'exit' to return, or 'help'\r\n\r\n# ");
+                continue;
+            }
+            if let Ok(Some(packet)) = self.parser.parse(b) {
+                self.respond(packet);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl SerialPort for MockFc {
+    fn name(&self) -> Option<String> {
+        Some("mock".to_owned())
+    }
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(115_200)
+    }
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+    fn timeout(&self) -> Duration {
+        Duration::ZERO
+    }
+    fn set_baud_rate(&mut self, _: u32) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_data_bits(&mut self, _: DataBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_flow_control(&mut self, _: FlowControl) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_parity(&mut self, _: Parity) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_stop_bits(&mut self, _: StopBits) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn set_timeout(&mut self, _: Duration) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn write_request_to_send(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn write_data_terminal_ready(&mut self, _: bool) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Ok(true)
+    }
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Ok(false)
+    }
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(self.outgoing.len() as u32)
+    }
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+    fn clear(&self, _: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Err(serialport::Error::new(serialport::ErrorKind::Unknown, "mock port cannot be cloned"))
+    }
+    fn set_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+    fn clear_break(&self) -> serialport::Result<()> {
+        Ok(())
+    }
+}
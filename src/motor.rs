@@ -0,0 +1,368 @@
+//! Bench motor testing over `MSP_SET_MOTOR` with the safety checks a props-on mistake calls for
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspAdvancedConfig, MspMotor, MspMotorConfig},
+};
+use crate::status::FcStatus;
+
+/// Motor values sent over MSP are always on the 1000-2000 PWM scale
+const DIGITAL_STOP: u16 = 1000;
+const DIGITAL_MAX: u16 = 2000;
+const REFRESH: Duration = Duration::from_millis(50);
+
+/// Where the `motor_pwm_protocol` table of a firmware turns digital, and its value for disabled
+#[derive(Debug, Copy, Clone)]
+struct MotorProtocols {
+    first_digital: u8,
+    disabled: Option<u8>,
+}
+
+impl MotorProtocols {
+    fn for_firmware(firmware: &FirmwareInfo) -> Result<MotorProtocols> {
+        match firmware.variant {
+            // STANDARD, ONESHOT125, MULTISHOT, BRUSHED, then DShot
+            FirmwareVariant::Inav => Ok(MotorProtocols { first_digital: 4, disabled: None }),
+            // PWM, ONESHOT125, ONESHOT42, MULTISHOT, BRUSHED, DShot from DSHOT150, then
+            // PROSHOT1000 and DISABLED, one place earlier since 4.2 dropped DSHOT1200
+            FirmwareVariant::Betaflight if firmware.api_at_least(1, 43) => {
+                Ok(MotorProtocols { first_digital: 5, disabled: Some(9) })
+            }
+            FirmwareVariant::Betaflight => Ok(MotorProtocols { first_digital: 5, disabled: Some(10) }),
+            ref other => Err(Error::msg(format!("Motor protocols of {} are not known", other))),
+        }
+    }
+}
+
+/// Raw motor values for stop and for 0 to 100 percent
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct MotorRange {
+    pub stop: u16,
+    pub min: u16,
+    pub max: u16,
+}
+
+impl MotorRange {
+    /// Analog protocols spin between min and max throttle and stop at min command, DShot and
+    /// ProShot use the whole range with 1000 as stop. The protocol is numbered as in the
+    /// table of `firmware`; a disabled protocol has no range.
+    pub fn new(motor: &MspMotorConfig, advanced: &MspAdvancedConfig, firmware: &FirmwareInfo) -> Result<MotorRange> {
+        let protocols = MotorProtocols::for_firmware(firmware)?;
+        let protocol = advanced.motor_pwm_protocol;
+        if protocols.disabled == Some(protocol) {
+            return Err(Error::msg("Motor protocol is disabled"));
+        }
+        if protocol >= protocols.first_digital {
+            Ok(MotorRange { stop: DIGITAL_STOP, min: DIGITAL_STOP + 1, max: DIGITAL_MAX })
+        } else {
+            Ok(MotorRange { stop: motor.min_command, min: motor.min_throttle, max: motor.max_throttle })
+        }
+    }
+
+    /// 0 percent stops the motor, anything above spins it between min and max
+    pub fn raw(&self, percent: f32) -> u16 {
+        match percent.clamp(0.0, 100.0) {
+            p if p <= 0.0 => self.stop,
+            p => self.min + ((self.max - self.min) as f32 * p / 100.0).round() as u16,
+        }
+    }
+}
+
+/// A motor test on a disarmed flight controller. Motors only spin inside [`MotorTest::spin`] and
+/// [`MotorTest::spin_all`], which always end by stopping every motor: after the requested time,
+/// past the time limit, on cancellation or on a failed request. A cancelled or timed out
+/// session refuses further commands, and dropping it sends all-stop once more.
+///
+/// The firmware cannot tell whether props are fitted, so the caller has to confirm they are off.
+/// This library installs no signal handler; to stop on Ctrl-C, set the flag given to
+/// [`MotorTest::with_cancel`] from the application's own handler.
+pub struct MotorTest<'a> {
+    port: &'a mut dyn SerialPort,
+    range: MotorRange,
+    motor_count: usize,
+    percent: [f32; 8],
+    /// Percent per second
+    ramp_rate: f32,
+    timeout: Duration,
+    cancel: Arc<AtomicBool>,
+    stopped: bool,
+}
+
+impl<'a> MotorTest<'a> {
+    /// Start a session. Refuses unless `props_removed` is set, the flight controller is disarmed
+    /// and it reports at least one reason it could not be armed.
+    pub fn start(port: &'a mut dyn SerialPort, firmware: &FirmwareInfo, props_removed: bool) -> Result<MotorTest<'a>> {
+        if !props_removed {
+            return Err(Error::msg("Remove the props and acknowledge it before testing motors"));
+        }
+        let status = FcStatus::read(port, firmware)?;
+        if status.armed {
+            return Err(Error::msg("Flight controller is armed"));
+        }
+        if status.arming_disabled.is_empty() {
+            return Err(Error::msg("Flight controller could be armed, disable arming before testing motors"));
+        }
+
+        let motor = request(port, MspCommandCode::MSP_MOTOR_CONFIG as u16, &[])?.decode_as::<MspMotorConfig>()?;
+        let advanced =
+            request(port, MspCommandCode::MSP_ADVANCED_CONFIG as u16, &[])?.decode_as_padded::<MspAdvancedConfig>()?;
+        let range = MotorRange::new(&motor, &advanced, firmware)?;
+
+        // unused outputs read as zero
+        let motors = request(port, MspCommandCode::MSP_MOTOR as u16, &[])?.decode_as_padded::<MspMotor>()?;
+        let motor_count = motors.motors.iter().take_while(|m| **m != 0).count();
+        if motor_count == 0 {
+            return Err(Error::msg("Flight controller reports no motors"));
+        }
+
+        let mut test = MotorTest {
+            port,
+            range,
+            motor_count,
+            percent: [0.0; 8],
+            ramp_rate: 50.0,
+            timeout: Duration::from_secs(30),
+            cancel: Arc::new(AtomicBool::new(false)),
+            stopped: false,
+        };
+        test.stop()?;
+        Ok(test)
+    }
+
+    /// How fast speed changes are ramped, in percent per second
+    pub fn with_ramp_rate(mut self, percent_per_second: f32) -> Self {
+        self.ramp_rate = percent_per_second;
+        self
+    }
+
+    /// Longest a single spin may run, ramp included, 30 s by default
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Flag that stops a running spin and ends the session once set, e.g. from a Ctrl-C handler
+    pub fn with_cancel(mut self, cancel: Arc<AtomicBool>) -> Self {
+        self.cancel = cancel;
+        self
+    }
+
+    pub fn range(&self) -> MotorRange {
+        self.range
+    }
+
+    pub fn motor_count(&self) -> usize {
+        self.motor_count
+    }
+
+    /// Ramp one 0 based motor up to `percent`, hold it for `duration` and stop
+    pub fn spin(&mut self, motor: usize, percent: f32, duration: Duration) -> Result<()> {
+        if motor >= self.motor_count {
+            return Err(Error::msg(format!("No motor {}, flight controller has {}", motor, self.motor_count)));
+        }
+        let mut target = [0.0; 8];
+        target[motor] = percent.clamp(0.0, 100.0);
+        self.run(target, duration)
+    }
+
+    /// Ramp every motor up to `percent`, hold them for `duration` and stop
+    pub fn spin_all(&mut self, percent: f32, duration: Duration) -> Result<()> {
+        let mut target = [0.0; 8];
+        target[..self.motor_count].fill(percent.clamp(0.0, 100.0));
+        self.run(target, duration)
+    }
+
+    /// Stop every motor at once, without ramping down
+    pub fn stop(&mut self) -> Result<()> {
+        self.percent = [0.0; 8];
+        let stop = MspMotor { motors: [self.range.stop; 8] };
+        request(self.port, MspCommandCode::MSP_SET_MOTOR as u16, &stop.pack_to_vec()?)?;
+        Ok(())
+    }
+
+    /// Spin towards `target` and stop every motor however that ends
+    fn run(&mut self, target: [f32; 8], duration: Duration) -> Result<()> {
+        if self.stopped {
+            return Err(Error::msg("Motor test is stopped"));
+        }
+        let result = self.ramp_and_hold(target, duration, Instant::now() + self.timeout);
+        if result.is_err() {
+            self.stopped = true;
+        }
+        let stopped = self.stop();
+        result.and(stopped)
+    }
+
+    fn ramp_and_hold(&mut self, target: [f32; 8], duration: Duration, deadline: Instant) -> Result<()> {
+        let step = self.ramp_rate * REFRESH.as_secs_f32();
+        let mut hold_until = None;
+        loop {
+            if self.cancel.load(Ordering::SeqCst) {
+                return Err(Error::msg("Motor test cancelled"));
+            }
+            if Instant::now() >= deadline {
+                return Err(Error::msg("Motor test timed out"));
+            }
+
+            for (current, target) in self.percent.iter_mut().zip(target) {
+                let delta = target - *current;
+                *current = if delta.abs() <= step { target } else { *current + step.copysign(delta) };
+            }
+            self.send()?;
+
+            if self.percent == target {
+                let end = *hold_until.get_or_insert_with(|| Instant::now() + duration);
+                let left = end.saturating_duration_since(Instant::now());
+                if left.is_zero() {
+                    return Ok(());
+                }
+                sleep(REFRESH.min(left));
+            } else {
+                sleep(REFRESH);
+            }
+        }
+    }
+
+    /// Send the current speeds, MSP_SET_MOTOR values hold until the next one
+    fn send(&mut self) -> Result<()> {
+        let mut motors = MspMotor { motors: [self.range.stop; 8] };
+        for (raw, percent) in motors.motors.iter_mut().zip(&self.percent[..self.motor_count]) {
+            *raw = self.range.raw(*percent);
+        }
+        request(self.port, MspCommandCode::MSP_SET_MOTOR as u16, &motors.pack_to_vec()?)?;
+        Ok(())
+    }
+}
+
+impl Drop for MotorTest<'_> {
+    fn drop(&mut self) {
+        // best effort, the port may be gone already
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::firmware::{FirmwareVariant, test_firmware};
+    use crate::status::{inav_status_payload, status_ex_payload};
+    use packed_struct::PackedStruct;
+
+    fn bench_fc(flight_mode: u32, arming_disabled: u32, protocol: u8) -> MockFc {
+        let advanced = MspAdvancedConfig { motor_pwm_protocol: protocol, ..Default::default() };
+        MockFc::new()
+            .reply(MSP_STATUS_EX as u16, &status_ex_payload(flight_mode, arming_disabled))
+            .reply(MSP_MOTOR_CONFIG as u16, &[0x4C, 0x04, 0xD0, 0x07, 0xE8, 0x03])
+            .reply(MSP_ADVANCED_CONFIG as u16, &advanced.pack().unwrap())
+            .reply(MSP_MOTOR as u16, &MspMotor { motors: [1000, 1000, 1000, 1000, 0, 0, 0, 0] }.pack().unwrap())
+            .reply(MSP_SET_MOTOR as u16, &[])
+    }
+
+    fn sent_motors(fc: &MockFc) -> Vec<[u16; 8]> {
+        fc.requests
+            .iter()
+            .filter(|p| p.cmd == MSP_SET_MOTOR as u16)
+            .map(|p| MspMotor::unpack_from_slice(p.data.as_slice()).unwrap().motors)
+            .collect()
+    }
+
+    #[test]
+    fn refuses_unsafe_start() {
+        let bf = test_firmware(FirmwareVariant::Betaflight, 46);
+        assert!(MotorTest::start(&mut bench_fc(0, 1 << 25, 6), &bf, false).is_err());
+        assert!(MotorTest::start(&mut bench_fc(1, 1 << 25, 6), &bf, true).is_err());
+        assert!(MotorTest::start(&mut bench_fc(0, 0, 6), &bf, true).is_err());
+        // DISABLED is 9 since Betaflight 4.2 and was 10 before, after DSHOT1200
+        let error = MotorTest::start(&mut bench_fc(0, 1 << 25, 9), &bf, true).err().unwrap();
+        assert_eq!("Motor protocol is disabled", error.to_string());
+        let bf41 = test_firmware(FirmwareVariant::Betaflight, 42);
+        assert!(MotorTest::start(&mut bench_fc(0, 1 << 25, 10), &bf41, true).is_err());
+        assert!(MotorTest::start(&mut bench_fc(0, 1 << 25, 9), &bf41, true).is_ok());
+        let other = test_firmware(FirmwareVariant::Cleanflight, 40);
+        assert!(MotorTest::start(&mut bench_fc(0, 1 << 25, 6), &other, true).is_err());
+
+        // iNav: armed, then disarmed with the arm switch blocking arming
+        let inav = test_firmware(FirmwareVariant::Inav, 5);
+        let mut fc = bench_fc(0, 0, 6).reply(MSP2_INAV_STATUS as u16, &inav_status_payload(1, 1 << 2, 0));
+        assert_eq!("Flight controller is armed", MotorTest::start(&mut fc, &inav, true).err().unwrap().to_string());
+        let mut fc = bench_fc(0, 0, 6).reply(MSP2_INAV_STATUS as u16, &inav_status_payload(0, 1 << 14, 0));
+        assert_eq!(4, MotorTest::start(&mut fc, &inav, true).unwrap().motor_count());
+
+        let motor = MspMotorConfig { min_throttle: 1070, max_throttle: 2000, min_command: 1000 };
+        let range = |protocol, firmware: &FirmwareInfo| {
+            let advanced = MspAdvancedConfig { motor_pwm_protocol: protocol, ..Default::default() };
+            MotorRange::new(&motor, &advanced, firmware).unwrap()
+        };
+        let analog = range(1, &bf);
+        assert_eq!([1000, 1535, 2000, 2000], [0.0, 50.0, 100.0, 150.0].map(|p| analog.raw(p)));
+        // PROSHOT1000 on Betaflight, BRUSHED and DSHOT150 on iNav
+        assert_eq!(1000, range(8, &bf).stop);
+        assert_eq!((analog, 1000), (range(3, &inav), range(4, &inav).stop));
+    }
+
+    #[test]
+    fn spins_then_stops() {
+        let bf = test_firmware(FirmwareVariant::Betaflight, 46);
+        let mut fc = bench_fc(0, 1 << 25, 6);
+        {
+            let mut test = MotorTest::start(&mut fc, &bf, true).unwrap().with_ramp_rate(400.0);
+            assert_eq!(4, test.motor_count());
+            assert_eq!(MotorRange { stop: 1000, min: 1001, max: 2000 }, test.range());
+            test.spin(2, 50.0, Duration::ZERO).unwrap();
+            assert!(test.spin(4, 10.0, Duration::ZERO).is_err());
+        }
+        let sent = sent_motors(&fc);
+        // 20 percent steps up to 50, stopped when the spin ends and again on drop
+        let m3: Vec<_> = sent.iter().map(|m| m[2]).collect();
+        assert_eq!(vec![1000, 1201, 1401, 1501, 1000, 1000], m3);
+        assert!(sent.iter().all(|m| m[0] == 1000 && m[7] == 1000));
+    }
+
+    #[test]
+    fn cancel_and_timeout_stop_the_motors() {
+        let bf = test_firmware(FirmwareVariant::Betaflight, 46);
+        // the caller's flag is raised while the motors ramp up
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let mut set_motor = 0;
+        let mut fc = bench_fc(0, 1 << 25, 6).handler(move |p| {
+            if p.cmd == MSP_SET_MOTOR as u16 {
+                set_motor += 1;
+                flag.store(set_motor == 3, Ordering::SeqCst);
+            }
+            None
+        });
+        {
+            let mut test = MotorTest::start(&mut fc, &bf, true).unwrap().with_ramp_rate(400.0).with_cancel(cancel);
+            let err = test.spin_all(50.0, Duration::from_secs(10)).unwrap_err();
+            assert_eq!("Motor test cancelled", err.to_string());
+            assert_eq!("Motor test is stopped", test.spin(0, 10.0, Duration::ZERO).unwrap_err().to_string());
+        }
+        let sent = sent_motors(&fc);
+        assert_eq!(vec![1000, 1201, 1401, 1000, 1000], sent.iter().map(|m| m[3]).collect::<Vec<_>>());
+        assert_eq!([1000; 8], sent[3]);
+
+        // past the time limit the motors are stopped once and further commands refused
+        let mut fc = bench_fc(0, 1 << 25, 6);
+        {
+            let mut test = MotorTest::start(&mut fc, &bf, true).unwrap().with_timeout(Duration::ZERO);
+            assert_eq!("Motor test timed out", test.spin_all(10.0, Duration::ZERO).unwrap_err().to_string());
+            assert!(test.spin_all(10.0, Duration::ZERO).is_err());
+        }
+        let sent = sent_motors(&fc);
+        assert_eq!(3, sent.len());
+        assert!(sent.iter().all(|m| *m == [1000; 8]));
+    }
+}
@@ -73,12 +73,13 @@ pub enum MspCommandCode {
     MSP_BATTERY_STATE = 130,
 
     MSP_MOTOR_CONFIG = 131,
+    MSP_SET_MOTOR_CONFIG = 222,
 
     // OSD commands
     MSP_OSD_VIDEO_CONFIG = 180,
     MSP_SET_OSD_VIDEO_CONFIG = 181,
-    MSP_OSD_VIDEO_STATUS = 182,
-    MSP_OSD_ELEMENT_SUMMARY = 183,
+    MSP_DISPLAYPORT = 182,
+    MSP_COPY_PROFILE = 183,
     MSP_OSD_LAYOUT_CONFIG = 184,
     MSP_SET_OSD_LAYOUT_CONFIG = 185,
 
@@ -137,10 +138,12 @@ pub enum MspCommandCode {
     MSP_DEBUG = 254,
 
     MSP_BF_CONFIG = 66,
+    MSP_SET_BF_CONFIG = 67,
 
     // Additional baseflight commands that are not compatible with MultiWii
     MSP_UID = 160,          // Unique device ID
     MSP_STATUS_EX = 150,    // cycletime, errors_count, CPU load, sensor present etc
+    MSP_SENSOR_STATUS = 151, // hardware health of each sensor
     MSP_ACC_TRIM = 240,     // get acc angle trim values
     MSP_SET_ACC_TRIM = 239, // set acc angle trim values
     MSP_GPS_SV_INFO = 164,  // get Signal Strength
@@ -149,6 +152,10 @@ pub enum MspCommandCode {
     MSP_RX_MAP = 64,     // get channel map (also returns number of channels total)
     MSP_SET_RX_MAP = 65, // set rc map, numchannels to set comes from MSP_RX_MAP
 
+    MSP_WP_MISSION_LOAD = 18, // load mission from EEPROM - inav
+    MSP_WP_MISSION_SAVE = 19, // save mission to EEPROM - inav
+    MSP_WP_GETINFO = 20,      // waypoint capabilities and mission state - inav
+
     MSP_SET_REBOOT = 68,    // reboot settings
     MSP_BF_BUILD_INFO = 69, // build date as well as some space for future expansion,
 
@@ -165,6 +172,13 @@ pub enum MspCommandCode {
     MSP_SENSOR_CONFIG = 96,
     MSP_SET_SENSOR_CONFIG = 97,
 
+    MSP_VTX_CONFIG = 88,
+    MSP_SET_VTX_CONFIG = 89,
+    MSP_VTXTABLE_BAND = 137,
+    MSP_VTXTABLE_POWERLEVEL = 138,
+    MSP_SET_VTXTABLE_BAND = 227,
+    MSP_SET_VTXTABLE_POWERLEVEL = 228,
+
     // Inav
     MSP2_COMMON_SETTING = 0x1003, //in/out message    Returns the value for a setting
     MSP2_COMMON_SET_SETTING = 0x1004, //in message        Sets the value for a setting
@@ -178,6 +192,16 @@ pub enum MspCommandCode {
     MSP2_SERIAL_CONFIG = 0x1009,
     MSP2_SET_SERIAL_CONFIG = 0x100A,
 
+    // external sensor data, no reply
+    MSP2_SENSOR_RANGEFINDER = 0x1F01,
+    MSP2_SENSOR_OPTIC_FLOW = 0x1F02,
+    MSP2_SENSOR_GPS = 0x1F03,
+    MSP2_SENSOR_COMPASS = 0x1F04,
+    MSP2_SENSOR_BAROMETER = 0x1F05,
+    MSP2_SENSOR_AIRSPEED = 0x1F06,
+
+    MSP2_INAV_STATUS = 0x2000,
+
     MSP2_INAV_OSD_LAYOUTS = 0x2012,
     MSP2_INAV_OSD_SET_LAYOUT_ITEM = 0x2013,
     MSP2_INAV_OSD_ALARMS = 0x2014,
@@ -189,9 +213,16 @@ pub enum MspCommandCode {
     MSP2_INAV_SET_SERVO_MIXER = 0x2021,
 }
 
+/// Former names of renamed commands
+impl MspCommandCode {
+    #[deprecated(note = "182 is MSP_DISPLAYPORT")]
+    pub const MSP_OSD_VIDEO_STATUS: MspCommandCode = MspCommandCode::MSP_DISPLAYPORT;
+    #[deprecated(note = "183 is MSP_COPY_PROFILE")]
+    pub const MSP_OSD_ELEMENT_SUMMARY: MspCommandCode = MspCommandCode::MSP_COPY_PROFILE;
+}
+
 impl From<u16> for MspCommandCode {
     fn from(value: u16) -> Self {
         Self::from_primitive(value).unwrap_or_else(|| panic!("Invalid MSP command code: {}", value))
     }
 }
-
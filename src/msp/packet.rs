@@ -1,6 +1,6 @@
 use std::fmt::Debug;
 use crc_any::CRCu8;
-use packed_struct::PackedStruct;
+use packed_struct::{PackedStruct, PackedStructSlice};
 use smallvec::{SmallVec, smallvec};
 
 use crate::msp::{
@@ -132,6 +132,15 @@ impl MspPacket {
 
         T::unpack(byte_array)
     }
+
+    /// Like [`decode_as`](Self::decode_as), but zero fills trailing fields that older firmware
+    /// leaves off the payload
+    pub fn decode_as_padded<T: PackedStruct>(&self) -> Result<T, packed_struct::PackingError> {
+        let mut buf = vec![0u8; size_of::<T::ByteArray>()];
+        let n = buf.len().min(self.data.0.len());
+        buf[..n].copy_from_slice(&self.data.0[..n]);
+        T::unpack_from_slice(&buf)
+    }
 }
 
 #[cfg(test)]
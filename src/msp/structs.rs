@@ -89,6 +89,7 @@ pub struct MspStatusEx {
 #[packed_struct(endian = "lsb")]
 pub struct MspBfConfig {
     pub mixer_configuration: u8,
+    /// See `crate::features::Features`
     pub features: u32,
     pub serial_rx_provider: u8,
     pub board_align_roll: i16,
@@ -138,11 +139,21 @@ pub struct MspDataFlashRead {
     pub read_length: u16,
 }
 
-#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone)]
+/// Angle trim [decidegrees]
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(endian = "lsb")]
 pub struct MspAccTrim {
-    pub pitch: u16,
-    pub roll: u16,
+    pub pitch: i16,
+    pub roll: i16,
+}
+
+/// Board orientation relative to the frame [deg], [0.1 deg] on iNav
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[packed_struct(endian = "lsb")]
+pub struct MspBoardAlignment {
+    pub roll: i16,
+    pub pitch: i16,
+    pub yaw: i16,
 }
 
 #[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone)]
@@ -233,6 +244,46 @@ pub struct MspCurrentMeter {
     pub amperage: u16,
 }
 
+/// One ADC voltage meter of `MSP_VOLTAGE_METER_CONFIG`
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspVoltageMeterConfig {
+    pub id: u8,
+    pub sensor_type: u8,
+    pub vbat_scale: u8,
+    pub vbat_res_div_val: u8,
+    pub vbat_res_div_multiplier: u8,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSetVoltageMeterConfig {
+    pub id: u8,
+    pub vbat_scale: u8,
+    pub vbat_res_div_val: u8,
+    pub vbat_res_div_multiplier: u8,
+}
+
+/// One current meter of `MSP_AMPERAGE_METER_CONFIG`
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspAmperageMeterConfig {
+    pub id: u8,
+    pub sensor_type: u8,
+    /// [0.1 mV/A]
+    pub scale: i16,
+    /// [mA]
+    pub offset: i16,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSetAmperageMeterConfig {
+    pub id: u8,
+    pub scale: i16,
+    pub offset: i16,
+}
+
 #[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone)]
 #[packed_struct(endian = "lsb")]
 pub struct MspBatteryState {
@@ -245,12 +296,17 @@ pub struct MspBatteryState {
     /// 0.01A
     pub amperage: i16,
 
+    /// Battery state, see `crate::battery::BatteryStatus`
     pub alerts: u8,
 }
 
 impl MspBatteryState {
-    pub fn cell_voltage(&self) -> f32 {
-        self.battery_voltage as f32 / (10 * self.battery_cell_count) as f32
+    /// Average cell voltage [V], `None` while the cell count is not known
+    pub fn cell_voltage(&self) -> Option<f32> {
+        match self.battery_cell_count {
+            0 => None,
+            cells => Some(self.battery_voltage as f32 / 10.0 / cells as f32),
+        }
     }
 }
 
@@ -331,8 +387,10 @@ pub struct MspRcMappedChannel {
 }
 
 #[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, Default)]
+#[packed_struct(endian = "lsb")]
 pub struct MspFeatures {
-    pub features: [bool; 32],
+    /// See `crate::features::Features`
+    pub features: u32,
 }
 
 #[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, Default)]
@@ -387,6 +445,8 @@ pub struct MspAdvancedConfig {
     pub motor_pwm_inversion: u8,
 }
 
+/// The 17 byte head of `MSP_FILTER_CONFIG` every version sends, see
+/// `crate::filter::FilterConfig` for the fields newer firmware appends
 #[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, Default)]
 #[packed_struct(endian = "lsb")]
 pub struct MspFilterConfig {
@@ -434,7 +494,7 @@ pub struct MspServos {
     pub servos: [u16; 8],
 }
 
-#[derive(PackedStruct, Debug, Copy, Clone)]
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bytes = "14", endian = "lsb", bit_numbering = "msb0")]
 pub struct MspServoConfig {
     pub min: u16,
@@ -447,7 +507,7 @@ pub struct MspServoConfig {
     pub reverse_input: u32, // Depracted, Input reversing is not required since it can be done on mixer level
 }
 
-#[derive(PackedStruct, Debug, Copy, Clone)]
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bytes = "1", endian = "lsb", bit_numbering = "msb0")]
 pub struct MspSetServoConfig {
     pub index: u8,
@@ -455,6 +515,27 @@ pub struct MspSetServoConfig {
     pub servo_config: MspServoConfig,
 }
 
+/// Betaflight's 12 byte `MSP_SERVO_CONFIGURATIONS` entry, [`MspServoConfig`] is the 14 byte
+/// one of iNav
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspBfServoConfig {
+    pub min: u16,
+    pub max: u16,
+    pub middle: u16,
+    pub rate: i8,
+    pub forward_from_channel: u8,
+    pub reversed_sources: u32,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSetBfServoConfig {
+    pub index: u8,
+    #[packed_field(size_bytes = "12")]
+    pub servo_config: MspBfServoConfig,
+}
+
 #[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone)]
 #[packed_struct(endian = "lsb")]
 pub struct MspMixerConfig {
@@ -495,7 +576,8 @@ pub enum MixerMode {
     OctoX8 = 11,
 }
 
-#[derive(PackedStruct, Debug, Copy, Clone)]
+/// Weights are sent offset by 2 and scaled by 1000, so 0 is -2.0 and 4000 is +2.0
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bytes = "8", endian = "lsb", bit_numbering = "msb0")]
 pub struct MspMotorMixer {
     pub throttle: u16,
@@ -536,8 +618,8 @@ pub struct MspSetGetOsdConfig {
 #[derive(PackedStruct, Debug, Copy, Clone)]
 #[packed_struct(bytes = "2", endian = "lsb", bit_numbering = "msb0")]
 pub struct MspOsdItemPosition {
-    pub col: u8,
-    pub row: u8,
+    /// Packed column, row and visibility bits, see `crate::osd::OsdPosition`
+    pub position: u16,
 }
 
 #[derive(PackedStruct, Debug, Copy, Clone)]
@@ -587,30 +669,6 @@ pub enum SerialIdentifier {
     SoftSerial2 = 31,
 }
 
-impl TryFrom<u8> for SerialIdentifier {
-    type Error = &'static str;
-
-    fn try_from(value: u8) -> Result<Self, Self::Error> {
-        let serial = match value {
-            255 => SerialIdentifier::None,
-            0 => SerialIdentifier::USART1,
-            1 => SerialIdentifier::USART2,
-            2 => SerialIdentifier::USART3,
-            3 => SerialIdentifier::USART4,
-            4 => SerialIdentifier::USART5,
-            5 => SerialIdentifier::USART6,
-            6 => SerialIdentifier::USART7,
-            7 => SerialIdentifier::USART8,
-            20 => SerialIdentifier::UsbVcp,
-            30 => SerialIdentifier::SoftSerial1,
-            31 => SerialIdentifier::SoftSerial2,
-            _ => return Err("Serial identifier not found"),
-        };
-
-        Ok(serial)
-    }
-}
-
 #[derive(PrimitiveEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum Baudrate {
     BaudAuto = 0,
@@ -686,11 +744,49 @@ impl From<Baudrate> for String {
     }
 }
 
-#[derive(PackedStruct, Debug, Copy, Clone)]
+/// HSV color of `MSP_LED_COLORS`
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[packed_struct(endian = "lsb")]
+pub struct MspLedColor {
+    /// 0-359
+    pub h: u16,
+    pub s: u8,
+    pub v: u8,
+}
+
+/// One LED of `MSP_LED_STRIP_CONFIG`
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
+#[packed_struct(endian = "lsb")]
+pub struct MspLedConfig {
+    /// Packed position, function, overlays, color and directions, see `crate::led::LedConfig`
+    pub config: u32,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(bytes = "1", endian = "lsb", bit_numbering = "msb0")]
+pub struct MspSetLedStripConfig {
+    pub index: u8,
+    #[packed_field(size_bytes = "4")]
+    pub led: MspLedConfig,
+}
+
+/// Color of a flight mode and direction, or of a special state, in `MSP_LED_STRIP_MODECOLOR`
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspLedModeColor {
+    pub mode: u8,
+    /// Direction, special color or aux channel slot
+    pub function: u8,
+    pub color: u8,
+}
+
+/// One port of `MSP2_SERIAL_CONFIG`
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(endian = "lsb", bit_numbering = "msb0")]
 pub struct MspSerialSetting {
     #[packed_field(size_bits = "8", ty = "enum")]
     pub identifier: SerialIdentifier,
+    /// See `crate::serial::SerialFunction`
     pub function_mask: u32,
     #[packed_field(size_bits = "8", ty = "enum")]
     pub msp_baudrate_index: Baudrate,
@@ -730,7 +826,7 @@ pub struct MspSetServoMixer {
     pub servo_rule: MspServoMixer,
 }
 
-#[derive(PackedStruct, Debug, Copy, Clone)]
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 #[packed_struct(bytes = "6", endian = "lsb", bit_numbering = "msb0")]
 pub struct MspServoMixer {
     pub target_channel: u8,
@@ -740,10 +836,14 @@ pub struct MspServoMixer {
     pub condition_id: i8,
 }
 
-#[derive(PackedStruct, Debug, Copy, Clone)]
+/// Receiver channel feeding each of roll, pitch, yaw, throttle and the first aux channels.
+/// Betaflight maps 8 inputs, iNav only the first 4, so decode it with
+/// [`MspPacket::decode_as_padded`](crate::msp::packet::MspPacket::decode_as_padded) and keep
+/// as many entries as the payload has.
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Default)]
 #[packed_struct(endian = "lsb", bit_numbering = "msb0")]
 pub struct MspRxMap {
-    pub map: [u8; 4], // MAX_MAPPABLE_RX_INPUTS
+    pub map: [u8; 8], // RX_MAPPABLE_CHANNEL_COUNT
 }
 
 #[derive(PackedStruct, Debug, Copy, Clone)]
@@ -770,13 +870,12 @@ pub enum SettingMode {
 #[derive(PrimitiveEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
 pub enum SettingType {
     VarUint8 = 0,
-    VarInt8,
-    VarUint16,
-    VarInt16,
-    VarUint32,
-    VarInt32,
-    VarFloat,
-    VarString,
+    VarInt8 = 1,
+    VarUint16 = 2,
+    VarInt16 = 3,
+    VarUint32 = 4,
+    VarFloat = 5,
+    VarString = 6,
 }
 
 #[derive(PackedStruct, Debug, Copy, Clone)]
@@ -806,7 +905,7 @@ pub struct MspSettingInfo {
     pub profile_id: u8,
     pub profile_count: u8,
     // if setting uses enum values, it will be written here
-    // pub enum_names: [String; ?] // TODO: packed_struct should support null terminated string parsing
+    // pub enum_names: [String; ?] // decoded by crate::settings::SettingDescriptor
     // pub value: [u8; ?]
 }
 
@@ -853,6 +952,331 @@ impl MspRc {
     }
 }
 
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspRawGps {
+    /// 0 no fix, 1 2D, 2 3D
+    pub fix_type: u8,
+    pub num_sat: u8,
+    /// [1e-7 deg]
+    pub lat: i32,
+    /// [1e-7 deg]
+    pub lon: i32,
+    /// [m]
+    pub alt: u16,
+    /// [cm/s]
+    pub ground_speed: u16,
+    /// [0.1 deg]
+    pub ground_course: u16,
+    /// [0.01], not sent before Betaflight MSP API 1.44
+    pub hdop: u16,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspCompGps {
+    /// [m]
+    pub distance_to_home: u16,
+    /// [deg]
+    pub direction_to_home: u16,
+    /// Toggles on every GPS update
+    pub update: u8,
+}
+
+/// One channel of `MSP_GPS_SV_INFO`, which is a u8 channel count followed by these
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspGpsSvInfo {
+    pub channel: u8,
+    pub sv_id: u8,
+    /// Receiver specific tracking flags
+    pub quality: u8,
+    /// Carrier to noise ratio [dB-Hz]
+    pub cno: u8,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSetRawGps {
+    /// 0 no fix, 1 2D, 2 3D
+    pub fix_type: u8,
+    pub num_sat: u8,
+    /// [1e-7 deg]
+    pub lat: i32,
+    /// [1e-7 deg]
+    pub lon: i32,
+    /// [m]
+    pub alt: u16,
+    /// [cm/s]
+    pub ground_speed: u16,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSensorRangefinder {
+    /// 0 - 255
+    pub quality: u8,
+    /// [mm], negative when out of range
+    pub distance: i32,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSensorOpticalFlow {
+    /// 0 - 255
+    pub quality: u8,
+    pub motion_x: i32,
+    pub motion_y: i32,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSensorGps {
+    pub instance: u8,
+    /// 0xFFFF when unknown
+    pub gps_week: u16,
+    /// Time of week [ms]
+    pub ms_tow: u32,
+    /// 0 no fix, 1 2D, 2 3D
+    pub fix_type: u8,
+    pub satellites_in_view: u8,
+    /// [cm]
+    pub horizontal_pos_accuracy: u16,
+    /// [cm]
+    pub vertical_pos_accuracy: u16,
+    /// [cm/s]
+    pub horizontal_vel_accuracy: u16,
+    /// [0.01]
+    pub hdop: u16,
+    /// [1e-7 deg]
+    pub longitude: i32,
+    /// [1e-7 deg]
+    pub latitude: i32,
+    /// [cm]
+    pub msl_altitude: i32,
+    /// [cm/s]
+    pub ned_vel_north: i32,
+    pub ned_vel_east: i32,
+    pub ned_vel_down: i32,
+    /// [0.01 deg]
+    pub ground_course: u16,
+    /// [0.01 deg], 0xFFFF when unknown
+    pub true_yaw: u16,
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub min: u8,
+    pub sec: u8,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSensorCompass {
+    pub instance: u8,
+    pub time_ms: u32,
+    /// [mGauss] front, right, down
+    pub mag_x: i16,
+    pub mag_y: i16,
+    pub mag_z: i16,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSensorBarometer {
+    pub instance: u8,
+    pub time_ms: u32,
+    /// f32 bits [Pa]
+    pub pressure: u32,
+    /// [0.01 degC]
+    pub temperature: i16,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSensorAirspeed {
+    pub instance: u8,
+    pub time_ms: u32,
+    /// f32 bits [Pa]
+    pub diff_pressure: u32,
+    /// [0.01 degC]
+    pub temperature: i16,
+}
+
+/// Reply of `MSP_SENSOR_STATUS`, each sensor is 0 not configured, 1 ok, 2 unavailable or
+/// 3 unhealthy
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSensorStatus {
+    pub hardware_healthy: u8,
+    pub gyro: u8,
+    pub acc: u8,
+    pub compass: u8,
+    pub baro: u8,
+    pub gps: u8,
+    pub rangefinder: u8,
+    pub pitot: u8,
+    pub optical_flow: u8,
+}
+
+/// A mission waypoint of `MSP_WP` and `MSP_SET_WP`
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspWaypoint {
+    /// 0 is home, the mission starts at 1
+    pub wp_no: u8,
+    pub action: u8,
+    /// [1e-7 deg]
+    pub lat: i32,
+    /// [1e-7 deg]
+    pub lon: i32,
+    /// [cm]
+    pub alt: i32,
+    pub p1: i16,
+    pub p2: i16,
+    pub p3: u16,
+    /// 0xA5 on the last waypoint, 0x48 on home
+    pub flag: u8,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspWaypointInfo {
+    pub capabilities: u8,
+    pub max_waypoints: u8,
+    pub mission_valid: u8,
+    pub waypoint_count: u8,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspVtxConfig {
+    /// 0 unsupported, 1 RTC6705, 3 SmartAudio, 4 Tramp, 5 MSP, 255 unknown
+    pub vtx_type: u8,
+    /// 1 based, 0 when set by frequency
+    pub band: u8,
+    /// 1 based
+    pub channel: u8,
+    /// 1 based power level of the VTX table
+    pub power: u8,
+    pub pit_mode: u8,
+    /// [MHz]
+    pub frequency: u16,
+    pub device_ready: u8,
+    pub low_power_disarm: u8,
+    /// [MHz]
+    pub pit_mode_frequency: u16,
+    pub vtx_table_available: u8,
+    pub bands: u8,
+    pub channels: u8,
+    pub power_levels: u8,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSetVtxConfig {
+    /// Frequency in MHz, or `(band - 1) * 8 + channel - 1` when below 64
+    pub frequency_or_band_channel: u16,
+    pub power: u8,
+    pub pit_mode: u8,
+    pub low_power_disarm: u8,
+    pub pit_mode_frequency: u16,
+    /// 1 based, 0 to use `frequency`
+    pub band: u8,
+    pub channel: u8,
+    pub frequency: u16,
+}
+
+/// Reply of `MSP_VTXTABLE_BAND`, length prefixed name and frequencies
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MspVtxTableBand {
+    /// 1 based
+    pub band: u8,
+    pub name: String,
+    pub letter: u8,
+    pub is_factory_band: bool,
+    /// [MHz] per channel, 0 for a disabled channel
+    pub frequencies: Vec<u16>,
+}
+
+/// Reply of `MSP_VTXTABLE_POWERLEVEL`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MspVtxTablePowerLevel {
+    /// 1 based
+    pub level: u8,
+    /// Value sent to the VTX, its meaning depends on the VTX protocol
+    pub value: u16,
+    pub label: String,
+}
+
+#[derive(PrimitiveEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum FailsafeSwitchMode {
+    /// Same as losing the link, with the stage 1 guard time
+    Stage1 = 0,
+    /// Disarm at once
+    Kill = 1,
+    /// Skip straight to the failsafe procedure
+    Stage2 = 2,
+}
+
+#[derive(PrimitiveEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum FailsafeProcedure {
+    /// Hold `failsafe_throttle` for `failsafe_off_delay`, then disarm
+    Land = 0,
+    /// Disarm at once
+    Drop = 1,
+    /// GPS rescue on Betaflight, RTH on iNav
+    Rescue = 2,
+    /// iNav only, keep flying on the failsafe channel values
+    None = 3,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspFailsafeConfig {
+    /// Guard time before stage 2 [0.1 s]
+    pub failsafe_delay: u8,
+    /// Time to land before disarming [0.1 s]
+    pub failsafe_off_delay: u8,
+    /// [us]
+    pub failsafe_throttle: u16,
+    #[packed_field(size_bits = "8", ty = "enum")]
+    pub failsafe_switch_mode: FailsafeSwitchMode,
+    /// Disarm straight away if throttle was low this long before the failsafe [0.1 s]
+    pub failsafe_throttle_low_delay: u16,
+    #[packed_field(size_bits = "8", ty = "enum")]
+    pub failsafe_procedure: FailsafeProcedure,
+}
+
+#[derive(PrimitiveEnum, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum RxFailMode {
+    /// Center sticks, low throttle; roll, pitch, yaw and throttle only
+    Auto = 0,
+    /// Keep the last good value
+    Hold = 1,
+    /// Use the configured value; aux channels only
+    Set = 2,
+    Invalid = 3,
+}
+
+/// One channel of `MSP_RXFAIL_CONFIG`, repeated for every channel
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspRxFailConfig {
+    #[packed_field(size_bits = "8", ty = "enum")]
+    pub mode: RxFailMode,
+    /// [us]
+    pub value: u16,
+}
+
+#[derive(PackedStruct, Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+#[packed_struct(endian = "lsb")]
+pub struct MspSetRxFailConfig {
+    pub index: u8,
+    #[packed_field(size_bytes = "3")]
+    pub config: MspRxFailConfig,
+}
+
 #[test]
 fn test_mixer() {
     use packed_struct::prelude::*;
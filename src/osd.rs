@@ -0,0 +1,532 @@
+//! OSD element layout, alarms and preferences
+
+use std::borrow::Cow;
+use std::fmt;
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspOsdItemPosition, MspOsdLayouts, MspSetOsdLayout, MspSetOsdLayoutItem},
+};
+
+/// `osd_items_e` of Betaflight 4.5
+const BETAFLIGHT_ELEMENTS: &[&str] = &[
+    "RSSI_VALUE", "MAIN_BATT_VOLTAGE", "CROSSHAIRS", "ARTIFICIAL_HORIZON", "HORIZON_SIDEBARS",
+    "ITEM_TIMER_1", "ITEM_TIMER_2", "FLYMODE", "CRAFT_NAME", "THROTTLE_POS", "VTX_CHANNEL",
+    "CURRENT_DRAW", "MAH_DRAWN", "GPS_SPEED", "GPS_SATS", "ALTITUDE", "ROLL_PIDS", "PITCH_PIDS",
+    "YAW_PIDS", "POWER", "PIDRATE_PROFILE", "WARNINGS", "AVG_CELL_VOLTAGE", "GPS_LON", "GPS_LAT",
+    "DEBUG", "PITCH_ANGLE", "ROLL_ANGLE", "MAIN_BATT_USAGE", "DISARMED", "HOME_DIR", "HOME_DIST",
+    "NUMERICAL_HEADING", "NUMERICAL_VARIO", "COMPASS_BAR", "ESC_TMP", "ESC_RPM",
+    "REMAINING_TIME_ESTIMATE", "RTC_DATETIME", "ADJUSTMENT_RANGE", "CORE_TEMPERATURE",
+    "ANTI_GRAVITY", "G_FORCE", "MOTOR_DIAG", "LOG_STATUS", "FLIP_ARROW", "LINK_QUALITY",
+    "FLIGHT_DIST", "STICK_OVERLAY_LEFT", "STICK_OVERLAY_RIGHT", "PILOT_NAME", "ESC_RPM_FREQ",
+    "RATE_PROFILE_NAME", "PID_PROFILE_NAME", "PROFILE_NAME", "RSSI_DBM_VALUE", "RC_CHANNELS",
+    "CAMERA_FRAME", "EFFICIENCY", "TOTAL_FLIGHTS", "UP_DOWN_REFERENCE", "TX_UPLINK_POWER",
+    "WATT_HOURS_DRAWN", "AUX_VALUE", "READY_MODE", "RSNR_VALUE", "SYS_GOGGLE_VOLTAGE",
+];
+
+/// Start of `osd_items_e` of iNav, later elements are named by index
+const INAV_ELEMENTS: &[&str] = &[
+    "RSSI_VALUE", "MAIN_BATT_VOLTAGE", "CROSSHAIRS", "ARTIFICIAL_HORIZON", "HORIZON_SIDEBARS",
+    "ONTIME", "FLYTIME", "FLYMODE", "CRAFT_NAME", "THROTTLE_POS", "VTX_CHANNEL", "CURRENT_DRAW",
+    "MAH_DRAWN", "GPS_SPEED", "GPS_SATS", "ALTITUDE", "ROLL_PIDS", "PITCH_PIDS", "YAW_PIDS",
+    "POWER", "GPS_LON", "GPS_LAT", "HOME_DIR", "HOME_DIST", "HEADING", "VARIO", "VARIO_NUM",
+    "AIR_SPEED", "ONTIME_FLYTIME", "RTC_TIME", "MESSAGES", "GPS_HDOP", "MAIN_BATT_CELL_VOLTAGE",
+    "SCALED_THROTTLE_POS", "HEADING_GRAPH", "EFFICIENCY_MAH_PER_KM", "WH_DRAWN",
+    "BATTERY_REMAINING_CAPACITY", "BATTERY_REMAINING_PERCENT", "EFFICIENCY_WH_PER_KM",
+    "TRIP_DIST", "ATTITUDE_PITCH", "ATTITUDE_ROLL", "MAP_NORTH", "MAP_TAKEOFF", "RADAR",
+    "WIND_SPEED_HORIZONTAL", "WIND_SPEED_VERTICAL", "REMAINING_FLIGHT_TIME_BEFORE_RTH",
+    "REMAINING_DISTANCE_BEFORE_RTH", "HOME_HEADING_ERROR", "COURSE_HOLD_ERROR",
+    "COURSE_HOLD_ADJUSTMENT", "SAG_COMPENSATED_MAIN_BATT_VOLTAGE",
+    "MAIN_BATT_SAG_COMPENSATED_CELL_VOLTAGE", "POWER_SUPPLY_IMPEDANCE", "LEVEL_PIDS",
+    "POS_XY_PIDS", "POS_Z_PIDS", "VEL_XY_PIDS", "VEL_Z_PIDS", "HEADING_P", "BOARD_ALIGN_ROLL",
+    "BOARD_ALIGN_PITCH", "RC_EXPO", "RC_YAW_EXPO", "THROTTLE_EXPO", "PITCH_RATE", "ROLL_RATE",
+    "YAW_RATE", "MANUAL_RC_EXPO", "MANUAL_RC_YAW_EXPO", "MANUAL_PITCH_RATE", "MANUAL_ROLL_RATE",
+    "MANUAL_YAW_RATE", "NAV_FW_CRUISE_THR", "NAV_FW_PITCH2THR",
+    "FW_MIN_THROTTLE_DOWN_PITCH_ANGLE", "DEBUG", "FW_ALT_PID_OUTPUTS", "FW_POS_PID_OUTPUTS",
+    "MC_VEL_X_PID_OUTPUTS", "MC_VEL_Y_PID_OUTPUTS", "MC_VEL_Z_PID_OUTPUTS",
+    "MC_POS_XYZ_P_OUTPUTS", "3D_SPEED", "TEMPERATURE",
+];
+
+/// Name of OSD element `index`, `ITEM_<index>` past the known table
+pub fn element_name(variant: &FirmwareVariant, index: u8) -> Cow<'static, str> {
+    let table = match variant {
+        FirmwareVariant::Inav => INAV_ELEMENTS,
+        _ => BETAFLIGHT_ELEMENTS,
+    };
+    match table.get(index as usize) {
+        Some(name) => Cow::Borrowed(name),
+        None => Cow::Owned(format!("ITEM_{}", index)),
+    }
+}
+
+/// Character grid the OSD is drawn on
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OsdGrid {
+    pub cols: u8,
+    pub rows: u8,
+}
+
+impl OsdGrid {
+    pub const PAL: OsdGrid = OsdGrid { cols: 30, rows: 16 };
+    pub const NTSC: OsdGrid = OsdGrid { cols: 30, rows: 13 };
+    pub const HD: OsdGrid = OsdGrid { cols: 53, rows: 20 };
+
+    /// Grid of the `video_system` setting. Auto is treated as PAL, the larger of the two
+    /// analog grids.
+    pub fn for_video_system(variant: &FirmwareVariant, video_system: u8) -> OsdGrid {
+        match (variant, video_system) {
+            (_, 2) => OsdGrid::NTSC,
+            (FirmwareVariant::Inav, 3) => OsdGrid { cols: 50, rows: 18 },
+            (FirmwareVariant::Inav, 4) | (FirmwareVariant::Inav, 7) => OsdGrid { cols: 60, rows: 22 },
+            (FirmwareVariant::Inav, 5) | (FirmwareVariant::Inav, 8) => OsdGrid::HD,
+            (FirmwareVariant::Inav, _) => OsdGrid::PAL,
+            (_, 3) => OsdGrid::HD,
+            _ => OsdGrid::PAL,
+        }
+    }
+}
+
+/// Position word of an OSD element.
+///
+/// Betaflight packs x into bits 0-4 plus bit 10, y into bits 5-9 and one visibility bit per
+/// OSD profile from bit 11. iNav uses bits 0-5 for x, 6-11 for y and bit 13 for visibility.
+/// Bits the format does not know are kept in `extra`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OsdPosition {
+    pub x: u8,
+    pub y: u8,
+    /// Betaflight: bit n is OSD profile n + 1. iNav: bit 0 is visible.
+    pub visibility: u8,
+    pub extra: u16,
+}
+
+impl OsdPosition {
+    pub fn decode(variant: &FirmwareVariant, raw: u16) -> OsdPosition {
+        match variant {
+            FirmwareVariant::Inav => OsdPosition {
+                x: (raw & 0x3F) as u8,
+                y: ((raw >> 6) & 0x3F) as u8,
+                visibility: ((raw >> 13) & 0x1) as u8,
+                extra: raw & 0xD000,
+            },
+            _ => OsdPosition {
+                x: ((raw & 0x1F) | ((raw >> 5) & 0x20)) as u8,
+                y: ((raw >> 5) & 0x1F) as u8,
+                visibility: ((raw >> 11) & 0x7) as u8,
+                extra: raw & 0xC000,
+            },
+        }
+    }
+
+    pub fn encode(&self, variant: &FirmwareVariant) -> u16 {
+        let (x, y, visibility) = (self.x as u16, self.y as u16, self.visibility as u16);
+        match variant {
+            FirmwareVariant::Inav => (x & 0x3F) | (y & 0x3F) << 6 | (visibility & 0x1) << 13 | self.extra,
+            _ => (x & 0x1F) | (x & 0x20) << 5 | (y & 0x1F) << 5 | (visibility & 0x7) << 11 | self.extra,
+        }
+    }
+
+    pub fn visible(&self) -> bool {
+        self.visibility != 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsdElement {
+    pub index: u8,
+    pub name: Cow<'static, str>,
+    pub position: OsdPosition,
+}
+
+/// OSD alarm thresholds. Fields only iNav has are `None` on Betaflight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsdAlarms {
+    pub rssi: u8,
+    /// mAh
+    pub capacity: u16,
+    /// Meters
+    pub altitude: u16,
+    /// Minutes
+    pub flight_time: Option<u16>,
+    /// Meters
+    pub distance: Option<u16>,
+    /// Meters below home
+    pub neg_altitude: Option<u16>,
+    /// The rest of iNav's `MSP2_INAV_OSD_ALARMS`, written back untouched
+    tail: Vec<u8>,
+}
+
+/// OSD display preferences
+#[derive(Debug, Clone, PartialEq)]
+pub struct OsdPreferences {
+    /// 0 auto, 1 PAL, 2 NTSC, higher values are HD systems
+    pub video_system: u8,
+    /// 0 imperial, 1 metric, firmware specific values above
+    pub units: u8,
+    /// iNav's whole `MSP2_INAV_OSD_PREFERENCES` payload, written back with the fields above
+    /// patched in
+    raw: Vec<u8>,
+}
+
+/// Every OSD element of one layout with the alarms and preferences, as read from the flight
+/// controller. Edits are tracked so [`OsdLayout::write`] only sends what changed.
+#[derive(Debug, Clone)]
+pub struct OsdLayout {
+    variant: FirmwareVariant,
+    /// iNav layout index, always 0 on Betaflight
+    pub layout: u8,
+    /// Layouts the firmware has, 1 on Betaflight
+    pub layout_count: u8,
+    pub elements: Vec<OsdElement>,
+    pub alarms: OsdAlarms,
+    pub preferences: OsdPreferences,
+    loaded_positions: Vec<OsdPosition>,
+    loaded_alarms: OsdAlarms,
+    loaded_preferences: OsdPreferences,
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Result<u16> {
+    data.get(offset..offset + 2)
+        .map(|b| u16::from_le_bytes([b[0], b[1]]))
+        .ok_or_else(|| Error::msg(format!("OSD payload too short, {} bytes", data.len())))
+}
+
+impl OsdLayout {
+    /// Read `layout` from the flight controller. Betaflight only has layout 0.
+    pub fn load(port: &mut dyn SerialPort, firmware: &FirmwareInfo, layout: u8) -> Result<OsdLayout> {
+        match firmware.variant {
+            FirmwareVariant::Inav => Self::load_inav(port, layout),
+            FirmwareVariant::Betaflight if layout == 0 => Self::load_betaflight(port),
+            FirmwareVariant::Betaflight => Err(Error::msg("Betaflight has a single OSD layout")),
+            ref other => Err(Error::msg(format!("OSD layout of {} is not supported", other))),
+        }
+    }
+
+    fn load_betaflight(port: &mut dyn SerialPort) -> Result<OsdLayout> {
+        let variant = FirmwareVariant::Betaflight;
+        let reply = request(port, MspCommandCode::MSP_OSD_CONFIG as u16, &[])?;
+        let data = reply.data.as_slice();
+
+        // flags, video system, units, rssi alarm, capacity alarm, unused, item count, altitude alarm
+        if data.len() < 10 {
+            return Err(Error::msg("Flight controller was built without OSD support"));
+        }
+        let item_count = data[7];
+        let positions = (0..item_count as usize)
+            .map(|i| Ok(OsdPosition::decode(&variant, u16_at(data, 10 + 2 * i)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let alarms = OsdAlarms {
+            rssi: data[3],
+            capacity: u16_at(data, 4)?,
+            altitude: u16_at(data, 8)?,
+            flight_time: None,
+            distance: None,
+            neg_altitude: None,
+            tail: Vec::new(),
+        };
+        let preferences = OsdPreferences { video_system: data[1], units: data[2], raw: Vec::new() };
+        Ok(Self::new(variant, 0, 1, positions, alarms, preferences))
+    }
+
+    fn load_inav(port: &mut dyn SerialPort, layout: u8) -> Result<OsdLayout> {
+        let variant = FirmwareVariant::Inav;
+        let counts = request(port, MspCommandCode::MSP2_INAV_OSD_LAYOUTS as u16, &[])?
+            .decode_as::<MspOsdLayouts>()?;
+        if layout >= counts.layout_count {
+            return Err(Error::msg(format!("iNav has {} OSD layouts, no layout {}", counts.layout_count, layout)));
+        }
+
+        let reply = request(port, MspCommandCode::MSP2_INAV_OSD_LAYOUTS as u16, &[layout])?;
+        let positions = (0..counts.item_count as usize)
+            .map(|i| Ok(OsdPosition::decode(&variant, u16_at(reply.data.as_slice(), 2 * i)?)))
+            .collect::<Result<Vec<_>>>()?;
+
+        let reply = request(port, MspCommandCode::MSP2_INAV_OSD_ALARMS as u16, &[])?;
+        let data = reply.data.as_slice();
+        let alarms = OsdAlarms {
+            rssi: *data.first().ok_or_else(|| Error::msg("Empty OSD alarms"))?,
+            capacity: u16_at(data, 1)?,
+            flight_time: Some(u16_at(data, 3)?),
+            altitude: u16_at(data, 5)?,
+            distance: Some(u16_at(data, 7)?),
+            neg_altitude: Some(u16_at(data, 9)?),
+            tail: data[11..].to_vec(),
+        };
+
+        let reply = request(port, MspCommandCode::MSP2_INAV_OSD_PREFERENCES as u16, &[])?;
+        let raw = reply.data.as_slice().to_vec();
+        if raw.len() < 8 {
+            return Err(Error::msg(format!("OSD preferences too short, {} bytes", raw.len())));
+        }
+        let preferences = OsdPreferences { video_system: raw[0], units: raw[7], raw };
+
+        Ok(Self::new(variant, layout, counts.layout_count, positions, alarms, preferences))
+    }
+
+    fn new(
+        variant: FirmwareVariant,
+        layout: u8,
+        layout_count: u8,
+        positions: Vec<OsdPosition>,
+        alarms: OsdAlarms,
+        preferences: OsdPreferences,
+    ) -> OsdLayout {
+        let elements = positions
+            .iter()
+            .enumerate()
+            .map(|(i, position)| OsdElement {
+                index: i as u8,
+                name: element_name(&variant, i as u8),
+                position: *position,
+            })
+            .collect();
+
+        OsdLayout {
+            variant,
+            layout,
+            layout_count,
+            elements,
+            loaded_positions: positions,
+            loaded_alarms: alarms.clone(),
+            loaded_preferences: preferences.clone(),
+            alarms,
+            preferences,
+        }
+    }
+
+    pub fn element(&self, name: &str) -> Option<&OsdElement> {
+        self.elements.iter().find(|e| e.name.eq_ignore_ascii_case(name))
+    }
+
+    pub fn element_mut(&mut self, name: &str) -> Option<&mut OsdElement> {
+        self.elements.iter_mut().find(|e| e.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Grid of the configured video system
+    pub fn grid(&self) -> OsdGrid {
+        OsdGrid::for_video_system(&self.variant, self.preferences.video_system)
+    }
+
+    /// Check every visible element fits on `grid`
+    pub fn validate(&self, grid: OsdGrid) -> Result<()> {
+        let outside: Vec<String> = self
+            .elements
+            .iter()
+            .filter(|e| e.position.visible() && (e.position.x >= grid.cols || e.position.y >= grid.rows))
+            .map(|e| format!("{} at {},{}", e.name, e.position.x, e.position.y))
+            .collect();
+
+        if !outside.is_empty() {
+            let grid = format!("{}x{}", grid.cols, grid.rows);
+            return Err(Error::msg(format!("Elements outside the {} grid: {}", grid, outside.join(", "))));
+        }
+        Ok(())
+    }
+
+    /// Elements moved or shown/hidden since loading or the last write
+    pub fn changed(&self) -> impl Iterator<Item = &OsdElement> {
+        self.elements
+            .iter()
+            .zip(&self.loaded_positions)
+            .filter(|(e, loaded)| e.position != **loaded)
+            .map(|(e, _)| e)
+    }
+
+    /// Validate against the configured grid, then send the changed elements, alarms and
+    /// preferences and save to EEPROM. Returns the number of set messages sent.
+    pub fn write(&mut self, port: &mut dyn SerialPort) -> Result<usize> {
+        self.validate(self.grid())?;
+
+        let mut writes: Vec<(MspCommandCode, Vec<u8>)> = Vec::new();
+        for element in self.changed() {
+            let item = MspSetOsdLayout {
+                item_index: element.index,
+                item: MspOsdItemPosition { position: element.position.encode(&self.variant) },
+            };
+            writes.push(match self.variant {
+                FirmwareVariant::Inav => (
+                    MspCommandCode::MSP2_INAV_OSD_SET_LAYOUT_ITEM,
+                    MspSetOsdLayoutItem { layout_index: self.layout, item }.pack_to_vec()?,
+                ),
+                _ => (MspCommandCode::MSP_SET_OSD_CONFIG, item.pack_to_vec()?),
+            });
+        }
+
+        let alarms_changed = self.alarms != self.loaded_alarms;
+        let preferences_changed = self.preferences != self.loaded_preferences;
+        match self.variant {
+            FirmwareVariant::Inav => {
+                if alarms_changed {
+                    writes.push((MspCommandCode::MSP2_INAV_OSD_SET_ALARMS, self.alarms_payload()));
+                }
+                if preferences_changed {
+                    let mut raw = self.preferences.raw.clone();
+                    raw[0] = self.preferences.video_system;
+                    raw[7] = self.preferences.units;
+                    writes.push((MspCommandCode::MSP2_INAV_OSD_SET_PREFERENCES, raw));
+                }
+            }
+            _ if alarms_changed || preferences_changed => {
+                // address 0xFF selects the general settings rather than an element
+                let (a, p) = (&self.alarms, &self.preferences);
+                let mut payload = vec![0xFF, p.video_system, p.units, a.rssi];
+                payload.extend(a.capacity.to_le_bytes());
+                payload.extend([0, 0]);
+                payload.extend(a.altitude.to_le_bytes());
+                writes.push((MspCommandCode::MSP_SET_OSD_CONFIG, payload));
+            }
+            _ => {}
+        }
+
+        for (cmd, payload) in &writes {
+            request(port, *cmd as u16, payload)?;
+        }
+        if !writes.is_empty() {
+            request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        }
+
+        self.loaded_positions = self.elements.iter().map(|e| e.position).collect();
+        self.loaded_alarms = self.alarms.clone();
+        self.loaded_preferences = self.preferences.clone();
+        Ok(writes.len())
+    }
+
+    fn alarms_payload(&self) -> Vec<u8> {
+        let a = &self.alarms;
+        let mut payload = vec![a.rssi];
+        for value in [
+            a.capacity,
+            a.flight_time.unwrap_or(0),
+            a.altitude,
+            a.distance.unwrap_or(0),
+            a.neg_altitude.unwrap_or(0),
+        ] {
+            payload.extend(value.to_le_bytes());
+        }
+        payload.extend_from_slice(&a.tail);
+        payload
+    }
+}
+
+impl fmt::Display for OsdLayout {
+    /// The visible elements drawn on the configured grid, each as the first letter of its name
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let grid = self.grid();
+        let mut rows = vec![vec![b'.'; grid.cols as usize]; grid.rows as usize];
+        for e in self.elements.iter().filter(|e| e.position.visible()) {
+            if let Some(cell) = rows.get_mut(e.position.y as usize).and_then(|r| r.get_mut(e.position.x as usize)) {
+                *cell = e.name.as_bytes()[0];
+            }
+        }
+        for row in rows {
+            writeln!(f, "{}", String::from_utf8_lossy(&row))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::firmware::test_firmware;
+
+    #[test]
+    fn position_formats() {
+        let bf = FirmwareVariant::Betaflight;
+        // x 40 needs the high x bit at bit 10, visible in OSD profile 1
+        let raw = OsdPosition { x: 40, y: 17, visibility: 0b001, extra: 0 }.encode(&bf);
+        assert_eq!((40 & 0x1F) | (1 << 10) | (17 << 5) | (1 << 11), raw);
+        assert_eq!(OsdPosition { x: 40, y: 17, visibility: 1, extra: 0 }, OsdPosition::decode(&bf, raw));
+
+        let inav = FirmwareVariant::Inav;
+        let raw = 12 | (9 << 6) | 0x2000 | 0x4000;
+        let position = OsdPosition::decode(&inav, raw);
+        assert_eq!((12, 9, true, 0x4000), (position.x, position.y, position.visible(), position.extra));
+        assert_eq!(raw, position.encode(&inav));
+    }
+
+    #[test]
+    fn betaflight_writes_only_changes() {
+        let bf = FirmwareVariant::Betaflight;
+        let mut config = vec![1, 1, 1, 20, 0xE8, 0x03, 0, 3, 100, 0];
+        for (x, y, visibility) in [(1, 1, 1), (0, 0, 0), (10, 14, 1)] {
+            config.extend(OsdPosition { x, y, visibility, extra: 0 }.encode(&bf).to_le_bytes());
+        }
+        let mut fc = MockFc::new()
+            .reply(MSP_OSD_CONFIG as u16, &config)
+            .reply(MSP_SET_OSD_CONFIG as u16, &[])
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+
+        let mut layout = OsdLayout::load(&mut fc, &test_firmware(bf.clone(), 46), 0).unwrap();
+        assert_eq!(OsdGrid::PAL, layout.grid());
+        assert_eq!(1000, layout.alarms.capacity);
+        assert_eq!((10, 14), {
+            let p = layout.element("crosshairs").unwrap().position;
+            (p.x, p.y)
+        });
+        assert_eq!(0, layout.write(&mut fc).unwrap());
+
+        layout.element_mut("CROSSHAIRS").unwrap().position.y = 20;
+        assert!(layout.write(&mut fc).is_err());
+
+        layout.element_mut("CROSSHAIRS").unwrap().position.y = 7;
+        assert_eq!(1, layout.write(&mut fc).unwrap());
+        let set = fc.requests.iter().find(|p| p.cmd == MSP_SET_OSD_CONFIG as u16).unwrap();
+        let expected = OsdPosition { x: 10, y: 7, visibility: 1, extra: 0 }.encode(&bf).to_le_bytes();
+        assert_eq!(&[2, expected[0], expected[1]], set.data.as_slice());
+        assert_eq!(0, layout.changed().count());
+    }
+
+    #[test]
+    fn inav_layouts_and_alarms() {
+        let inav = FirmwareVariant::Inav;
+        let mut alarms = vec![20, 0xE8, 0x03, 10, 0, 100, 0, 0xF4, 0x01, 5, 0];
+        alarms.extend([7, 8]);
+        let mut fc = MockFc::new()
+            .reply(MSP2_INAV_OSD_ALARMS as u16, &alarms)
+            .reply(MSP2_INAV_OSD_PREFERENCES as u16, &[1, 0, 0, 0, 0, 0, 0, 1, 0])
+            .reply(MSP2_INAV_OSD_SET_LAYOUT_ITEM as u16, &[])
+            .reply(MSP2_INAV_OSD_SET_ALARMS as u16, &[])
+            .reply(MSP_EEPROM_WRITE as u16, &[])
+            .handler(|p| match p.cmd {
+                // four layouts of two items without a layout index, the items of one with it
+                c if c == MSP2_INAV_OSD_LAYOUTS as u16 => Some(match p.data.as_slice() {
+                    [] => vec![4, 2],
+                    _ => [(2 | 3 << 6 | 0x2000) as u16, 0].iter().flat_map(|v| v.to_le_bytes()).collect(),
+                }),
+                _ => None,
+            });
+
+        let error = OsdLayout::load(&mut fc, &test_firmware(inav.clone(), 5), 4).unwrap_err();
+        assert_eq!("iNav has 4 OSD layouts, no layout 4", error.to_string());
+        let bf = test_firmware(FirmwareVariant::Betaflight, 46);
+        assert!(OsdLayout::load(&mut fc, &bf, 1).is_err());
+
+        let mut layout = OsdLayout::load(&mut fc, &test_firmware(inav.clone(), 5), 1).unwrap();
+        assert_eq!((Some(10), Some(5)), (layout.alarms.flight_time, layout.alarms.neg_altitude));
+        assert_eq!(vec![7, 8], layout.alarms.tail);
+
+        layout.elements[0].position.x = 99;
+        let sent = fc.commands().len();
+        assert!(layout.write(&mut fc).unwrap_err().to_string().starts_with("Elements outside the"));
+        assert_eq!(sent, fc.commands().len());
+
+        layout.elements[0].position.x = 4;
+        layout.alarms.capacity = 1500;
+        assert_eq!(2, layout.write(&mut fc).unwrap());
+        let set = |cmd: MspCommandCode| fc.requests.iter().find(|p| p.cmd == cmd as u16).unwrap().data.as_slice();
+        let position = OsdPosition { x: 4, y: 3, visibility: 1, extra: 0 }.encode(&inav).to_le_bytes();
+        assert_eq!([1, 0, position[0], position[1]], set(MSP2_INAV_OSD_SET_LAYOUT_ITEM));
+        // unknown trailing alarm bytes go back as they came
+        assert_eq!(&[20, 0xDC, 0x05], &set(MSP2_INAV_OSD_SET_ALARMS)[..3]);
+        assert_eq!(&[7, 8], &set(MSP2_INAV_OSD_SET_ALARMS)[11..]);
+    }
+}
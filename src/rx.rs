@@ -0,0 +1,305 @@
+//! Receiver channels from `MSP_RC` with the RX config and channel map applied
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspRcDeadband, MspRxConfig, MspRxMap},
+};
+
+/// Movement from rest that counts as a stick being moved [us]
+const MOVED: u16 = 200;
+/// Largest offset of a centred stick from `midrc` before it needs trimming [us]
+const CENTER_TOLERANCE: u16 = 20;
+
+/// Name of a channel in `MSP_RC` order
+pub fn channel_name(index: usize) -> String {
+    match index {
+        0 => "Roll".to_owned(),
+        1 => "Pitch".to_owned(),
+        2 => "Yaw".to_owned(),
+        3 => "Throttle".to_owned(),
+        n => format!("Aux{}", n - 3),
+    }
+}
+
+/// Split an `MSP_RC` payload into channel values [us]
+pub fn decode_rc(data: &[u8]) -> Vec<u16> {
+    data.chunks_exact(2).map(|b| u16::from_le_bytes([b[0], b[1]])).collect()
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ChannelReading {
+    /// Position in `MSP_RC`, which the firmware has already reordered through the channel map
+    pub index: usize,
+    pub name: String,
+    /// 1 based receiver channel this one comes from
+    pub source: usize,
+    pub value: u16,
+    /// Within the deadband around `midrc`, for roll, pitch and yaw only
+    pub centered: Option<bool>,
+}
+
+/// Lowest and highest value seen on a channel
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ChannelRange {
+    pub min: u16,
+    pub max: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum EndpointIssue {
+    /// Outside `rx_min_usec`..=`rx_max_usec`, the firmware treats it as an invalid pulse
+    OutOfRange { value: u16 },
+    /// Never got below `mincheck`, so stick commands and arming may not work
+    LowEndShort { min: u16, mincheck: u16 },
+    /// Never got above `maxcheck`
+    HighEndShort { max: u16, maxcheck: u16 },
+    /// Resting stick away from `midrc`
+    OffCenter { center: u16, midrc: u16 },
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum StickDirection {
+    Low,
+    High,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum StickCheck {
+    Ok,
+    /// The expected channel moved the other way
+    Reversed,
+    /// A different channel moved
+    MisMapped { moved: usize, name: String },
+}
+
+/// Live receiver monitor. Every poll updates the stick ranges seen so far, which
+/// [`RxMonitor::endpoints`] checks against the RX config.
+pub struct RxMonitor<'a> {
+    port: &'a mut dyn SerialPort,
+    pub config: MspRxConfig,
+    /// Receiver channel of each mapped input, 0 based
+    pub map: Vec<u8>,
+    pub deadband: MspRcDeadband,
+    ranges: Vec<ChannelRange>,
+    rest: Vec<u16>,
+}
+
+impl<'a> RxMonitor<'a> {
+    pub fn load(port: &'a mut dyn SerialPort) -> Result<RxMonitor<'a>> {
+        let config = request(port, MspCommandCode::MSP_RX_CONFIG as u16, &[])?.decode_as_padded::<MspRxConfig>()?;
+        // as many entries as the firmware maps
+        let reply = request(port, MspCommandCode::MSP_RX_MAP as u16, &[])?;
+        let mut map = reply.decode_as_padded::<MspRxMap>()?.map.to_vec();
+        map.truncate(reply.data.as_slice().len());
+        let deadband =
+            request(port, MspCommandCode::MSP_RC_DEADBAND as u16, &[])?.decode_as_padded::<MspRcDeadband>()?;
+        Ok(RxMonitor { port, config, map, deadband, ranges: Vec::new(), rest: Vec::new() })
+    }
+
+    /// Receiver channel feeding `index`, 1 based
+    pub fn source(&self, index: usize) -> usize {
+        self.map.get(index).map_or(index, |m| *m as usize) + 1
+    }
+
+    pub fn read(&mut self) -> Result<Vec<u16>> {
+        let values = decode_rc(request(self.port, MspCommandCode::MSP_RC as u16, &[])?.data.as_slice());
+        self.ranges.resize(values.len(), ChannelRange { min: u16::MAX, max: 0 });
+        for (range, value) in self.ranges.iter_mut().zip(&values) {
+            range.min = range.min.min(*value);
+            range.max = range.max.max(*value);
+        }
+        Ok(values)
+    }
+
+    /// Read the channels, named and with their receiver channel
+    pub fn poll(&mut self) -> Result<Vec<ChannelReading>> {
+        let values = self.read()?;
+        Ok(values
+            .iter()
+            .enumerate()
+            .map(|(index, &value)| {
+                let deadband = match index {
+                    0 | 1 => Some(self.deadband.deadband),
+                    2 => Some(self.deadband.yaw_deadband),
+                    _ => None,
+                };
+                ChannelReading {
+                    index,
+                    name: channel_name(index),
+                    source: self.source(index),
+                    value,
+                    centered: deadband.map(|d| value.abs_diff(self.config.midrc) <= d as u16),
+                }
+            })
+            .collect())
+    }
+
+    /// Remember the current values as sticks at rest, centred with throttle low
+    pub fn capture_rest(&mut self) -> Result<()> {
+        self.rest = self.read()?;
+        Ok(())
+    }
+
+    pub fn ranges(&self) -> &[ChannelRange] {
+        &self.ranges
+    }
+
+    /// Forget the ranges seen so far
+    pub fn reset_ranges(&mut self) {
+        self.ranges.clear();
+    }
+
+    /// Problems with the ranges seen on roll, pitch, yaw and throttle after the sticks have been
+    /// moved to every corner, and with the resting sticks if captured
+    pub fn endpoints(&self) -> Vec<(usize, EndpointIssue)> {
+        let c = &self.config;
+        let mut issues = Vec::new();
+        for (index, range) in self.ranges.iter().enumerate().take(4) {
+            for value in [range.min, range.max] {
+                if value < c.rx_min_usec || value > c.rx_max_usec {
+                    issues.push((index, EndpointIssue::OutOfRange { value }));
+                }
+            }
+            if range.min >= c.mincheck {
+                issues.push((index, EndpointIssue::LowEndShort { min: range.min, mincheck: c.mincheck }));
+            }
+            if range.max <= c.maxcheck {
+                issues.push((index, EndpointIssue::HighEndShort { max: range.max, maxcheck: c.maxcheck }));
+            }
+        }
+        for (index, &center) in self.rest.iter().enumerate().take(3) {
+            if center.abs_diff(c.midrc) > CENTER_TOLERANCE {
+                issues.push((index, EndpointIssue::OffCenter { center, midrc: c.midrc }));
+            }
+        }
+        issues
+    }
+
+    /// Wait for the pilot to move the stick of channel `index` towards `direction` and check
+    /// the right channel moved the right way. Needs [`RxMonitor::capture_rest`] first.
+    pub fn check_stick(&mut self, index: usize, direction: StickDirection, timeout: Duration) -> Result<StickCheck> {
+        if self.rest.is_empty() {
+            return Err(Error::msg("Capture the sticks at rest first"));
+        }
+        let end = Instant::now() + timeout;
+        while Instant::now() < end {
+            let values = self.read()?;
+            let moved = values
+                .iter()
+                .zip(&self.rest)
+                .enumerate()
+                .map(|(i, (v, r))| (i, *v as i32 - *r as i32))
+                .filter(|(_, delta)| delta.unsigned_abs() >= MOVED as u32)
+                .max_by_key(|(_, delta)| delta.unsigned_abs());
+
+            if let Some((moved, delta)) = moved {
+                return Ok(match (moved == index, delta > 0, direction) {
+                    (false, _, _) => StickCheck::MisMapped { moved, name: channel_name(moved) },
+                    (true, true, StickDirection::High) | (true, false, StickDirection::Low) => StickCheck::Ok,
+                    (true, _, _) => StickCheck::Reversed,
+                });
+            }
+            sleep(Duration::from_millis(20));
+        }
+        Err(Error::msg(format!("{} did not move", channel_name(index))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use packed_struct::PackedStruct;
+    use std::collections::VecDeque;
+
+    fn rc(values: &[u16]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_le_bytes()).collect()
+    }
+
+    fn receiver(frames: Vec<Vec<u16>>) -> MockFc {
+        let config = MspRxConfig {
+            maxcheck: 1900,
+            midrc: 1500,
+            mincheck: 1050,
+            rx_min_usec: 885,
+            rx_max_usec: 2115,
+            ..Default::default()
+        };
+        let deadband = MspRcDeadband { deadband: 5, yaw_deadband: 10, ..Default::default() };
+        let mut frames: VecDeque<_> = frames.into();
+        MockFc::new()
+            .reply(MSP_RX_CONFIG as u16, &config.pack().unwrap())
+            // TAER receiver, so roll and pitch come from channels 2 and 3
+            .reply(MSP_RX_MAP as u16, &[1, 2, 3, 0, 4, 5, 6, 7])
+            .reply(MSP_RC_DEADBAND as u16, &deadband.pack().unwrap())
+            .handler(move |p| {
+                (p.cmd == MSP_RC as u16).then(|| rc(&frames.pop_front().unwrap_or(vec![1500, 1500, 1500, 1000, 1000])))
+            })
+    }
+
+    #[test]
+    fn named_channels_and_endpoints() {
+        let mut fc = receiver(vec![
+            vec![1503, 1530, 1490, 1000, 1000],
+            vec![1000, 990, 1010, 1000, 2000],
+            vec![2000, 2120, 2010, 2000, 2000],
+        ]);
+        let mut rx = RxMonitor::load(&mut fc).unwrap();
+        assert_eq!(8, rx.map.len());
+        rx.capture_rest().unwrap();
+
+        let readings = rx.poll().unwrap();
+        assert_eq!(("Roll", 2), (readings[0].name.as_str(), readings[0].source));
+        assert_eq!(("Throttle", 1), (readings[3].name.as_str(), readings[3].source));
+        assert_eq!("Aux1", readings[4].name);
+        assert_eq!(Some(false), readings[0].centered);
+        assert_eq!(None, readings[3].centered);
+
+        rx.read().unwrap();
+        assert_eq!(ChannelRange { min: 990, max: 2120 }, rx.ranges()[1]);
+        assert_eq!(
+            vec![
+                (1, EndpointIssue::OutOfRange { value: 2120 }),
+                (1, EndpointIssue::OffCenter { center: 1530, midrc: 1500 }),
+            ],
+            rx.endpoints()
+        );
+
+        // iNav maps only the sticks, aux channels pass straight through
+        let mut fc = receiver(Vec::new()).reply(MSP_RX_MAP as u16, &[1, 2, 3, 0]);
+        let rx = RxMonitor::load(&mut fc).unwrap();
+        assert_eq!(vec![1, 2, 3, 0], rx.map);
+        assert_eq!((2, 5), (rx.source(0), rx.source(4)));
+    }
+
+    #[test]
+    fn reversed_and_mismapped_sticks() {
+        let rest = vec![1500, 1500, 1500, 1000];
+        let mut fc = receiver(vec![
+            rest.clone(),
+            vec![1510, 1500, 1500, 1000],
+            vec![1900, 1500, 1500, 1000],
+            vec![1500, 1100, 1500, 1000],
+            vec![1500, 1500, 1500, 1700],
+        ]);
+        let mut rx = RxMonitor::load(&mut fc).unwrap();
+        let timeout = Duration::from_secs(1);
+        assert!(rx.check_stick(0, StickDirection::High, timeout).is_err());
+        rx.capture_rest().unwrap();
+
+        assert_eq!(StickCheck::Ok, rx.check_stick(0, StickDirection::High, timeout).unwrap());
+        assert_eq!(StickCheck::Reversed, rx.check_stick(1, StickDirection::High, timeout).unwrap());
+        assert_eq!(
+            StickCheck::MisMapped { moved: 3, name: "Throttle".into() },
+            rx.check_stick(2, StickDirection::High, timeout).unwrap()
+        );
+    }
+}
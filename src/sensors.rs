@@ -0,0 +1,508 @@
+//! Feeding external sensor data into the flight controller: motion capture, companion computer
+//! navigation or simulated sensors
+
+use std::thread::sleep;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use anyhow::Result;
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::gps::{GeoPosition, GpsFix};
+use crate::helpers::{request, send_request};
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{
+        MspSensorAirspeed, MspSensorBarometer, MspSensorCompass, MspSensorGps,
+        MspSensorOpticalFlow, MspSensorRangefinder, MspSensorStatus, MspSetRawGps,
+    },
+};
+
+/// Seconds from the Unix epoch to the GPS epoch, 1980-01-06
+const GPS_EPOCH_OFFSET: u64 = 315_964_800;
+/// GPS time runs ahead of UTC by the leap seconds since 1980
+const GPS_LEAP_SECONDS: u64 = 18;
+const SECONDS_PER_WEEK: u64 = 604_800;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GpsSample {
+    pub fix: GpsFix,
+    pub satellites: u8,
+    pub position: GeoPosition,
+    /// Above mean sea level [m]
+    pub altitude: f64,
+    /// North, east, down [m/s]
+    pub velocity: [f32; 3],
+    /// [m]
+    pub horizontal_accuracy: f32,
+    /// [m]
+    pub vertical_accuracy: f32,
+    /// [m/s]
+    pub speed_accuracy: f32,
+    pub hdop: f32,
+    /// Heading of the vehicle [deg], for dual antenna or motion capture setups
+    pub yaw: Option<f32>,
+    /// Time of the fix, `None` leaves GPS week and date unset
+    pub time: Option<SystemTime>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct RangefinderSample {
+    /// [m], `None` when out of range
+    pub distance: Option<f32>,
+    /// 0 - 255
+    pub quality: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct OpticalFlowSample {
+    /// Accumulated flow in sensor counts, as reported by MSP flow sensors
+    pub motion: [i32; 2],
+    /// 0 - 255
+    pub quality: u8,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct CompassSample {
+    /// Magnetic field [T], front, right, down
+    pub field: [f32; 3],
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BarometerSample {
+    /// [Pa]
+    pub pressure: f32,
+    /// [degC]
+    pub temperature: f32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct AirspeedSample {
+    /// Pitot differential pressure [Pa]
+    pub differential_pressure: f32,
+    /// [degC]
+    pub temperature: f32,
+}
+
+/// Where injected readings come from. Each method is called when its sensor is due and returns
+/// `None` when there is no new reading.
+pub trait SensorSource {
+    fn gps(&mut self) -> Option<GpsSample> {
+        None
+    }
+    fn rangefinder(&mut self) -> Option<RangefinderSample> {
+        None
+    }
+    fn optical_flow(&mut self) -> Option<OpticalFlowSample> {
+        None
+    }
+    fn compass(&mut self) -> Option<CompassSample> {
+        None
+    }
+    fn barometer(&mut self) -> Option<BarometerSample> {
+        None
+    }
+    fn airspeed(&mut self) -> Option<AirspeedSample> {
+        None
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum SensorKind {
+    Gps,
+    Rangefinder,
+    OpticalFlow,
+    Compass,
+    Barometer,
+    Airspeed,
+}
+
+impl SensorKind {
+    const ALL: [SensorKind; 6] = [
+        SensorKind::Gps,
+        SensorKind::Rangefinder,
+        SensorKind::OpticalFlow,
+        SensorKind::Compass,
+        SensorKind::Barometer,
+        SensorKind::Airspeed,
+    ];
+
+    /// Update rate the firmware drivers expect [Hz]
+    pub fn default_rate(self) -> f32 {
+        match self {
+            SensorKind::Gps => 10.0,
+            SensorKind::Compass => 20.0,
+            SensorKind::Rangefinder | SensorKind::OpticalFlow => 20.0,
+            SensorKind::Barometer | SensorKind::Airspeed => 25.0,
+        }
+    }
+}
+
+/// Health of one sensor in `MSP_SENSOR_STATUS`
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum SensorHealth {
+    NotConfigured,
+    Ok,
+    Unavailable,
+    Unhealthy,
+}
+
+impl SensorHealth {
+    pub fn from_u8(value: u8) -> SensorHealth {
+        match value {
+            0 => SensorHealth::NotConfigured,
+            1 => SensorHealth::Ok,
+            2 => SensorHealth::Unavailable,
+            _ => SensorHealth::Unhealthy,
+        }
+    }
+}
+
+impl MspSensorStatus {
+    /// Health of the flight controller's driver for an injected sensor
+    pub fn health(&self, kind: SensorKind) -> SensorHealth {
+        SensorHealth::from_u8(match kind {
+            SensorKind::Gps => self.gps,
+            SensorKind::Rangefinder => self.rangefinder,
+            SensorKind::OpticalFlow => self.optical_flow,
+            SensorKind::Compass => self.compass,
+            SensorKind::Barometer => self.baro,
+            SensorKind::Airspeed => self.pitot,
+        })
+    }
+}
+
+/// Civil UTC date and time of a Unix timestamp, (year, month, day, hour, min, sec)
+fn utc_date_time(unix: u64) -> (u16, u8, u8, u8, u8, u8) {
+    let (days, secs) = ((unix / 86_400) as i64, unix % 86_400);
+    // days to civil date, Howard Hinnant's algorithm
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + (month <= 2) as i64;
+
+    (year as u16, month as u8, day as u8, (secs / 3_600) as u8, (secs / 60 % 60) as u8, (secs % 60) as u8)
+}
+
+fn centi(value: f32) -> u16 {
+    (value * 100.0).round().clamp(0.0, u16::MAX as f32) as u16
+}
+
+fn fix_type(fix: GpsFix) -> u8 {
+    match fix {
+        GpsFix::None => 0,
+        GpsFix::Fix2D => 1,
+        GpsFix::Fix3D => 2,
+    }
+}
+
+impl GpsSample {
+    /// `MSP2_SENSOR_GPS` payload
+    pub fn to_sensor_gps(&self) -> MspSensorGps {
+        let (latitude, longitude) = self.position.to_e7();
+        let [north, east, down] = self.velocity;
+        let course = east.atan2(north).to_degrees().rem_euclid(360.0);
+
+        let mut msg = MspSensorGps {
+            instance: 0,
+            gps_week: 0xFFFF,
+            ms_tow: 0,
+            fix_type: fix_type(self.fix),
+            satellites_in_view: self.satellites,
+            horizontal_pos_accuracy: centi(self.horizontal_accuracy),
+            vertical_pos_accuracy: centi(self.vertical_accuracy),
+            horizontal_vel_accuracy: centi(self.speed_accuracy),
+            hdop: centi(self.hdop),
+            longitude,
+            latitude,
+            msl_altitude: (self.altitude * 100.0).round() as i32,
+            ned_vel_north: (north * 100.0).round() as i32,
+            ned_vel_east: (east * 100.0).round() as i32,
+            ned_vel_down: (down * 100.0).round() as i32,
+            ground_course: centi(course) % 36_000,
+            true_yaw: self.yaw.map(|y| centi(y.rem_euclid(360.0)) % 36_000).unwrap_or(0xFFFF),
+            year: 0,
+            month: 0,
+            day: 0,
+            hour: 0,
+            min: 0,
+            sec: 0,
+        };
+
+        if let Some(unix) = self.time.and_then(|t| t.duration_since(UNIX_EPOCH).ok()) {
+            let gps_ms = (unix.as_secs().saturating_sub(GPS_EPOCH_OFFSET) + GPS_LEAP_SECONDS) * 1000 + unix.subsec_millis() as u64;
+            msg.gps_week = (gps_ms / 1000 / SECONDS_PER_WEEK) as u16;
+            msg.ms_tow = (gps_ms % (SECONDS_PER_WEEK * 1000)) as u32;
+            (msg.year, msg.month, msg.day, msg.hour, msg.min, msg.sec) = utc_date_time(unix.as_secs());
+        }
+        msg
+    }
+
+    /// `MSP_SET_RAW_GPS` payload, for firmware without the MSP2 sensor messages
+    pub fn to_set_raw_gps(&self) -> MspSetRawGps {
+        let (lat, lon) = self.position.to_e7();
+        let [north, east, _] = self.velocity;
+        MspSetRawGps {
+            fix_type: fix_type(self.fix),
+            num_sat: self.satellites,
+            lat,
+            lon,
+            alt: self.altitude.round().clamp(0.0, u16::MAX as f64) as u16,
+            ground_speed: centi(north.hypot(east)),
+        }
+    }
+}
+
+/// Streams readings from a [`SensorSource`] to the flight controller, each sensor at its own
+/// rate
+pub struct SensorInjector<'a> {
+    port: &'a mut dyn SerialPort,
+    start: Instant,
+    /// (sensor, interval, last sent)
+    schedule: Vec<(SensorKind, Duration, Option<Instant>)>,
+    legacy_gps: bool,
+}
+
+impl<'a> SensorInjector<'a> {
+    pub fn new(port: &'a mut dyn SerialPort) -> SensorInjector<'a> {
+        let schedule = SensorKind::ALL
+            .iter()
+            .map(|k| (*k, Duration::from_secs_f32(1.0 / k.default_rate()), None))
+            .collect();
+        SensorInjector { port, start: Instant::now(), schedule, legacy_gps: false }
+    }
+
+    /// Update rate of one sensor [Hz], 0 stops it being sent
+    pub fn with_rate(mut self, kind: SensorKind, hz: f32) -> Self {
+        let interval = if hz > 0.0 {
+            Duration::from_secs_f32(1.0 / hz)
+        } else {
+            Duration::MAX
+        };
+        for entry in self.schedule.iter_mut().filter(|e| e.0 == kind) {
+            entry.1 = interval;
+        }
+        self
+    }
+
+    /// Send GPS as `MSP_SET_RAW_GPS` instead of `MSP2_SENSOR_GPS`
+    pub fn with_legacy_gps(mut self, legacy: bool) -> Self {
+        self.legacy_gps = legacy;
+        self
+    }
+
+    fn time_ms(&self) -> u32 {
+        self.start.elapsed().as_millis() as u32
+    }
+
+    /// Send every sensor that is due and has a reading. Returns how many messages were sent.
+    pub fn poll(&mut self, source: &mut dyn SensorSource) -> Result<usize> {
+        let now = Instant::now();
+        let mut sent = 0;
+
+        for i in 0..self.schedule.len() {
+            let (kind, interval, last) = self.schedule[i];
+            if interval == Duration::MAX || last.is_some_and(|l| now.duration_since(l) < interval) {
+                continue;
+            }
+            if self.send(kind, source)? {
+                self.schedule[i].2 = Some(now);
+                sent += 1;
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Keep polling for `duration`, sleeping until the next sensor is due
+    pub fn run_for(&mut self, source: &mut dyn SensorSource, duration: Duration) -> Result<()> {
+        let end = Instant::now() + duration;
+        while Instant::now() < end {
+            self.poll(source)?;
+            let next = self
+                .schedule
+                .iter()
+                .filter(|(_, interval, _)| *interval != Duration::MAX)
+                .map(|(_, interval, last)| last.map_or(Instant::now(), |l| l + *interval))
+                .min()
+                .unwrap_or(end);
+            sleep(next.min(end).saturating_duration_since(Instant::now()).max(Duration::from_millis(1)));
+        }
+        Ok(())
+    }
+
+    fn send(&mut self, kind: SensorKind, source: &mut dyn SensorSource) -> Result<bool> {
+        let time_ms = self.time_ms();
+        let (cmd, payload) = match kind {
+            SensorKind::Gps => match source.gps() {
+                Some(gps) if self.legacy_gps => {
+                    // the only one of these messages that is answered
+                    let payload = gps.to_set_raw_gps().pack_to_vec()?;
+                    request(self.port, MspCommandCode::MSP_SET_RAW_GPS as u16, &payload)?;
+                    return Ok(true);
+                }
+                Some(gps) => (MspCommandCode::MSP2_SENSOR_GPS, gps.to_sensor_gps().pack_to_vec()?),
+                None => return Ok(false),
+            },
+            SensorKind::Rangefinder => match source.rangefinder() {
+                Some(r) => {
+                    let distance = r.distance.map_or(-1, |d| (d * 1000.0).round() as i32);
+                    let msg = MspSensorRangefinder { quality: r.quality, distance };
+                    (MspCommandCode::MSP2_SENSOR_RANGEFINDER, msg.pack_to_vec()?)
+                }
+                None => return Ok(false),
+            },
+            SensorKind::OpticalFlow => match source.optical_flow() {
+                Some(f) => {
+                    let msg = MspSensorOpticalFlow { quality: f.quality, motion_x: f.motion[0], motion_y: f.motion[1] };
+                    (MspCommandCode::MSP2_SENSOR_OPTIC_FLOW, msg.pack_to_vec()?)
+                }
+                None => return Ok(false),
+            },
+            SensorKind::Compass => match source.compass() {
+                Some(c) => {
+                    // 1 mGauss is 1e-7 T
+                    let [x, y, z] = c.field.map(|t| (t * 1e7).round().clamp(i16::MIN as f32, i16::MAX as f32) as i16);
+                    let msg = MspSensorCompass { instance: 0, time_ms, mag_x: x, mag_y: y, mag_z: z };
+                    (MspCommandCode::MSP2_SENSOR_COMPASS, msg.pack_to_vec()?)
+                }
+                None => return Ok(false),
+            },
+            SensorKind::Barometer => match source.barometer() {
+                Some(b) => {
+                    let msg = MspSensorBarometer {
+                        instance: 0,
+                        time_ms,
+                        pressure: b.pressure.to_bits(),
+                        temperature: (b.temperature * 100.0).round() as i16,
+                    };
+                    (MspCommandCode::MSP2_SENSOR_BAROMETER, msg.pack_to_vec()?)
+                }
+                None => return Ok(false),
+            },
+            SensorKind::Airspeed => match source.airspeed() {
+                Some(a) => {
+                    let msg = MspSensorAirspeed {
+                        instance: 0,
+                        time_ms,
+                        diff_pressure: a.differential_pressure.to_bits(),
+                        temperature: (a.temperature * 100.0).round() as i16,
+                    };
+                    (MspCommandCode::MSP2_SENSOR_AIRSPEED, msg.pack_to_vec()?)
+                }
+                None => return Ok(false),
+            },
+        };
+
+        send_request(self.port, cmd as u16, &payload)?;
+        Ok(true)
+    }
+
+    /// Ask the flight controller how its sensor drivers are doing, to see whether injected
+    /// data is being accepted
+    pub fn status(&mut self) -> Result<MspSensorStatus> {
+        Ok(request(self.port, MspCommandCode::MSP_SENSOR_STATUS as u16, &[])?.decode_as::<MspSensorStatus>()?)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use packed_struct::PackedStruct;
+
+    fn sample() -> GpsSample {
+        GpsSample {
+            fix: GpsFix::Fix3D,
+            satellites: 12,
+            position: GeoPosition::new(47.3977419, 8.5455938),
+            altitude: 488.2,
+            velocity: [0.0, 2.5, -0.5],
+            horizontal_accuracy: 0.8,
+            vertical_accuracy: 1.2,
+            speed_accuracy: 0.1,
+            hdop: 0.9,
+            yaw: Some(-90.0),
+            // 2024-03-01 12:34:56.789 UTC
+            time: Some(UNIX_EPOCH + Duration::from_millis(1_709_296_496_789)),
+        }
+    }
+
+    #[test]
+    fn gps_si_conversion() {
+        let msg = sample().to_sensor_gps();
+        assert_eq!((473_977_419, 85_455_938), (msg.latitude, msg.longitude));
+        assert_eq!(48_820, msg.msl_altitude);
+        assert_eq!((0, 250, -50), (msg.ned_vel_north, msg.ned_vel_east, msg.ned_vel_down));
+        assert_eq!(9_000, msg.ground_course);
+        assert_eq!(27_000, msg.true_yaw);
+        assert_eq!((80, 120, 90), (msg.horizontal_pos_accuracy, msg.vertical_pos_accuracy, msg.hdop));
+        assert_eq!((2024, 3, 1, 12, 34, 56), (msg.year, msg.month, msg.day, msg.hour, msg.min, msg.sec));
+        // GPS week 2303 started on 2024-02-25
+        assert_eq!(2303, msg.gps_week);
+        assert_eq!(((5 * 86_400 + 12 * 3_600 + 34 * 60 + 56 + 18) * 1000 + 789) as u32, msg.ms_tow);
+        assert_eq!(52, msg.pack().unwrap().len());
+
+        let raw = sample().to_set_raw_gps();
+        assert_eq!((488, 250), (raw.alt, raw.ground_speed));
+    }
+
+    struct Source;
+
+    impl SensorSource for Source {
+        fn gps(&mut self) -> Option<GpsSample> {
+            Some(sample())
+        }
+        fn barometer(&mut self) -> Option<BarometerSample> {
+            Some(BarometerSample { pressure: 101_325.0, temperature: 21.5 })
+        }
+    }
+
+    #[test]
+    fn injector_rates_and_status() {
+        let mut fc = MockFc::new().reply(MSP_SENSOR_STATUS as u16, &[1, 1, 1, 0, 1, 1, 0, 0, 0]);
+        let mut injector = SensorInjector::new(&mut fc).with_rate(SensorKind::Barometer, 0.0);
+
+        assert_eq!(1, injector.poll(&mut Source).unwrap());
+        // nothing is due again straight away
+        assert_eq!(0, injector.poll(&mut Source).unwrap());
+
+        let status = injector.status().unwrap();
+        assert_eq!(SensorHealth::Ok, status.health(SensorKind::Gps));
+        assert_eq!(SensorHealth::NotConfigured, status.health(SensorKind::Rangefinder));
+
+        let gps: Vec<_> = fc.requests.iter().filter(|p| p.cmd == MSP2_SENSOR_GPS as u16).collect();
+        assert_eq!(1, gps.len());
+        let msg = MspSensorGps::unpack_from_slice(gps[0].data.as_slice()).unwrap();
+        assert_eq!(sample().to_sensor_gps(), msg);
+        assert!(!fc.commands().contains(&(MSP2_SENSOR_BAROMETER as u16)));
+    }
+
+    struct NoData;
+
+    impl SensorSource for NoData {}
+
+    #[test]
+    fn legacy_gps_is_answered() {
+        // MSP_SET_RAW_GPS gets a reply, so a firmware without it fails the poll
+        let mut fc = MockFc::new();
+        let mut injector = SensorInjector::new(&mut fc).with_legacy_gps(true);
+        let error = injector.poll(&mut Source).unwrap_err().to_string();
+        assert!(error.contains("not supported"), "{}", error);
+        // nothing is sent without readings
+        assert_eq!(0, injector.poll(&mut NoData).unwrap());
+        assert_eq!(vec![MSP_SET_RAW_GPS as u16], fc.commands());
+
+        let mut fc = MockFc::new().reply(MSP_SET_RAW_GPS as u16, &[]);
+        let mut injector = SensorInjector::new(&mut fc).with_legacy_gps(true).with_rate(SensorKind::Barometer, 0.0);
+        assert_eq!(1, injector.poll(&mut Source).unwrap());
+        let raw = MspSetRawGps::unpack_from_slice(fc.requests[0].data.as_slice()).unwrap();
+        assert_eq!(sample().to_set_raw_gps(), raw);
+        assert!(!fc.commands().contains(&(MSP2_SENSOR_GPS as u16)));
+    }
+}
@@ -0,0 +1,383 @@
+//! Serial port functions and baud rates from `MSP2_SERIAL_CONFIG`, or the older
+//! `MSP_CF_SERIAL_CONFIG` with 16 bit function masks
+
+use anyhow::{Error, Result};
+use packed_struct::{PackedStructSlice, PrimitiveEnum};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{Baudrate, MspSerialSetting, SerialIdentifier},
+};
+
+/// Betaflight `serialPortFunction_e` names, by bit
+pub const BETAFLIGHT_FUNCTIONS: [&str; 18] = [
+    "MSP", "GPS", "TELEMETRY_FRSKY_HUB", "TELEMETRY_HOTT", "TELEMETRY_LTM", "TELEMETRY_SMARTPORT",
+    "RX_SERIAL", "BLACKBOX", "", "TELEMETRY_MAVLINK", "ESC_SENSOR", "VTX_SMARTAUDIO", "TELEMETRY_IBUS",
+    "VTX_TRAMP", "RCDEVICE", "LIDAR_TF", "FRSKY_OSD", "VTX_MSP",
+];
+
+/// iNav `serialPortFunction_e` names, by bit
+pub const INAV_FUNCTIONS: [&str; 28] = [
+    "MSP", "GPS", "TELEMETRY_FRSKY", "TELEMETRY_HOTT", "TELEMETRY_LTM", "TELEMETRY_SMARTPORT",
+    "RX_SERIAL", "BLACKBOX", "TELEMETRY_MAVLINK", "TELEMETRY_IBUS", "RCDEVICE", "VTX_SMARTAUDIO",
+    "VTX_TRAMP", "UAV_INTERCONNECT", "OPTICAL_FLOW", "LOG", "RANGEFINDER", "VTX_FFPV", "ESCSERIAL",
+    "TELEMETRY_SIM", "FRSKY_OSD", "DJI_HD_OSD", "SERVO_SERIAL", "TELEMETRY_SMARTPORT_MASTER", "IMU2",
+    "MSP_DISPLAYPORT", "GIMBAL", "GIMBAL_HEADTRACKER",
+];
+
+fn function_names(variant: &FirmwareVariant) -> &'static [&'static str] {
+    match variant {
+        FirmwareVariant::Inav => &INAV_FUNCTIONS,
+        _ => &BETAFLIGHT_FUNCTIONS,
+    }
+}
+
+/// Functions assigned to a serial port, one bit each. The low 8 bits mean the same on
+/// Betaflight and iNav, the rest is looked up by name per firmware.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct SerialFunction(pub u32);
+
+impl SerialFunction {
+    pub const MSP: u32 = 1 << 0;
+    pub const GPS: u32 = 1 << 1;
+    pub const TELEMETRY_FRSKY_HUB: u32 = 1 << 2;
+    pub const TELEMETRY_HOTT: u32 = 1 << 3;
+    pub const TELEMETRY_LTM: u32 = 1 << 4;
+    pub const TELEMETRY_SMARTPORT: u32 = 1 << 5;
+    pub const RX_SERIAL: u32 = 1 << 6;
+    pub const BLACKBOX: u32 = 1 << 7;
+
+    pub fn contains(self, bits: u32) -> bool {
+        self.0 & bits == bits
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Bit of function `name` on `variant`, e.g. `ESC_SENSOR`
+    pub fn bit(variant: &FirmwareVariant, name: &str) -> Option<u32> {
+        function_names(variant)
+            .iter()
+            .position(|n| !n.is_empty() && n.eq_ignore_ascii_case(name))
+            .map(|bit| 1 << bit)
+    }
+
+    /// Functions by name, failing on names `variant` does not have
+    pub fn from_names(variant: &FirmwareVariant, names: &[&str]) -> Result<SerialFunction> {
+        names.iter().try_fold(SerialFunction(0), |functions, name| {
+            let bit = Self::bit(variant, name)
+                .ok_or_else(|| Error::msg(format!("{} has no serial function {}", variant, name)))?;
+            Ok(SerialFunction(functions.0 | bit))
+        })
+    }
+
+    /// Names of the set functions, `BIT<n>` for ones newer than the table of `variant`
+    pub fn names(self, variant: &FirmwareVariant) -> Vec<String> {
+        let table = function_names(variant);
+        (0..32)
+            .filter(|bit| self.0 & (1 << bit) != 0)
+            .map(|bit| match table.get(bit) {
+                Some(name) if !name.is_empty() => name.to_string(),
+                _ => format!("BIT{}", bit),
+            })
+            .collect()
+    }
+}
+
+impl MspSerialSetting {
+    pub fn functions(&self) -> SerialFunction {
+        SerialFunction(self.function_mask)
+    }
+
+    pub fn set_functions(&mut self, functions: SerialFunction) {
+        self.function_mask = functions.0;
+    }
+}
+
+/// Size of an `MSP_CF_SERIAL_CONFIG` entry, with a 16 bit function mask
+const CF_ENTRY: usize = 7;
+/// Size of an `MSP2_SERIAL_CONFIG` entry
+const ENTRY: usize = 9;
+
+fn decode_cf_entry(b: &[u8]) -> Result<MspSerialSetting> {
+    let baudrate = |v: u8| Baudrate::from_primitive(v).ok_or_else(|| Error::msg(format!("Unknown baud rate {}", v)));
+    Ok(MspSerialSetting {
+        identifier: SerialIdentifier::from_primitive(b[0])
+            .ok_or_else(|| Error::msg(format!("Unknown serial port {}", b[0])))?,
+        function_mask: u16::from_le_bytes([b[1], b[2]]) as u32,
+        msp_baudrate_index: baudrate(b[3])?,
+        gps_baudrate_index: baudrate(b[4])?,
+        telemetry_baudrate_index: baudrate(b[5])?,
+        peripheral_baudrate_index: baudrate(b[6])?,
+    })
+}
+
+/// Every serial port of the flight controller with its functions and baud rates
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SerialPortsConfig {
+    variant: FirmwareVariant,
+    /// Read through `MSP_CF_SERIAL_CONFIG`, so functions above bit 15 cannot be set
+    legacy: bool,
+    pub ports: Vec<MspSerialSetting>,
+}
+
+impl SerialPortsConfig {
+    /// Read the port list, through `MSP2_SERIAL_CONFIG` on iNav and Betaflight 4.3 and later
+    pub fn load(port: &mut dyn SerialPort, firmware: &FirmwareInfo) -> Result<SerialPortsConfig> {
+        let variant = firmware.variant.clone();
+        let legacy = variant != FirmwareVariant::Inav && !firmware.api_at_least(1, 44);
+        let ports = if legacy {
+            Self::decode_cf(request(port, MspCommandCode::MSP_CF_SERIAL_CONFIG as u16, &[])?.data.as_slice())?
+        } else {
+            Self::decode(request(port, MspCommandCode::MSP2_SERIAL_CONFIG as u16, &[])?.data.as_slice())?
+        };
+        Ok(SerialPortsConfig { variant, legacy, ports })
+    }
+
+    /// Decode an `MSP2_SERIAL_CONFIG` reply. Betaflight puts a port count in front, iNav
+    /// does not.
+    pub fn decode(data: &[u8]) -> Result<Vec<MspSerialSetting>> {
+        let entries = match data.first() {
+            Some(&count) if data.len() == 1 + count as usize * ENTRY => &data[1..],
+            _ if data.len().is_multiple_of(ENTRY) => data,
+            _ => return Err(Error::msg(format!("Serial config of {} bytes is not a port list", data.len()))),
+        };
+        Ok(entries.chunks_exact(ENTRY).map(MspSerialSetting::unpack_from_slice).collect::<Result<_, _>>()?)
+    }
+
+    /// Decode an `MSP_CF_SERIAL_CONFIG` reply
+    pub fn decode_cf(data: &[u8]) -> Result<Vec<MspSerialSetting>> {
+        if !data.len().is_multiple_of(CF_ENTRY) {
+            return Err(Error::msg(format!("Serial config of {} bytes is not a port list", data.len())));
+        }
+        data.chunks_exact(CF_ENTRY).map(decode_cf_entry).collect()
+    }
+
+    pub fn get(&self, identifier: SerialIdentifier) -> Option<&MspSerialSetting> {
+        self.ports.iter().find(|p| p.identifier == identifier)
+    }
+
+    pub fn get_mut(&mut self, identifier: SerialIdentifier) -> Option<&mut MspSerialSetting> {
+        self.ports.iter_mut().find(|p| p.identifier == identifier)
+    }
+
+    /// Ports with all of `bits` enabled
+    pub fn with_function(&self, bits: u32) -> Vec<SerialIdentifier> {
+        self.ports.iter().filter(|p| p.functions().contains(bits)).map(|p| p.identifier).collect()
+    }
+
+    /// Names of the functions of every port, for display
+    pub fn describe(&self) -> Vec<(SerialIdentifier, Vec<String>)> {
+        self.ports.iter().map(|p| (p.identifier, p.functions().names(&self.variant))).collect()
+    }
+
+    /// Check for setups the firmware rejects or that lock out the configurator: MSP on no port
+    /// or not on USB, more than one serial receiver or GPS, a serial receiver sharing its port
+    /// with MSP, and on the legacy command functions that do not fit 16 bits. Betaflight resets
+    /// the whole serial config to defaults when it saves one of these.
+    pub fn validate(&self) -> Result<()> {
+        let mut problems = Vec::new();
+        if self.with_function(SerialFunction::MSP).is_empty() {
+            problems.push("MSP is disabled on every port".to_owned());
+        }
+        if self.get(SerialIdentifier::UsbVcp).is_some_and(|p| !p.functions().contains(SerialFunction::MSP)) {
+            problems.push("UsbVcp has no MSP".to_owned());
+        }
+        for (bits, name) in [(SerialFunction::RX_SERIAL, "serial receiver"), (SerialFunction::GPS, "GPS")] {
+            let ports = self.with_function(bits);
+            if ports.len() > 1 {
+                problems.push(format!("{} on more than one port: {:?}", name, ports));
+            }
+        }
+        for p in &self.ports {
+            if p.functions().contains(SerialFunction::RX_SERIAL | SerialFunction::MSP) {
+                problems.push(format!("{:?} shares the serial receiver with MSP", p.identifier));
+            }
+            if self.legacy && p.function_mask > u16::MAX as u32 {
+                problems.push(format!("{:?} has functions this firmware cannot store", p.identifier));
+            }
+        }
+
+        if !problems.is_empty() {
+            return Err(Error::msg(format!("Invalid serial config: {}", problems.join(", "))));
+        }
+        Ok(())
+    }
+
+    fn encode(&self) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        match (self.legacy, &self.variant) {
+            (true, _) => {
+                for p in &self.ports {
+                    data.push(p.identifier.to_primitive());
+                    data.extend((p.function_mask as u16).to_le_bytes());
+                    data.extend([
+                        p.msp_baudrate_index.to_primitive(),
+                        p.gps_baudrate_index.to_primitive(),
+                        p.telemetry_baudrate_index.to_primitive(),
+                        p.peripheral_baudrate_index.to_primitive(),
+                    ]);
+                }
+            }
+            (false, variant) => {
+                if *variant != FirmwareVariant::Inav {
+                    data.push(self.ports.len() as u8);
+                }
+                for p in &self.ports {
+                    data.extend(p.pack_to_vec()?);
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Validate, send every port, read the list back and save to EEPROM. The firmware only
+    /// reopens its ports after a reboot.
+    pub fn write(&self, port: &mut dyn SerialPort) -> Result<()> {
+        self.validate()?;
+        let (set, get) = if self.legacy {
+            (MspCommandCode::MSP_SET_CF_SERIAL_CONFIG, MspCommandCode::MSP_CF_SERIAL_CONFIG)
+        } else {
+            (MspCommandCode::MSP2_SET_SERIAL_CONFIG, MspCommandCode::MSP2_SERIAL_CONFIG)
+        };
+        request(port, set as u16, &self.encode()?)?;
+
+        let reply = request(port, get as u16, &[])?;
+        let stored = if self.legacy {
+            Self::decode_cf(reply.data.as_slice())?
+        } else {
+            Self::decode(reply.data.as_slice())?
+        };
+        if stored != self.ports {
+            return Err(Error::msg(format!("Serial config read back as {:?}", stored)));
+        }
+        request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::firmware::test_firmware;
+
+    fn setting(identifier: SerialIdentifier, functions: u32) -> MspSerialSetting {
+        MspSerialSetting {
+            identifier,
+            function_mask: functions,
+            msp_baudrate_index: Baudrate::Baud115200,
+            gps_baudrate_index: Baudrate::Baud57600,
+            telemetry_baudrate_index: Baudrate::BaudAuto,
+            peripheral_baudrate_index: Baudrate::Baud115200,
+        }
+    }
+
+    #[test]
+    fn function_names_per_firmware() {
+        let bf = FirmwareVariant::Betaflight;
+        let functions = SerialFunction::from_names(&bf, &["msp", "VTX_MSP", "ESC_SENSOR"]).unwrap();
+        assert_eq!(SerialFunction(1 | 1 << 10 | 1 << 17), functions);
+        assert_eq!(vec!["MSP", "ESC_SENSOR", "VTX_MSP"], functions.names(&bf));
+        assert_eq!(vec!["MSP", "RCDEVICE", "VTX_FFPV"], functions.names(&FirmwareVariant::Inav));
+        assert_eq!(vec!["BIT8", "BIT30"], SerialFunction(1 << 8 | 1 << 30).names(&bf));
+        assert!(SerialFunction::from_names(&bf, &["MSP_DISPLAYPORT"]).is_err());
+        assert_eq!(Some(1 << 25), SerialFunction::bit(&FirmwareVariant::Inav, "MSP_DISPLAYPORT"));
+
+        // Betaflight counts its ports, iNav does not
+        let mut data = vec![2];
+        data.extend(setting(SerialIdentifier::UsbVcp, 1).pack_to_vec().unwrap());
+        data.extend(setting(SerialIdentifier::USART1, 1 << 6).pack_to_vec().unwrap());
+        let ports = SerialPortsConfig::decode(&data).unwrap();
+        assert_eq!(ports, SerialPortsConfig::decode(&data[1..]).unwrap());
+        assert_eq!(SerialIdentifier::USART1, ports[1].identifier);
+        assert!(ports[1].functions().contains(SerialFunction::RX_SERIAL));
+        assert!(SerialPortsConfig::decode(&data[..12]).is_err());
+
+        let legacy = SerialPortsConfig::decode_cf(&[20, 1, 0, 8, 7, 0, 8, 0, 64, 0, 8, 7, 0, 8]).unwrap();
+        assert_eq!(vec![setting(SerialIdentifier::UsbVcp, 1), setting(SerialIdentifier::USART1, 64)], legacy);
+    }
+
+    #[test]
+    fn validate_and_write_ports() {
+        let mut table = vec![3];
+        for (identifier, functions) in [
+            (SerialIdentifier::UsbVcp, SerialFunction::MSP),
+            (SerialIdentifier::USART1, 0),
+            (SerialIdentifier::USART2, SerialFunction::GPS),
+        ] {
+            table.extend(setting(identifier, functions).pack_to_vec().unwrap());
+        }
+        let mut fc = MockFc::new()
+            .reply(MSP2_SERIAL_CONFIG as u16, &table)
+            .setter(MSP2_SET_SERIAL_CONFIG as u16, MSP2_SERIAL_CONFIG as u16)
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+        let mut config = SerialPortsConfig::load(&mut fc, &test_firmware(FirmwareVariant::Betaflight, 46)).unwrap();
+        assert_eq!(3, config.ports.len());
+        config.validate().unwrap();
+
+        config.get_mut(SerialIdentifier::UsbVcp).unwrap().set_functions(SerialFunction(0));
+        config.get_mut(SerialIdentifier::USART1).unwrap().function_mask =
+            SerialFunction::RX_SERIAL | SerialFunction::MSP;
+        config.get_mut(SerialIdentifier::USART2).unwrap().function_mask = SerialFunction::RX_SERIAL;
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("UsbVcp has no MSP"));
+        assert!(error.contains("serial receiver on more than one port: [USART1, USART2]"));
+        assert!(error.contains("USART1 shares the serial receiver"));
+        assert!(config.write(&mut fc).is_err());
+        assert!(!fc.commands().contains(&(MSP2_SET_SERIAL_CONFIG as u16)));
+
+        // MSP moved from USB to a UART still counts as MSP on some port, but not on USB
+        config.get_mut(SerialIdentifier::USART1).unwrap().function_mask = SerialFunction::MSP;
+        config.get_mut(SerialIdentifier::USART2).unwrap().function_mask = SerialFunction::GPS;
+        assert_eq!("Invalid serial config: UsbVcp has no MSP", config.validate().unwrap_err().to_string());
+
+        config.get_mut(SerialIdentifier::UsbVcp).unwrap().function_mask = SerialFunction::MSP;
+        config.get_mut(SerialIdentifier::USART1).unwrap().function_mask = SerialFunction::RX_SERIAL;
+        config.get_mut(SerialIdentifier::USART2).unwrap().function_mask = SerialFunction::GPS;
+        config.write(&mut fc).unwrap();
+        assert_eq!(Some(MSP_EEPROM_WRITE as u16), fc.commands().last().copied());
+        assert_eq!(Some(3), fc.value(MSP2_SERIAL_CONFIG as u16).map(|v| v[0]));
+        assert_eq!(vec![SerialIdentifier::USART1], config.with_function(SerialFunction::RX_SERIAL));
+    }
+
+    #[test]
+    fn legacy_and_inav_layouts() {
+        // Betaflight before 4.3 only has the legacy command, with 16 bit function masks
+        let mut fc = MockFc::new()
+            .reply(MSP_CF_SERIAL_CONFIG as u16, &[20, 1, 0, 8, 7, 0, 8, 0, 2, 0, 8, 7, 0, 8])
+            .setter(MSP_SET_CF_SERIAL_CONFIG as u16, MSP_CF_SERIAL_CONFIG as u16)
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+        let mut config = SerialPortsConfig::load(&mut fc, &test_firmware(FirmwareVariant::Betaflight, 43)).unwrap();
+        assert_eq!(vec![SerialIdentifier::USART1], config.with_function(SerialFunction::GPS));
+
+        config.get_mut(SerialIdentifier::USART1).unwrap().function_mask = 1 << 17;
+        let error = config.write(&mut fc).unwrap_err().to_string();
+        assert!(error.contains("USART1 has functions this firmware cannot store"));
+        config.get_mut(SerialIdentifier::USART1).unwrap().function_mask = SerialFunction::RX_SERIAL;
+        config.write(&mut fc).unwrap();
+        assert_eq!(Some(&[0, 64, 0][..]), fc.value(MSP_CF_SERIAL_CONFIG as u16).map(|v| &v[7..10]));
+        assert!(!fc.commands().contains(&(MSP2_SET_SERIAL_CONFIG as u16)));
+
+        // iNav sends no port count, and a port the firmware did not take fails before saving
+        let mut table = Vec::new();
+        table.extend(setting(SerialIdentifier::UsbVcp, SerialFunction::MSP).pack_to_vec().unwrap());
+        table.extend(setting(SerialIdentifier::USART1, SerialFunction::GPS).pack_to_vec().unwrap());
+        let mut fc = MockFc::new()
+            .reply(MSP2_SERIAL_CONFIG as u16, &table)
+            .reply(MSP2_SET_SERIAL_CONFIG as u16, &[])
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+        let mut config = SerialPortsConfig::load(&mut fc, &test_firmware(FirmwareVariant::Inav, 5)).unwrap();
+        config.get_mut(SerialIdentifier::USART1).unwrap().gps_baudrate_index = Baudrate::Baud115200;
+        assert!(config.write(&mut fc).unwrap_err().to_string().starts_with("Serial config read back"));
+        let set = fc.requests.iter().find(|p| p.cmd == MSP2_SET_SERIAL_CONFIG as u16).unwrap();
+        assert_eq!(2 * ENTRY, set.data.as_slice().len());
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+    }
+}
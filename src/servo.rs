@@ -0,0 +1,311 @@
+//! Servo endpoints over `MSP_SERVO_CONFIGURATIONS` and live outputs from `MSP_SERVO`
+
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspBfServoConfig, MspServoConfig, MspSetBfServoConfig, MspSetServoConfig},
+};
+
+/// Pulse widths the firmware accepts for servo endpoints [us]
+pub const PULSE_RANGE: std::ops::RangeInclusive<u16> = 500..=2500;
+/// Servo rate [%]
+pub const RATE_RANGE: std::ops::RangeInclusive<i8> = -125..=125;
+
+impl From<MspBfServoConfig> for MspServoConfig {
+    fn from(servo: MspBfServoConfig) -> Self {
+        MspServoConfig {
+            min: servo.min,
+            max: servo.max,
+            middle: servo.middle,
+            rate: servo.rate,
+            unused1: 0,
+            unused2: 0,
+            forward_from_channel: servo.forward_from_channel,
+            reverse_input: servo.reversed_sources,
+        }
+    }
+}
+
+impl From<MspServoConfig> for MspBfServoConfig {
+    fn from(servo: MspServoConfig) -> Self {
+        MspBfServoConfig {
+            min: servo.min,
+            max: servo.max,
+            middle: servo.middle,
+            rate: servo.rate,
+            forward_from_channel: servo.forward_from_channel,
+            reversed_sources: servo.reverse_input,
+        }
+    }
+}
+
+/// The servo configurations of a flight controller, as loaded and as edited
+pub struct ServoSetup {
+    pub servos: Vec<MspServoConfig>,
+    loaded: Vec<MspServoConfig>,
+    variant: FirmwareVariant,
+}
+
+/// iNav sends the 14 byte [`MspServoConfig`] per servo, Betaflight the 12 byte [`MspBfServoConfig`]
+fn read_configs(port: &mut dyn SerialPort, variant: &FirmwareVariant) -> Result<Vec<MspServoConfig>> {
+    let reply = request(port, MspCommandCode::MSP_SERVO_CONFIGURATIONS as u16, &[])?;
+    let data = reply.data.as_slice();
+    Ok(match variant {
+        FirmwareVariant::Inav => {
+            data.chunks_exact(14).map(MspServoConfig::unpack_from_slice).collect::<Result<_, _>>()?
+        }
+        _ => data
+            .chunks_exact(12)
+            .map(|c| MspBfServoConfig::unpack_from_slice(c).map(MspServoConfig::from))
+            .collect::<Result<_, _>>()?,
+    })
+}
+
+fn encode_set(index: usize, servo: MspServoConfig, variant: &FirmwareVariant) -> Result<Vec<u8>> {
+    let index = index as u8;
+    Ok(match variant {
+        FirmwareVariant::Inav => MspSetServoConfig { index, servo_config: servo }.pack_to_vec()?,
+        _ => MspSetBfServoConfig { index, servo_config: servo.into() }.pack_to_vec()?,
+    })
+}
+
+impl ServoSetup {
+    pub fn load(port: &mut dyn SerialPort, firmware: &FirmwareInfo) -> Result<ServoSetup> {
+        let servos = read_configs(port, &firmware.variant)?;
+        Ok(ServoSetup { loaded: servos.clone(), servos, variant: firmware.variant.clone() })
+    }
+
+    /// Set the endpoints and rate of a 0 based servo, checked by [`ServoSetup::validate`] on write
+    pub fn set(&mut self, index: usize, min: u16, middle: u16, max: u16, rate: i8) -> Result<()> {
+        let servo = self
+            .servos
+            .get_mut(index)
+            .ok_or_else(|| Error::msg(format!("No servo {}", index)))?;
+        (servo.min, servo.middle, servo.max, servo.rate) = (min, middle, max, rate);
+        Ok(())
+    }
+
+    /// Every servo must have min <= middle <= max within [`PULSE_RANGE`] and a rate within
+    /// [`RATE_RANGE`]
+    pub fn validate(&self) -> Result<()> {
+        let invalid: Vec<String> = self
+            .servos
+            .iter()
+            .enumerate()
+            .filter(|(_, s)| {
+                s.min > s.middle
+                    || s.middle > s.max
+                    || !PULSE_RANGE.contains(&s.min)
+                    || !PULSE_RANGE.contains(&s.max)
+                    || !RATE_RANGE.contains(&s.rate)
+            })
+            .map(|(i, s)| format!("servo {} min {} mid {} max {} rate {}", i, s.min, s.middle, s.max, s.rate))
+            .collect();
+
+        if !invalid.is_empty() {
+            return Err(Error::msg(format!("Invalid servo configuration: {}", invalid.join(", "))));
+        }
+        Ok(())
+    }
+
+    /// 0 based indexes of the servos edited since loading
+    pub fn changed(&self) -> Vec<usize> {
+        (0..self.servos.len()).filter(|i| self.servos[*i] != self.loaded[*i]).collect()
+    }
+
+    /// Validate, send the changed servos, read them back and save to EEPROM. Returns the number
+    /// of servos sent.
+    pub fn write(&mut self, port: &mut dyn SerialPort) -> Result<usize> {
+        self.validate()?;
+        let changed = self.changed();
+        if changed.is_empty() {
+            return Ok(0);
+        }
+
+        for &index in &changed {
+            let set = encode_set(index, self.servos[index], &self.variant)?;
+            request(port, MspCommandCode::MSP_SET_SERVO_CONFIGURATION as u16, &set)?;
+        }
+        let stored = read_configs(port, &self.variant)?;
+        if let Some(&index) = changed.iter().find(|i| stored.get(**i) != Some(&self.servos[**i])) {
+            return Err(Error::msg(format!("Servo {} read back as {:?}", index, stored.get(index))));
+        }
+
+        request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        self.loaded = stored;
+        Ok(changed.len())
+    }
+}
+
+/// One servo output next to its configured limits
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ServoOutput {
+    pub index: usize,
+    /// [us]
+    pub value: u16,
+    pub min: u16,
+    pub middle: u16,
+    pub max: u16,
+}
+
+impl ServoOutput {
+    /// Deflection from middle, -100 at min and 100 at max
+    pub fn percent(&self) -> f32 {
+        let (value, middle) = (self.value as f32, self.middle as f32);
+        if self.value >= self.middle {
+            100.0 * (value - middle) / (self.max as f32 - middle).max(1.0)
+        } else {
+            100.0 * (value - middle) / (middle - self.min as f32).max(1.0)
+        }
+    }
+
+    /// At or past an endpoint, so the control surface cannot move any further
+    pub fn saturated(&self) -> bool {
+        self.value <= self.min || self.value >= self.max
+    }
+}
+
+/// Servo outputs of one `MSP_SERVO` reply, for the servos in `configs`
+pub fn read_outputs(port: &mut dyn SerialPort, configs: &[MspServoConfig]) -> Result<Vec<ServoOutput>> {
+    let reply = request(port, MspCommandCode::MSP_SERVO as u16, &[])?;
+    Ok(reply
+        .data
+        .as_slice()
+        .chunks_exact(2)
+        .zip(configs)
+        .enumerate()
+        .map(|(index, (b, c))| ServoOutput {
+            index,
+            value: u16::from_le_bytes([b[0], b[1]]),
+            min: c.min,
+            middle: c.middle,
+            max: c.max,
+        })
+        .collect())
+}
+
+/// Poll the servo outputs every `interval` for `duration`, handing each set to `f`. Polls at
+/// least once.
+pub fn monitor(
+    port: &mut dyn SerialPort,
+    configs: &[MspServoConfig],
+    interval: Duration,
+    duration: Duration,
+    mut f: impl FnMut(&[ServoOutput]),
+) -> Result<()> {
+    let end = Instant::now() + duration;
+    loop {
+        let next = Instant::now() + interval;
+        f(&read_outputs(port, configs)?);
+        if Instant::now() >= end {
+            return Ok(());
+        }
+        sleep(next.min(end).saturating_duration_since(Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firmware::test_firmware;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+
+    fn servo(min: u16, middle: u16, max: u16) -> MspServoConfig {
+        MspServoConfig {
+            min,
+            max,
+            middle,
+            rate: 100,
+            unused1: 0,
+            unused2: 0,
+            forward_from_channel: 255,
+            reverse_input: 0,
+        }
+    }
+
+    /// Four servos in the layout of `variant`, storing what MSP_SET_SERVO_CONFIGURATION sends
+    fn servo_fc(variant: FirmwareVariant) -> MockFc {
+        let size = if variant == FirmwareVariant::Inav { 14 } else { 12 };
+        let mut table = Vec::new();
+        for i in 0..4 {
+            table.extend(&encode_set(i, servo(1000, 1500, 2000), &variant).unwrap()[1..]);
+        }
+        MockFc::new().reply(MSP_EEPROM_WRITE as u16, &[]).handler(move |p| match p.cmd {
+            c if c == MSP_SERVO_CONFIGURATIONS as u16 => Some(table.clone()),
+            c if c == MSP_SET_SERVO_CONFIGURATION as u16 => {
+                let data = p.data.as_slice();
+                assert_eq!(1 + size, data.len());
+                let at = data[0] as usize * size;
+                table[at..at + size].copy_from_slice(&data[1..]);
+                Some(Vec::new())
+            }
+            _ => None,
+        })
+    }
+
+    #[test]
+    fn edit_and_write_servos() {
+        for variant in [FirmwareVariant::Betaflight, FirmwareVariant::Inav] {
+            let firmware = test_firmware(variant.clone(), 46);
+            let mut fc = servo_fc(variant);
+            let mut setup = ServoSetup::load(&mut fc, &firmware).unwrap();
+            assert_eq!(4, setup.servos.len());
+            assert!(setup.servos.iter().all(|s| *s == servo(1000, 1500, 2000)));
+
+            setup.set(2, 1600, 1500, 2000, 100).unwrap();
+            assert!(setup.validate().unwrap_err().to_string().contains("servo 2 min 1600"));
+            setup.set(2, 1100, 1480, 1900, -100).unwrap();
+            assert!(setup.set(4, 1000, 1500, 2000, 100).is_err());
+
+            assert_eq!(vec![2], setup.changed());
+            assert_eq!(1, setup.write(&mut fc).unwrap());
+            assert_eq!(Some(MSP_EEPROM_WRITE as u16), fc.commands().last().copied());
+            assert!(setup.changed().is_empty());
+            let stored = ServoSetup::load(&mut fc, &firmware).unwrap().servos;
+            assert_eq!((1100, 1480, 1900, -100), (stored[2].min, stored[2].middle, stored[2].max, stored[2].rate));
+            assert_eq!(servo(1000, 1500, 2000), stored[3]);
+        }
+    }
+
+    #[test]
+    fn write_fails_when_not_stored() {
+        // the firmware acknowledges the write but keeps its old values
+        let table = servo(1000, 1500, 2000).pack_to_vec().unwrap();
+        let mut fc = MockFc::new()
+            .reply(MSP_SERVO_CONFIGURATIONS as u16, &table)
+            .reply(MSP_SET_SERVO_CONFIGURATION as u16, &[])
+            .reply(MSP_EEPROM_WRITE as u16, &[]);
+        let mut setup = ServoSetup::load(&mut fc, &test_firmware(FirmwareVariant::Inav, 5)).unwrap();
+        setup.set(0, 1100, 1500, 1900, 100).unwrap();
+        assert!(setup.write(&mut fc).unwrap_err().to_string().starts_with("Servo 0 read back as"));
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+        assert_eq!(vec![0], setup.changed());
+    }
+
+    #[test]
+    fn outputs_against_limits() {
+        let configs = [servo(1000, 1500, 2000), servo(1100, 1400, 1900)];
+        let outputs = [1750u16, 1100, 1500].iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        let mut fc = MockFc::new().reply(MSP_SERVO as u16, &outputs);
+
+        let servos = read_outputs(&mut fc, &configs).unwrap();
+        assert_eq!(2, servos.len());
+        assert_eq!(50.0, servos[0].percent());
+        assert!(!servos[0].saturated());
+        assert_eq!(-100.0, servos[1].percent());
+        assert!(servos[1].saturated());
+
+        let mut seen = Vec::new();
+        monitor(&mut fc, &configs, Duration::from_millis(5), Duration::ZERO, |o| seen.push(o.to_vec())).unwrap();
+        assert_eq!(vec![servos], seen);
+    }
+}
@@ -0,0 +1,389 @@
+//! Named settings access over `MSP2_COMMON_SETTING`, as implemented by iNav
+
+use std::collections::HashMap;
+use std::fmt;
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspSettingGroup, MspSettingInfo, MspSettingInfoRequest, SettingMode, SettingType},
+};
+
+const SETTING_INFO_SIZE: usize = 17;
+
+/// Metadata of a single setting, as reported by `MSP2_COMMON_SETTING_INFO`
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingDescriptor {
+    pub name: String,
+    pub group_id: u16,
+    pub setting_type: SettingType,
+    pub section: u8,
+    pub mode: SettingMode,
+    pub min: i32,
+    pub max: u32,
+    pub index: u16,
+    pub profile_id: u8,
+    pub profile_count: u8,
+    /// Labels of a lookup setting, indexed by `value - min`
+    pub lookup: Vec<String>,
+}
+
+/// Value of a setting
+#[derive(Debug, Clone, PartialEq)]
+pub enum SettingValue {
+    Int(i64),
+    Float(f32),
+    String(String),
+    /// Label of a lookup setting
+    Lookup(String),
+}
+
+impl fmt::Display for SettingValue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SettingValue::Int(v) => write!(f, "{}", v),
+            SettingValue::Float(v) => write!(f, "{}", v),
+            SettingValue::String(v) | SettingValue::Lookup(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+impl From<i32> for SettingValue {
+    fn from(value: i32) -> Self {
+        SettingValue::Int(value.into())
+    }
+}
+
+impl From<u32> for SettingValue {
+    fn from(value: u32) -> Self {
+        SettingValue::Int(value.into())
+    }
+}
+
+impl From<i64> for SettingValue {
+    fn from(value: i64) -> Self {
+        SettingValue::Int(value)
+    }
+}
+
+impl From<f32> for SettingValue {
+    fn from(value: f32) -> Self {
+        SettingValue::Float(value)
+    }
+}
+
+/// Strings are matched against lookup labels for lookup settings
+impl From<&str> for SettingValue {
+    fn from(value: &str) -> Self {
+        SettingValue::String(value.to_owned())
+    }
+}
+
+impl From<String> for SettingValue {
+    fn from(value: String) -> Self {
+        SettingValue::String(value)
+    }
+}
+
+fn value_size(setting_type: SettingType) -> usize {
+    match setting_type {
+        SettingType::VarUint8 | SettingType::VarInt8 => 1,
+        SettingType::VarUint16 | SettingType::VarInt16 => 2,
+        SettingType::VarUint32 | SettingType::VarFloat => 4,
+        SettingType::VarString => 0,
+    }
+}
+
+fn read_cstr(data: &[u8]) -> Result<(String, &[u8])> {
+    let end = data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| Error::msg("Unterminated string in setting info"))?;
+    Ok((String::from_utf8_lossy(&data[..end]).into_owned(), &data[end + 1..]))
+}
+
+impl SettingDescriptor {
+    /// Decode a `MSP2_COMMON_SETTING_INFO` reply, returning the descriptor and the raw
+    /// current value that trails it
+    pub fn decode(data: &[u8]) -> Result<(SettingDescriptor, &[u8])> {
+        let (name, rest) = read_cstr(data)?;
+        if rest.len() < SETTING_INFO_SIZE {
+            return Err(Error::msg(format!("Setting info for {} is truncated", name)));
+        }
+        let info = MspSettingInfo::unpack_from_slice(&rest[..SETTING_INFO_SIZE])?;
+        let mut rest = &rest[SETTING_INFO_SIZE..];
+
+        let mut descriptor = SettingDescriptor {
+            name,
+            group_id: info.group_id,
+            setting_type: info.setting_type,
+            section: info.setting_section,
+            mode: info.setting_mode,
+            // sent as the bits of an int32
+            min: info.min as i32,
+            max: info.max,
+            index: info.absolute_index,
+            profile_id: info.profile_id,
+            profile_count: info.profile_count,
+            lookup: Vec::new(),
+        };
+
+        if descriptor.mode == SettingMode::ModeLookup {
+            for _ in descriptor.min..=descriptor.max as i32 {
+                let (label, tail) = read_cstr(rest)?;
+                descriptor.lookup.push(label);
+                rest = tail;
+            }
+        }
+
+        Ok((descriptor, rest))
+    }
+
+    /// Decode the raw value bytes of this setting
+    pub fn decode_value(&self, data: &[u8]) -> Result<SettingValue> {
+        let size = value_size(self.setting_type);
+        if data.len() < size {
+            return Err(Error::msg(format!("Value of {} is truncated", self.name)));
+        }
+
+        let int = match self.setting_type {
+            SettingType::VarUint8 => data[0] as i64,
+            SettingType::VarInt8 => data[0] as i8 as i64,
+            SettingType::VarUint16 => u16::from_le_bytes([data[0], data[1]]) as i64,
+            SettingType::VarInt16 => i16::from_le_bytes([data[0], data[1]]) as i64,
+            SettingType::VarUint32 => u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as i64,
+            SettingType::VarFloat => {
+                return Ok(SettingValue::Float(f32::from_le_bytes([data[0], data[1], data[2], data[3]])));
+            }
+            SettingType::VarString => {
+                let end = data.iter().position(|&b| b == 0).unwrap_or(data.len());
+                return Ok(SettingValue::String(String::from_utf8_lossy(&data[..end]).into_owned()));
+            }
+        };
+
+        if self.mode == SettingMode::ModeLookup {
+            let label = usize::try_from(int - self.min as i64)
+                .ok()
+                .and_then(|i| self.lookup.get(i));
+            if let Some(label) = label {
+                return Ok(SettingValue::Lookup(label.clone()));
+            }
+        }
+        Ok(SettingValue::Int(int))
+    }
+
+    /// Type and range check `value`, then encode it as raw value bytes
+    pub fn encode_value(&self, value: &SettingValue) -> Result<Vec<u8>> {
+        let out_of_range = |v: &dyn fmt::Display| {
+            Error::msg(format!("{} is out of range for {} ({}..={})", v, self.name, self.min, self.max))
+        };
+
+        let int = match (self.setting_type, value) {
+            (SettingType::VarString, SettingValue::String(s)) => {
+                if s.len() > self.max as usize {
+                    return Err(Error::msg(format!("{} is limited to {} characters", self.name, self.max)));
+                }
+                return Ok(s.as_bytes().to_vec());
+            }
+            (SettingType::VarFloat, SettingValue::Float(_) | SettingValue::Int(_)) => {
+                let v = match *value {
+                    SettingValue::Int(v) => v as f32,
+                    SettingValue::Float(v) => v,
+                    _ => f32::NAN,
+                };
+                if !(self.min as f32..=self.max as f32).contains(&v) {
+                    return Err(out_of_range(&v));
+                }
+                return Ok(v.to_le_bytes().to_vec());
+            }
+            (_, SettingValue::String(label) | SettingValue::Lookup(label))
+                if self.mode == SettingMode::ModeLookup =>
+            {
+                let i = self
+                    .lookup
+                    .iter()
+                    .position(|l| l.eq_ignore_ascii_case(label))
+                    .ok_or_else(|| {
+                        Error::msg(format!("{} is not one of {:?} for {}", label, self.lookup, self.name))
+                    })?;
+                self.min as i64 + i as i64
+            }
+            (SettingType::VarString | SettingType::VarFloat, _) | (_, SettingValue::Float(_)) => {
+                return Err(Error::msg(format!("{} expects a {:?} value, got {:?}", self.name, self.setting_type, value)));
+            }
+            (_, SettingValue::String(_) | SettingValue::Lookup(_)) => {
+                return Err(Error::msg(format!("{} expects a number, got {:?}", self.name, value)));
+            }
+            (_, SettingValue::Int(v)) => *v,
+        };
+
+        if int < self.min as i64 || int > self.max as i64 {
+            return Err(out_of_range(&int));
+        }
+
+        let bytes = match self.setting_type {
+            SettingType::VarUint8 | SettingType::VarInt8 => vec![int as u8],
+            SettingType::VarUint16 | SettingType::VarInt16 => (int as u16).to_le_bytes().to_vec(),
+            _ => (int as u32).to_le_bytes().to_vec(),
+        };
+        Ok(bytes)
+    }
+
+    /// Addresses the setting by absolute index in requests
+    fn selector(&self) -> Result<Vec<u8>> {
+        Ok(MspSettingInfoRequest { null: 0, id: self.index }.pack_to_vec()?)
+    }
+}
+
+/// Cached setting metadata with by-name reads and writes
+pub struct Settings<'a> {
+    port: &'a mut dyn SerialPort,
+    groups: Vec<MspSettingGroup>,
+    settings: Vec<SettingDescriptor>,
+    by_name: HashMap<String, usize>,
+}
+
+impl<'a> Settings<'a> {
+    /// Enumerate every parameter group and fetch the metadata of all their settings
+    pub fn load(port: &'a mut dyn SerialPort) -> Result<Settings<'a>> {
+        let reply = request(port, MspCommandCode::MSP2_COMMON_PG_LIST as u16, &[])?;
+        let groups = reply
+            .data
+            .as_slice()
+            .chunks_exact(6)
+            .map(MspSettingGroup::unpack_from_slice)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut settings = Vec::new();
+        for group in &groups {
+            for id in group.start_id..=group.end_id {
+                let selector = MspSettingInfoRequest { null: 0, id }.pack_to_vec()?;
+                let reply = request(port, MspCommandCode::MSP2_COMMON_SETTING_INFO as u16, &selector)?;
+                let (descriptor, _) = SettingDescriptor::decode(reply.data.as_slice())?;
+                settings.push(descriptor);
+            }
+        }
+
+        let by_name = settings.iter().enumerate().map(|(i, s)| (s.name.clone(), i)).collect();
+        Ok(Settings { port, groups, settings, by_name })
+    }
+
+    pub fn groups(&self) -> &[MspSettingGroup] {
+        &self.groups
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &SettingDescriptor> {
+        self.settings.iter()
+    }
+
+    pub fn descriptor(&self, name: &str) -> Option<&SettingDescriptor> {
+        self.by_name.get(name).map(|&i| &self.settings[i])
+    }
+
+    fn lookup(&self, name: &str) -> Result<&SettingDescriptor> {
+        self.descriptor(name)
+            .ok_or_else(|| Error::msg(format!("Unknown setting {}", name)))
+    }
+
+    /// Current value of `name`, lookup settings are returned by label
+    pub fn get(&mut self, name: &str) -> Result<SettingValue> {
+        let descriptor = self.lookup(name)?.clone();
+        let reply = request(&mut *self.port, MspCommandCode::MSP2_COMMON_SETTING as u16, &descriptor.selector()?)?;
+        descriptor.decode_value(reply.data.as_slice())
+    }
+
+    /// Write `name` after checking the value against its type and `min`/`max`
+    pub fn set(&mut self, name: &str, value: impl Into<SettingValue>) -> Result<()> {
+        let descriptor = self.lookup(name)?;
+        let mut payload = descriptor.selector()?;
+        payload.extend(descriptor.encode_value(&value.into())?);
+        request(&mut *self.port, MspCommandCode::MSP2_COMMON_SET_SETTING as u16, &payload)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+    use super::*;
+    use crate::mock::MockFc;
+    use MspCommandCode::*;
+
+    fn info(name: &str, setting_type: u8, mode: u8, (min, max): (i32, u32), index: u16, lookup: &[&str], value: &[u8]) -> Vec<u8> {
+        let mut out = name.as_bytes().to_vec();
+        out.push(0);
+        out.extend(7u16.to_le_bytes());
+        out.extend([setting_type, 0, mode]);
+        out.extend(min.to_le_bytes());
+        out.extend(max.to_le_bytes());
+        out.extend(index.to_le_bytes());
+        out.extend([0, 0]);
+        for label in lookup {
+            out.extend(label.as_bytes());
+            out.push(0);
+        }
+        out.extend(value);
+        out
+    }
+
+    fn mock_fc(values: Arc<Mutex<HashMap<u16, Vec<u8>>>>) -> MockFc {
+        let mut pg = Vec::new();
+        pg.extend([7u16, 0, 1].iter().flat_map(|v| v.to_le_bytes()));
+        MockFc::new()
+            .reply(MSP2_COMMON_PG_LIST as u16, &pg)
+            .handler(move |packet| {
+                let data = packet.data.as_slice();
+                let id = || u16::from_le_bytes([data[1], data[2]]);
+                let mut values = values.lock().unwrap();
+                match MspCommandCode::from(packet.cmd) {
+                    MSP2_COMMON_SETTING_INFO => Some(match id() {
+                        0 => info("nav_rth_altitude", 2, 0, (0, 65000), 0, &[], &values[&0]),
+                        _ => info("failsafe_procedure", 0, 0x40, (0, 3), 1, &["LAND", "DROP", "RTH", "NONE"], &values[&1]),
+                    }),
+                    MSP2_COMMON_SETTING => Some(values[&id()].clone()),
+                    MSP2_COMMON_SET_SETTING => {
+                        values.insert(id(), data[3..].to_vec());
+                        Some(Vec::new())
+                    }
+                    _ => None,
+                }
+            })
+    }
+
+    #[test]
+    fn read_and_write_by_name() {
+        let values = Arc::new(Mutex::new(HashMap::from([
+            (0, 1000u16.to_le_bytes().to_vec()),
+            (1, vec![2]),
+        ])));
+        let mut fc = mock_fc(values.clone());
+        let mut settings = Settings::load(&mut fc).unwrap();
+
+        assert_eq!(2, settings.iter().count());
+        assert_eq!(vec!["LAND", "DROP", "RTH", "NONE"], settings.descriptor("failsafe_procedure").unwrap().lookup);
+        assert_eq!(SettingValue::Lookup("RTH".into()), settings.get("failsafe_procedure").unwrap());
+
+        settings.set("nav_rth_altitude", 3000).unwrap();
+        assert_eq!(SettingValue::Int(3000), settings.get("nav_rth_altitude").unwrap());
+
+        settings.set("failsafe_procedure", "drop").unwrap();
+        assert_eq!(vec![1], values.lock().unwrap()[&1]);
+    }
+
+    #[test]
+    fn rejects_out_of_range_and_wrong_type() {
+        let values = Arc::new(Mutex::new(HashMap::from([(0, vec![0, 0]), (1, vec![0])])));
+        let mut fc = mock_fc(values);
+        let mut settings = Settings::load(&mut fc).unwrap();
+
+        assert!(settings.set("nav_rth_altitude", 70000).is_err());
+        assert!(settings.set("nav_rth_altitude", -1).is_err());
+        assert!(settings.set("nav_rth_altitude", 1.5f32).is_err());
+        assert!(settings.set("failsafe_procedure", "HOVER").is_err());
+        assert!(settings.set("failsafe_procedure", 4).is_err());
+        assert!(settings.set("no_such_setting", 1).is_err());
+    }
+}
@@ -0,0 +1,263 @@
+//! Full configuration backup and restore
+
+use anyhow::{Error, Result};
+use packed_struct::{PackedStruct, PackedStructSlice};
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::FirmwareInfo;
+use crate::helpers::{read_until_response, request, send_request};
+use crate::msp::{
+    commands::MspCommandCode,
+    packet::MspPacketDirection,
+    structs::{
+        MspAdvancedConfig, MspBatteryConfig, MspBfConfig, MspFilterConfig, MspMixerConfig,
+        MspMotor3DConfig, MspMotorConfig, MspPidAdvanced, MspRcDeadband, MspRcTuning,
+        MspRxConfig, MspSensorAlignment, MspSensorConfig,
+    },
+};
+
+/// Bumped whenever the snapshot document layout changes
+pub const SNAPSHOT_FORMAT_VERSION: u32 = 1;
+
+/// Every configuration block readable over MSP, tagged with the firmware it was taken from.
+/// Blocks the firmware does not support are left out.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ConfigSnapshot {
+    pub format_version: u32,
+    pub firmware: FirmwareInfo,
+
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mixer: Option<MspMixerConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bf_config: Option<MspBfConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor_config: Option<MspSensorConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sensor_alignment: Option<MspSensorAlignment>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub advanced_config: Option<MspAdvancedConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motor_config: Option<MspMotorConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub motor_3d_config: Option<MspMotor3DConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub battery_config: Option<MspBatteryConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rx_config: Option<MspRxConfig>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc_deadband: Option<MspRcDeadband>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub rc_tuning: Option<MspRcTuning>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pid_advanced: Option<MspPidAdvanced>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter_config: Option<MspFilterConfig>,
+}
+
+/// A single `MSP_SET_*` write and the GET command used to read it back
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigWrite {
+    pub section: &'static str,
+    pub set: MspCommandCode,
+    pub get: MspCommandCode,
+    pub payload: Vec<u8>,
+}
+
+/// Read a configuration block, `None` if the firmware does not know the command
+fn read_section<T: PackedStruct>(port: &mut dyn SerialPort, cmd: MspCommandCode) -> Result<Option<T>> {
+    send_request(port, cmd as u16, &[])?;
+    let reply = read_until_response(port, cmd as u16)?;
+    if reply.direction == MspPacketDirection::Unsupported {
+        return Ok(None);
+    }
+    Ok(Some(reply.decode_as::<T>()?))
+}
+
+fn push_write<T: PackedStruct>(
+    writes: &mut Vec<ConfigWrite>,
+    section: &'static str,
+    set: MspCommandCode,
+    get: MspCommandCode,
+    value: &Option<T>,
+) -> Result<()> {
+    if let Some(value) = value {
+        writes.push(ConfigWrite { section, set, get, payload: value.pack_to_vec()? });
+    }
+    Ok(())
+}
+
+impl ConfigSnapshot {
+    /// Read every supported configuration block from the flight controller
+    pub fn capture(port: &mut dyn SerialPort) -> Result<ConfigSnapshot> {
+        use MspCommandCode::*;
+
+        Ok(ConfigSnapshot {
+            format_version: SNAPSHOT_FORMAT_VERSION,
+            firmware: FirmwareInfo::probe(port)?,
+            mixer: read_section(port, MSP_MIXER)?,
+            bf_config: read_section(port, MSP_BF_CONFIG)?,
+            sensor_config: read_section(port, MSP_SENSOR_CONFIG)?,
+            sensor_alignment: read_section(port, MSP_SENSOR_ALIGNMENT)?,
+            advanced_config: read_section(port, MSP_ADVANCED_CONFIG)?,
+            motor_config: read_section(port, MSP_MOTOR_CONFIG)?,
+            motor_3d_config: read_section(port, MSP_MOTOR_3D_CONFIG)?,
+            battery_config: read_section(port, MSP_BATTERY_CONFIG)?,
+            rx_config: read_section(port, MSP_RX_CONFIG)?,
+            rc_deadband: read_section(port, MSP_RC_DEADBAND)?,
+            rc_tuning: read_section(port, MSP_RC_TUNING)?,
+            pid_advanced: read_section(port, MSP_PID_ADVANCED)?,
+            filter_config: read_section(port, MSP_FILTER_CONFIG)?,
+        })
+    }
+
+    /// The writes needed to restore this snapshot, in dependency order: the mixer and
+    /// features first, then hardware and motor setup, then receiver, rates, PIDs and filters.
+    pub fn writes(&self) -> Result<Vec<ConfigWrite>> {
+        use MspCommandCode::*;
+
+        let mut w = Vec::new();
+        push_write(&mut w, "mixer", MSP_SET_MIXER, MSP_MIXER, &self.mixer)?;
+        push_write(&mut w, "bf_config", MSP_SET_BF_CONFIG, MSP_BF_CONFIG, &self.bf_config)?;
+        push_write(&mut w, "sensor_config", MSP_SET_SENSOR_CONFIG, MSP_SENSOR_CONFIG, &self.sensor_config)?;
+        push_write(&mut w, "sensor_alignment", MSP_SET_SENSOR_ALIGNMENT, MSP_SENSOR_ALIGNMENT, &self.sensor_alignment)?;
+        push_write(&mut w, "advanced_config", MSP_SET_ADVANCED_CONFIG, MSP_ADVANCED_CONFIG, &self.advanced_config)?;
+        push_write(&mut w, "motor_config", MSP_SET_MOTOR_CONFIG, MSP_MOTOR_CONFIG, &self.motor_config)?;
+        push_write(&mut w, "motor_3d_config", MSP_SET_3D, MSP_MOTOR_3D_CONFIG, &self.motor_3d_config)?;
+        push_write(&mut w, "battery_config", MSP_SET_BATTERY_CONFIG, MSP_BATTERY_CONFIG, &self.battery_config)?;
+        push_write(&mut w, "rx_config", MSP_SET_RX_CONFIG, MSP_RX_CONFIG, &self.rx_config)?;
+        push_write(&mut w, "rc_deadband", MSP_SET_RC_DEADBAND, MSP_RC_DEADBAND, &self.rc_deadband)?;
+        push_write(&mut w, "rc_tuning", MSP_SET_RC_TUNING, MSP_RC_TUNING, &self.rc_tuning)?;
+        push_write(&mut w, "pid_advanced", MSP_SET_PID_ADVANCED, MSP_PID_ADVANCED, &self.pid_advanced)?;
+        push_write(&mut w, "filter_config", MSP_SET_FILTER_CONFIG, MSP_FILTER_CONFIG, &self.filter_config)?;
+        Ok(w)
+    }
+
+    /// Write the snapshot back to the flight controller, verify every block by reading it
+    /// back and save to EEPROM. Refuses snapshots taken from a different firmware family or
+    /// MSP API version, since the payload layouts would not match.
+    pub fn restore(&self, port: &mut dyn SerialPort) -> Result<()> {
+        let target = FirmwareInfo::probe(port)?;
+        if !self.firmware.is_compatible_with(&target) {
+            return Err(Error::msg(format!(
+                "Snapshot from {} cannot be restored to {}",
+                self.firmware, target
+            )));
+        }
+
+        apply_writes(port, &self.writes()?)
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn from_json(s: &str) -> Result<ConfigSnapshot> {
+        Self::checked(serde_json::from_str(s)?)
+    }
+
+    pub fn to_toml(&self) -> Result<String> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+
+    pub fn from_toml(s: &str) -> Result<ConfigSnapshot> {
+        Self::checked(toml::from_str(s)?)
+    }
+
+    fn checked(snapshot: ConfigSnapshot) -> Result<ConfigSnapshot> {
+        if snapshot.format_version > SNAPSHOT_FORMAT_VERSION {
+            return Err(Error::msg(format!(
+                "Snapshot format version {} is newer than supported version {}",
+                snapshot.format_version, SNAPSHOT_FORMAT_VERSION
+            )));
+        }
+        Ok(snapshot)
+    }
+}
+
+/// Send each write, read the block back to verify it, then save to EEPROM
+pub fn apply_writes(port: &mut dyn SerialPort, writes: &[ConfigWrite]) -> Result<()> {
+    for w in writes {
+        request(port, w.set as u16, &w.payload)?;
+
+        let readback = request(port, w.get as u16, &[])?;
+        let data = readback.data.as_slice();
+        if data.len() < w.payload.len() || data[..w.payload.len()] != w.payload[..] {
+            return Err(Error::msg(format!(
+                "Verification of {} failed: wrote {:02X?}, read back {:02X?}",
+                w.section, w.payload, data
+            )));
+        }
+    }
+
+    request(port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firmware::FirmwareVariant;
+    use crate::mock::MockFc;
+    use MspCommandCode::*;
+
+    fn mock_fc() -> MockFc {
+        MockFc::new()
+            .reply(MSP_API_VERSION as u16, &[0, 1, 46])
+            .reply(MSP_FC_VARIANT as u16, b"BTFL")
+            .reply(MSP_FC_VERSION as u16, &[4, 5, 1])
+            .reply(MSP_RC_TUNING as u16, &[100, 0, 70, 70, 70, 0, 50, 0, 0xDC, 0x05, 0, 100])
+            .reply(MSP_MOTOR_CONFIG as u16, &[0x4C, 0x04, 0xD0, 0x07, 0xE8, 0x03])
+            .reply(MSP_EEPROM_WRITE as u16, &[])
+            .setter(MSP_SET_RC_TUNING as u16, MSP_RC_TUNING as u16)
+            .setter(MSP_SET_MOTOR_CONFIG as u16, MSP_MOTOR_CONFIG as u16)
+    }
+
+    #[test]
+    fn capture_skips_unsupported() {
+        let mut fc = mock_fc();
+        let snapshot = ConfigSnapshot::capture(&mut fc).unwrap();
+
+        assert_eq!(FirmwareVariant::Betaflight, snapshot.firmware.variant);
+        assert_eq!(100, snapshot.rc_tuning.unwrap().rc_rate8);
+        assert_eq!(1100, snapshot.motor_config.unwrap().min_throttle);
+        assert!(snapshot.filter_config.is_none());
+    }
+
+    #[test]
+    fn json_and_toml_roundtrip() {
+        let mut fc = mock_fc();
+        let snapshot = ConfigSnapshot::capture(&mut fc).unwrap();
+
+        let json = ConfigSnapshot::from_json(&snapshot.to_json().unwrap()).unwrap();
+        assert_eq!(snapshot.writes().unwrap(), json.writes().unwrap());
+
+        let toml = ConfigSnapshot::from_toml(&snapshot.to_toml().unwrap()).unwrap();
+        assert_eq!(snapshot.writes().unwrap(), toml.writes().unwrap());
+    }
+
+    #[test]
+    fn restore_writes_verifies_and_saves() {
+        let mut fc = mock_fc();
+        let mut snapshot = ConfigSnapshot::capture(&mut fc).unwrap();
+        snapshot.motor_config.as_mut().unwrap().min_throttle = 1070;
+        snapshot.restore(&mut fc).unwrap();
+
+        let cmds = fc.commands();
+        let set_motor = cmds.iter().position(|&c| c == MSP_SET_MOTOR_CONFIG as u16).unwrap();
+        let set_rates = cmds.iter().position(|&c| c == MSP_SET_RC_TUNING as u16).unwrap();
+        assert!(set_motor < set_rates);
+        assert_eq!(Some(&(MSP_EEPROM_WRITE as u16)), cmds.last());
+        assert_eq!(Some(&[0x2E, 0x04, 0xD0, 0x07, 0xE8, 0x03][..]), fc.value(MSP_MOTOR_CONFIG as u16));
+    }
+
+    #[test]
+    fn restore_refuses_other_firmware() {
+        let mut fc = mock_fc();
+        let mut snapshot = ConfigSnapshot::capture(&mut fc).unwrap();
+        snapshot.firmware.variant = FirmwareVariant::Inav;
+
+        assert!(snapshot.restore(&mut fc).is_err());
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+    }
+}
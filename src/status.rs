@@ -0,0 +1,227 @@
+//! Armed state and arming-disable flags from `MSP_STATUS_EX`, or `MSP2_INAV_STATUS` on iNav
+
+use std::fmt;
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{commands::MspCommandCode, structs::MspStatusEx};
+
+/// Betaflight arming-disable flag names, by bit
+pub const ARMING_DISABLE_NAMES: [&str; 26] = [
+    "NOGYRO", "FAILSAFE", "RXLOSS", "NOT_DISARMED", "BOXFAILSAFE", "RUNAWAY", "CRASH", "THROTTLE",
+    "ANGLE", "BOOTGRACE", "NOPREARM", "LOAD", "CALIB", "CLI", "CMS", "BST", "MSP", "PARALYZE",
+    "GPS", "RESCUE_SW", "RPMFILTER", "REBOOT_REQD", "DSHOT_BBANG", "NO_ACC_CAL", "MOTOR_PROTO",
+    "ARMSWITCH",
+];
+
+/// iNav `armingFlag_e` reasons with a Betaflight counterpart, as (iNav bit, Betaflight bit)
+const INAV_ARMING_DISABLED: [(u32, u32); 15] = [
+    (7, 1), (8, 8), (9, 12), (10, 11), (11, 18), (13, 23), (14, 25), (15, 0), (16, 4), (18, 2), (19, 7),
+    (20, 13), (21, 14), (22, 14), (28, 10),
+];
+const INAV_ARMED: u32 = 1 << 2;
+/// iNav has three config profiles, each with its own PIDs and rates
+const INAV_PROFILE_COUNT: u8 = 3;
+
+/// Reasons the flight controller refuses to arm, one bit each as numbered by Betaflight
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct ArmingDisabled(pub u32);
+
+impl ArmingDisabled {
+    /// Failsafe is active
+    pub const FAILSAFE: u32 = 1 << 1;
+    /// No valid receiver signal
+    pub const RX_LOSS: u32 = 1 << 2;
+    pub const CALIBRATING: u32 = 1 << 12;
+    pub const CLI: u32 = 1 << 13;
+    pub const MSP: u32 = 1 << 16;
+    pub const ARM_SWITCH: u32 = 1 << 25;
+
+    pub fn contains(self, bits: u32) -> bool {
+        self.0 & bits == bits
+    }
+
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// Names of the set flags, `BIT<n>` for ones newer than this table
+    pub fn names(self) -> Vec<String> {
+        (0..32)
+            .filter(|bit| self.0 & (1 << bit) != 0)
+            .map(|bit| ARMING_DISABLE_NAMES.get(bit).map_or_else(|| format!("BIT{}", bit), |n| n.to_string()))
+            .collect()
+    }
+}
+
+impl fmt::Display for ArmingDisabled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_empty() {
+            f.write_str("none")
+        } else {
+            f.write_str(&self.names().join(" "))
+        }
+    }
+}
+
+/// `MSP_STATUS_EX` together with the variable length tail Betaflight appends to it. On iNav
+/// the same fields come from `MSP2_INAV_STATUS`, the only status with all arming flags.
+#[derive(Debug, Copy, Clone)]
+pub struct FcStatus {
+    pub status: MspStatusEx,
+    pub armed: bool,
+    /// iNav reasons without a Betaflight counterpart are only in [`FcStatus::arming_flags`]
+    pub arming_disabled: ArmingDisabled,
+    /// Arming flags as the firmware sent them
+    pub arming_flags: u32,
+    pub reboot_required: bool,
+}
+
+impl FcStatus {
+    /// Size of the fixed [`MspStatusEx`] head
+    const HEAD: usize = 15;
+
+    pub fn decode(data: &[u8], variant: &FirmwareVariant) -> Result<FcStatus> {
+        if *variant == FirmwareVariant::Inav {
+            return Self::decode_inav(data);
+        }
+        let short = || Error::msg(format!("MSP_STATUS_EX payload too short, {} bytes", data.len()));
+        let head = data.get(..Self::HEAD).ok_or_else(short)?;
+        let status = MspStatusEx::unpack_from_slice(head)?;
+
+        // extra flight mode bytes, then the arming-disable flag count, flags and config state
+        let mut rest = &data[Self::HEAD..];
+        let (arming_disabled, reboot_required) = match rest.split_first() {
+            Some((&extra, tail)) => {
+                rest = tail.get(extra as usize..).ok_or_else(short)?;
+                let [_count, a, b, c, d, tail @ ..] = rest else {
+                    return Err(short());
+                };
+                let flags = u32::from_le_bytes([*a, *b, *c, *d]);
+                (flags, tail.first().is_some_and(|s| s & 0x01 != 0))
+            }
+            // pre 3.4 firmware ends after the profile indexes
+            None => (0, false),
+        };
+
+        Ok(FcStatus {
+            // the ARM box is the first flight mode bit
+            armed: status.flight_mode & 0x01 != 0,
+            status,
+            arming_disabled: ArmingDisabled(arming_disabled),
+            arming_flags: arming_disabled,
+            reboot_required,
+        })
+    }
+
+    /// Cycle time, I2C errors, sensors and system load as u16, the battery and config profile
+    /// packed in a byte, u32 arming flags, then the box mode flags and mixer profile
+    fn decode_inav(data: &[u8]) -> Result<FcStatus> {
+        if data.len() < 13 {
+            return Err(Error::msg(format!("MSP2_INAV_STATUS payload too short, {} bytes", data.len())));
+        }
+        let u32_at = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]]);
+        let flags = u32_at(9);
+        let flight_mode = if data.len() >= 17 { u32_at(13) } else { 0 };
+        let profile = data[8] & 0x0F;
+
+        let mut head = Vec::with_capacity(Self::HEAD);
+        head.extend_from_slice(&data[..6]);
+        head.extend(flight_mode.to_le_bytes());
+        head.push(profile);
+        head.extend_from_slice(&data[6..8]);
+        head.extend([INAV_PROFILE_COUNT, profile]);
+
+        let arming_disabled = INAV_ARMING_DISABLED
+            .iter()
+            .filter(|(inav, _)| flags & (1 << inav) != 0)
+            .fold(0, |bits, (_, betaflight)| bits | (1 << betaflight));
+        Ok(FcStatus {
+            status: MspStatusEx::unpack_from_slice(&head)?,
+            armed: flags & INAV_ARMED != 0,
+            arming_disabled: ArmingDisabled(arming_disabled),
+            arming_flags: flags,
+            reboot_required: false,
+        })
+    }
+
+    pub fn read(port: &mut dyn SerialPort, firmware: &FirmwareInfo) -> Result<FcStatus> {
+        let cmd = match firmware.variant {
+            FirmwareVariant::Inav => MspCommandCode::MSP2_INAV_STATUS,
+            _ => MspCommandCode::MSP_STATUS_EX,
+        };
+        Self::decode(request(port, cmd as u16, &[])?.data.as_slice(), &firmware.variant)
+    }
+}
+
+#[cfg(test)]
+pub(crate) fn status_ex_payload(flight_mode: u32, arming_disabled: u32) -> Vec<u8> {
+    let mut payload = vec![0xF4, 0x01, 0, 0, 0x23, 0, 0, 0, 0, 0, 0, 0x0A, 0, 3, 0];
+    payload[6..10].copy_from_slice(&flight_mode.to_le_bytes());
+    payload.extend([0, 26]);
+    payload.extend(arming_disabled.to_le_bytes());
+    payload.push(0);
+    payload
+}
+
+/// `MSP2_INAV_STATUS` in config profile `profile` with two words of box mode flags
+#[cfg(test)]
+pub(crate) fn inav_status_payload(flight_mode: u32, arming_flags: u32, profile: u8) -> Vec<u8> {
+    let mut payload = vec![0xE8, 0x03, 0, 0, 0x23, 0, 0x0F, 0, 0x10 | profile];
+    payload.extend(arming_flags.to_le_bytes());
+    payload.extend(flight_mode.to_le_bytes());
+    payload.extend([0; 5]);
+    payload
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const BF: FirmwareVariant = FirmwareVariant::Betaflight;
+
+    #[test]
+    fn decode_arming_disable_flags() {
+        let status = FcStatus::decode(&status_ex_payload(0, (1 << 12) | (1 << 16) | (1 << 30)), &BF).unwrap();
+        assert!(!status.armed);
+        assert_eq!(500, status.status.cycle_time);
+        assert!(status.arming_disabled.contains(ArmingDisabled::CALIBRATING | ArmingDisabled::MSP));
+        assert_eq!("CALIB MSP BIT30", status.arming_disabled.to_string());
+
+        let mut payload = status_ex_payload(1, 0);
+        payload[15] = 2;
+        payload.splice(16..16, [0, 0]);
+        *payload.last_mut().unwrap() = 1;
+        let status = FcStatus::decode(&payload, &BF).unwrap();
+        assert!(status.armed && status.reboot_required && status.arming_disabled.is_empty());
+
+        assert!(FcStatus::decode(&payload[..15], &BF).unwrap().arming_disabled.is_empty());
+        assert!(FcStatus::decode(&payload[..18], &BF).is_err());
+    }
+
+    #[test]
+    fn decode_inav_status() {
+        // sensors calibrating, arm switch on and RC link lost, plus reasons Betaflight lacks
+        let flags = (1 << 9) | (1 << 14) | (1 << 18) | (1 << 12) | (1 << 23);
+        let status = FcStatus::decode(&inav_status_payload(0, flags, 2), &FirmwareVariant::Inav).unwrap();
+        assert!(!status.armed);
+        assert_eq!(1000, status.status.cycle_time);
+        assert_eq!((2, 3, 2), (
+            status.status.current_pid_profile_index,
+            status.status.max_profile_count,
+            status.status.current_control_rate_profile_index
+        ));
+        assert_eq!("RXLOSS CALIB ARMSWITCH", status.arming_disabled.to_string());
+        assert_eq!(flags, status.arming_flags);
+
+        // armed comes from the arming flags, the box modes follow them
+        let status = FcStatus::decode(&inav_status_payload(1, 1 << 2, 0), &FirmwareVariant::Inav).unwrap();
+        assert!(status.armed && status.arming_disabled.is_empty());
+        assert_eq!(1, status.status.flight_mode);
+        assert!(FcStatus::decode(&inav_status_payload(0, 0, 0)[..12], &FirmwareVariant::Inav).is_err());
+    }
+}
@@ -0,0 +1,431 @@
+//! PID and rate profiles: named P/I/D/F gains, profile selection and copying, and the rate
+//! curves of every Betaflight rates type
+
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serde::{Deserialize, Serialize};
+use serialport::SerialPort;
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::helpers::request;
+use crate::msp::{commands::MspCommandCode, structs::MspRcTuning};
+use crate::status::FcStatus;
+
+/// Rate profiles of Betaflight 4.x
+pub const RATE_PROFILE_COUNT: u8 = 6;
+/// Highest rotation rate the firmware commands [deg/s]
+pub const SETPOINT_RATE_LIMIT: f32 = 1998.0;
+/// Offset of the roll, pitch and yaw feedforward gains in `MSP_PID_ADVANCED`
+const FEEDFORWARD_OFFSET: usize = 32;
+/// `MSP_SELECT_SETTING` bit that selects a rate profile instead of a PID profile
+const SELECT_RATE_PROFILE: u8 = 0x80;
+
+/// Gains of one `MSP_PIDNAMES` entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Pid {
+    pub name: String,
+    pub p: u8,
+    pub i: u8,
+    pub d: u8,
+    /// Feedforward, roll, pitch and yaw only on firmware that sends it
+    pub f: Option<u16>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub enum RatesType {
+    Betaflight,
+    Raceflight,
+    Kiss,
+    Actual,
+    Quick,
+}
+
+impl RatesType {
+    pub fn from_u8(value: u8) -> Option<RatesType> {
+        match value {
+            0 => Some(RatesType::Betaflight),
+            1 => Some(RatesType::Raceflight),
+            2 => Some(RatesType::Kiss),
+            3 => Some(RatesType::Actual),
+            4 => Some(RatesType::Quick),
+            _ => None,
+        }
+    }
+}
+
+/// Raw rate settings of one axis. What they mean depends on the [`RatesType`], e.g. Actual
+/// rates keep the center sensitivity in `rc_rate` and the max rate in `rate`, both / 10.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct AxisRates {
+    pub rc_rate: u8,
+    pub expo: u8,
+    pub rate: u8,
+}
+
+/// The rate profile of `MSP_RC_TUNING`, with the fields newer firmware appends to the legacy
+/// [`MspRcTuning`] layout
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct RateProfile {
+    pub rates_type: RatesType,
+    /// Roll, pitch, yaw
+    pub axes: [AxisRates; 3],
+    /// Roll, pitch, yaw [deg/s]
+    pub rate_limits: [u16; 3],
+}
+
+impl RateProfile {
+    /// Older firmware shares roll rates with pitch, has no rate limits and only Betaflight
+    /// rates
+    pub fn decode(data: &[u8]) -> Result<RateProfile> {
+        let head = data
+            .get(..12)
+            .ok_or_else(|| Error::msg(format!("MSP_RC_TUNING payload too short, {} bytes", data.len())))?;
+        let legacy = MspRcTuning::unpack_from_slice(head)?;
+        let roll = AxisRates { rc_rate: legacy.rc_rate8, expo: legacy.rc_expo8, rate: legacy.rate_roll };
+        let pitch = match data.get(12..14) {
+            Some(b) => AxisRates { rc_rate: b[0], expo: b[1], rate: legacy.rate_pitch },
+            None => AxisRates { rate: legacy.rate_pitch, ..roll },
+        };
+        let yaw = AxisRates { rc_rate: legacy.rc_yaw_rate8, expo: legacy.rc_yaw_expo8, rate: legacy.rate_yaw };
+
+        // throttle limit type and percent, then the rate limits and the rates type
+        let u16_at = |at: usize| data.get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let rate_limits = match (u16_at(16), u16_at(18), u16_at(20)) {
+            (Some(r), Some(p), Some(y)) => [r, p, y],
+            _ => [SETPOINT_RATE_LIMIT as u16; 3],
+        };
+        let rates_type = match data.get(22) {
+            Some(&t) => RatesType::from_u8(t).ok_or_else(|| Error::msg(format!("Unknown rates type {}", t)))?,
+            None => RatesType::Betaflight,
+        };
+        Ok(RateProfile { rates_type, axes: [roll, pitch, yaw], rate_limits })
+    }
+
+    /// Rotation rate at stick deflection `stick`, -1 to 1 [deg/s]
+    pub fn rate(&self, axis: usize, stick: f32) -> f32 {
+        let rates = self.axes[axis];
+        let (x, abs) = (stick.clamp(-1.0, 1.0), stick.abs().min(1.0));
+        let (rc_rate, expo, rate) = (rates.rc_rate as f32, rates.expo as f32 / 100.0, rates.rate as f32);
+        let super_factor = |v: f32| 1.0 / (1.0 - v).clamp(0.01, 1.0);
+
+        let angle_rate = match self.rates_type {
+            RatesType::Betaflight => {
+                let curved = x * abs.powi(3) * expo + x * (1.0 - expo);
+                let mut rc_rate = rc_rate / 100.0;
+                if rc_rate > 2.0 {
+                    rc_rate += 14.54 * (rc_rate - 2.0);
+                }
+                200.0 * rc_rate * curved * super_factor(abs * rate / 100.0)
+            }
+            RatesType::Raceflight => {
+                let curved = (1.0 + expo * (x * x - 1.0)) * x;
+                10.0 * rc_rate * curved * (1.0 + abs * rate / 100.0)
+            }
+            RatesType::Kiss => {
+                let curved = (x.powi(3) * expo + x * (1.0 - expo)) * rc_rate / 1000.0;
+                2000.0 * super_factor(abs * rate / 100.0) * curved
+            }
+            RatesType::Actual => {
+                let curved = abs * (x.powi(5) * expo + x * (1.0 - expo));
+                let center = rc_rate * 10.0;
+                x * center + (rate * 10.0 - center).max(0.0) * curved
+            }
+            RatesType::Quick => {
+                let rc_rate = rc_rate * 2.0;
+                let max = (rate * 10.0).max(rc_rate);
+                let config = (max / rc_rate - 1.0) / (max / rc_rate);
+                let curved = abs.powi(3) * expo + abs * (1.0 - expo);
+                x * rc_rate * super_factor(curved * config)
+            }
+        };
+        let limit = (self.rate_limits[axis] as f32).min(SETPOINT_RATE_LIMIT);
+        angle_rate.clamp(-limit, limit)
+    }
+
+    /// Rate at full stick of roll, pitch and yaw [deg/s]
+    pub fn max_rates(&self) -> [f32; 3] {
+        [0, 1, 2].map(|axis| self.rate(axis, 1.0))
+    }
+
+    /// `points` evenly spaced samples of an axis from center to full stick, as (stick, deg/s)
+    pub fn curve(&self, axis: usize, points: usize) -> Vec<(f32, f32)> {
+        let steps = points.max(2) - 1;
+        (0..=steps)
+            .map(|i| {
+                let stick = i as f32 / steps as f32;
+                (stick, self.rate(axis, stick))
+            })
+            .collect()
+    }
+}
+
+/// PID and rate profiles of a flight controller. Switching and copying profiles is refused
+/// while armed. iNav keeps its rates in the config profile, selected as the PID profile, so
+/// the rate profile calls are refused there.
+pub struct Tuning<'a> {
+    port: &'a mut dyn SerialPort,
+    firmware: FirmwareInfo,
+    pub pid_profile: u8,
+    pub pid_profile_count: u8,
+    pub rate_profile: u8,
+}
+
+impl<'a> Tuning<'a> {
+    pub fn load(port: &'a mut dyn SerialPort, firmware: &FirmwareInfo) -> Result<Tuning<'a>> {
+        let status = FcStatus::read(port, firmware)?.status;
+        Ok(Tuning {
+            port,
+            firmware: firmware.clone(),
+            pid_profile: status.current_pid_profile_index,
+            pid_profile_count: status.max_profile_count,
+            rate_profile: status.current_control_rate_profile_index,
+        })
+    }
+
+    /// Gains of the current PID profile, named from `MSP_PIDNAMES`
+    pub fn pids(&mut self) -> Result<Vec<Pid>> {
+        let names = request(self.port, MspCommandCode::MSP_PIDNAMES as u16, &[])?;
+        let names = String::from_utf8_lossy(names.data.as_slice()).into_owned();
+        let gains = request(self.port, MspCommandCode::MSP_PID as u16, &[])?;
+        let advanced = request(self.port, MspCommandCode::MSP_PID_ADVANCED as u16, &[])?;
+        let feedforward = |axis: usize| {
+            let at = FEEDFORWARD_OFFSET + 2 * axis;
+            advanced.data.as_slice().get(at..at + 2).map(|b| u16::from_le_bytes([b[0], b[1]]))
+        };
+
+        Ok(names
+            .split(';')
+            .filter(|n| !n.is_empty())
+            .zip(gains.data.as_slice().chunks_exact(3))
+            .enumerate()
+            .map(|(i, (name, pid))| Pid {
+                name: name.to_owned(),
+                p: pid[0],
+                i: pid[1],
+                d: pid[2],
+                f: (i < 3).then(|| feedforward(i)).flatten(),
+            })
+            .collect())
+    }
+
+    pub fn rates(&mut self) -> Result<RateProfile> {
+        self.check_rate_profiles()?;
+        RateProfile::decode(request(self.port, MspCommandCode::MSP_RC_TUNING as u16, &[])?.data.as_slice())
+    }
+
+    /// Make PID profile `index`, 0 based, the current one
+    pub fn select_pid_profile(&mut self, index: u8) -> Result<()> {
+        if index >= self.pid_profile_count {
+            return Err(Error::msg(format!("No PID profile {}, there are {}", index, self.pid_profile_count)));
+        }
+        self.select(index)?;
+        if self.pid_profile != index {
+            return Err(Error::msg(format!("PID profile is still {}", self.pid_profile)));
+        }
+        Ok(())
+    }
+
+    /// Make rate profile `index`, 0 based, the current one
+    pub fn select_rate_profile(&mut self, index: u8) -> Result<()> {
+        self.check_rate_profiles()?;
+        if index >= RATE_PROFILE_COUNT {
+            return Err(Error::msg(format!("No rate profile {}, there are {}", index, RATE_PROFILE_COUNT)));
+        }
+        self.select(index | SELECT_RATE_PROFILE)?;
+        if self.rate_profile != index {
+            return Err(Error::msg(format!("Rate profile is still {}", self.rate_profile)));
+        }
+        Ok(())
+    }
+
+    /// Overwrite PID profile `to` with `from`
+    pub fn copy_pid_profile(&mut self, from: u8, to: u8) -> Result<()> {
+        let count = self.pid_profile_count;
+        self.copy(0, from, to, count)
+    }
+
+    /// Overwrite rate profile `to` with `from`
+    pub fn copy_rate_profile(&mut self, from: u8, to: u8) -> Result<()> {
+        self.check_rate_profiles()?;
+        self.copy(1, from, to, RATE_PROFILE_COUNT)
+    }
+
+    /// Reset the current PID profile to the firmware defaults
+    pub fn reset_pid_profile(&mut self) -> Result<()> {
+        self.check_disarmed()?;
+        request(self.port, MspCommandCode::MSP_SET_RESET_CURR_PID as u16, &[])?;
+        Ok(())
+    }
+
+    /// Store the profiles and the selection in EEPROM
+    pub fn save(&mut self) -> Result<()> {
+        request(self.port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        Ok(())
+    }
+
+    fn select(&mut self, setting: u8) -> Result<()> {
+        self.check_disarmed()?;
+        request(self.port, MspCommandCode::MSP_SELECT_SETTING as u16, &[setting])?;
+        let status = FcStatus::read(self.port, &self.firmware)?.status;
+        (self.pid_profile, self.rate_profile) =
+            (status.current_pid_profile_index, status.current_control_rate_profile_index);
+        Ok(())
+    }
+
+    fn copy(&mut self, kind: u8, from: u8, to: u8, count: u8) -> Result<()> {
+        if from >= count || to >= count || from == to {
+            return Err(Error::msg(format!("Cannot copy profile {} to {} of {}", from, to, count)));
+        }
+        self.check_disarmed()?;
+        request(self.port, MspCommandCode::MSP_COPY_PROFILE as u16, &[kind, to, from])?;
+        Ok(())
+    }
+
+    /// iNav selects its config profile for any `MSP_SELECT_SETTING` index, clamps unknown
+    /// ones to 0 and saves the choice, and sends its own `MSP_RC_TUNING` layout
+    fn check_rate_profiles(&self) -> Result<()> {
+        if self.firmware.variant == FirmwareVariant::Inav {
+            return Err(Error::msg("INAV has no rate profiles, its rates follow the PID profile"));
+        }
+        Ok(())
+    }
+
+    fn check_disarmed(&mut self) -> Result<()> {
+        if FcStatus::read(self.port, &self.firmware)?.armed {
+            return Err(Error::msg("Cannot change profiles while armed"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use crate::msp::commands::MspCommandCode::*;
+    use crate::firmware::{FirmwareVariant, test_firmware};
+    use crate::status::{inav_status_payload, status_ex_payload};
+
+    fn profile(rates_type: RatesType, rc_rate: u8, expo: u8, rate: u8) -> RateProfile {
+        RateProfile {
+            rates_type,
+            axes: [AxisRates { rc_rate, expo, rate }; 3],
+            rate_limits: [1998; 3],
+        }
+    }
+
+    #[test]
+    fn rate_curves() {
+        // Betaflight defaults before 4.2: 1.0 rc rate, 0.7 super rate
+        let betaflight = profile(RatesType::Betaflight, 100, 0, 70);
+        assert!((betaflight.max_rates()[0] - 666.67).abs() < 0.01);
+        assert_eq!(0.0, betaflight.rate(0, 0.0));
+        assert_eq!(-betaflight.rate(0, 0.5), betaflight.rate(0, -0.5));
+
+        // Actual: 70 center sensitivity, 670 max rate
+        let actual = profile(RatesType::Actual, 7, 0, 67);
+        assert!((actual.max_rates()[1] - 670.0).abs() < 1e-3);
+        assert!((actual.curve(1, 5)[2].1 - 0.5 * 70.0 - 600.0 * 0.25).abs() < 1e-3);
+
+        assert!((profile(RatesType::Kiss, 100, 0, 70).max_rates()[2] - 666.67).abs() < 0.01);
+        let quick = profile(RatesType::Quick, 100, 0, 67);
+        assert!((quick.max_rates()[0] - 670.0).abs() < 0.01);
+        assert!((quick.rate(0, 0.01) - 2.0).abs() < 0.05);
+        assert_eq!(SETPOINT_RATE_LIMIT, profile(RatesType::Betaflight, 255, 0, 100).max_rates()[0]);
+
+        // legacy layout, pitch shares the roll rates
+        let legacy = RateProfile::decode(&[100, 10, 70, 60, 50, 0, 50, 0, 0xDC, 0x05, 0, 120]).unwrap();
+        assert_eq!(RatesType::Betaflight, legacy.rates_type);
+        assert_eq!(AxisRates { rc_rate: 100, expo: 10, rate: 60 }, legacy.axes[1]);
+        assert_eq!(AxisRates { rc_rate: 120, expo: 0, rate: 50 }, legacy.axes[2]);
+        let mut current = vec![7, 0, 67, 67, 50, 0, 50, 0, 0, 0, 0, 7, 7, 0, 0, 100];
+        current.extend([0xE8, 0x03, 0xE8, 0x03, 0xE8, 0x03, 3]);
+        let current = RateProfile::decode(&current).unwrap();
+        assert_eq!((RatesType::Actual, [1000; 3]), (current.rates_type, current.rate_limits));
+        assert_eq!(670.0, current.max_rates()[0]);
+    }
+
+    #[test]
+    fn pids_and_profiles() {
+        let mut advanced = vec![0u8; 32];
+        advanced.extend([90, 0, 95, 0, 0, 0, 1]);
+        let (mut pid_profile, mut rate_profile) = (0, 0);
+        let mut fc = MockFc::new()
+            .reply(MSP_PIDNAMES as u16, b"ROLL;PITCH;YAW;LEVEL;MAG;")
+            .reply(MSP_PID as u16, &[45, 80, 40, 47, 84, 46, 45, 80, 0, 50, 50, 75, 40, 0, 0])
+            .reply(MSP_PID_ADVANCED as u16, &advanced)
+            .reply(MSP_COPY_PROFILE as u16, &[])
+            .handler(move |p| match p.cmd {
+                c if c == MSP_SELECT_SETTING as u16 => {
+                    let setting = p.data.as_slice()[0];
+                    match setting & 0x80 {
+                        0 => pid_profile = setting,
+                        _ => rate_profile = setting & 0x7F,
+                    }
+                    Some(Vec::new())
+                }
+                c if c == MSP_STATUS_EX as u16 => {
+                    let mut status = status_ex_payload(0, 0);
+                    (status[10], status[14]) = (pid_profile, rate_profile);
+                    Some(status)
+                }
+                _ => None,
+            });
+
+        let mut tuning = Tuning::load(&mut fc, &test_firmware(FirmwareVariant::Betaflight, 46)).unwrap();
+        assert_eq!((0, 3, 0), (tuning.pid_profile, tuning.pid_profile_count, tuning.rate_profile));
+        let pids = tuning.pids().unwrap();
+        assert_eq!(5, pids.len());
+        assert_eq!(Pid { name: "PITCH".into(), p: 47, i: 84, d: 46, f: Some(95) }, pids[1]);
+        assert_eq!((Some(0), None), (pids[2].f, pids[3].f));
+
+        tuning.select_pid_profile(2).unwrap();
+        tuning.select_rate_profile(4).unwrap();
+        assert_eq!((2, 4), (tuning.pid_profile, tuning.rate_profile));
+        assert!(tuning.select_pid_profile(3).is_err());
+
+        tuning.copy_rate_profile(4, 5).unwrap();
+        assert!(tuning.copy_pid_profile(1, 1).is_err());
+        assert_eq!(Some(&[1, 5, 4][..]), fc.requests.last().map(|p| p.data.as_slice()));
+
+        // iNav has one config profile for PIDs and rates, and no room in MSP_STATUS_EX for the count
+        let mut fc = MockFc::new().reply(MSP2_INAV_STATUS as u16, &inav_status_payload(0, 0, 1));
+        let mut tuning = Tuning::load(&mut fc, &test_firmware(FirmwareVariant::Inav, 5)).unwrap();
+        assert_eq!((1, 3, 1), (tuning.pid_profile, tuning.pid_profile_count, tuning.rate_profile));
+        // its MSP_SELECT_SETTING would switch to and save config profile 0
+        assert!(tuning.select_rate_profile(2).is_err());
+        assert!(tuning.copy_rate_profile(0, 2).is_err());
+        assert!(tuning.rates().is_err());
+        assert_eq!(vec![MSP2_INAV_STATUS as u16], fc.commands());
+    }
+
+    #[test]
+    fn refused_while_armed_or_not_switched() {
+        let firmware = test_firmware(FirmwareVariant::Betaflight, 46);
+        let changes = [MSP_SELECT_SETTING as u16, MSP_COPY_PROFILE as u16, MSP_SET_RESET_CURR_PID as u16];
+        let mut fc = MockFc::new()
+            .reply(MSP_STATUS_EX as u16, &status_ex_payload(1, 0))
+            .reply(MSP_SELECT_SETTING as u16, &[])
+            .reply(MSP_COPY_PROFILE as u16, &[])
+            .reply(MSP_SET_RESET_CURR_PID as u16, &[]);
+        let mut tuning = Tuning::load(&mut fc, &firmware).unwrap();
+        assert_eq!("Cannot change profiles while armed", tuning.select_pid_profile(1).unwrap_err().to_string());
+        assert!(tuning.copy_rate_profile(0, 1).is_err());
+        assert!(tuning.reset_pid_profile().is_err());
+        assert!(!fc.commands().iter().any(|c| changes.contains(c)));
+
+        // the iNav armed flag counts the same
+        let mut fc = MockFc::new().reply(MSP2_INAV_STATUS as u16, &inav_status_payload(0, 1 << 2, 0));
+        let mut tuning = Tuning::load(&mut fc, &test_firmware(FirmwareVariant::Inav, 5)).unwrap();
+        assert!(tuning.select_pid_profile(1).is_err());
+        assert!(!fc.commands().iter().any(|c| changes.contains(c)));
+
+        // a selection that does not take effect
+        let mut fc = MockFc::new()
+            .reply(MSP_STATUS_EX as u16, &status_ex_payload(0, 0))
+            .reply(MSP_SELECT_SETTING as u16, &[]);
+        let mut tuning = Tuning::load(&mut fc, &firmware).unwrap();
+        assert_eq!("PID profile is still 0", tuning.select_pid_profile(1).unwrap_err().to_string());
+        assert_eq!("Rate profile is still 0", tuning.select_rate_profile(2).unwrap_err().to_string());
+    }
+}
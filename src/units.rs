@@ -0,0 +1,244 @@
+//! SI unit newtypes and `to_si` conversions for telemetry structs that carry firmware units
+
+use std::fmt;
+use serde::{Deserialize, Serialize};
+
+use crate::firmware::{FirmwareInfo, FirmwareVariant};
+use crate::msp::structs::{MspAltitude, MspAnalog, MspAttitude, MspBatteryState, MspRawImu};
+
+/// Standard gravity [m/s^2]
+pub const STANDARD_GRAVITY: f32 = 9.806_65;
+
+macro_rules! unit {
+    ($(#[$doc:meta])* $name:ident, $symbol:literal) => {
+        $(#[$doc])*
+        #[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq, PartialOrd, Default)]
+        pub struct $name(pub f32);
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                match f.precision() {
+                    Some(p) => write!(f, "{:.*} {}", p, self.0, $symbol),
+                    None => write!(f, "{} {}", self.0, $symbol),
+                }
+            }
+        }
+    };
+}
+
+unit!(Volts, "V");
+unit!(Amperes, "A");
+unit!(
+    /// Battery charge, kept in Ah rather than coulombs as every charger and pack label uses it
+    AmpHours,
+    "Ah"
+);
+unit!(Meters, "m");
+unit!(MetersPerSecond, "m/s");
+unit!(MetersPerSecondSquared, "m/s²");
+unit!(Radians, "rad");
+unit!(RadiansPerSecond, "rad/s");
+
+impl MetersPerSecondSquared {
+    pub fn to_g(self) -> f32 {
+        self.0 / STANDARD_GRAVITY
+    }
+}
+
+impl Radians {
+    pub fn from_degrees(degrees: f32) -> Radians {
+        Radians(degrees.to_radians())
+    }
+
+    pub fn to_degrees(self) -> f32 {
+        self.0.to_degrees()
+    }
+}
+
+impl RadiansPerSecond {
+    pub fn from_degrees(degrees: f32) -> RadiansPerSecond {
+        RadiansPerSecond(degrees.to_radians())
+    }
+
+    pub fn to_degrees(self) -> f32 {
+        self.0.to_degrees()
+    }
+}
+
+/// How `MSP_RAW_IMU` counts map to physical values. The scaling depends on the firmware only,
+/// not on the sensor: Betaflight rescales every accelerometer to 512 per G and iNav sends its
+/// acceleration in G times 512, both send the gyro in deg/s. Older MultiWii style firmware
+/// sends raw gyro counts at 16.4 LSB per deg/s divided by 4.
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct ImuScale {
+    /// Accelerometer reading at 1 G
+    pub acc_1g: f32,
+    /// Gyro reading per deg/s
+    pub gyro_per_dps: f32,
+}
+
+impl ImuScale {
+    pub fn for_firmware(firmware: &FirmwareInfo) -> ImuScale {
+        match firmware.variant {
+            FirmwareVariant::Betaflight | FirmwareVariant::Inav => ImuScale { acc_1g: 512.0, gyro_per_dps: 1.0 },
+            _ => ImuScale { acc_1g: 512.0, gyro_per_dps: 16.4 / 4.0 },
+        }
+    }
+
+    /// Override the 1 G value, for firmware other than Betaflight and iNav that sends the raw
+    /// sensor counts
+    pub fn with_acc_1g(mut self, acc_1g: f32) -> Self {
+        self.acc_1g = acc_1g;
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Imu {
+    pub acc: [MetersPerSecondSquared; 3],
+    pub gyro: [RadiansPerSecond; 3],
+    /// Unscaled, the firmware does not say which magnetometer units it sends
+    pub mag: [f32; 3],
+}
+
+impl MspRawImu {
+    pub fn to_si(&self, scale: &ImuScale) -> Imu {
+        let acc = |raw: i16| MetersPerSecondSquared(raw as f32 / scale.acc_1g * STANDARD_GRAVITY);
+        let gyro = |raw: i16| RadiansPerSecond::from_degrees(raw as f32 / scale.gyro_per_dps);
+        Imu {
+            acc: [acc(self.acc_x), acc(self.acc_y), acc(self.acc_z)],
+            gyro: [gyro(self.gyro_x), gyro(self.gyro_y), gyro(self.gyro_z)],
+            mag: [self.mag_x as f32, self.mag_y as f32, self.mag_z as f32],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Attitude {
+    pub roll: Radians,
+    pub pitch: Radians,
+    /// Heading, 0 north
+    pub yaw: Radians,
+}
+
+impl MspAttitude {
+    /// Roll and pitch come in decidegrees, yaw in whole degrees
+    pub fn to_si(&self) -> Attitude {
+        Attitude {
+            roll: Radians::from_degrees(self.roll as f32 / 10.0),
+            pitch: Radians::from_degrees(self.pitch as f32 / 10.0),
+            yaw: Radians::from_degrees(self.yaw as f32),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Altitude {
+    /// Above the arming point
+    pub altitude: Meters,
+    pub vario: MetersPerSecond,
+}
+
+impl MspAltitude {
+    pub fn to_si(&self) -> Altitude {
+        Altitude {
+            altitude: Meters(self.altitude as f32 / 100.0),
+            vario: MetersPerSecond(self.vario as f32 / 100.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct Analog {
+    pub voltage: Volts,
+    pub drawn: AmpHours,
+    /// 0 to 1
+    pub rssi: f32,
+    pub current: Amperes,
+}
+
+impl MspAnalog {
+    pub fn to_si(&self) -> Analog {
+        Analog {
+            voltage: Volts(self.battery_voltage as f32 / 10.0),
+            drawn: AmpHours(self.mah_drawn as f32 / 1000.0),
+            rssi: self.rssi as f32 / 1023.0,
+            current: Amperes(self.amperage as f32 / 100.0),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Copy, Clone, PartialEq)]
+pub struct BatteryState {
+    pub cells: u8,
+    pub capacity: AmpHours,
+    pub voltage: Volts,
+    pub drawn: AmpHours,
+    pub current: Amperes,
+    /// `None` until the flight controller has detected the cell count
+    pub cell_voltage: Option<Volts>,
+}
+
+impl MspBatteryState {
+    pub fn to_si(&self) -> BatteryState {
+        BatteryState {
+            cells: self.battery_cell_count,
+            capacity: AmpHours(self.battery_capacity as f32 / 1000.0),
+            voltage: Volts(self.battery_voltage as f32 / 10.0),
+            drawn: AmpHours(self.mah_drawn as f32 / 1000.0),
+            current: Amperes(self.amperage as f32 / 100.0),
+            cell_voltage: self.cell_voltage().map(Volts),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::firmware::test_firmware;
+
+    #[test]
+    fn telemetry_to_si() {
+        let firmware = test_firmware(FirmwareVariant::Betaflight, 46);
+        let imu = MspRawImu {
+            acc_x: 0,
+            acc_y: -256,
+            acc_z: 512,
+            gyro_x: 90,
+            gyro_y: 0,
+            gyro_z: -180,
+            mag_x: 1,
+            mag_y: 2,
+            mag_z: 3,
+        };
+        let si = imu.to_si(&ImuScale::for_firmware(&firmware));
+        assert!((si.acc[2].to_g() - 1.0).abs() < 1e-6);
+        assert!((si.acc[1].0 + STANDARD_GRAVITY / 2.0).abs() < 1e-5);
+        assert!((si.gyro[0].0 - std::f32::consts::FRAC_PI_2).abs() < 1e-6);
+        assert!((si.gyro[2].to_degrees() + 180.0).abs() < 1e-4);
+        let raw = imu.to_si(&ImuScale::for_firmware(&firmware).with_acc_1g(2048.0));
+        assert!((raw.acc[2].to_g() - 0.25).abs() < 1e-6);
+
+        let attitude = MspAttitude { roll: -125, pitch: 300, yaw: 270 }.to_si();
+        assert!((attitude.roll.to_degrees() + 12.5).abs() < 1e-4);
+        assert!((attitude.yaw.to_degrees() - 270.0).abs() < 1e-4);
+
+        assert_eq!(Meters(12.34), MspAltitude { altitude: 1234, vario: -50 }.to_si().altitude);
+        let analog = MspAnalog { battery_voltage: 168, mah_drawn: 250, rssi: 1023, amperage: 150 }.to_si();
+        assert_eq!("1.50 A", format!("{:.2}", analog.current));
+        assert_eq!((Volts(16.8), 1.0), (analog.voltage, analog.rssi));
+
+        let mut battery = MspBatteryState {
+            battery_cell_count: 0,
+            battery_capacity: 1300,
+            battery_voltage: 168,
+            mah_drawn: 650,
+            amperage: -20,
+            alerts: 0,
+        };
+        assert_eq!(None, battery.to_si().cell_voltage);
+        battery.battery_cell_count = 4;
+        assert_eq!(Some(Volts(4.2)), battery.to_si().cell_voltage);
+        assert_eq!(AmpHours(0.65), battery.to_si().drawn);
+    }
+}
@@ -0,0 +1,322 @@
+//! Video transmitter band, channel, power and pit mode through the Betaflight VTX table
+
+use std::ops::RangeInclusive;
+use anyhow::{Error, Result};
+use packed_struct::PackedStructSlice;
+use serialport::SerialPort;
+
+use crate::helpers::request;
+use crate::msp::{
+    commands::MspCommandCode,
+    structs::{MspSetVtxConfig, MspVtxConfig, MspVtxTableBand, MspVtxTablePowerLevel},
+};
+
+fn too_short(what: &str, data: &[u8]) -> Error {
+    Error::msg(format!("{} payload too short, {} bytes", what, data.len()))
+}
+
+/// Split a u8 length prefixed string off the front of `data`
+fn take_string<'d>(what: &str, data: &'d [u8]) -> Result<(String, &'d [u8])> {
+    let (&len, rest) = data.split_first().ok_or_else(|| too_short(what, data))?;
+    let (text, rest) = rest.split_at_checked(len as usize).ok_or_else(|| too_short(what, data))?;
+    Ok((String::from_utf8_lossy(text).trim_end().to_owned(), rest))
+}
+
+impl MspVtxTableBand {
+    pub fn decode(data: &[u8]) -> Result<MspVtxTableBand> {
+        let (&band, rest) = data.split_first().ok_or_else(|| too_short("VTX band", data))?;
+        let (name, rest) = take_string("VTX band", rest)?;
+        let [letter, is_factory_band, count, rest @ ..] = rest else {
+            return Err(too_short("VTX band", data));
+        };
+        let frequencies = rest
+            .chunks_exact(2)
+            .take(*count as usize)
+            .map(|b| u16::from_le_bytes([b[0], b[1]]))
+            .collect::<Vec<_>>();
+        if frequencies.len() != *count as usize {
+            return Err(too_short("VTX band", data));
+        }
+
+        Ok(MspVtxTableBand { band, name, letter: *letter, is_factory_band: *is_factory_band != 0, frequencies })
+    }
+
+    /// Payload of `MSP_SET_VTXTABLE_BAND`, same layout as the reply
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![self.band, self.name.len() as u8];
+        payload.extend_from_slice(self.name.as_bytes());
+        payload.extend([self.letter, self.is_factory_band as u8, self.frequencies.len() as u8]);
+        for f in &self.frequencies {
+            payload.extend(f.to_le_bytes());
+        }
+        payload
+    }
+}
+
+impl MspVtxTablePowerLevel {
+    pub fn decode(data: &[u8]) -> Result<MspVtxTablePowerLevel> {
+        let [level, lo, hi, rest @ ..] = data else {
+            return Err(too_short("VTX power level", data));
+        };
+        let (label, _) = take_string("VTX power level", rest)?;
+        Ok(MspVtxTablePowerLevel { level: *level, value: u16::from_le_bytes([*lo, *hi]), label })
+    }
+
+    /// Payload of `MSP_SET_VTXTABLE_POWERLEVEL`, same layout as the reply
+    pub fn encode(&self) -> Vec<u8> {
+        let mut payload = vec![self.level];
+        payload.extend(self.value.to_le_bytes());
+        payload.push(self.label.len() as u8);
+        payload.extend_from_slice(self.label.as_bytes());
+        payload
+    }
+}
+
+/// The VTX of a flight controller together with its VTX table. Frequencies outside the allow
+/// list are refused before anything is sent.
+pub struct VtxControl<'a> {
+    port: &'a mut dyn SerialPort,
+    allowed: Vec<RangeInclusive<u16>>,
+    pub config: MspVtxConfig,
+    pub bands: Vec<MspVtxTableBand>,
+    pub power_levels: Vec<MspVtxTablePowerLevel>,
+}
+
+impl<'a> VtxControl<'a> {
+    /// Read the VTX state and table. `allowed` lists the frequency ranges in MHz that may be
+    /// transmitted on.
+    pub fn load(port: &'a mut dyn SerialPort, allowed: Vec<RangeInclusive<u16>>) -> Result<VtxControl<'a>> {
+        let config = Self::read_config(port)?;
+        if config.vtx_type == 0 {
+            return Err(Error::msg("Flight controller has no VTX configured"));
+        }
+
+        let mut bands = Vec::new();
+        let mut power_levels = Vec::new();
+        if config.vtx_table_available != 0 {
+            for band in 1..=config.bands {
+                let reply = request(port, MspCommandCode::MSP_VTXTABLE_BAND as u16, &[band])?;
+                bands.push(MspVtxTableBand::decode(reply.data.as_slice())?);
+            }
+            for level in 1..=config.power_levels {
+                let reply = request(port, MspCommandCode::MSP_VTXTABLE_POWERLEVEL as u16, &[level])?;
+                power_levels.push(MspVtxTablePowerLevel::decode(reply.data.as_slice())?);
+            }
+        }
+
+        Ok(VtxControl { port, allowed, config, bands, power_levels })
+    }
+
+    fn read_config(port: &mut dyn SerialPort) -> Result<MspVtxConfig> {
+        // older firmware stops before the VTX table fields
+        Ok(request(port, MspCommandCode::MSP_VTX_CONFIG as u16, &[])?.decode_as_padded::<MspVtxConfig>()?)
+    }
+
+    pub fn is_allowed(&self, frequency: u16) -> bool {
+        self.allowed.iter().any(|r| r.contains(&frequency))
+    }
+
+    /// Frequency of a 1 based band and channel, `None` if not in the table or disabled
+    pub fn frequency(&self, band: u8, channel: u8) -> Option<u16> {
+        let b = self.bands.get((band as usize).checked_sub(1)?)?;
+        b.frequencies.get((channel as usize).checked_sub(1)?).copied().filter(|f| *f != 0)
+    }
+
+    /// Band and channel of a frequency, if the table has it
+    pub fn find(&self, frequency: u16) -> Option<(u8, u8)> {
+        self.bands.iter().find_map(|b| {
+            let channel = b.frequencies.iter().position(|f| *f == frequency)?;
+            Some((b.band, channel as u8 + 1))
+        })
+    }
+
+    /// Tune to a band and channel of the VTX table
+    pub fn set_band_channel(&mut self, band: u8, channel: u8) -> Result<()> {
+        let frequency = self
+            .frequency(band, channel)
+            .ok_or_else(|| Error::msg(format!("VTX table has no band {} channel {}", band, channel)))?;
+        self.check_allowed(frequency)?;
+
+        let set = MspSetVtxConfig { band, channel, frequency, ..self.current() };
+        self.send(set)?;
+        self.verify(|c| c.band == band && c.channel == channel && c.frequency == frequency)
+    }
+
+    /// Tune to a frequency in MHz, outside of the band/channel scheme
+    pub fn set_frequency(&mut self, frequency: u16) -> Result<()> {
+        self.check_allowed(frequency)?;
+
+        let set = MspSetVtxConfig {
+            frequency_or_band_channel: frequency,
+            band: 0,
+            channel: 0,
+            frequency,
+            ..self.current()
+        };
+        self.send(set)?;
+        self.verify(|c| c.frequency == frequency)
+    }
+
+    /// Select a 1 based power level of the VTX table
+    pub fn set_power(&mut self, level: u8) -> Result<()> {
+        if level == 0 || level as usize > self.power_levels.len().max(self.config.power_levels as usize) {
+            return Err(Error::msg(format!("VTX has no power level {}", level)));
+        }
+        self.send(MspSetVtxConfig { power: level, ..self.current() })?;
+        self.verify(|c| c.power == level)
+    }
+
+    pub fn set_pit_mode(&mut self, on: bool) -> Result<()> {
+        self.send(MspSetVtxConfig { pit_mode: on as u8, ..self.current() })?;
+        self.verify(|c| (c.pit_mode != 0) == on)
+    }
+
+    /// Store the VTX settings in EEPROM
+    pub fn save(&mut self) -> Result<()> {
+        request(self.port, MspCommandCode::MSP_EEPROM_WRITE as u16, &[])?;
+        Ok(())
+    }
+
+    fn check_allowed(&self, frequency: u16) -> Result<()> {
+        if !self.is_allowed(frequency) {
+            return Err(Error::msg(format!("{} MHz is outside the allowed frequencies", frequency)));
+        }
+        Ok(())
+    }
+
+    /// A set message that leaves everything as it currently is
+    fn current(&self) -> MspSetVtxConfig {
+        let c = &self.config;
+        MspSetVtxConfig {
+            frequency_or_band_channel: c.frequency,
+            power: c.power,
+            pit_mode: c.pit_mode,
+            low_power_disarm: c.low_power_disarm,
+            pit_mode_frequency: c.pit_mode_frequency,
+            band: c.band,
+            channel: c.channel,
+            frequency: c.frequency,
+        }
+    }
+
+    fn send(&mut self, set: MspSetVtxConfig) -> Result<()> {
+        request(self.port, MspCommandCode::MSP_SET_VTX_CONFIG as u16, &set.pack_to_vec()?)?;
+        Ok(())
+    }
+
+    fn verify(&mut self, ok: impl Fn(&MspVtxConfig) -> bool) -> Result<()> {
+        self.config = Self::read_config(self.port)?;
+        if !ok(&self.config) {
+            return Err(Error::msg(format!("VTX did not take the new setting, now {:?}", self.config)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::mock::MockFc;
+    use packed_struct::PackedStruct;
+    use crate::msp::commands::MspCommandCode::*;
+    use std::sync::{Arc, Mutex};
+
+    const RACEBAND: [u16; 8] = [5658, 5695, 5732, 5769, 5806, 5843, 5880, 5917];
+
+    fn raceband() -> MspVtxTableBand {
+        MspVtxTableBand {
+            band: 1,
+            name: "RACEBAND".into(),
+            letter: b'R',
+            is_factory_band: false,
+            frequencies: RACEBAND.to_vec(),
+        }
+    }
+
+    /// SmartAudio on R1 at the lowest of two power levels
+    fn vtx_config() -> MspVtxConfig {
+        MspVtxConfig {
+            vtx_type: 3,
+            band: 1,
+            channel: 1,
+            power: 1,
+            pit_mode: 0,
+            frequency: 5658,
+            device_ready: 1,
+            low_power_disarm: 0,
+            pit_mode_frequency: 0,
+            vtx_table_available: 1,
+            bands: 1,
+            channels: 8,
+            power_levels: 2,
+        }
+    }
+
+    #[test]
+    fn band_and_power_payloads() {
+        let payload = raceband().encode();
+        assert_eq!([1, 8, b'R', b'A'], payload[..4]);
+        assert_eq!(1 + 1 + 8 + 3 + 16, payload.len());
+        assert_eq!(raceband(), MspVtxTableBand::decode(&payload).unwrap());
+        assert!(MspVtxTableBand::decode(&payload[..20]).is_err());
+
+        let level = MspVtxTablePowerLevel { level: 2, value: 200, label: "200".into() };
+        assert_eq!(vec![2, 200, 0, 3, b'2', b'0', b'0'], level.encode());
+        assert_eq!(level, MspVtxTablePowerLevel::decode(&level.encode()).unwrap());
+    }
+
+    #[test]
+    fn set_channel_within_allow_list() {
+        let state = Arc::new(Mutex::new(vtx_config()));
+        let vtx = state.clone();
+        let mut fc = MockFc::new()
+            .reply(MSP_VTXTABLE_BAND as u16, &raceband().encode())
+            .reply(MSP_VTXTABLE_POWERLEVEL as u16, &[1, 25, 0, 2, b'2', b'5'])
+            .handler(move |p| {
+                let mut c = vtx.lock().unwrap();
+                match p.cmd {
+                    x if x == MSP_VTX_CONFIG as u16 => Some(c.pack().unwrap().to_vec()),
+                    x if x == MSP_SET_VTX_CONFIG as u16 => {
+                        let set = MspSetVtxConfig::unpack_from_slice(p.data.as_slice()).unwrap();
+                        (c.band, c.channel, c.frequency) = (set.band, set.channel, set.frequency);
+                        (c.power, c.pit_mode) = (set.power, set.pit_mode);
+                        Some(Vec::new())
+                    }
+                    _ => None,
+                }
+            });
+
+        let mut vtx = VtxControl::load(&mut fc, vec![5645..=5900]).unwrap();
+        assert_eq!(Some(5806), vtx.frequency(1, 5));
+        assert_eq!(Some((1, 7)), vtx.find(5880));
+
+        vtx.set_band_channel(1, 5).unwrap();
+        assert_eq!(5806, vtx.config.frequency);
+        vtx.set_pit_mode(true).unwrap();
+        assert_eq!((1, 5, 1), (vtx.config.band, vtx.config.channel, vtx.config.pit_mode));
+
+        // R8 is 5917 MHz, outside the allow list
+        assert!(vtx.set_band_channel(1, 8).is_err());
+        assert!(vtx.set_frequency(5950).is_err());
+        assert!(vtx.set_power(3).is_err());
+        assert_eq!(5806, state.lock().unwrap().frequency);
+    }
+
+    #[test]
+    fn setting_not_taken_is_an_error() {
+        // a VTX that acknowledges every change and keeps its old settings
+        let mut fc = MockFc::new()
+            .reply(MSP_VTX_CONFIG as u16, &vtx_config().pack().unwrap())
+            .reply(MSP_SET_VTX_CONFIG as u16, &[])
+            .reply(MSP_VTXTABLE_BAND as u16, &raceband().encode())
+            .reply(MSP_VTXTABLE_POWERLEVEL as u16, &[1, 25, 0, 2, b'2', b'5']);
+        let mut vtx = VtxControl::load(&mut fc, vec![5645..=5900]).unwrap();
+
+        let error = vtx.set_power(2).unwrap_err().to_string();
+        assert!(error.starts_with("VTX did not take the new setting"));
+        assert_eq!(1, vtx.config.power);
+        assert!(vtx.set_band_channel(1, 3).is_err());
+        assert_eq!((1, 5658), (vtx.config.channel, vtx.config.frequency));
+        assert!(!fc.commands().contains(&(MSP_EEPROM_WRITE as u16)));
+    }
+}